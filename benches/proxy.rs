@@ -1,24 +1,27 @@
 #![feature(test)]
 
 extern crate env_logger;
-#[macro_use]
-extern crate log;
 extern crate futures;
 extern crate tokio_core;
 extern crate hyper;
 extern crate weldr;
-extern crate reqwest;
 extern crate test;
 
 use std::net::SocketAddr;
 use std::sync::mpsc::channel;
 use std::thread;
 
-use hyper::Post;
-use hyper::server::{Server, Service, Request, Response};
+use futures::{Future, Stream};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+use hyper::{Client, Get, Uri};
+use hyper::server::{Http, Service, Request, Response};
 use hyper::header::ContentLength;
 
+use weldr::config::Config;
 use weldr::pool::Pool;
+use weldr::server::Server;
+use weldr::vhost::Pools;
 
 use test::Bencher;
 
@@ -33,12 +36,11 @@ impl Service for Origin {
 
     fn call(&self, req: Request) -> Self::Future {
         ::futures::finished(match (req.method(), req.path()) {
-            (&Post, Some("/echo")) => {
-                let mut res = Response::new();
-                if let Some(len) = req.headers().get::<ContentLength>() {
-                    res.headers_mut().set(len.clone());
-                }
-                res.with_body(req.body())
+            (&Get, "/") => {
+                let body = "Hello World";
+                Response::new()
+                    .with_header(ContentLength(body.len() as u64))
+                    .with_body(body)
             }
             _ => {
                 panic!("benchmark should not be getting a 404");
@@ -47,54 +49,72 @@ impl Service for Origin {
     }
 }
 
-/// Send a request through the proxy and get back a response.
+/// Start an origin server and a proxy, started with `config`, in front of it
 ///
-/// The client request is to created via the callback. The callback provides the host so the client
-/// can connect to the correct proxy.
-fn with_server<R>(mut req: R)
-where
-    R: FnMut(String),
-{
+/// Returns the address of the proxy.
+fn start_proxy(config: Config) -> SocketAddr {
     let _ = env_logger::init();
 
-    let pool = Pool::with_servers(vec![]);
-
-    let addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
-    let (_, proxy) = weldr::proxy::listen(addr, pool.clone()).expect("Failed to start server");
-
     let (tx, rx) = channel();
     thread::spawn(move || {
         let addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
-        let (listening, server) = Server::standalone(|tokio| {
-            Server::http(&addr, tokio)?.handle(|| Ok(Origin), tokio)
-        }).unwrap();
-        tx.send(listening).unwrap();
-        server.run();
+        let server = Http::new().bind(&addr, || Ok(Origin)).unwrap();
+        tx.send(server.local_addr().unwrap()).unwrap();
+        server.run().unwrap();
     });
-
     let origin = rx.recv().unwrap();
-    pool.add(*origin.addr());
 
-    req(format!("http://127.0.0.1:{}", origin.addr().port()));
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+        let listener = TcpListener::bind(&addr, &handle).unwrap();
+        tx.send(listener.local_addr().unwrap()).unwrap();
+
+        let pool = Pool::default();
+        let url = format!("http://127.0.0.1:{}", origin.port()).parse::<Uri>().unwrap();
+        pool.add(Server::new(url, false));
+
+        let srv = weldr::proxy::serve(listener, Pools::from(pool), &handle, &config).unwrap();
+        core.run(srv).unwrap();
+    });
 
-    proxy.close();
-    origin.close();
+    rx.recv().unwrap()
 }
 
+/// Send requests through a proxy started with `config`
+///
+/// Each request is sent by a new client, as if it came from a different user, so the proxy can
+/// only reuse a connection to the origin server if it keeps them open between clients.
+fn bench_requests(b: &mut Bencher, config: Config) {
+    let proxy = start_proxy(config);
+    let url = format!("http://{}/", proxy).parse::<Uri>().unwrap();
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    b.iter(|| {
+        let client = Client::new(&handle);
+        let work = client.get(url.clone()).and_then(|res| res.body().concat2());
+        core.run(work).unwrap()
+    });
+}
 
 #[bench]
 // This is a relative benchmark as the cost of sending the request and the cost of the response
 // from the hyper origin server is included.
-fn bench_server_hello_world(b: &mut Bencher) {
-    with_server(|host| {
-
-        let url = format!("{}{}", host, "/echo");
-        let client = reqwest::Client::new().expect("client failed to construct");
+fn bench_keep_alive(b: &mut Bencher) {
+    bench_requests(b, Config::default());
+}
 
-        b.iter(|| {
-            let res = client.post(&url).body("hello").send().unwrap();
+#[bench]
+// Opens a new connection to the origin server for every request. Compare with `bench_keep_alive`
+// to see the cost of connecting.
+fn bench_without_keep_alive(b: &mut Bencher) {
+    let mut config = Config::default();
+    config.keep_alive.enabled = false;
 
-            res
-        })
-    });
+    bench_requests(b, config);
 }
@@ -0,0 +1,661 @@
+//! Strategies used by the pool to pick a backend for each request
+
+use std::cmp;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::{self, FromStr};
+use std::time::Duration;
+
+use hyper::{Headers, Uri};
+use rand::{self, Rng, XorShiftRng};
+
+use pool::Backend;
+
+/// Number of points each unit of weight puts on the consistent hash ring
+const RING_POINTS_PER_WEIGHT: u64 = 160;
+
+/// Largest number of points on the consistent hash ring. When the weights would put more points
+/// on the ring, each backend gets fewer points in proportion to its weight.
+const MAX_RING_POINTS: u64 = 1 << 18;
+
+/// Picks the backend the next request is sent to
+pub trait Balancer: fmt::Debug {
+    /// Pick one of the active `backends`
+    ///
+    /// The `key` identifies the request for balancers that send related requests to the same
+    /// backend. Returns `None` if there are no active backends.
+    fn pick(&mut self, backends: &[Backend], key: Option<&[u8]>) -> Option<Backend>;
+
+    /// Called with the new list of backends whenever a backend is added to or removed from the pool
+    fn update(&mut self, backends: &[Backend]);
+}
+
+/// Latency, in seconds, recorded for a request that fails without a response and assumed for a
+/// backend that has not responded yet, unless set otherwise
+pub const DEFAULT_PENALTY_SECS: u64 = 2;
+
+/// The balancing strategies that can be configured
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Strategy {
+    RoundRobin,
+    LeastRequests,
+    ConsistentHash(HashKey),
+    P2cEwma,
+}
+
+impl Strategy {
+    /// Create a new balancer that implements this strategy
+    ///
+    /// `penalty` is the latency assumed for a backend that has not responded yet, which is only
+    /// used by `P2cEwma`.
+    pub fn balancer(&self, penalty: Duration) -> Box<Balancer> {
+        match *self {
+            Strategy::RoundRobin => Box::new(RoundRobin::new()),
+            Strategy::LeastRequests => Box::new(LeastRequests::new()),
+            Strategy::ConsistentHash(_) => Box::new(ConsistentHash::new()),
+            Strategy::P2cEwma => Box::new(P2cEwma::new(penalty)),
+        }
+    }
+
+    /// The part of the request the balancer uses to pick a backend, if any
+    pub fn hash_key(&self) -> Option<&HashKey> {
+        match *self {
+            Strategy::ConsistentHash(ref key) => Some(key),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Strategy {
+    fn default() -> Strategy {
+        Strategy::RoundRobin
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    /// Parse the name of a strategy
+    ///
+    /// A `consistent_hash` strategy is keyed by the client ip. Use `Strategy::ConsistentHash`
+    /// directly to hash a different part of the request.
+    fn from_str(s: &str) -> Result<Strategy, String> {
+        match s {
+            "round_robin" => Ok(Strategy::RoundRobin),
+            "least_requests" => Ok(Strategy::LeastRequests),
+            "consistent_hash" => Ok(Strategy::ConsistentHash(HashKey::ClientIp)),
+            "p2c_ewma" => Ok(Strategy::P2cEwma),
+            _ => Err(format!("unknown balancer strategy {:?}", s)),
+        }
+    }
+}
+
+/// The part of a request hashed to pick a backend
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HashKey {
+    ClientIp,
+    Path,
+    Header(String),
+    Cookie(String),
+}
+
+impl HashKey {
+    /// Get the key of a request
+    ///
+    /// Returns `None` if the request does not have the header or cookie used as the key.
+    pub fn extract(&self, client: &SocketAddr, uri: &Uri, headers: &Headers) -> Option<Vec<u8>> {
+        match *self {
+            HashKey::ClientIp => Some(client.ip().to_string().into_bytes()),
+            HashKey::Path => Some(uri.path().as_bytes().to_vec()),
+            HashKey::Header(ref name) => {
+                headers
+                    .get_raw(name)
+                    .and_then(|raw| raw.one())
+                    .map(|value| value.to_vec())
+            }
+            HashKey::Cookie(ref name) => cookie(headers, name).map(|value| value.as_bytes().to_vec()),
+        }
+    }
+}
+
+impl FromStr for HashKey {
+    type Err = String;
+
+    /// Parse `client_ip`, `path`, `header:<name>` or `cookie:<name>`
+    fn from_str(s: &str) -> Result<HashKey, String> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("client_ip"), None) => Ok(HashKey::ClientIp),
+            (Some("path"), None) => Ok(HashKey::Path),
+            (Some("header"), Some(name)) if !name.is_empty() => Ok(HashKey::Header(name.to_string())),
+            (Some("cookie"), Some(name)) if !name.is_empty() => Ok(HashKey::Cookie(name.to_string())),
+            _ => Err(format!("unknown hash key {:?}", s)),
+        }
+    }
+}
+
+/// Find the value of the cookie called `name` in the request headers
+pub fn cookie<'a>(headers: &'a Headers, name: &str) -> Option<&'a str> {
+    let raw = match headers.get_raw("Cookie") {
+        Some(raw) => raw,
+        None => return None,
+    };
+
+    for line in raw.iter() {
+        let line = match str::from_utf8(line) {
+            Ok(line) => line,
+            Err(_) => continue,
+        };
+
+        for pair in line.split(';') {
+            let mut pair = pair.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some(key), Some(value)) if key.trim() == name => return Some(value.trim()),
+                _ => continue,
+            }
+        }
+    }
+
+    None
+}
+
+/// Remove the cookie called `name` from the request headers, along with the `Cookie` header if
+/// no other cookies are left
+pub fn remove_cookie(headers: &mut Headers, name: &str) {
+    let lines: Vec<Vec<u8>> = match headers.get_raw("Cookie") {
+        Some(raw) => {
+            raw.iter()
+                .map(|line| match str::from_utf8(line) {
+                    Ok(line) => {
+                        line.split(';')
+                            .filter(|pair| pair.splitn(2, '=').next().map(str::trim) != Some(name))
+                            .map(str::trim)
+                            .filter(|pair| !pair.is_empty())
+                            .collect::<Vec<&str>>()
+                            .join("; ")
+                            .into_bytes()
+                    }
+                    Err(_) => line.to_vec(),
+                })
+                .filter(|line| !line.is_empty())
+                .collect()
+        }
+        None => return,
+    };
+
+    if lines.is_empty() {
+        headers.remove_raw("Cookie");
+    } else {
+        headers.set_raw("Cookie", lines);
+    }
+}
+
+/// Smooth weighted round-robin
+///
+/// This is the algorithm used by nginx. A server with a weight of 3 is sent three times as many
+/// requests as a server with a weight of 1, and those requests are spread out instead of being
+/// sent in a burst.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    /// The current weight of each backend, in the same order as the backends
+    current_weights: Vec<i64>,
+}
+
+impl RoundRobin {
+    pub fn new() -> RoundRobin {
+        RoundRobin::default()
+    }
+}
+
+impl Balancer for RoundRobin {
+    /// Each active backend has its weight added to its current weight. The backend with the
+    /// highest current weight is picked and has the total weight of all active backends subtracted
+    /// from its current weight.
+    fn pick(&mut self, backends: &[Backend], _key: Option<&[u8]>) -> Option<Backend> {
+        if self.current_weights.len() != backends.len() {
+            self.update(backends);
+        }
+
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for (i, backend) in backends.iter().enumerate() {
+            if !backend.is_active() {
+                continue;
+            }
+
+            let weight = backend.weight() as i64;
+            self.current_weights[i] += weight;
+            total += weight;
+
+            best = match best {
+                Some(b) if self.current_weights[b] >= self.current_weights[i] => Some(b),
+                _ => Some(i),
+            };
+        }
+
+        best.map(|i| {
+            self.current_weights[i] -= total;
+            backends[i].clone()
+        })
+    }
+
+    fn update(&mut self, backends: &[Backend]) {
+        self.current_weights = vec![0; backends.len()];
+    }
+}
+
+/// Send each request to the active backend with the fewest requests in flight
+///
+/// Ties are broken round-robin, so an idle pool is still spread across all backends. Weights are
+/// not taken into account.
+#[derive(Debug, Default)]
+pub struct LeastRequests {
+    /// Where to start looking for the backend with the fewest requests in flight
+    next: usize,
+}
+
+impl LeastRequests {
+    pub fn new() -> LeastRequests {
+        LeastRequests::default()
+    }
+}
+
+impl Balancer for LeastRequests {
+    fn pick(&mut self, backends: &[Backend], _key: Option<&[u8]>) -> Option<Backend> {
+        let len = backends.len();
+        let mut best: Option<&Backend> = None;
+        let mut best_index = 0;
+        for offset in 0..len {
+            let i = (self.next + offset) % len;
+            let backend = &backends[i];
+            if !backend.is_active() {
+                continue;
+            }
+
+            let fewer = match best {
+                Some(b) => backend.in_flight() < b.in_flight(),
+                None => true,
+            };
+
+            if fewer {
+                best = Some(backend);
+                best_index = i;
+            }
+        }
+
+        best.map(|backend| {
+            self.next = best_index + 1;
+            backend.clone()
+        })
+    }
+
+    fn update(&mut self, _backends: &[Backend]) {
+        self.next = 0;
+    }
+}
+
+/// Send requests with the same key to the same backend
+///
+/// Each backend is placed on a hash ring many times, in proportion to its weight. A request goes
+/// to the first backend found on the ring at or after the hash of its key. Adding or removing a
+/// backend only moves the keys next to its points on the ring, about 1/N of all keys. If that
+/// backend is down, the ring is walked until an active backend is found. Requests without a key
+/// are balanced round-robin.
+#[derive(Debug, Default)]
+pub struct ConsistentHash {
+    /// Points on the ring, sorted by hash, with the index of the backend they belong to
+    ring: Vec<(u64, usize)>,
+
+    /// Number of backends the ring was built from
+    len: usize,
+
+    fallback: RoundRobin,
+}
+
+impl ConsistentHash {
+    pub fn new() -> ConsistentHash {
+        ConsistentHash::default()
+    }
+}
+
+impl Balancer for ConsistentHash {
+    fn pick(&mut self, backends: &[Backend], key: Option<&[u8]>) -> Option<Backend> {
+        let key = match key {
+            Some(key) => key,
+            None => return self.fallback.pick(backends, None),
+        };
+
+        if self.len != backends.len() {
+            self.update(backends);
+        }
+
+        if self.ring.is_empty() {
+            return None;
+        }
+
+        let hash = hash(key);
+        let start = match self.ring.binary_search_by(|&(point, _)| point.cmp(&hash)) {
+            Ok(i) => i,
+            Err(i) => i % self.ring.len(),
+        };
+
+        for offset in 0..self.ring.len() {
+            let (_, i) = self.ring[(start + offset) % self.ring.len()];
+            if backends[i].is_active() {
+                return Some(backends[i].clone());
+            }
+        }
+
+        None
+    }
+
+    fn update(&mut self, backends: &[Backend]) {
+        let total: u64 = backends.iter().map(|backend| u64::from(backend.weight())).sum();
+        let points = |weight: u64| if total * RING_POINTS_PER_WEIGHT <= MAX_RING_POINTS {
+            weight * RING_POINTS_PER_WEIGHT
+        } else {
+            cmp::max(1, weight * MAX_RING_POINTS / total)
+        };
+
+        let mut ring = Vec::new();
+        for (i, backend) in backends.iter().enumerate() {
+            let url = backend.server().url();
+            for point in 0..points(u64::from(backend.weight())) {
+                ring.push((hash(format!("{}-{}", url, point).as_bytes()), i));
+            }
+        }
+        ring.sort();
+
+        self.ring = ring;
+        self.len = backends.len();
+        self.fallback.update(backends);
+    }
+}
+
+/// Power of two choices, scored by latency
+///
+/// Two active backends are sampled at random and the request is sent to the one with the lower
+/// score. The score is the moving average of the latency of a backend multiplied by the number of
+/// requests in flight to it, plus one. A backend that is slow, but not failing, is sent fewer
+/// requests without being taken out of the pool. Backends that have not responded yet are scored
+/// as if their latency was `penalty`, so a new backend is not sent every request at once. The pool
+/// records a penalty for each request that fails without a response, so a backend that refuses
+/// connections is not picked over the others. Weights are not taken into account.
+#[derive(Debug)]
+pub struct P2cEwma {
+    rng: XorShiftRng,
+    penalty: Duration,
+}
+
+impl P2cEwma {
+    pub fn new(penalty: Duration) -> P2cEwma {
+        P2cEwma::with_rng(rand::weak_rng(), penalty)
+    }
+
+    /// Create a balancer that samples backends using `rng`
+    pub fn with_rng(rng: XorShiftRng, penalty: Duration) -> P2cEwma {
+        P2cEwma {
+            rng: rng,
+            penalty: penalty,
+        }
+    }
+
+    fn score(&self, backend: &Backend) -> u64 {
+        let latency = backend.latency().unwrap_or(self.penalty);
+        let latency = latency.as_secs() * 1_000_000_000 + latency.subsec_nanos() as u64;
+
+        latency.saturating_mul(backend.in_flight() as u64 + 1)
+    }
+}
+
+impl Default for P2cEwma {
+    fn default() -> P2cEwma {
+        P2cEwma::new(Duration::from_secs(DEFAULT_PENALTY_SECS))
+    }
+}
+
+impl Balancer for P2cEwma {
+    fn pick(&mut self, backends: &[Backend], _key: Option<&[u8]>) -> Option<Backend> {
+        let active: Vec<&Backend> = backends.iter().filter(|b| b.is_active()).collect();
+
+        match active.len() {
+            0 => None,
+            1 => Some(active[0].clone()),
+            len => {
+                let a = self.rng.gen_range(0, len);
+                let mut b = self.rng.gen_range(0, len - 1);
+                if b >= a {
+                    b += 1;
+                }
+
+                let (a, b) = (active[a], active[b]);
+                if self.score(b) < self.score(a) {
+                    Some(b.clone())
+                } else {
+                    Some(a.clone())
+                }
+            }
+        }
+    }
+
+    fn update(&mut self, _backends: &[Backend]) {}
+}
+
+/// FNV-1a, followed by the murmur3 finalizer to spread similar keys across the whole ring
+///
+/// The hash must be the same in every worker, including workers started from a newer binary,
+/// so the hashers in the standard library are not used.
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^= h >> 33;
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use hyper::Headers;
+    use rand::{SeedableRng, XorShiftRng};
+
+    use super::{cookie, remove_cookie, Balancer, ConsistentHash, HashKey, LeastRequests, P2cEwma, Strategy};
+    use pool::Backend;
+    use server::Server;
+
+    fn backend(port: u16) -> Backend {
+        Backend::new(Server::new(
+            FromStr::from_str(&format!("http://127.0.0.1:{}", port)).unwrap(),
+            false,
+        ))
+    }
+
+    #[test]
+    fn test_least_requests() {
+        let backends = vec![backend(6000), backend(6001), backend(6002)];
+        let mut balancer = LeastRequests::new();
+
+        // with nothing in flight, backends are picked round-robin
+        assert_eq!(backends[0], balancer.pick(&backends, None).unwrap());
+        assert_eq!(backends[1], balancer.pick(&backends, None).unwrap());
+        assert_eq!(backends[2], balancer.pick(&backends, None).unwrap());
+        assert_eq!(backends[0], balancer.pick(&backends, None).unwrap());
+
+        backends[0].inc_in_flight();
+        backends[1].inc_in_flight();
+        backends[1].inc_in_flight();
+        assert_eq!(backends[2], balancer.pick(&backends, None).unwrap());
+        backends[2].inc_in_flight();
+        assert_eq!(backends[0], balancer.pick(&backends, None).unwrap());
+
+        backends[0].dec_in_flight();
+        backends[2].mark_down();
+        assert_eq!(backends[0], balancer.pick(&backends, None).unwrap());
+
+        backends[0].mark_down();
+        backends[1].mark_down();
+        assert!(balancer.pick(&backends, None).is_none());
+    }
+
+    #[test]
+    fn test_consistent_hash() {
+        let mut backends: Vec<_> = (0..5).map(|i| backend(6000 + i)).collect();
+        let mut balancer = ConsistentHash::new();
+        balancer.update(&backends);
+
+        let keys: Vec<String> = (0..1000).map(|i| format!("10.0.{}.{}", i / 256, i % 256)).collect();
+        let picked: Vec<_> = keys.iter()
+            .map(|key| balancer.pick(&backends, Some(key.as_bytes())).unwrap())
+            .collect();
+
+        // the same key always goes to the same backend
+        for (key, backend) in keys.iter().zip(picked.iter()) {
+            assert_eq!(*backend, balancer.pick(&backends, Some(key.as_bytes())).unwrap());
+        }
+
+        // a down backend only moves its own keys
+        backends[1].mark_down();
+        for (key, backend) in keys.iter().zip(picked.iter()) {
+            let now = balancer.pick(&backends, Some(key.as_bytes())).unwrap();
+            assert!(now != backends[1]);
+            if *backend != backends[1] {
+                assert_eq!(*backend, now);
+            }
+        }
+        backends[1].mark_active();
+
+        // adding a backend only moves about 1/N of the keys, all of them to the new backend
+        backends.push(backend(6005));
+        balancer.update(&backends);
+        let mut moved = 0;
+        for (key, backend) in keys.iter().zip(picked.iter()) {
+            let now = balancer.pick(&backends, Some(key.as_bytes())).unwrap();
+            if *backend != now {
+                assert_eq!(backends[5], now);
+                moved += 1;
+            }
+        }
+        assert!(moved > 100 && moved < 250, "moved {} keys", moved);
+
+        for backend in backends.iter() {
+            backend.mark_down();
+        }
+        assert!(balancer.pick(&backends, Some(b"10.0.0.1")).is_none());
+    }
+
+    #[test]
+    fn test_consistent_hash_large_weights() {
+        let backends = vec![
+            Backend::new(backend(6000).server().with_weight(u32::max_value())),
+            Backend::new(backend(6001).server().with_weight(u32::max_value() / 4)),
+        ];
+        let mut balancer = ConsistentHash::new();
+        balancer.update(&backends);
+
+        // the ring is kept small, and the points stay proportional to the weights
+        assert!(balancer.ring.len() as u64 <= super::MAX_RING_POINTS);
+        let first = balancer.ring.iter().filter(|&&(_, i)| i == 0).count();
+        assert_eq!(4, first / (balancer.ring.len() - first));
+    }
+
+    #[test]
+    fn test_p2c_ewma() {
+        let backends = vec![backend(6000), backend(6001)];
+        let mut balancer = P2cEwma::with_rng(XorShiftRng::from_seed([1, 2, 3, 4]), Duration::from_millis(50));
+
+        // with two backends, both are always sampled and the faster one wins
+        backends[0].record_latency(Duration::from_millis(100));
+        backends[1].record_latency(Duration::from_millis(10));
+        for _ in 0..10 {
+            assert_eq!(backends[1], balancer.pick(&backends, None).unwrap());
+        }
+
+        // until it has enough requests in flight to be slower
+        for _ in 0..10 {
+            backends[1].inc_in_flight();
+        }
+        assert_eq!(backends[0], balancer.pick(&backends, None).unwrap());
+
+        // a backend that has not responded yet is scored with the penalty, so it is tried before
+        // slower backends, but not sent every request while its first ones are in flight
+        let mut backends = backends;
+        backends.push(backend(6002));
+        let mut picked = 0;
+        for _ in 0..300 {
+            if backends[2] == balancer.pick(&backends, None).unwrap() {
+                picked += 1;
+            }
+        }
+        assert!(picked > 150, "picked {} times", picked);
+
+        backends[2].inc_in_flight();
+        backends[2].inc_in_flight();
+        for _ in 0..10 {
+            assert!(backends[2] != balancer.pick(&backends, None).unwrap());
+        }
+        backends[2].dec_in_flight();
+        backends[2].dec_in_flight();
+
+        backends[0].mark_down();
+        backends[2].mark_down();
+        assert_eq!(backends[1], balancer.pick(&backends, None).unwrap());
+
+        backends[1].mark_down();
+        assert!(balancer.pick(&backends, None).is_none());
+    }
+
+    #[test]
+    fn test_hash_key() {
+        let client = "10.0.0.1:5000".parse().unwrap();
+        let uri = "/users/1?a=b".parse().unwrap();
+        let mut headers = Headers::new();
+        headers.set_raw("X-User", "abc");
+        headers.set_raw("Cookie", "a=1; session=xyz");
+
+        let extract = |key: &str| key.parse::<HashKey>().unwrap().extract(&client, &uri, &headers);
+        assert_eq!(Some(b"10.0.0.1".to_vec()), extract("client_ip"));
+        assert_eq!(Some(b"/users/1".to_vec()), extract("path"));
+        assert_eq!(Some(b"abc".to_vec()), extract("header:X-User"));
+        assert_eq!(None, extract("header:X-Missing"));
+        assert_eq!(Some(b"xyz".to_vec()), extract("cookie:session"));
+        assert_eq!(None, extract("cookie:missing"));
+
+        assert!("header:".parse::<HashKey>().is_err());
+        assert!("query".parse::<HashKey>().is_err());
+        assert_eq!(None, cookie(&Headers::new(), "session"));
+    }
+
+    #[test]
+    fn test_remove_cookie() {
+        let mut headers = Headers::new();
+        headers.set_raw("Cookie", "a=1; weldr=abc; b=2");
+        remove_cookie(&mut headers, "weldr");
+        assert_eq!(None, cookie(&headers, "weldr"));
+        assert_eq!(Some(&b"a=1; b=2"[..]), headers.get_raw("Cookie").and_then(|raw| raw.one()));
+
+        headers.set_raw("Cookie", "weldr=abc");
+        remove_cookie(&mut headers, "weldr");
+        assert!(headers.get_raw("Cookie").is_none());
+    }
+
+    #[test]
+    fn test_strategy_from_str() {
+        assert_eq!(Ok(Strategy::RoundRobin), "round_robin".parse());
+        assert_eq!(Ok(Strategy::LeastRequests), "least_requests".parse());
+        assert_eq!(
+            Ok(Strategy::ConsistentHash(HashKey::ClientIp)),
+            "consistent_hash".parse()
+        );
+        assert_eq!(Ok(Strategy::P2cEwma), "p2c_ewma".parse());
+        assert!("random".parse::<Strategy>().is_err());
+    }
+}
@@ -1,9 +1,131 @@
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-#[derive(Debug, Default, Clone)]
+use hyper::Uri;
+use toml;
+
+use balancer::{HashKey, Strategy};
+use forwarded::Cidr;
+use header_rules::HeaderRules;
+use proxy_protocol::ProxyProtocol;
+use tls::{Certificate, ServerTls};
+use server::{Server, DEFAULT_WEIGHT, MAX_WEIGHT};
+use route::{Route, RouteSpec};
+use vhost;
+
+#[derive(Debug, Clone)]
 pub struct Config {
     pub health_check: HealthCheck,
     pub timeout: Timeout,
+
+    /// Reuse of connections to the servers
+    pub keep_alive: KeepAlive,
+
+    /// Passive health checking of live traffic in each worker, if enabled
+    pub outlier_detection: Option<OutlierDetection>,
+
+    /// Sending failed requests again to another server, which is disabled by default
+    pub retry: Retry,
+
+    /// Responses sent to the client when a request cannot be proxied
+    pub error_pages: ErrorPages,
+
+    /// Headers added to each request to tell the server about the client
+    pub forwarded: ForwardedHeaders,
+
+    /// How workers pick the server each request is sent to
+    pub balancer: Strategy,
+
+    /// Name of the cookie used to keep sending a client to the same server, if any
+    pub sticky_cookie: Option<String>,
+
+    /// Amount of time to wait for in-flight requests to finish when shutting down
+    pub drain_timeout: Duration,
+
+    /// Number of worker processes started by the manager
+    pub workers: usize,
+
+    /// Address the workers accept client connections on
+    pub listen: SocketAddr,
+
+    /// Whether client connections start with a PROXY protocol header, as sent by a load balancer
+    /// in front of weldr
+    pub proxy_protocol: bool,
+
+    /// A second listener that terminates TLS, if enabled
+    pub tls: Option<Tls>,
+
+    /// Address of the management API
+    pub admin: SocketAddr,
+
+    /// Address the manager uses to publish changes to the workers
+    pub internal: SocketAddr,
+
+    /// Servers added to the default pool when the manager starts
+    pub servers: Vec<Server>,
+
+    /// Named pools that serve requests for their hostnames instead of the default pool
+    pub pools: Vec<NamedPool>,
+
+    /// Routes that pick the pool of a request by its path, in the order they are checked
+    pub routes: Vec<Route>,
+
+    /// Rules that change the headers of requests to the default pool and their responses, if set
+    pub headers: Option<HeaderRules>,
+
+    /// File the pool is saved to whenever it changes, so it can be restored on start
+    pub state_file: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            health_check: HealthCheck::default(),
+            timeout: Timeout::default(),
+            keep_alive: KeepAlive::default(),
+            outlier_detection: None,
+            retry: Retry::default(),
+            error_pages: ErrorPages::default(),
+            forwarded: ForwardedHeaders::default(),
+            balancer: Strategy::default(),
+            sticky_cookie: None,
+            drain_timeout: Duration::from_secs(30),
+            workers: 5,
+            listen: "0.0.0.0:8080".parse().unwrap(),
+            proxy_protocol: false,
+            tls: None,
+            admin: "0.0.0.0:8687".parse().unwrap(),
+            internal: "127.0.0.1:4000".parse().unwrap(),
+            servers: Vec::new(),
+            pools: Vec::new(),
+            routes: Vec::new(),
+            headers: None,
+            state_file: None,
+        }
+    }
+}
+
+impl Config {
+    /// Load the configuration from a TOML file
+    ///
+    /// Any value missing from the file keeps its default.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+
+        Config::parse(&contents)
+    }
+
+    /// Parse the configuration from a TOML string
+    pub fn parse(contents: &str) -> Result<Config, ConfigError> {
+        let raw: raw::Config = toml::from_str(contents)?;
+        raw.into_config()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -32,6 +154,208 @@ impl Default for HealthCheck {
     }
 }
 
+/// Eject servers that fail live requests from the pool of a worker for a while
+///
+/// A request fails when the server responds with a 5xx status or cannot be reached.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutlierDetection {
+    /// The number of consecutive failed requests that eject a server, if any
+    pub consecutive_errors: Option<u32>,
+
+    /// The percentage of failed requests within a window that ejects a server, if any
+    pub error_rate: Option<u32>,
+
+    /// The amount of time the error rate is measured over
+    pub window: Duration,
+
+    /// The number of requests a server must receive within a window before its error rate is used
+    pub min_requests: u32,
+
+    /// The amount of time a server is ejected for the first time. This doubles on each ejection
+    /// after that.
+    pub base_ejection: Duration,
+
+    /// The longest amount of time a server is ejected for
+    pub max_ejection: Duration,
+
+    /// The percentage of servers in the pool that may be ejected at the same time
+    pub max_ejected_percent: u32,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> OutlierDetection {
+        OutlierDetection {
+            consecutive_errors: Some(5),
+            error_rate: None,
+            window: Duration::from_secs(10),
+            min_requests: 20,
+            base_ejection: Duration::from_secs(30),
+            max_ejection: Duration::from_secs(300),
+            max_ejected_percent: 50,
+        }
+    }
+}
+
+/// Send failed requests again to a different server
+#[derive(Debug, Clone, PartialEq)]
+pub struct Retry {
+    /// The number of times a request is retried. A value of `0` disables retries.
+    pub attempts: u32,
+
+    /// Response statuses that cause an idempotent request to be retried
+    ///
+    /// Requests that failed to connect to the server are retried regardless of the method.
+    pub statuses: Vec<u16>,
+
+    /// The percentage of requests each worker may retry
+    pub budget_percent: u32,
+
+    /// The number of retries each worker may make regardless of the budget percentage
+    pub budget_min_retries: u32,
+
+    /// The largest request body that is kept in memory so the request can be retried
+    pub max_body_bytes: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Retry {
+        Retry {
+            attempts: 0,
+            statuses: vec![502, 503, 504],
+            budget_percent: 20,
+            budget_min_retries: 10,
+            max_body_bytes: 64 * 1024,
+        }
+    }
+}
+
+/// The headers added to each request to tell the server about the client
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardedHeaders {
+    pub x_forwarded_for: bool,
+    pub x_forwarded_proto: bool,
+    pub x_forwarded_host: bool,
+    pub x_real_ip: bool,
+
+    /// The `Forwarded` header defined in RFC 7239
+    pub forwarded: bool,
+
+    /// Clients whose forwarding headers are kept and appended to, instead of replaced
+    pub trusted_proxies: Vec<Cidr>,
+}
+
+impl Default for ForwardedHeaders {
+    fn default() -> ForwardedHeaders {
+        ForwardedHeaders {
+            x_forwarded_for: true,
+            x_forwarded_proto: true,
+            x_forwarded_host: true,
+            x_real_ip: false,
+            forwarded: false,
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+/// The responses sent to the client when a request cannot be sent to a server
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorPages {
+    /// The value of the `Retry-After` header sent with a 503 response
+    pub retry_after: Duration,
+
+    /// Sent with a 502 response when a server could not be reached or sent an invalid response
+    pub bad_gateway: ErrorPage,
+
+    /// Sent with a 503 response when there is no active server in the pool
+    pub service_unavailable: ErrorPage,
+
+    /// Sent with a 504 response when a server took too long to respond
+    pub gateway_timeout: ErrorPage,
+}
+
+impl Default for ErrorPages {
+    fn default() -> ErrorPages {
+        ErrorPages {
+            retry_after: Duration::from_secs(10),
+            bad_gateway: ErrorPage::text("502 Bad Gateway"),
+            service_unavailable: ErrorPage::text("503 Service Unavailable"),
+            gateway_timeout: ErrorPage::text("504 Gateway Timeout"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorPage {
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+impl ErrorPage {
+    fn text(body: &str) -> ErrorPage {
+        ErrorPage {
+            content_type: "text/plain; charset=utf-8".to_string(),
+            body: format!("{}\n", body).into_bytes(),
+        }
+    }
+
+    /// Load an error page from a file
+    ///
+    /// The content type is based on the extension of the file. Files ending in `.html` or `.htm`
+    /// are sent as HTML and files ending in `.json` are sent as JSON. Any other file is sent as
+    /// plain text.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<ErrorPage> {
+        let path = path.as_ref();
+        let mut body = Vec::new();
+        File::open(path)?.read_to_end(&mut body)?;
+
+        let content_type = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("html") | Some("htm") => "text/html; charset=utf-8",
+            Some("json") => "application/json",
+            _ => "text/plain; charset=utf-8",
+        };
+
+        Ok(ErrorPage {
+            content_type: content_type.to_string(),
+            body: body,
+        })
+    }
+}
+
+/// Accept HTTPS connections from clients
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tls {
+    /// Address the workers accept TLS connections on
+    pub listen: SocketAddr,
+
+    /// The certificates served to clients. The first one is served to clients that ask for a
+    /// hostname without a certificate.
+    pub certificates: Vec<TlsCertificate>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsCertificate {
+    /// Hostnames the certificate is served for, which may start with a `*.` wildcard
+    pub hostnames: Vec<String>,
+
+    pub certificate: Certificate,
+}
+
+/// A pool that serves requests for a set of hostnames
+#[derive(Debug, Clone)]
+pub struct NamedPool {
+    /// Name of the pool in the management API
+    pub name: String,
+
+    /// Hostnames the pool serves, which may start with a `*.` wildcard
+    pub hosts: Vec<String>,
+
+    /// Servers added to the pool when the manager starts
+    pub servers: Vec<Server>,
+
+    /// Rules that change the headers of requests to the pool and their responses, if set
+    pub headers: Option<HeaderRules>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Timeout {
     /// Amount of time to wait connecting
@@ -42,6 +366,10 @@ pub struct Timeout {
 
     /// Amount of time to wait reading response
     pub read: Option<Duration>,
+
+    /// Amount of time an upgraded connection, such as a WebSocket, may go without sending a byte
+    /// either way
+    pub tunnel_idle: Option<Duration>,
 }
 
 impl Default for Timeout {
@@ -50,6 +378,860 @@ impl Default for Timeout {
             connect: Some(Duration::from_millis(200)),
             write: Some(Duration::from_secs(2)),
             read: Some(Duration::from_secs(2)),
+            tunnel_idle: Some(Duration::from_secs(300)),
+        }
+    }
+}
+
+/// Connections to the servers are kept open after a response and reused by later requests from
+/// any client of the worker
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeepAlive {
+    /// Whether connections are reused. When disabled, each request opens a new connection.
+    pub enabled: bool,
+
+    /// The number of connections to each server that are kept open while no request is using
+    /// them
+    ///
+    /// This is a soft limit. A connection is closed once its response is read if more requests
+    /// than this are in flight to the server, so the number of idle connections can briefly go
+    /// over it.
+    pub max_idle_per_server: usize,
+
+    /// Amount of time an unused connection is kept open, or forever if `None`
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for KeepAlive {
+    fn default() -> KeepAlive {
+        KeepAlive {
+            enabled: true,
+            max_idle_per_server: 32,
+            idle_timeout: Some(Duration::from_secs(90)),
+        }
+    }
+}
+
+/// An error loading the configuration
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file could not be read
+    Io(io::Error),
+
+    /// The configuration file is not valid TOML or has values of the wrong type
+    Parse(toml::de::Error),
+
+    /// A value in the configuration is not allowed
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref e) => write!(f, "failed to read config: {}", e),
+            ConfigError::Parse(ref e) => write!(f, "failed to parse config: {}", e),
+            ConfigError::Invalid(ref msg) => write!(f, "invalid config: {}", msg),
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn description(&self) -> &str {
+        match *self {
+            ConfigError::Io(_) => "failed to read config",
+            ConfigError::Parse(_) => "failed to parse config",
+            ConfigError::Invalid(_) => "invalid config",
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> ConfigError {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> ConfigError {
+        ConfigError::Parse(e)
+    }
+}
+
+/// The configuration file as written by the user
+///
+/// Durations are written as an integer number of seconds or milliseconds, as noted by the name of
+/// the key.
+mod raw {
+    use super::*;
+
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Config {
+        workers: Option<usize>,
+        listen: Option<String>,
+        proxy_protocol: Option<bool>,
+        tls: Option<Tls>,
+        admin: Option<String>,
+        internal: Option<String>,
+        drain_timeout_secs: Option<u64>,
+        health_check: Option<HealthCheck>,
+        timeout: Option<Timeout>,
+        keep_alive: Option<KeepAlive>,
+        outlier_detection: Option<OutlierDetection>,
+        retry: Option<Retry>,
+        error_pages: Option<ErrorPages>,
+        forwarded: Option<ForwardedHeaders>,
+        balancer: Option<Balancer>,
+        #[serde(default)]
+        servers: Vec<Server>,
+        #[serde(default)]
+        pools: Vec<NamedPool>,
+        #[serde(default)]
+        routes: Vec<RouteSpec>,
+        headers: Option<HeaderRules>,
+        state_file: Option<PathBuf>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct NamedPool {
+        name: String,
+        hosts: Vec<String>,
+        #[serde(default)]
+        servers: Vec<Server>,
+        headers: Option<HeaderRules>,
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct HealthCheck {
+        interval_secs: Option<u64>,
+        uri_path: Option<String>,
+        failures: Option<u64>,
+        passes: Option<u64>,
+    }
+
+    /// A `consecutive_errors` or `error_rate_percent` of `0` disables that check
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct OutlierDetection {
+        consecutive_errors: Option<u32>,
+        error_rate_percent: Option<u32>,
+        window_secs: Option<u64>,
+        min_requests: Option<u32>,
+        base_ejection_secs: Option<u64>,
+        max_ejection_secs: Option<u64>,
+        max_ejected_percent: Option<u32>,
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Retry {
+        attempts: Option<u32>,
+        statuses: Option<Vec<u16>>,
+        budget_percent: Option<u32>,
+        budget_min_retries: Option<u32>,
+        max_body_bytes: Option<u64>,
+    }
+
+    /// Each page is the path to a file with the body of the response
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct ErrorPages {
+        retry_after_secs: Option<u64>,
+        bad_gateway: Option<PathBuf>,
+        service_unavailable: Option<PathBuf>,
+        gateway_timeout: Option<PathBuf>,
+    }
+
+    /// Each trusted proxy is an ip address or a CIDR block
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct ForwardedHeaders {
+        x_forwarded_for: Option<bool>,
+        x_forwarded_proto: Option<bool>,
+        x_forwarded_host: Option<bool>,
+        x_real_ip: Option<bool>,
+        forwarded: Option<bool>,
+        trusted_proxies: Option<Vec<String>>,
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Tls {
+        listen: Option<String>,
+        #[serde(default)]
+        certificates: Vec<TlsCertificate>,
+    }
+
+    /// A certificate is either a PEM `cert` chain and `key`, or a `pkcs12` archive
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct TlsCertificate {
+        hostnames: Option<Vec<String>>,
+        cert: Option<PathBuf>,
+        key: Option<PathBuf>,
+        pkcs12: Option<PathBuf>,
+        password: Option<String>,
+    }
+
+    /// A timeout of `0` disables that timeout
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Timeout {
+        connect_ms: Option<u64>,
+        write_ms: Option<u64>,
+        read_ms: Option<u64>,
+        tunnel_idle_ms: Option<u64>,
+    }
+
+    /// An `idle_timeout_secs` of `0` keeps unused connections open until the server closes them
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct KeepAlive {
+        enabled: Option<bool>,
+        max_idle_per_server: Option<usize>,
+        idle_timeout_secs: Option<u64>,
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Balancer {
+        strategy: Option<String>,
+        hash_key: Option<String>,
+        sticky_cookie: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Server {
+        url: String,
+        map_host: Option<bool>,
+        weight: Option<u32>,
+        proxy_protocol: Option<String>,
+        tls: Option<ServerTls>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct ServerTls {
+        ca: Option<PathBuf>,
+        cert: Option<PathBuf>,
+        key: Option<PathBuf>,
+        server_name: Option<String>,
+        insecure_skip_verify: Option<bool>,
+    }
+
+    fn invalid<T>(msg: String) -> Result<T, ConfigError> {
+        Err(ConfigError::Invalid(msg))
+    }
+
+    fn addr(key: &str, value: Option<String>, default: SocketAddr) -> Result<SocketAddr, ConfigError> {
+        match value {
+            Some(value) => {
+                match value.parse::<SocketAddr>() {
+                    Ok(addr) => Ok(addr),
+                    Err(e) => invalid(format!("`{}` must be an ip and port: {}", key, e)),
+                }
+            }
+            None => Ok(default),
+        }
+    }
+
+    /// A cookie name is a non-empty token, as defined by RFC 6265
+    fn is_cookie_name(name: &str) -> bool {
+        !name.is_empty() &&
+            name.bytes().all(|b| {
+                b > 0x20 && b < 0x7f && !b"()<>@,;:\\\"/[]?={}".contains(&b)
+            })
+    }
+
+    fn timeout(value: Option<u64>, default: Option<Duration>) -> Option<Duration> {
+        match value {
+            Some(0) => None,
+            Some(ms) => Some(Duration::from_millis(ms)),
+            None => default,
+        }
+    }
+
+    impl Config {
+        pub fn into_config(self) -> Result<super::Config, ConfigError> {
+            let default = super::Config::default();
+
+            let workers = self.workers.unwrap_or(default.workers);
+            if workers == 0 {
+                return invalid("`workers` must be at least 1".to_string());
+            }
+
+            let drain_timeout = self.drain_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or(default.drain_timeout);
+
+            let health_check = self.health_check.unwrap_or_default().into_health_check()?;
+            let timeout = self.timeout.unwrap_or_default().into_timeout();
+            let keep_alive = self.keep_alive.unwrap_or_default().into_keep_alive()?;
+            let outlier_detection = match self.outlier_detection {
+                Some(outlier_detection) => Some(outlier_detection.into_outlier_detection()?),
+                None => None,
+            };
+            let retry = self.retry.unwrap_or_default().into_retry()?;
+            let error_pages = self.error_pages.unwrap_or_default().into_error_pages()?;
+            let forwarded = self.forwarded.unwrap_or_default().into_forwarded_headers()?;
+            let tls = match self.tls {
+                Some(tls) => Some(tls.into_tls()?),
+                None => None,
+            };
+            let balancer = self.balancer.unwrap_or_default();
+            let sticky_cookie = balancer.sticky_cookie()?;
+            let balancer = balancer.into_strategy()?;
+
+            let servers = self.servers
+                .into_iter()
+                .map(|server| server.into_server())
+                .collect::<Result<Vec<super::Server>, ConfigError>>()?;
+
+            let mut pools: Vec<super::NamedPool> = Vec::new();
+            for pool in self.pools {
+                let pool = pool.into_named_pool()?;
+                if pools.iter().any(|p| p.name == pool.name) {
+                    return invalid(format!("pool {:?} is configured more than once", pool.name));
+                }
+                for host in &pool.hosts {
+                    if let Some(other) = pools.iter().find(|p| p.hosts.contains(host)) {
+                        return invalid(format!(
+                            "host {:?} is served by both pool {:?} and pool {:?}",
+                            host,
+                            other.name,
+                            pool.name
+                        ));
+                    }
+                }
+                pools.push(pool);
+            }
+
+            // the connectors use the settings of the pool a server is in, so it can be in only one
+            let mut owners: Vec<(String, &str)> = Vec::new();
+            let all_servers = servers
+                .iter()
+                .map(|server| (vhost::DEFAULT_POOL, server))
+                .chain(pools.iter().flat_map(|p| p.servers.iter().map(move |s| (p.name.as_str(), s))));
+            for (name, server) in all_servers {
+                let url = server.url();
+                let origin = format!("{}://{}", url.scheme().unwrap_or(""), url.authority().unwrap_or(""));
+                if let Some(&(_, other)) = owners.iter().find(|&&(ref o, n)| *o == origin && n != name) {
+                    return invalid(format!(
+                        "server {:?} is in both pool {:?} and pool {:?}",
+                        origin,
+                        other,
+                        name
+                    ));
+                }
+                owners.push((origin, name));
+            }
+
+            let mut routes = Vec::new();
+            for spec in self.routes {
+                if spec.pool != vhost::DEFAULT_POOL && !pools.iter().any(|p| p.name == spec.pool) {
+                    return invalid(format!("route to pool {:?}, which is not configured", spec.pool));
+                }
+                routes.push(Route::new(spec).or_else(|e| invalid(format!("invalid route: {}", e)))?);
+            }
+
+            if let Some(ref headers) = self.headers {
+                headers.validate().or_else(|e| invalid(format!("invalid header rule: {}", e)))?;
+            }
+
+            Ok(super::Config {
+                health_check: health_check,
+                timeout: timeout,
+                keep_alive: keep_alive,
+                outlier_detection: outlier_detection,
+                retry: retry,
+                error_pages: error_pages,
+                forwarded: forwarded,
+                balancer: balancer,
+                sticky_cookie: sticky_cookie,
+                drain_timeout: drain_timeout,
+                workers: workers,
+                listen: addr("listen", self.listen, default.listen)?,
+                proxy_protocol: self.proxy_protocol.unwrap_or(default.proxy_protocol),
+                tls: tls,
+                admin: addr("admin", self.admin, default.admin)?,
+                internal: addr("internal", self.internal, default.internal)?,
+                servers: servers,
+                pools: pools,
+                routes: routes,
+                headers: self.headers,
+                state_file: self.state_file,
+            })
+        }
+    }
+
+    impl HealthCheck {
+        fn into_health_check(self) -> Result<super::HealthCheck, ConfigError> {
+            let default = super::HealthCheck::default();
+
+            let interval = self.interval_secs
+                .map(Duration::from_secs)
+                .unwrap_or(default.interval);
+            if interval == Duration::from_secs(0) {
+                return invalid("`health_check.interval_secs` must be at least 1".to_string());
+            }
+
+            let uri_path = self.uri_path.unwrap_or(default.uri_path);
+            if !uri_path.starts_with('/') {
+                return invalid(format!(
+                    "`health_check.uri_path` must start with a `/`, got {:?}",
+                    uri_path
+                ));
+            }
+
+            let failures = self.failures.unwrap_or(default.failures);
+            if failures == 0 {
+                return invalid("`health_check.failures` must be at least 1".to_string());
+            }
+
+            let passes = self.passes.unwrap_or(default.passes);
+            if passes == 0 {
+                return invalid("`health_check.passes` must be at least 1".to_string());
+            }
+
+            Ok(super::HealthCheck {
+                interval: interval,
+                uri_path: uri_path,
+                failures: failures,
+                passes: passes,
+            })
+        }
+    }
+
+    impl OutlierDetection {
+        fn into_outlier_detection(self) -> Result<super::OutlierDetection, ConfigError> {
+            let default = super::OutlierDetection::default();
+
+            let consecutive_errors = match self.consecutive_errors {
+                Some(0) => None,
+                Some(errors) => Some(errors),
+                None => default.consecutive_errors,
+            };
+
+            let error_rate = match self.error_rate_percent {
+                Some(0) => None,
+                Some(rate) if rate > 100 => {
+                    return invalid(
+                        "`outlier_detection.error_rate_percent` must be at most 100".to_string(),
+                    )
+                }
+                Some(rate) => Some(rate),
+                None => default.error_rate,
+            };
+
+            if consecutive_errors.is_none() && error_rate.is_none() {
+                return invalid(
+                    "`outlier_detection` needs `consecutive_errors` or `error_rate_percent`"
+                        .to_string(),
+                );
+            }
+
+            let window = self.window_secs
+                .map(Duration::from_secs)
+                .unwrap_or(default.window);
+            if window == Duration::from_secs(0) {
+                return invalid("`outlier_detection.window_secs` must be at least 1".to_string());
+            }
+
+            let base_ejection = self.base_ejection_secs
+                .map(Duration::from_secs)
+                .unwrap_or(default.base_ejection);
+            if base_ejection == Duration::from_secs(0) {
+                return invalid(
+                    "`outlier_detection.base_ejection_secs` must be at least 1".to_string(),
+                );
+            }
+
+            let max_ejection = self.max_ejection_secs
+                .map(Duration::from_secs)
+                .unwrap_or(default.max_ejection);
+            if max_ejection < base_ejection {
+                return invalid(
+                    "`outlier_detection.max_ejection_secs` must be at least `base_ejection_secs`"
+                        .to_string(),
+                );
+            }
+
+            let max_ejected_percent = self.max_ejected_percent.unwrap_or(default.max_ejected_percent);
+            if max_ejected_percent == 0 || max_ejected_percent > 100 {
+                return invalid(
+                    "`outlier_detection.max_ejected_percent` must be between 1 and 100".to_string(),
+                );
+            }
+
+            Ok(super::OutlierDetection {
+                consecutive_errors: consecutive_errors,
+                error_rate: error_rate,
+                window: window,
+                min_requests: self.min_requests.unwrap_or(default.min_requests),
+                base_ejection: base_ejection,
+                max_ejection: max_ejection,
+                max_ejected_percent: max_ejected_percent,
+            })
+        }
+    }
+
+    impl Retry {
+        fn into_retry(self) -> Result<super::Retry, ConfigError> {
+            let default = super::Retry::default();
+
+            let statuses = self.statuses.unwrap_or(default.statuses);
+            if let Some(status) = statuses.iter().find(|&&s| s < 100 || s > 599) {
+                return invalid(format!("`retry.statuses` has an invalid status {}", status));
+            }
+
+            let budget_percent = self.budget_percent.unwrap_or(default.budget_percent);
+            if budget_percent > 100 {
+                return invalid("`retry.budget_percent` must be at most 100".to_string());
+            }
+
+            Ok(super::Retry {
+                attempts: self.attempts.unwrap_or(default.attempts),
+                statuses: statuses,
+                budget_percent: budget_percent,
+                budget_min_retries: self.budget_min_retries.unwrap_or(default.budget_min_retries),
+                max_body_bytes: self.max_body_bytes.unwrap_or(default.max_body_bytes),
+            })
+        }
+    }
+
+    fn error_page(
+        key: &str,
+        path: Option<PathBuf>,
+        default: super::ErrorPage,
+    ) -> Result<super::ErrorPage, ConfigError> {
+        match path {
+            Some(path) => {
+                super::ErrorPage::from_file(&path).or_else(|e| {
+                    invalid(format!(
+                        "`error_pages.{}` could not be read from {}: {}",
+                        key,
+                        path.display(),
+                        e
+                    ))
+                })
+            }
+            None => Ok(default),
+        }
+    }
+
+    impl ErrorPages {
+        fn into_error_pages(self) -> Result<super::ErrorPages, ConfigError> {
+            let default = super::ErrorPages::default();
+
+            Ok(super::ErrorPages {
+                retry_after: self.retry_after_secs
+                    .map(Duration::from_secs)
+                    .unwrap_or(default.retry_after),
+                bad_gateway: error_page("bad_gateway", self.bad_gateway, default.bad_gateway)?,
+                service_unavailable: error_page(
+                    "service_unavailable",
+                    self.service_unavailable,
+                    default.service_unavailable,
+                )?,
+                gateway_timeout: error_page(
+                    "gateway_timeout",
+                    self.gateway_timeout,
+                    default.gateway_timeout,
+                )?,
+            })
+        }
+    }
+
+    impl ForwardedHeaders {
+        fn into_forwarded_headers(self) -> Result<super::ForwardedHeaders, ConfigError> {
+            let default = super::ForwardedHeaders::default();
+
+            let trusted_proxies = match self.trusted_proxies {
+                Some(trusted_proxies) => {
+                    trusted_proxies
+                        .iter()
+                        .map(|cidr| {
+                            cidr.parse::<Cidr>().or_else(|e| {
+                                invalid(format!("`forwarded.trusted_proxies` is invalid: {}", e))
+                            })
+                        })
+                        .collect::<Result<Vec<Cidr>, ConfigError>>()?
+                }
+                None => default.trusted_proxies,
+            };
+
+            Ok(super::ForwardedHeaders {
+                x_forwarded_for: self.x_forwarded_for.unwrap_or(default.x_forwarded_for),
+                x_forwarded_proto: self.x_forwarded_proto.unwrap_or(default.x_forwarded_proto),
+                x_forwarded_host: self.x_forwarded_host.unwrap_or(default.x_forwarded_host),
+                x_real_ip: self.x_real_ip.unwrap_or(default.x_real_ip),
+                forwarded: self.forwarded.unwrap_or(default.forwarded),
+                trusted_proxies: trusted_proxies,
+            })
+        }
+    }
+
+    fn read_file(key: &str, path: &Path) -> Result<Vec<u8>, ConfigError> {
+        let mut contents = Vec::new();
+        match File::open(path).and_then(|mut file| file.read_to_end(&mut contents)) {
+            Ok(_) => Ok(contents),
+            Err(e) => invalid(format!("`{}` could not be read from {}: {}", key, path.display(), e)),
+        }
+    }
+
+    impl Tls {
+        fn into_tls(self) -> Result<super::Tls, ConfigError> {
+            if self.certificates.is_empty() {
+                return invalid("`tls` must have at least one `tls.certificates`".to_string());
+            }
+
+            let certificates = self.certificates
+                .into_iter()
+                .map(|certificate| certificate.into_tls_certificate())
+                .collect::<Result<Vec<super::TlsCertificate>, ConfigError>>()?;
+
+            Ok(super::Tls {
+                listen: addr("tls.listen", self.listen, "0.0.0.0:8443".parse().unwrap())?,
+                certificates: certificates,
+            })
+        }
+    }
+
+    impl TlsCertificate {
+        fn into_tls_certificate(self) -> Result<super::TlsCertificate, ConfigError> {
+            let certificate = match (self.cert, self.key, self.pkcs12) {
+                (Some(cert), Some(key), None) => {
+                    let chain = read_file("tls.certificates.cert", &cert)?;
+                    let key = read_file("tls.certificates.key", &key)?;
+                    Certificate::from_pem(chain, key).or_else(|e| {
+                        invalid(format!("certificate {} is invalid: {}", cert.display(), e))
+                    })?
+                }
+                (None, None, Some(pkcs12)) => {
+                    let der = read_file("tls.certificates.pkcs12", &pkcs12)?;
+                    let password = self.password.unwrap_or_default();
+                    Certificate::from_pkcs12(&der, &password).or_else(|e| {
+                        invalid(format!("certificate {} is invalid: {}", pkcs12.display(), e))
+                    })?
+                }
+                _ => {
+                    return invalid(
+                        "each of `tls.certificates` must have either a `cert` and `key` or a `pkcs12`"
+                            .to_string(),
+                    )
+                }
+            };
+
+            let hostnames = match self.hostnames {
+                Some(hostnames) => hostnames,
+                None => {
+                    certificate.hostnames().or_else(|e| {
+                        invalid(format!("certificate hostnames could not be read: {}", e))
+                    })?
+                }
+            };
+
+            Ok(super::TlsCertificate {
+                hostnames: hostnames,
+                certificate: certificate,
+            })
+        }
+    }
+
+    /// Read a PEM encoded file
+    fn read_pem(key: &str, path: Option<PathBuf>) -> Result<Option<String>, ConfigError> {
+        match path {
+            Some(path) => {
+                match String::from_utf8(read_file(key, &path)?) {
+                    Ok(pem) => Ok(Some(pem)),
+                    Err(_) => invalid(format!("`{}` in {} is not PEM encoded", key, path.display())),
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    impl ServerTls {
+        fn into_server_tls(self, url: &str) -> Result<super::ServerTls, ConfigError> {
+            let tls = super::ServerTls {
+                ca: read_pem("servers.tls.ca", self.ca)?,
+                cert: read_pem("servers.tls.cert", self.cert)?,
+                key: read_pem("servers.tls.key", self.key)?,
+                server_name: self.server_name,
+                insecure_skip_verify: self.insecure_skip_verify.unwrap_or(false),
+            };
+
+            if let Err(e) = tls.connector() {
+                return invalid(format!("server {:?} has an invalid `tls`: {}", url, e));
+            }
+
+            Ok(tls)
+        }
+    }
+
+    impl Timeout {
+        fn into_timeout(self) -> super::Timeout {
+            let default = super::Timeout::default();
+
+            super::Timeout {
+                connect: timeout(self.connect_ms, default.connect),
+                write: timeout(self.write_ms, default.write),
+                read: timeout(self.read_ms, default.read),
+                tunnel_idle: timeout(self.tunnel_idle_ms, default.tunnel_idle),
+            }
+        }
+    }
+
+    impl KeepAlive {
+        fn into_keep_alive(self) -> Result<super::KeepAlive, ConfigError> {
+            let default = super::KeepAlive::default();
+
+            let enabled = self.enabled.unwrap_or(default.enabled);
+            let max_idle_per_server = self.max_idle_per_server.unwrap_or(default.max_idle_per_server);
+            if enabled && max_idle_per_server == 0 {
+                return invalid(
+                    "`keep_alive.max_idle_per_server` must be at least 1. Set `enabled = false` to disable keep-alive"
+                        .to_string(),
+                );
+            }
+
+            let idle_timeout = match self.idle_timeout_secs {
+                Some(0) => None,
+                Some(secs) => Some(Duration::from_secs(secs)),
+                None => default.idle_timeout,
+            };
+
+            Ok(super::KeepAlive {
+                enabled: enabled,
+                max_idle_per_server: max_idle_per_server,
+                idle_timeout: idle_timeout,
+            })
+        }
+    }
+
+    impl Balancer {
+        fn sticky_cookie(&self) -> Result<Option<String>, ConfigError> {
+            match self.sticky_cookie {
+                Some(ref name) if !is_cookie_name(name) => {
+                    invalid(format!("`balancer.sticky_cookie` {:?} is not a valid cookie name", name))
+                }
+                ref name => Ok(name.clone()),
+            }
+        }
+
+        fn into_strategy(self) -> Result<Strategy, ConfigError> {
+            let strategy = match self.strategy {
+                Some(strategy) => {
+                    strategy.parse().or_else(|e| {
+                        invalid(format!("`balancer.strategy` is invalid: {}", e))
+                    })?
+                }
+                None => Strategy::default(),
+            };
+
+            match (strategy, self.hash_key) {
+                (Strategy::ConsistentHash(_), Some(key)) => {
+                    match key.parse::<HashKey>() {
+                        Ok(key) => Ok(Strategy::ConsistentHash(key)),
+                        Err(e) => invalid(format!("`balancer.hash_key` is invalid: {}", e)),
+                    }
+                }
+                (_, Some(_)) => {
+                    invalid("`balancer.hash_key` requires the `consistent_hash` strategy".to_string())
+                }
+                (strategy, None) => Ok(strategy),
+            }
+        }
+    }
+
+    impl NamedPool {
+        fn into_named_pool(self) -> Result<super::NamedPool, ConfigError> {
+            if !vhost::is_pool_name(&self.name) || self.name == vhost::DEFAULT_POOL {
+                return invalid(format!(
+                    "pool name {:?} must only have letters, digits, `-` and `_`, and not be {:?}",
+                    self.name,
+                    vhost::DEFAULT_POOL
+                ));
+            }
+            if self.hosts.is_empty() {
+                return invalid(format!("pool {:?} must have at least one host", self.name));
+            }
+            if let Some(host) = self.hosts.iter().find(|host| !vhost::is_host(host)) {
+                return invalid(format!("pool {:?} has an invalid host {:?}", self.name, host));
+            }
+
+            if let Some(ref headers) = self.headers {
+                headers.validate().or_else(|e| {
+                    invalid(format!("pool {:?} has an invalid header rule: {}", self.name, e))
+                })?;
+            }
+
+            let servers = self.servers
+                .into_iter()
+                .map(|server| server.into_server())
+                .collect::<Result<Vec<super::Server>, ConfigError>>()?;
+
+            Ok(super::NamedPool {
+                name: self.name,
+                hosts: self.hosts.iter().map(|host| host.to_lowercase()).collect(),
+                servers: servers,
+                headers: self.headers,
+            })
+        }
+    }
+
+    impl Server {
+        fn into_server(self) -> Result<super::Server, ConfigError> {
+            let url = match self.url.parse::<Uri>() {
+                Ok(url) => url,
+                Err(e) => return invalid(format!("server url {:?} is invalid: {}", self.url, e)),
+            };
+
+            match url.scheme() {
+                Some("http") | Some("https") => {}
+                _ => {
+                    return invalid(format!(
+                        "server url {:?} must start with http:// or https://",
+                        self.url
+                    ))
+                }
+            }
+
+            let weight = self.weight.unwrap_or(DEFAULT_WEIGHT);
+            if weight == 0 || weight > MAX_WEIGHT {
+                return invalid(format!(
+                    "server {:?} must have a weight between 1 and {}",
+                    self.url,
+                    MAX_WEIGHT
+                ));
+            }
+
+            let proxy_protocol = match self.proxy_protocol {
+                Some(ref version) => {
+                    Some(version.parse::<ProxyProtocol>().or_else(|e| {
+                        invalid(format!("server {:?} has an invalid `proxy_protocol`: {}", self.url, e))
+                    })?)
+                }
+                None => None,
+            };
+
+            let tls = match self.tls {
+                Some(_) if url.scheme() != Some("https") => {
+                    return invalid(format!("server {:?} must be https:// to set `tls`", self.url))
+                }
+                Some(tls) => Some(tls.into_server_tls(&self.url)?),
+                None => None,
+            };
+
+            Ok(
+                super::Server::new(url, self.map_host.unwrap_or(true))
+                    .with_weight(weight)
+                    .with_proxy_protocol(proxy_protocol)
+                    .with_tls(tls),
+            )
         }
     }
 }
@@ -62,4 +1244,467 @@ fn test_config() {
     assert_eq!(Some(Duration::from_millis(200)), conf.timeout.connect);
     assert_eq!(Some(Duration::from_secs(2)), conf.timeout.write);
     assert_eq!(Some(Duration::from_secs(2)), conf.timeout.read);
+    assert_eq!(Some(Duration::from_secs(300)), conf.timeout.tunnel_idle);
+    assert_eq!(Duration::from_secs(30), conf.drain_timeout);
+    assert_eq!(5, conf.workers);
+    assert_eq!("0.0.0.0:8080".parse::<SocketAddr>().unwrap(), conf.listen);
+    assert!(!conf.proxy_protocol);
+    assert_eq!("0.0.0.0:8687".parse::<SocketAddr>().unwrap(), conf.admin);
+    assert_eq!("127.0.0.1:4000".parse::<SocketAddr>().unwrap(), conf.internal);
+    assert!(conf.servers.is_empty());
+    assert!(conf.pools.is_empty());
+    assert!(conf.routes.is_empty());
+    assert_eq!(None, conf.headers);
+    assert_eq!(None, conf.state_file);
+    assert_eq!(Strategy::RoundRobin, conf.balancer);
+    assert_eq!(None, conf.sticky_cookie);
+    assert_eq!(None, conf.outlier_detection);
+    assert_eq!(None, conf.tls);
+    assert_eq!(0, conf.retry.attempts);
+    assert_eq!(vec![502, 503, 504], conf.retry.statuses);
+    assert!(conf.forwarded.x_forwarded_for);
+    assert!(!conf.forwarded.forwarded);
+    assert_eq!(KeepAlive::default(), conf.keep_alive);
+}
+
+#[test]
+fn test_parse_config() {
+    let conf = Config::parse(
+        r#"
+        workers = 2
+        listen = "127.0.0.1:80"
+        proxy_protocol = true
+        admin = "127.0.0.1:9000"
+        internal = "127.0.0.1:9001"
+        drain_timeout_secs = 5
+        state_file = "/var/lib/weldr/state.json"
+
+        [health_check]
+        interval_secs = 30
+        uri_path = "/health"
+        failures = 5
+        passes = 1
+
+        [timeout]
+        connect_ms = 100
+        read_ms = 0
+        tunnel_idle_ms = 60000
+
+        [balancer]
+        strategy = "least_requests"
+        sticky_cookie = "weldr_backend"
+
+        [[servers]]
+        url = "http://127.0.0.1:12345"
+
+        [[servers]]
+        url = "https://10.0.0.1"
+        map_host = false
+        weight = 3
+        proxy_protocol = "v2"
+
+        [servers.tls]
+        server_name = "backend.internal"
+        insecure_skip_verify = true
+        "#,
+    ).unwrap();
+
+    assert_eq!(2, conf.workers);
+    assert_eq!("127.0.0.1:80".parse::<SocketAddr>().unwrap(), conf.listen);
+    assert_eq!("127.0.0.1:9000".parse::<SocketAddr>().unwrap(), conf.admin);
+    assert_eq!("127.0.0.1:9001".parse::<SocketAddr>().unwrap(), conf.internal);
+    assert_eq!(Duration::from_secs(5), conf.drain_timeout);
+    assert_eq!(Some(PathBuf::from("/var/lib/weldr/state.json")), conf.state_file);
+    assert_eq!(Duration::from_secs(30), conf.health_check.interval);
+    assert_eq!("/health", conf.health_check.uri_path);
+    assert_eq!(5, conf.health_check.failures);
+    assert_eq!(1, conf.health_check.passes);
+    assert_eq!(Some(Duration::from_millis(100)), conf.timeout.connect);
+    assert_eq!(Some(Duration::from_secs(2)), conf.timeout.write);
+    assert_eq!(None, conf.timeout.read);
+    assert_eq!(Some(Duration::from_secs(60)), conf.timeout.tunnel_idle);
+    assert_eq!(Strategy::LeastRequests, conf.balancer);
+    assert_eq!(Some("weldr_backend".to_string()), conf.sticky_cookie);
+    assert_eq!(1, conf.servers[0].weight());
+    assert_eq!(3, conf.servers[1].weight());
+    assert_eq!(None, conf.servers[0].proxy_protocol());
+    assert_eq!(Some(ProxyProtocol::V2), conf.servers[1].proxy_protocol());
+    assert_eq!(None, conf.servers[0].tls());
+    assert_eq!(
+        Some(ServerTls {
+            server_name: Some("backend.internal".to_string()),
+            insecure_skip_verify: true,
+            ..ServerTls::default()
+        }),
+        conf.servers[1].tls()
+    );
+    assert!(conf.proxy_protocol);
+    assert_eq!(
+        vec![
+            Server::new("http://127.0.0.1:12345".parse().unwrap(), true),
+            Server::new("https://10.0.0.1".parse().unwrap(), false),
+        ],
+        conf.servers
+    );
+}
+
+#[test]
+fn test_parse_empty_config() {
+    let conf = Config::parse("").unwrap();
+    assert_eq!(5, conf.workers);
+    assert_eq!(Duration::from_secs(10), conf.health_check.interval);
+}
+
+#[test]
+fn test_parse_pools_config() {
+    let conf = Config::parse(
+        r#"
+        [[servers]]
+        url = "http://127.0.0.1:12345"
+
+        [[pools]]
+        name = "api"
+        hosts = ["API.example.com", "*.api.example.com"]
+
+        [[pools.servers]]
+        url = "http://127.0.0.1:12346"
+
+        [[pools]]
+        name = "static-assets"
+        hosts = ["static.example.com"]
+        "#,
+    ).unwrap();
+
+    assert_eq!(1, conf.servers.len());
+    assert_eq!(2, conf.pools.len());
+    assert_eq!("api", conf.pools[0].name);
+    assert_eq!(vec!["api.example.com", "*.api.example.com"], conf.pools[0].hosts);
+    assert_eq!(
+        vec![Server::new("http://127.0.0.1:12346".parse().unwrap(), true)],
+        conf.pools[0].servers
+    );
+    assert_eq!("static-assets", conf.pools[1].name);
+    assert!(conf.pools[1].servers.is_empty());
+    assert_eq!(None, conf.pools[1].headers);
+}
+
+#[test]
+fn test_parse_routes_config() {
+    let conf = Config::parse(
+        r#"
+        [[pools]]
+        name = "api"
+        hosts = ["api.example.com"]
+
+        [[routes]]
+        prefix = "/api/"
+        pool = "api"
+        strip_prefix = true
+
+        [[routes]]
+        regex = '^/static/(.+)\.v[0-9]+\.js$'
+        pool = "default"
+        rewrite = "/static/$1.js"
+
+        [[routes]]
+        prefix = "/"
+        pool = "api"
+        host = "*.api.example.com"
+        "#,
+    ).unwrap();
+
+    assert_eq!(3, conf.routes.len());
+    assert_eq!(
+        &RouteSpec {
+            prefix: Some("/api/".to_string()),
+            regex: None,
+            pool: "api".to_string(),
+            host: None,
+            strip_prefix: true,
+            rewrite: None,
+            headers: HeaderRules::default(),
+        },
+        conf.routes[0].spec()
+    );
+    assert_eq!("default", conf.routes[1].pool());
+    assert_eq!(Some("/static/app.js".to_string()), conf.routes[1].rewrite("/static/app.v3.js"));
+    assert_eq!(Some("*.api.example.com"), conf.routes[2].spec().host.as_ref().map(|h| h.as_str()));
+}
+
+#[test]
+fn test_parse_headers_config() {
+    use header_rules::{Action, HeaderRule};
+
+    let conf = Config::parse(
+        r#"
+        [headers]
+        response = [
+            { action = "remove", name = "Server" },
+            { action = "add", name = "Strict-Transport-Security", value = "max-age=31536000" },
+        ]
+
+        [[pools]]
+        name = "api"
+        hosts = ["api.example.com"]
+
+        [pools.headers]
+        request = [{ action = "set", name = "X-Request-Id", value = "$request_id" }]
+
+        [[routes]]
+        prefix = "/v1/"
+        pool = "api"
+        headers = { response = [{ action = "append", name = "Warning", value = "299 - \"Deprecated\"" }] }
+        "#,
+    ).unwrap();
+
+    let headers = conf.headers.unwrap();
+    assert!(headers.request.is_empty());
+    assert_eq!(
+        vec![
+            HeaderRule {
+                action: Action::Remove,
+                name: "Server".to_string(),
+                value: None,
+            },
+            HeaderRule {
+                action: Action::Add,
+                name: "Strict-Transport-Security".to_string(),
+                value: Some("max-age=31536000".to_string()),
+            },
+        ],
+        headers.response
+    );
+    assert_eq!(
+        vec![
+            HeaderRule {
+                action: Action::Set,
+                name: "X-Request-Id".to_string(),
+                value: Some("$request_id".to_string()),
+            },
+        ],
+        conf.pools[0].headers.as_ref().unwrap().request
+    );
+    assert_eq!(Action::Append, conf.routes[0].spec().headers.response[0].action);
+}
+
+#[test]
+fn test_parse_balancer_config() {
+    let conf = Config::parse("[balancer]\nstrategy = \"consistent_hash\"").unwrap();
+    assert_eq!(Strategy::ConsistentHash(HashKey::ClientIp), conf.balancer);
+
+    let conf = Config::parse(
+        "[balancer]\nstrategy = \"consistent_hash\"\nhash_key = \"header:X-User\"",
+    ).unwrap();
+    assert_eq!(
+        Strategy::ConsistentHash(HashKey::Header("X-User".to_string())),
+        conf.balancer
+    );
+
+    let conf = Config::parse("[balancer]\nstrategy = \"p2c_ewma\"").unwrap();
+    assert_eq!(Strategy::P2cEwma, conf.balancer);
+}
+
+#[test]
+fn test_parse_outlier_detection_config() {
+    let conf = Config::parse("[outlier_detection]").unwrap();
+    assert_eq!(Some(OutlierDetection::default()), conf.outlier_detection);
+
+    let conf = Config::parse(
+        r#"
+        [outlier_detection]
+        consecutive_errors = 0
+        error_rate_percent = 50
+        window_secs = 30
+        min_requests = 10
+        base_ejection_secs = 5
+        max_ejection_secs = 60
+        max_ejected_percent = 20
+        "#,
+    ).unwrap();
+    assert_eq!(
+        Some(OutlierDetection {
+            consecutive_errors: None,
+            error_rate: Some(50),
+            window: Duration::from_secs(30),
+            min_requests: 10,
+            base_ejection: Duration::from_secs(5),
+            max_ejection: Duration::from_secs(60),
+            max_ejected_percent: 20,
+        }),
+        conf.outlier_detection
+    );
+}
+
+#[test]
+fn test_parse_keep_alive_config() {
+    let conf = Config::parse(
+        r#"
+        [keep_alive]
+        max_idle_per_server = 4
+        idle_timeout_secs = 0
+        "#,
+    ).unwrap();
+    assert_eq!(
+        KeepAlive {
+            enabled: true,
+            max_idle_per_server: 4,
+            idle_timeout: None,
+        },
+        conf.keep_alive
+    );
+
+    let conf = Config::parse("[keep_alive]\nenabled = false\nmax_idle_per_server = 0").unwrap();
+    assert!(!conf.keep_alive.enabled);
+}
+
+#[test]
+fn test_parse_retry_config() {
+    let conf = Config::parse(
+        r#"
+        [retry]
+        attempts = 2
+        statuses = [503]
+        budget_percent = 10
+        budget_min_retries = 0
+        max_body_bytes = 1024
+        "#,
+    ).unwrap();
+    assert_eq!(
+        Retry {
+            attempts: 2,
+            statuses: vec![503],
+            budget_percent: 10,
+            budget_min_retries: 0,
+            max_body_bytes: 1024,
+        },
+        conf.retry
+    );
+}
+
+#[test]
+fn test_parse_error_pages_config() {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    let path = env::temp_dir().join(format!("weldr-503-{}.json", ::nix::unistd::getpid()));
+    File::create(&path)
+        .unwrap()
+        .write_all(b"{\"error\":\"unavailable\"}")
+        .unwrap();
+
+    let conf = Config::parse(&format!(
+        "[error_pages]\nretry_after_secs = 30\nservice_unavailable = {:?}",
+        path.to_str().unwrap()
+    ));
+    fs::remove_file(&path).unwrap();
+    let conf = conf.unwrap();
+
+    assert_eq!(Duration::from_secs(30), conf.error_pages.retry_after);
+    assert_eq!(
+        ErrorPage {
+            content_type: "application/json".to_string(),
+            body: b"{\"error\":\"unavailable\"}".to_vec(),
+        },
+        conf.error_pages.service_unavailable
+    );
+    assert_eq!(ErrorPages::default().bad_gateway, conf.error_pages.bad_gateway);
+}
+
+#[test]
+fn test_parse_forwarded_config() {
+    let conf = Config::parse(
+        r#"
+        [forwarded]
+        x_forwarded_host = false
+        x_real_ip = true
+        forwarded = true
+        trusted_proxies = ["10.0.0.0/8", "::1"]
+        "#,
+    ).unwrap();
+    assert_eq!(
+        ForwardedHeaders {
+            x_forwarded_for: true,
+            x_forwarded_proto: true,
+            x_forwarded_host: false,
+            x_real_ip: true,
+            forwarded: true,
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()],
+        },
+        conf.forwarded
+    );
+}
+
+#[test]
+fn test_parse_invalid_config() {
+    let invalid = vec![
+        "workers = 0",
+        "workers = \"five\"",
+        "listen = \"localhost\"",
+        "unknown = 1",
+        "[health_check]\ninterval_secs = 0",
+        "[health_check]\nuri_path = \"health\"",
+        "[health_check]\nfailures = 0",
+        "[health_check]\npasses = 0",
+        "[outlier_detection]\nconsecutive_errors = 0",
+        "[keep_alive]\nmax_idle_per_server = 0",
+        "[outlier_detection]\nerror_rate_percent = 101",
+        "[outlier_detection]\nwindow_secs = 0",
+        "[outlier_detection]\nbase_ejection_secs = 0",
+        "[outlier_detection]\nbase_ejection_secs = 60\nmax_ejection_secs = 30",
+        "[outlier_detection]\nmax_ejected_percent = 0",
+        "[retry]\nstatuses = [600]",
+        "[error_pages]\nbad_gateway = \"/weldr/does/not/exist.html\"",
+        "[retry]\nbudget_percent = 101",
+        "[forwarded]\ntrusted_proxies = [\"10.0.0.0/40\"]",
+        "[forwarded]\ntrusted_proxies = [\"localhost\"]",
+        "[tls]",
+        "[[tls.certificates]]\ncert = \"/weldr/does/not/exist.pem\"",
+        "[[tls.certificates]]\ncert = \"/weldr/does/not/exist.pem\"\nkey = \"/weldr/does/not/exist.key\"",
+        "[[tls.certificates]]\npkcs12 = \"/weldr/does/not/exist.p12\"",
+        "[balancer]\nstrategy = \"random\"",
+        "[balancer]\nstrategy = \"consistent_hash\"\nhash_key = \"query\"",
+        "[balancer]\nhash_key = \"path\"",
+        "[balancer]\nsticky_cookie = \"\"",
+        "[balancer]\nsticky_cookie = \"a=b\"",
+        "[[servers]]\nurl = \"127.0.0.1:12345\"",
+        "[[servers]]\nurl = \"http://127.0.0.1:12345\"\nweight = 0",
+        "[[servers]]\nurl = \"http://127.0.0.1:12345\"\nweight = 1001",
+        "[[servers]]\nurl = \"http://127.0.0.1:12345\"\nproxy_protocol = \"v3\"",
+        "[[servers]]\nurl = \"http://127.0.0.1:12345\"\n[servers.tls]\ninsecure_skip_verify = true",
+        "[[servers]]\nurl = \"https://127.0.0.1:12345\"\n[servers.tls]\nca = \"/weldr/does/not/exist.pem\"",
+        "[[servers]]\nurl = \"https://127.0.0.1:12345\"\n[servers.tls]\nverify = false",
+        "[[pools]]\nname = \"api\"",
+        "[[pools]]\nname = \"api\"\nhosts = []",
+        "[[pools]]\nname = \"default\"\nhosts = [\"example.com\"]",
+        "[[pools]]\nname = \"a/b\"\nhosts = [\"example.com\"]",
+        "[[pools]]\nname = \"api\"\nhosts = [\"example.com:8080\"]",
+        "[[pools]]\nname = \"api\"\nhosts = [\"a.example.com\"]\n[[pools]]\nname = \"api\"\nhosts = [\"b.example.com\"]",
+        "[[pools]]\nname = \"a\"\nhosts = [\"example.com\"]\n[[pools]]\nname = \"b\"\nhosts = [\"Example.com\"]",
+        "[[pools]]\nname = \"api\"\nhosts = [\"example.com\"]\n[[pools.servers]]\nurl = \"127.0.0.1:12345\"",
+        "[[servers]]\nurl = \"http://127.0.0.1:12345\"\n[[pools]]\nname = \"api\"\nhosts = [\"example.com\"]\n[[pools.servers]]\nurl = \"http://127.0.0.1:12345/\"",
+        "[[routes]]\nprefix = \"/api/\"\npool = \"api\"",
+        "[[routes]]\npool = \"default\"",
+        "[[routes]]\nprefix = \"api/\"\npool = \"default\"",
+        "[[routes]]\nprefix = \"/api/\"\nregex = \"^/api/\"\npool = \"default\"",
+        "[[routes]]\nregex = \"^/api/(\"\npool = \"default\"",
+        "[[routes]]\nregex = \"^/api/\"\npool = \"default\"\nstrip_prefix = true",
+        "[[routes]]\nprefix = \"/api/\"\npool = \"default\"\nrewrite = \"/\"",
+        "[[routes]]\nprefix = \"/api/\"\npool = \"default\"\nhost = \"example.com:8080\"",
+        "[[routes]]\nprefix = \"/api/\"\npool = \"default\"\n[routes.headers]\nrequest = [{ action = \"set\", name = \"X-Id\" }]",
+        "[headers]\nresponse = [{ action = \"strip\", name = \"Server\" }]",
+        "[headers]\nresponse = [{ action = \"remove\", name = \"Server\", value = \"nginx\" }]",
+        "[headers]\nrequest = [{ action = \"set\", name = \"X-Id\", value = \"$uuid\" }]",
+        "[headers]\nrequest = [{ action = \"set\", name = \"Content-Length\", value = \"0\" }]",
+        "[headers]\nheaders = []",
+        "[[pools]]\nname = \"api\"\nhosts = [\"example.com\"]\n[pools.headers]\nresponse = [{ action = \"add\", name = \"X Frame\", value = \"DENY\" }]",
+    ];
+
+    for contents in invalid {
+        assert!(
+            Config::parse(contents).is_err(),
+            "expected {:?} to be invalid",
+            contents
+        );
+    }
 }
@@ -0,0 +1,301 @@
+//! Headers that tell a backend about the client a request was proxied for
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::{self, FromStr};
+
+use hyper::Headers;
+
+use config::ForwardedHeaders;
+
+/// A block of ip addresses, such as `10.0.0.0/8` or `2001:db8::/32`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Whether `ip` is in this block
+    ///
+    /// An IPv4 address never matches an IPv6 block, and the other way around.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, *ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = mask(self.prefix, 32) as u32;
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = mask(self.prefix, 128);
+                to_u128(&net) & mask == to_u128(&ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    /// Parse `<ip>/<prefix>`, or a single `<ip>`
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let mut parts = s.splitn(2, '/');
+        let addr = parts
+            .next()
+            .and_then(|addr| addr.parse::<IpAddr>().ok())
+            .ok_or_else(|| format!("invalid ip address in {:?}", s))?;
+
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match parts.next() {
+            Some(prefix) => {
+                match prefix.parse::<u8>() {
+                    Ok(prefix) if prefix <= max => prefix,
+                    _ => return Err(format!("invalid prefix length in {:?}", s)),
+                }
+            }
+            None => max,
+        };
+
+        Ok(Cidr {
+            addr: addr,
+            prefix: prefix,
+        })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// A mask with the top `prefix` bits of a `bits` wide address set
+fn mask(prefix: u8, bits: u32) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        (!0u128 << (128 - prefix as u32)) >> (128 - bits)
+    }
+}
+
+fn to_u128(ip: &Ipv6Addr) -> u128 {
+    ip.segments()
+        .iter()
+        .fold(0u128, |acc, segment| (acc << 16) | *segment as u128)
+}
+
+/// Treat an IPv4 client connected to an IPv6 socket as an IPv4 client
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => {
+            match v6.segments() {
+                [0, 0, 0, 0, 0, 0xffff, hi, lo] => {
+                    IpAddr::V4(Ipv4Addr::new(
+                        (hi >> 8) as u8,
+                        hi as u8,
+                        (lo >> 8) as u8,
+                        lo as u8,
+                    ))
+                }
+                _ => ip,
+            }
+        }
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// All values of a header, joined into a single comma separated list
+fn joined(headers: &Headers, name: &str) -> Option<String> {
+    headers.get_raw(name).map(|raw| {
+        raw.iter()
+            .map(|line| String::from_utf8_lossy(line).into_owned())
+            .collect::<Vec<String>>()
+            .join(", ")
+    })
+}
+
+/// Quote a `Forwarded` parameter value unless it is a token
+///
+/// See RFC 7239 Section 4.
+fn forwarded_value(value: &str) -> String {
+    let token = !value.is_empty() &&
+        value.bytes().all(|b| {
+            b > 0x20 && b < 0x7f && !b"()<>@,;:\\\"/[]?={}".contains(&b)
+        });
+
+    if token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// Add the forwarding headers enabled in `config` to a backend request
+///
+/// If the client is one of the trusted proxies, the values it sent are kept and the client address
+/// is appended to `X-Forwarded-For` and `Forwarded`. Otherwise, the values sent by the client are
+/// replaced, since the client could have made them up. `proto` is the scheme the client used to
+/// connect to weldr.
+pub fn set_headers(headers: &mut Headers, config: &ForwardedHeaders, client: &SocketAddr, proto: &str) {
+    let ip = canonical(client.ip());
+    let trusted = config.trusted_proxies.iter().any(|cidr| cidr.contains(&ip));
+    let host = headers
+        .get_raw("Host")
+        .and_then(|raw| raw.one())
+        .and_then(|host| str::from_utf8(host).ok())
+        .map(|host| host.to_string());
+
+    if config.x_forwarded_for {
+        let value = match joined(headers, "X-Forwarded-For") {
+            Some(ref previous) if trusted => format!("{}, {}", previous, ip),
+            _ => ip.to_string(),
+        };
+        headers.set_raw("X-Forwarded-For", value);
+    }
+
+    if config.x_forwarded_proto && !(trusted && headers.get_raw("X-Forwarded-Proto").is_some()) {
+        headers.set_raw("X-Forwarded-Proto", proto.to_string());
+    }
+
+    if config.x_forwarded_host && !(trusted && headers.get_raw("X-Forwarded-Host").is_some()) {
+        match host {
+            Some(ref host) => headers.set_raw("X-Forwarded-Host", host.clone()),
+            None => headers.remove_raw("X-Forwarded-Host"),
+        }
+    }
+
+    if config.x_real_ip && !(trusted && headers.get_raw("X-Real-IP").is_some()) {
+        headers.set_raw("X-Real-IP", ip.to_string());
+    }
+
+    if config.forwarded {
+        let node = match ip {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+        };
+        let mut element = format!("for={};proto={}", node, forwarded_value(proto));
+        if let Some(ref host) = host {
+            element.push_str(&format!(";host={}", forwarded_value(host)));
+        }
+
+        let value = match joined(headers, "Forwarded") {
+            Some(ref previous) if trusted => format!("{}, {}", previous, element),
+            _ => element,
+        };
+        headers.set_raw("Forwarded", value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Headers;
+
+    use config::ForwardedHeaders;
+    use super::{set_headers, Cidr};
+
+    fn all() -> ForwardedHeaders {
+        ForwardedHeaders {
+            x_forwarded_for: true,
+            x_forwarded_proto: true,
+            x_forwarded_host: true,
+            x_real_ip: true,
+            forwarded: true,
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+        }
+    }
+
+    fn get(headers: &Headers, name: &str) -> Option<String> {
+        headers
+            .get_raw(name)
+            .and_then(|raw| raw.one())
+            .map(|value| String::from_utf8(value.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn test_cidr() {
+        let cidr: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(cidr.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains(&"10.2.0.1".parse().unwrap()));
+        assert!(!cidr.contains(&"::1".parse().unwrap()));
+
+        let cidr: Cidr = "127.0.0.1".parse().unwrap();
+        assert!(cidr.contains(&"127.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains(&"127.0.0.2".parse().unwrap()));
+
+        let cidr: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(cidr.contains(&"192.168.1.1".parse().unwrap()));
+
+        let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(cidr.contains(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!cidr.contains(&"2001:db9::1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_untrusted_client() {
+        let mut headers = Headers::new();
+        headers.set_raw("Host", "example.com:8080");
+        headers.set_raw("X-Forwarded-For", "1.1.1.1");
+        headers.set_raw("X-Forwarded-Proto", "https");
+        headers.set_raw("X-Real-IP", "1.1.1.1");
+        headers.set_raw("Forwarded", "for=1.1.1.1");
+
+        set_headers(&mut headers, &all(), &"192.168.1.5:5000".parse().unwrap(), "http");
+
+        assert_eq!(Some("192.168.1.5".to_string()), get(&headers, "X-Forwarded-For"));
+        assert_eq!(Some("http".to_string()), get(&headers, "X-Forwarded-Proto"));
+        assert_eq!(Some("example.com:8080".to_string()), get(&headers, "X-Forwarded-Host"));
+        assert_eq!(Some("192.168.1.5".to_string()), get(&headers, "X-Real-IP"));
+        assert_eq!(
+            Some("for=192.168.1.5;proto=http;host=\"example.com:8080\"".to_string()),
+            get(&headers, "Forwarded")
+        );
+    }
+
+    #[test]
+    fn test_trusted_proxy() {
+        let mut headers = Headers::new();
+        headers.set_raw("Host", "example.com");
+        headers.set_raw("X-Forwarded-For", "1.1.1.1");
+        headers.set_raw("X-Forwarded-Proto", "https");
+        headers.set_raw("X-Real-IP", "1.1.1.1");
+        headers.set_raw("Forwarded", "for=1.1.1.1;proto=https");
+
+        set_headers(&mut headers, &all(), &"10.0.0.5:5000".parse().unwrap(), "http");
+
+        assert_eq!(Some("1.1.1.1, 10.0.0.5".to_string()), get(&headers, "X-Forwarded-For"));
+        assert_eq!(Some("https".to_string()), get(&headers, "X-Forwarded-Proto"));
+        assert_eq!(Some("example.com".to_string()), get(&headers, "X-Forwarded-Host"));
+        assert_eq!(Some("1.1.1.1".to_string()), get(&headers, "X-Real-IP"));
+        assert_eq!(
+            Some("for=1.1.1.1;proto=https, for=10.0.0.5;proto=http;host=example.com".to_string()),
+            get(&headers, "Forwarded")
+        );
+    }
+
+    #[test]
+    fn test_ipv6_client() {
+        let mut headers = Headers::new();
+        let mut config = all();
+        config.x_forwarded_host = false;
+
+        set_headers(&mut headers, &config, &"[2001:db8::1]:5000".parse().unwrap(), "http");
+        assert_eq!(Some("2001:db8::1".to_string()), get(&headers, "X-Forwarded-For"));
+        assert_eq!(None, get(&headers, "X-Forwarded-Host"));
+        assert_eq!(
+            Some("for=\"[2001:db8::1]\";proto=http".to_string()),
+            get(&headers, "Forwarded")
+        );
+
+        // an IPv4 client connected to an IPv6 socket
+        let mut headers = Headers::new();
+        set_headers(&mut headers, &config, &"[::ffff:10.0.0.5]:5000".parse().unwrap(), "http");
+        assert_eq!(Some("10.0.0.5".to_string()), get(&headers, "X-Forwarded-For"));
+    }
+}
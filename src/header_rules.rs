@@ -0,0 +1,344 @@
+//! Operator defined rules that change the headers of requests and responses
+//!
+//! Rules are set on a pool or a route. The rules of the pool are applied first, followed by the
+//! rules of the route, so a route can override its pool. Request rules are applied after the
+//! hop-by-hop and forwarded headers are handled, right before the request is sent to a server.
+//! Response rules are applied to each response from a server before it is returned to the client.
+//! They are not applied to the `101 Switching Protocols` response of an upgraded connection, which
+//! is passed through as is, or to the error responses weldr sends itself.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use hyper::Headers;
+use rand;
+
+/// The variables a value can refer to as `$name`
+const VARIABLES: &'static [&'static str] = &["backend", "client_ip", "host", "request_id", "scheme"];
+
+/// Headers that define how the message is framed, which rules must not change
+const FRAMING: &'static [&'static str] = &["connection", "content-length", "transfer-encoding", "upgrade"];
+
+/// What a rule does to a header
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Action {
+    /// Set the header only if the message does not have it
+    #[serde(rename = "add")]
+    Add,
+
+    /// Replace any values of the header
+    #[serde(rename = "set")]
+    Set,
+
+    /// Add another value to the header, keeping any values it has
+    #[serde(rename = "append")]
+    Append,
+
+    /// Remove the header
+    #[serde(rename = "remove")]
+    Remove,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = match *self {
+            Action::Add => "add",
+            Action::Set => "set",
+            Action::Append => "append",
+            Action::Remove => "remove",
+        };
+        f.write_str(action)
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Action, String> {
+        match s {
+            "add" => Ok(Action::Add),
+            "set" => Ok(Action::Set),
+            "append" => Ok(Action::Append),
+            "remove" => Ok(Action::Remove),
+            _ => Err(format!("unknown header action {:?}", s)),
+        }
+    }
+}
+
+/// A change to a single header
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRule {
+    pub action: Action,
+    pub name: String,
+
+    /// The value of the header, which may refer to variables such as `$client_ip`. Use `$$` for a
+    /// literal `$`. Not set for `remove`.
+    pub value: Option<String>,
+}
+
+impl HeaderRule {
+    /// Check the header name, and that the value only refers to known variables
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() || !self.name.bytes().all(is_token) {
+            return Err(format!("invalid header name {:?}", self.name));
+        }
+        if FRAMING.contains(&self.name.to_lowercase().as_str()) {
+            return Err(format!("header {} cannot be changed by a rule", self.name));
+        }
+
+        match (self.action, &self.value) {
+            (Action::Remove, &Some(_)) => {
+                Err(format!("rule to remove header {} cannot have a value", self.name))
+            }
+            (Action::Remove, &None) => Ok(()),
+            (_, &None) => Err(format!("rule for header {} must have a value", self.name)),
+            (_, &Some(ref value)) => {
+                if value.contains('\r') || value.contains('\n') {
+                    return Err(format!("value of header {} cannot span lines", self.name));
+                }
+                parse(value).map(|_| ())
+            }
+        }
+    }
+}
+
+/// The rules of a pool or a route
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRules {
+    /// Applied in order to each request before it is sent to a server
+    #[serde(default)]
+    pub request: Vec<HeaderRule>,
+
+    /// Applied in order to each response from a server before it is returned to the client
+    #[serde(default)]
+    pub response: Vec<HeaderRule>,
+}
+
+impl HeaderRules {
+    pub fn validate(&self) -> Result<(), String> {
+        for rule in self.request.iter().chain(self.response.iter()) {
+            rule.validate()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.request.is_empty() && self.response.is_empty()
+    }
+
+    /// These rules followed by `other`
+    pub fn then(&self, other: &HeaderRules) -> HeaderRules {
+        HeaderRules {
+            request: self.request.iter().chain(other.request.iter()).cloned().collect(),
+            response: self.response.iter().chain(other.response.iter()).cloned().collect(),
+        }
+    }
+}
+
+/// The values of the variables for one request
+#[derive(Clone, Debug)]
+pub struct Variables {
+    /// The address of the client, taken from the PROXY protocol header if there is one
+    pub client_ip: IpAddr,
+
+    /// A random id, unique to the request
+    pub request_id: String,
+
+    /// The hostname the request is for, without a port
+    pub host: String,
+
+    /// The scheme the client used to connect, either `http` or `https`
+    pub scheme: &'static str,
+
+    /// The host and port of the server the request is sent to, once one is picked
+    pub backend: String,
+}
+
+impl Variables {
+    fn get(&self, name: &str) -> String {
+        match name {
+            "backend" => self.backend.clone(),
+            "client_ip" => self.client_ip.to_string(),
+            "host" => self.host.clone(),
+            "request_id" => self.request_id.clone(),
+            "scheme" => self.scheme.to_string(),
+            _ => unreachable!("variables are checked when the rule is added"),
+        }
+    }
+}
+
+/// A new random request id of 32 hex digits
+pub fn request_id() -> String {
+    format!("{:016x}{:016x}", rand::random::<u64>(), rand::random::<u64>())
+}
+
+/// Apply `rules` to `headers` in order
+pub fn apply(rules: &[HeaderRule], headers: &mut Headers, vars: &Variables) {
+    for rule in rules {
+        let name = rule.name.clone();
+        let value = rule.value.as_ref().map(|value| interpolate(value, vars));
+        match (rule.action, value) {
+            (Action::Add, Some(value)) => {
+                if headers.get_raw(&name).is_none() {
+                    headers.set_raw(name, value);
+                }
+            }
+            (Action::Set, Some(value)) => headers.set_raw(name, value),
+            (Action::Append, Some(value)) => headers.append_raw(name, value),
+            (Action::Remove, _) => headers.remove_raw(&name),
+            (_, None) => {}
+        }
+    }
+}
+
+/// A part of a value
+#[derive(Debug, PartialEq)]
+enum Part<'a> {
+    Text(&'a str),
+    Variable(&'a str),
+}
+
+/// Split a value into text and variables
+fn parse(value: &str) -> Result<Vec<Part>, String> {
+    let mut parts = Vec::new();
+    let mut rest = value;
+    while let Some(dollar) = rest.find('$') {
+        if dollar > 0 {
+            parts.push(Part::Text(&rest[..dollar]));
+        }
+        rest = &rest[dollar + 1..];
+
+        if rest.starts_with('$') {
+            parts.push(Part::Text("$"));
+            rest = &rest[1..];
+            continue;
+        }
+
+        let end = rest.find(|c: char| !(c.is_ascii_lowercase() || c == '_')).unwrap_or(rest.len());
+        let name = &rest[..end];
+        if !VARIABLES.contains(&name) {
+            return Err(format!("unknown variable ${} in {:?}", name, value));
+        }
+        parts.push(Part::Variable(name));
+        rest = &rest[end..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Text(rest));
+    }
+
+    Ok(parts)
+}
+
+/// Replace the variables in a value that was checked with `validate`
+fn interpolate(value: &str, vars: &Variables) -> String {
+    let mut s = String::new();
+    for part in parse(value).unwrap_or_default() {
+        match part {
+            Part::Text(text) => s.push_str(text),
+            Part::Variable(name) => s.push_str(&vars.get(name)),
+        }
+    }
+    s
+}
+
+/// Whether `b` may be part of a header name, per RFC 7230 Section 3.2.6
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use hyper::Headers;
+
+    use super::{apply, parse, Action, HeaderRule, Part, Variables};
+
+    fn rule(action: Action, name: &str, value: Option<&str>) -> HeaderRule {
+        HeaderRule {
+            action: action,
+            name: name.to_string(),
+            value: value.map(|v| v.to_string()),
+        }
+    }
+
+    fn vars() -> Variables {
+        Variables {
+            client_ip: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            request_id: "abc123".to_string(),
+            host: "www.example.com".to_string(),
+            scheme: "https",
+            backend: "127.0.0.1:8080".to_string(),
+        }
+    }
+
+    fn raw(headers: &Headers, name: &str) -> Option<Vec<String>> {
+        headers.get_raw(name).map(|raw| {
+            raw.iter().map(|v| String::from_utf8_lossy(v).into_owned()).collect()
+        })
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut headers = Headers::new();
+        headers.set_raw("Server", "nginx");
+        headers.set_raw("X-Powered-By", "PHP");
+        headers.set_raw("Strict-Transport-Security", "max-age=60");
+        headers.set_raw("Via", "1.1 cdn");
+
+        let rules = vec![
+            rule(Action::Remove, "Server", None),
+            rule(Action::Remove, "x-powered-by", None),
+            rule(Action::Add, "Strict-Transport-Security", Some("max-age=31536000")),
+            rule(Action::Add, "X-Frame-Options", Some("DENY")),
+            rule(Action::Set, "X-Request-Id", Some("$request_id")),
+            rule(Action::Append, "Via", Some("1.1 weldr ($backend)")),
+            rule(Action::Set, "X-Client", Some("$client_ip via $scheme://$host, $$5")),
+        ];
+        apply(&rules, &mut headers, &vars());
+
+        assert_eq!(None, raw(&headers, "Server"));
+        assert_eq!(None, raw(&headers, "X-Powered-By"));
+        assert_eq!(Some(vec!["max-age=60".to_string()]), raw(&headers, "Strict-Transport-Security"));
+        assert_eq!(Some(vec!["DENY".to_string()]), raw(&headers, "X-Frame-Options"));
+        assert_eq!(Some(vec!["abc123".to_string()]), raw(&headers, "X-Request-Id"));
+        assert_eq!(
+            Some(vec!["1.1 cdn".to_string(), "1.1 weldr (127.0.0.1:8080)".to_string()]),
+            raw(&headers, "Via")
+        );
+        assert_eq!(
+            Some(vec!["10.0.0.1 via https://www.example.com, $5".to_string()]),
+            raw(&headers, "X-Client")
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            vec![Part::Text("id="), Part::Variable("request_id"), Part::Text(";")],
+            parse("id=$request_id;").unwrap()
+        );
+        assert_eq!(vec![Part::Text("$"), Part::Text("5")], parse("$$5").unwrap());
+        assert!(parse("$unknown").is_err());
+        assert!(parse("$").is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(rule(Action::Remove, "Server", None).validate().is_ok());
+        assert!(rule(Action::Set, "X-Id", Some("$request_id")).validate().is_ok());
+
+        assert!(rule(Action::Remove, "Server", Some("nginx")).validate().is_err());
+        assert!(rule(Action::Set, "X-Id", None).validate().is_err());
+        assert!(rule(Action::Set, "X Id", Some("1")).validate().is_err());
+        assert!(rule(Action::Set, "", Some("1")).validate().is_err());
+        assert!(rule(Action::Set, "X-Id", Some("1\r\nX-Other: 2")).validate().is_err());
+        assert!(rule(Action::Set, "X-Id", Some("$nope")).validate().is_err());
+        assert!(rule(Action::Set, "Content-Length", Some("0")).validate().is_err());
+        assert!(rule(Action::Remove, "Transfer-Encoding", None).validate().is_err());
+    }
+}
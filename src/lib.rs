@@ -4,9 +4,11 @@ extern crate log;
 extern crate env_logger;
 #[macro_use]
 extern crate hyper;
+extern crate httparse;
 extern crate hyper_tls;
 extern crate hyper_timeout;
 extern crate native_tls;
+extern crate openssl;
 extern crate serde;
 extern crate serde_json;
 #[macro_use]
@@ -15,20 +17,36 @@ extern crate tokio_core;
 extern crate tokio_service;
 extern crate tokio_timer;
 extern crate tokio_io;
+extern crate tokio_tls;
+extern crate tokio_signal;
 extern crate nix;
 extern crate libc;
 extern crate capnp;
 #[macro_use]
 extern crate capnp_rpc;
 extern crate net2;
+extern crate rand;
+extern crate regex;
+extern crate toml;
 
 pub mod weldr_capnp {
     include!(concat!(env!("OUT_DIR"), "/weldr_capnp.rs"));
 }
 
+pub mod balancer;
 pub mod server;
 pub mod pool;
 pub mod proxy;
 pub mod mgmt;
 pub mod stats;
+pub mod outlier;
+pub mod retry;
+pub mod forwarded;
+pub mod proxy_protocol;
+pub mod tls;
+pub mod upgrade;
+pub mod vhost;
+pub mod route;
+pub mod header_rules;
 pub mod config;
+pub mod signal;
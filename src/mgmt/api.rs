@@ -1,15 +1,22 @@
+use std::time::SystemTime;
+
 use serde_json;
 
 use futures::{Future, Stream};
 
 use tokio_core::reactor::Handle;
 
-use hyper::{self, Delete, Get, Post, StatusCode, Uri};
+use hyper::{self, Delete, Get, Post, Put, StatusCode, Uri};
 use hyper::server::{Service, Request, Response};
 use hyper::header::{ContentLength, ContentType};
 
-use server::Server;
+use server::{Server, DEFAULT_WEIGHT, MAX_WEIGHT};
+use header_rules::HeaderRules;
 use pool::Pool;
+use proxy_protocol::ProxyProtocol;
+use route::{Route, RouteSpec};
+use tls::{Certificate, ServerTls};
+use vhost::{self, Pools, DEFAULT_POOL};
 use super::manager::Manager;
 
 // HATEOAS links: https://en.wikipedia.org/wiki/HATEOAS
@@ -29,8 +36,61 @@ struct PoolServers {
 #[derive(Debug, Serialize, Deserialize)]
 struct PoolServer {
     pub url: String,
+    pub weight: Option<u32>,
+    pub proxy_protocol: Option<ProxyProtocol>,
+    pub tls: Option<ServerTls>,
+    pub ejections: Option<Vec<Ejection>>,
+    pub links: Option<Vec<Link>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PoolList {
+    pub pools: Vec<PoolHosts>,
+    pub links: Option<Vec<Link>>,
+}
+
+/// A pool and the hostnames it serves
+#[derive(Debug, Serialize, Deserialize)]
+struct PoolHosts {
+    pub name: String,
+    pub hosts: Vec<String>,
+    pub headers: Option<HeaderRules>,
+    pub links: Option<Vec<Link>>,
+}
+
+/// The routes, in the order they are checked
+#[derive(Debug, Serialize, Deserialize)]
+struct RouteList {
+    pub routes: Vec<RouteSpec>,
     pub links: Option<Vec<Link>>,
 }
+
+/// A worker that stopped sending requests to a server because the requests kept failing
+#[derive(Debug, Serialize, Deserialize)]
+struct Ejection {
+    pub worker: u64,
+    pub remaining_secs: u64,
+}
+#[derive(Debug, Serialize, Deserialize)]
+struct HostCertificates {
+    pub certificates: Vec<HostCertificate>,
+    pub links: Option<Vec<Link>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HostCertificate {
+    pub hostname: String,
+    pub links: Option<Vec<Link>>,
+}
+
+/// A PEM encoded certificate chain and private key uploaded to be served to TLS clients
+#[derive(Debug, Serialize, Deserialize)]
+struct UploadCertificate {
+    pub hostnames: Option<Vec<String>>,
+    pub cert: String,
+    pub key: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Index {
     pub about: String,
@@ -46,6 +106,21 @@ fn index() -> Response {
                 href: "/servers".to_string(),
                 method: None,
             },
+            Link {
+                rel: "pools".to_string(),
+                href: "/pools".to_string(),
+                method: None,
+            },
+            Link {
+                rel: "routes".to_string(),
+                href: "/routes".to_string(),
+                method: None,
+            },
+            Link {
+                rel: "certificates".to_string(),
+                href: "/certificates".to_string(),
+                method: None,
+            },
         ],
     };
 
@@ -57,15 +132,47 @@ fn index() -> Response {
         .with_body(body)
 }
 
-fn all_servers_reponse(pool: &Pool) -> Response {
+/// The path of the servers of the pool called `name`
+///
+/// The servers of the default pool are also found at `/servers`.
+fn servers_path(name: &str) -> String {
+    if name == DEFAULT_POOL {
+        "/servers".to_string()
+    } else {
+        format!("/pools/{}/servers", name)
+    }
+}
+
+fn all_servers_reponse(name: &str, pool: &Pool, manager: &Manager) -> Response {
     let backends = pool.all();
     let all_servers: Vec<Server> = backends.iter().map(|backend| backend.server()).collect();
     let servers: Vec<PoolServer> = all_servers
         .into_iter()
         .map(|server| {
-            let delete_href = format!("/servers/{}", server.url());
+            let url = server.url();
+            let delete_href = format!(
+                "{}/{}/{}",
+                servers_path(name),
+                url.host().unwrap_or(""),
+                server_port(&url).unwrap_or(0)
+            );
+            let ejections = manager
+                .ejections(&server)
+                .into_iter()
+                .map(|(worker, remaining)| {
+                    Ejection {
+                        worker: worker,
+                        remaining_secs: remaining.as_secs(),
+                    }
+                })
+                .collect();
             PoolServer {
                 url: server.url().as_ref().to_string(),
+                weight: Some(server.weight()),
+                proxy_protocol: server.proxy_protocol(),
+                // the private key is never sent back
+                tls: server.tls().map(|tls| ServerTls { key: None, ..tls }),
+                ejections: Some(ejections),
                 links: Some(vec![
                     Link {
                         rel: "delete".to_string(),
@@ -82,7 +189,7 @@ fn all_servers_reponse(pool: &Pool) -> Response {
         links: Some(vec![
             Link {
                 rel: "add".to_string(),
-                href: "/servers".to_string(),
+                href: servers_path(name),
                 method: Some("POST".to_string()),
             },
         ]),
@@ -96,13 +203,15 @@ fn all_servers_reponse(pool: &Pool) -> Response {
         .with_body(body)
 }
 
-fn get_servers(pool: &Pool) -> Response {
-    all_servers_reponse(pool)
+fn get_servers(name: &str, pool: &Pool, manager: &Manager) -> Response {
+    all_servers_reponse(name, pool, manager)
 }
 
 fn add_server(
     request: Request,
+    name: String,
     pool: Pool,
+    pools: Pools,
     manager: Manager,
     handle: Handle,
 ) -> Box<Future<Item = Response, Error = hyper::Error>> {
@@ -110,24 +219,61 @@ fn add_server(
     let work = request
         .body().concat2().and_then(move |chunk| {
             let response = match serde_json::from_slice::<PoolServer>(&chunk) {
+                Ok(PoolServer { weight: Some(weight), .. }) if weight == 0 || weight > MAX_WEIGHT => {
+                    let body = format!("weight must be between 1 and {}", MAX_WEIGHT);
+                    Response::new()
+                        .with_status(StatusCode::BadRequest)
+                        .with_header(ContentLength(body.len() as u64))
+                        .with_body(body)
+                }
                 Ok(server) => {
                     debug!("body = {:?}", server);
 
-                    let backend = server
-                        .url
-                        .parse::<Uri>()
-                        .expect("Failed to parse server url");
-                    let backend = Server::new(backend, true);
-                    pool.add(backend);
-                    debug!("Added new server to pool");
-
-                    let backend = server
-                        .url
-                        .parse::<Uri>()
-                        .expect("Failed to parse server url");
-                    manager.publish_new_server(backend, handle);
-
-                    all_servers_reponse(&pool)
+                    let url = parse_server_url(&server.url)
+                        .and_then(|url| validate_tls(&url, server.tls.as_ref()).map(|()| url))
+                        .map(|url| {
+                            let owner = pools.pool_of(&url);
+                            (url, owner)
+                        });
+                    match url {
+                        Ok((ref url, Some(ref owner))) if *owner != name => {
+                            let body = format!("server {} is already in pool {}", url, owner);
+                            Response::new()
+                                .with_status(StatusCode::Conflict)
+                                .with_header(ContentLength(body.len() as u64))
+                                .with_body(body)
+                        }
+                        Ok((url, _)) => {
+                            let backend = Server::new(url, true)
+                                .with_weight(server.weight.unwrap_or(DEFAULT_WEIGHT))
+                                .with_proxy_protocol(server.proxy_protocol)
+                                .with_tls(server.tls);
+                            match pool.find(&backend) {
+                                // the workers keep the weight a server was added with
+                                Some(ref existing) if existing.weight() != backend.weight() => {
+                                    let body = format!(
+                                        "server {} is already in pool {} with weight {}",
+                                        backend.url(),
+                                        name,
+                                        existing.weight()
+                                    );
+                                    Response::new()
+                                        .with_status(StatusCode::Conflict)
+                                        .with_header(ContentLength(body.len() as u64))
+                                        .with_body(body)
+                                }
+                                _ => {
+                                    pool.add(backend.clone());
+                                    debug!("Added new server to pool {}", name);
+
+                                    manager.publish_new_server(&name, &backend, handle);
+
+                                    all_servers_reponse(&name, &pool, &manager)
+                                }
+                            }
+                        }
+                        Err(e) => bad_request(e),
+                    }
                 }
                 Err(e) => {
                     let body = format!("invalid JSON: {}", e);
@@ -144,36 +290,676 @@ fn add_server(
     Box::new(work)
 }
 
-// TODO figure out how to parse out query k/v pairs or parse the path
-//fn remove_server(context: Context, response: Response) {
-//
-//    let pool: &Pool = context.global.get().expect("Failed to get global pool");
-//    let url = context.variables.get("url").expect("Failed to get url");
-//    match FromStr::from_str(&url) {
-//        Ok(server) => {
-//            pool.remove(&server);
-//            info!("Removed server {:?} from pool", server);
-//        }
-//        _ => ()
-//    };
-//    all_servers_reponse(pool, response)
-//}
+/// Check the TLS settings of a server being added to the pool
+/// Parse the url of a server, which must be `http://` or `https://`
+fn parse_server_url(url: &str) -> Result<Uri, String> {
+    let uri = url.parse::<Uri>().map_err(|e| format!("server url {:?} is invalid: {}", url, e))?;
+    match uri.scheme() {
+        Some("http") | Some("https") if uri.authority().is_some() => Ok(uri),
+        _ => Err(format!("server url {:?} must start with http:// or https://", url)),
+    }
+}
+
+fn validate_tls(url: &Uri, tls: Option<&ServerTls>) -> Result<(), String> {
+    let tls = match tls {
+        Some(tls) => tls,
+        None => return Ok(()),
+    };
+
+    if url.scheme() != Some("https") {
+        return Err(format!("server {} must be https:// to set `tls`", url));
+    }
+    tls.connector().map_err(|e| format!("invalid `tls`: {}", e))?;
+
+    if tls.insecure_skip_verify {
+        warn!("Adding server {} without verifying its certificate. Do not do this in production.", url);
+    }
+
+    Ok(())
+}
+
+/// Port of a server url, falling back to the default port of the scheme
+fn server_port(url: &Uri) -> Option<u16> {
+    url.port().or_else(|| match url.scheme() {
+        Some("http") => Some(80),
+        Some("https") => Some(443),
+        _ => None,
+    })
+}
+
+/// Parse the ip and port out of a `/servers/:ip/:port` path
+fn parse_server_path(path: &str) -> Option<(&str, u16)> {
+    if !path.starts_with("/servers/") {
+        return None;
+    }
+
+    let mut segments = path["/servers/".len()..].split('/');
+    let ip = match segments.next() {
+        Some(ip) if !ip.is_empty() => ip,
+        _ => return None,
+    };
+    let port = match segments.next().and_then(|port| port.parse::<u16>().ok()) {
+        Some(port) => port,
+        None => return None,
+    };
+
+    if segments.next().is_some() {
+        return None;
+    }
+
+    Some((ip, port))
+}
+
+fn remove_server(path: &str, name: &str, pool: &Pool, manager: &Manager, handle: Handle) -> Response {
+    let (ip, port) = match parse_server_path(path) {
+        Some(addr) => addr,
+        None => return Response::new().with_status(StatusCode::NotFound),
+    };
+
+    let backend = pool.all().into_iter().find(|backend| {
+        let url = backend.server().url();
+        url.host() == Some(ip) && server_port(&url) == Some(port)
+    });
+
+    match backend {
+        Some(backend) => {
+            let server = backend.server();
+            pool.remove(&server);
+            info!("Removed server {:?} from pool {}", server, name);
+
+            manager.publish_remove_server(name, &server, handle);
+
+            all_servers_reponse(name, pool, manager)
+        }
+        None => {
+            let body = format!("server {}:{} is not in pool {}", ip, port, name);
+            Response::new()
+                .with_status(StatusCode::NotFound)
+                .with_header(ContentLength(body.len() as u64))
+                .with_body(body)
+        }
+    }
+}
+
+fn all_pools_response(pools: &Pools) -> Response {
+    let all = pools
+        .all()
+        .into_iter()
+        .map(|vhost| {
+            let mut links = vec![
+                Link {
+                    rel: "servers".to_string(),
+                    href: servers_path(vhost.name()),
+                    method: None,
+                },
+                Link {
+                    rel: "headers".to_string(),
+                    href: format!("/pools/{}/headers", vhost.name()),
+                    method: Some("PUT".to_string()),
+                },
+            ];
+            if !vhost.is_default() {
+                links.push(Link {
+                    rel: "delete".to_string(),
+                    href: format!("/pools/{}", vhost.name()),
+                    method: Some("DELETE".to_string()),
+                });
+            }
+
+            PoolHosts {
+                name: vhost.name().to_string(),
+                hosts: vhost.hosts().to_vec(),
+                headers: Some((*vhost.headers()).clone()),
+                links: Some(links),
+            }
+        })
+        .collect();
+
+    let pool_list = PoolList {
+        pools: all,
+        links: Some(vec![
+            Link {
+                rel: "add".to_string(),
+                href: "/pools".to_string(),
+                method: Some("POST".to_string()),
+            },
+        ]),
+    };
+
+    let body = serde_json::to_string_pretty(&pool_list).expect("Failed to encode into json");
+
+    Response::new()
+        .with_header(ContentLength(body.len() as u64))
+        .with_header(ContentType::json())
+        .with_body(body)
+}
+
+/// Check a pool being added, the hostnames it serves and its header rules
+///
+/// Returns the hostnames in lowercase. A hostname served by another pool is a conflict.
+fn validate_pool(
+    pools: &Pools,
+    pool: PoolHosts,
+) -> Result<(String, Vec<String>, HeaderRules), (StatusCode, String)> {
+    if !vhost::is_pool_name(&pool.name) {
+        return Err((
+            StatusCode::BadRequest,
+            format!("pool name {:?} must only have letters, digits, `-` and `_`", pool.name),
+        ));
+    }
+    if pools.get(&pool.name).is_some() {
+        return Err((StatusCode::Conflict, format!("pool {} already exists", pool.name)));
+    }
+
+    let mut hosts = Vec::new();
+    for host in pool.hosts {
+        if !vhost::is_host(&host) {
+            return Err((StatusCode::BadRequest, format!("invalid host {:?}", host)));
+        }
+        if let Some(owner) = pools.owner(&host) {
+            return Err((
+                StatusCode::Conflict,
+                format!("host {} is already served by pool {}", host, owner),
+            ));
+        }
+        hosts.push(host.to_lowercase());
+    }
+
+    let headers = pool.headers.unwrap_or_default();
+    if let Err(e) = headers.validate() {
+        return Err((StatusCode::BadRequest, format!("invalid header rule: {}", e)));
+    }
+
+    Ok((pool.name, hosts, headers))
+}
+
+fn add_pool(
+    request: Request,
+    pools: Pools,
+    manager: Manager,
+    handle: Handle,
+) -> Box<Future<Item = Response, Error = hyper::Error>> {
+    let work = request.body().concat2().and_then(move |chunk| {
+        let response = match serde_json::from_slice::<PoolHosts>(&chunk) {
+            Ok(pool) => {
+                match validate_pool(&pools, pool) {
+                    Ok((name, hosts, headers)) => {
+                        pools.add(&name, hosts.clone());
+                        pools.set_headers(&name, headers.clone());
+                        info!("Added pool {} for {:?}", name, hosts);
+
+                        manager.publish_add_pool(&name, &hosts, &headers, handle);
+
+                        all_pools_response(&pools)
+                    }
+                    Err((status, body)) => {
+                        Response::new()
+                            .with_status(status)
+                            .with_header(ContentLength(body.len() as u64))
+                            .with_body(body)
+                    }
+                }
+            }
+            Err(e) => bad_request(format!("invalid JSON: {}", e)),
+        };
+
+        ::futures::finished(response)
+    });
+
+    Box::new(work)
+}
+
+fn remove_pool(name: &str, pools: &Pools, manager: &Manager, handle: Handle) -> Response {
+    if name == DEFAULT_POOL {
+        return bad_request("the default pool cannot be removed".to_string());
+    }
+
+    if let Some(route) = pools.routes().iter().find(|route| route.pool() == name) {
+        let body = format!("pool {} is used by route {:?}", name, route.spec());
+        return Response::new()
+            .with_status(StatusCode::Conflict)
+            .with_header(ContentLength(body.len() as u64))
+            .with_body(body);
+    }
+
+    pools.remove(name);
+    info!("Removed pool {}", name);
+
+    manager.publish_remove_pool(name, handle);
+
+    all_pools_response(pools)
+}
+
+/// Replace the header rules of the pool called `name`
+fn set_pool_headers(
+    request: Request,
+    name: String,
+    pools: Pools,
+    manager: Manager,
+    handle: Handle,
+) -> Box<Future<Item = Response, Error = hyper::Error>> {
+    let work = request.body().concat2().and_then(move |chunk| {
+        let headers = match serde_json::from_slice::<HeaderRules>(&chunk) {
+            Ok(headers) => headers,
+            Err(e) => return ::futures::finished(bad_request(format!("invalid JSON: {}", e))),
+        };
+        if let Err(e) = headers.validate() {
+            return ::futures::finished(bad_request(format!("invalid header rule: {}", e)));
+        }
+
+        pools.set_headers(&name, headers.clone());
+        info!("Changed header rules of pool {}", name);
+
+        manager.publish_pool_headers(&name, &headers, handle);
+
+        ::futures::finished(all_pools_response(&pools))
+    });
+
+    Box::new(work)
+}
+
+/// Split a `/pools/:name` path into the name of the pool and the rest of the path
+fn parse_pool_path(path: &str) -> Option<(&str, &str)> {
+    if !path.starts_with("/pools/") {
+        return None;
+    }
+
+    let path = &path["/pools/".len()..];
+    let (name, rest) = match path.find('/') {
+        Some(slash) => (&path[..slash], &path[slash..]),
+        None => (path, ""),
+    };
+
+    if name.is_empty() {
+        return None;
+    }
+
+    Some((name, rest))
+}
+
+fn all_routes_response(pools: &Pools) -> Response {
+    let route_list = RouteList {
+        routes: pools.routes().iter().map(|route| route.spec().clone()).collect(),
+        links: Some(vec![
+            Link {
+                rel: "add".to_string(),
+                href: "/routes".to_string(),
+                method: Some("POST".to_string()),
+            },
+            Link {
+                rel: "replace".to_string(),
+                href: "/routes".to_string(),
+                method: Some("PUT".to_string()),
+            },
+        ]),
+    };
+
+    let body = serde_json::to_string_pretty(&route_list).expect("Failed to encode into json");
+
+    Response::new()
+        .with_header(ContentLength(body.len() as u64))
+        .with_header(ContentType::json())
+        .with_body(body)
+}
+
+/// Check a route being added and compile it
+///
+/// The route must send requests to a pool that exists.
+fn validate_route(pools: &Pools, spec: RouteSpec) -> Result<Route, String> {
+    if pools.get(&spec.pool).is_none() {
+        return Err(format!("pool {} does not exist", spec.pool));
+    }
+
+    Route::new(spec).map_err(|e| format!("invalid route: {}", e))
+}
+
+/// Add a route after the existing routes, or replace all routes when `replace` is set
+fn set_routes(
+    request: Request,
+    replace: bool,
+    pools: Pools,
+    manager: Manager,
+    handle: Handle,
+) -> Box<Future<Item = Response, Error = hyper::Error>> {
+    let work = request.body().concat2().and_then(move |chunk| {
+        let specs = if replace {
+            serde_json::from_slice::<RouteList>(&chunk).map(|list| list.routes)
+        } else {
+            serde_json::from_slice::<RouteSpec>(&chunk).map(|spec| vec![spec])
+        };
+
+        let specs = match specs {
+            Ok(specs) => specs,
+            Err(e) => return ::futures::finished(bad_request(format!("invalid JSON: {}", e))),
+        };
+
+        let mut routes = if replace { Vec::new() } else { pools.routes() };
+        for spec in specs {
+            match validate_route(&pools, spec) {
+                Ok(route) => routes.push(route),
+                Err(e) => return ::futures::finished(bad_request(e)),
+            }
+        }
+
+        info!("Changed routes to {} routes", routes.len());
+        pools.set_routes(routes);
+
+        manager.publish_routes(handle);
+
+        ::futures::finished(all_routes_response(&pools))
+    });
+
+    Box::new(work)
+}
+
+/// Remove the route at `/routes/:index`, where the first route has an index of 0
+fn remove_route(path: &str, pools: &Pools, manager: &Manager, handle: Handle) -> Response {
+    let index = match parse_route_path(path) {
+        Some(index) => index,
+        None => return bad_request(format!("invalid route path {}", path)),
+    };
+
+    let mut routes = pools.routes();
+    if index >= routes.len() {
+        let body = format!("there is no route {}", index);
+        return Response::new()
+            .with_status(StatusCode::NotFound)
+            .with_header(ContentLength(body.len() as u64))
+            .with_body(body);
+    }
+
+    let route = routes.remove(index);
+    pools.set_routes(routes);
+    info!("Removed route {:?}", route.spec());
+
+    manager.publish_routes(handle);
+
+    all_routes_response(pools)
+}
+
+/// The index of the route in a `/routes/:index` path
+fn parse_route_path(path: &str) -> Option<usize> {
+    if !path.starts_with("/routes/") {
+        return None;
+    }
+
+    path["/routes/".len()..].parse().ok()
+}
+
+fn bad_request(body: String) -> Response {
+    Response::new()
+        .with_status(StatusCode::BadRequest)
+        .with_header(ContentLength(body.len() as u64))
+        .with_body(body)
+}
+
+fn all_certificates_response(manager: &Manager) -> Response {
+    let certificates = manager
+        .certificates()
+        .into_iter()
+        .map(|hostname| {
+            let href = format!("/certificates/{}", hostname);
+            HostCertificate {
+                hostname: hostname,
+                links: Some(vec![
+                    Link {
+                        rel: "replace".to_string(),
+                        href: href.clone(),
+                        method: Some("PUT".to_string()),
+                    },
+                    Link {
+                        rel: "delete".to_string(),
+                        href: href,
+                        method: Some("DELETE".to_string()),
+                    },
+                ]),
+            }
+        })
+        .collect();
+
+    let host_certificates = HostCertificates {
+        certificates: certificates,
+        links: Some(vec![
+            Link {
+                rel: "add".to_string(),
+                href: "/certificates".to_string(),
+                method: Some("POST".to_string()),
+            },
+        ]),
+    };
+
+    let body = serde_json::to_string_pretty(&host_certificates).expect("Failed to encode into json");
+
+    Response::new()
+        .with_header(ContentLength(body.len() as u64))
+        .with_header(ContentType::json())
+        .with_body(body)
+}
+
+/// Parse the hostname out of a `/certificates/:hostname` path
+fn parse_certificate_path(path: &str) -> Option<&str> {
+    if !path.starts_with("/certificates/") {
+        return None;
+    }
+
+    let hostname = &path["/certificates/".len()..];
+    if hostname.is_empty() || hostname.contains('/') {
+        return None;
+    }
+
+    Some(hostname)
+}
+
+/// Check an uploaded certificate and work out the hostnames it is served for
+///
+/// `hostname` is the hostname in the path of a `PUT` request. Without it, the hostnames in the
+/// upload are used, or else the hostnames in the certificate itself. The certificate must be
+/// valid for every hostname it is served for, and valid now.
+fn validate_certificate(
+    upload: UploadCertificate,
+    hostname: Option<&str>,
+) -> Result<(Vec<String>, Certificate), String> {
+    let certificate = Certificate::from_pem(upload.cert.into_bytes(), upload.key.into_bytes())
+        .map_err(|e| format!("invalid certificate: {}", e))?;
+
+    let (not_before, not_after) = certificate
+        .validity()
+        .map_err(|e| format!("invalid certificate: {}", e))?;
+    let now = SystemTime::now();
+    if now < not_before {
+        return Err("certificate is not valid yet".to_string());
+    }
+    if now > not_after {
+        return Err("certificate has expired".to_string());
+    }
+
+    let hostnames = match (hostname, upload.hostnames) {
+        (Some(_), Some(_)) => return Err("hostnames are taken from the path".to_string()),
+        (Some(hostname), None) => vec![hostname.to_string()],
+        (None, Some(hostnames)) => hostnames,
+        (None, None) => {
+            certificate
+                .hostnames()
+                .map_err(|e| format!("invalid certificate: {}", e))?
+        }
+    };
+    if hostnames.is_empty() {
+        return Err("certificate is not for any hostname".to_string());
+    }
+
+    for hostname in &hostnames {
+        let valid = certificate
+            .is_for(hostname)
+            .map_err(|e| format!("invalid certificate: {}", e))?;
+        if !valid {
+            return Err(format!("certificate is not valid for {}", hostname));
+        }
+    }
+
+    let hostnames = hostnames.iter().map(|hostname| hostname.to_lowercase()).collect();
+    Ok((hostnames, certificate))
+}
+
+/// Add or replace a certificate on every worker
+///
+/// A `POST` adds a certificate for hostnames that do not have one yet. A `PUT` to
+/// `/certificates/:hostname` adds or replaces the certificate for that hostname.
+fn upload_certificate(
+    request: Request,
+    manager: Manager,
+    handle: Handle,
+) -> Box<Future<Item = Response, Error = hyper::Error>> {
+    if !manager.tls_enabled() {
+        return Box::new(::futures::finished(
+            bad_request("TLS is not enabled".to_string()),
+        ));
+    }
+
+    let replace = request.method() == &Put;
+    let hostname = if replace {
+        match parse_certificate_path(request.path()) {
+            Some(hostname) => Some(hostname.to_string()),
+            None => {
+                return Box::new(::futures::finished(
+                    Response::new().with_status(StatusCode::NotFound),
+                ))
+            }
+        }
+    } else {
+        None
+    };
+
+    let work = request.body().concat2().and_then(move |chunk| {
+        let response = match serde_json::from_slice::<UploadCertificate>(&chunk) {
+            Ok(upload) => {
+                match validate_certificate(upload, hostname.as_ref().map(|h| h.as_str())) {
+                    Ok((hostnames, certificate)) => {
+                        let existing = manager.certificates();
+                        let taken: Vec<&String> = hostnames
+                            .iter()
+                            .filter(|hostname| existing.contains(hostname))
+                            .collect();
+
+                        if !replace && !taken.is_empty() {
+                            let body = format!("certificate for {:?} already exists", taken);
+                            Response::new()
+                                .with_status(StatusCode::Conflict)
+                                .with_header(ContentLength(body.len() as u64))
+                                .with_body(body)
+                        } else {
+                            info!("Publishing certificate for {:?}", hostnames);
+                            manager.publish_add_certificate(&hostnames, &certificate, handle);
+                            all_certificates_response(&manager)
+                        }
+                    }
+                    Err(e) => bad_request(e),
+                }
+            }
+            Err(e) => bad_request(format!("invalid JSON: {}", e)),
+        };
+
+        ::futures::finished(response)
+    });
+
+    Box::new(work)
+}
+
+fn remove_certificate(path: &str, manager: &Manager, handle: Handle) -> Response {
+    let hostname = match parse_certificate_path(path) {
+        Some(hostname) => hostname,
+        None => return Response::new().with_status(StatusCode::NotFound),
+    };
+
+    if manager.publish_remove_certificate(hostname, handle) {
+        info!("Removed certificate for {}", hostname);
+        all_certificates_response(manager)
+    } else {
+        let body = format!("no certificate for {}", hostname);
+        Response::new()
+            .with_status(StatusCode::NotFound)
+            .with_header(ContentLength(body.len() as u64))
+            .with_body(body)
+    }
+}
 
 #[derive(Debug)]
 pub struct Mgmt {
-    pool: Pool,
+    pools: Pools,
     handle: Handle,
     manager: Manager,
 }
 
 impl Mgmt {
-    pub fn new(pool: Pool, handle: Handle, manager: Manager) -> Mgmt {
+    pub fn new(pools: Pools, handle: Handle, manager: Manager) -> Mgmt {
         Mgmt {
-            pool: pool,
+            pools: pools,
             handle: handle,
             manager: manager,
         }
     }
+
+    /// Serve a request to `/pools/:name` or one of the resources of the pool
+    fn pool_request(&self, req: Request) -> Box<Future<Item = Response, Error = hyper::Error>> {
+        let (name, rest) = match parse_pool_path(req.path()) {
+            Some((name, rest)) => (name.to_string(), rest.to_string()),
+            None => {
+                return Box::new(::futures::finished(
+                    Response::new().with_status(StatusCode::NotFound),
+                ))
+            }
+        };
+
+        let pool = match self.pools.get(&name) {
+            Some(pool) => pool,
+            None => {
+                let body = format!("pool {} does not exist", name);
+                return Box::new(::futures::finished(
+                    Response::new()
+                        .with_status(StatusCode::NotFound)
+                        .with_header(ContentLength(body.len() as u64))
+                        .with_body(body),
+                ));
+            }
+        };
+
+        match (req.method(), rest.as_str()) {
+            (&Delete, "") => {
+                Box::new(::futures::finished(
+                    remove_pool(&name, &self.pools, &self.manager, self.handle.clone()),
+                ))
+            }
+            (&Get, "/servers") => {
+                Box::new(::futures::finished(get_servers(&name, &pool, &self.manager)))
+            }
+            (&Put, "/headers") => {
+                set_pool_headers(req, name, self.pools.clone(), self.manager.clone(), self.handle.clone())
+            }
+            (&Post, "/servers") => {
+                add_server(
+                    req,
+                    name,
+                    pool,
+                    self.pools.clone(),
+                    self.manager.clone(),
+                    self.handle.clone(),
+                )
+            }
+            (&Delete, path) if path.starts_with("/servers/") => {
+                Box::new(::futures::finished(remove_server(
+                    path,
+                    &name,
+                    &pool,
+                    &self.manager,
+                    self.handle.clone(),
+                )))
+            }
+            _ => {
+                Box::new(::futures::finished(
+                    Response::new().with_status(StatusCode::NotFound),
+                ))
+            }
+        }
+    }
 }
 
 impl Service for Mgmt {
@@ -185,22 +971,64 @@ impl Service for Mgmt {
     fn call(&self, req: Request) -> Self::Future {
         match (req.method(), req.path()) {
             (&Get, "/") => Box::new(::futures::finished(index())),
-            (&Get, "/servers") => Box::new(::futures::finished(get_servers(&self.pool))),
+            (&Get, "/servers") => {
+                let pool = self.pools.default_pool();
+                Box::new(::futures::finished(get_servers(DEFAULT_POOL, &pool, &self.manager)))
+            }
             (&Post, "/servers") => {
                 add_server(
                     req,
-                    self.pool.clone(),
+                    DEFAULT_POOL.to_string(),
+                    self.pools.default_pool(),
+                    self.pools.clone(),
                     self.manager.clone(),
                     self.handle.clone(),
                 )
             }
-            (&Delete, "/servers") => {
-                let body = "Remove server";
-                Box::new(::futures::finished(
-                    Response::new()
-                        .with_header(ContentLength(body.len() as u64))
-                        .with_body(body),
-                ))
+            (&Delete, path) if path.starts_with("/servers/") => {
+                Box::new(::futures::finished(remove_server(
+                    path,
+                    DEFAULT_POOL,
+                    &self.pools.default_pool(),
+                    &self.manager,
+                    self.handle.clone(),
+                )))
+            }
+            (&Get, "/pools") => Box::new(::futures::finished(all_pools_response(&self.pools))),
+            (&Post, "/pools") => {
+                add_pool(req, self.pools.clone(), self.manager.clone(), self.handle.clone())
+            }
+            (_, path) if path.starts_with("/pools/") => self.pool_request(req),
+            (&Get, "/routes") => Box::new(::futures::finished(all_routes_response(&self.pools))),
+            (&Post, "/routes") => {
+                set_routes(req, false, self.pools.clone(), self.manager.clone(), self.handle.clone())
+            }
+            (&Put, "/routes") => {
+                set_routes(req, true, self.pools.clone(), self.manager.clone(), self.handle.clone())
+            }
+            (&Delete, path) if path.starts_with("/routes/") => {
+                Box::new(::futures::finished(remove_route(
+                    path,
+                    &self.pools,
+                    &self.manager,
+                    self.handle.clone(),
+                )))
+            }
+            (&Get, "/certificates") => {
+                Box::new(::futures::finished(all_certificates_response(&self.manager)))
+            }
+            (&Post, "/certificates") => {
+                upload_certificate(req, self.manager.clone(), self.handle.clone())
+            }
+            (&Put, path) if path.starts_with("/certificates/") => {
+                upload_certificate(req, self.manager.clone(), self.handle.clone())
+            }
+            (&Delete, path) if path.starts_with("/certificates/") => {
+                Box::new(::futures::finished(remove_certificate(
+                    path,
+                    &self.manager,
+                    self.handle.clone(),
+                )))
             }
             _ => {
                 Box::new(::futures::finished(
@@ -210,3 +1038,77 @@ impl Service for Mgmt {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_certificate_path, parse_pool_path, parse_route_path, parse_server_path,
+                parse_server_url, server_port};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_parse_server_path() {
+        assert_eq!(
+            Some(("127.0.0.1", 12345)),
+            parse_server_path("/servers/127.0.0.1/12345")
+        );
+        assert_eq!(None, parse_server_path("/servers/127.0.0.1"));
+        assert_eq!(None, parse_server_path("/servers/127.0.0.1/abc"));
+        assert_eq!(None, parse_server_path("/servers//12345"));
+        assert_eq!(None, parse_server_path("/servers/127.0.0.1/12345/extra"));
+        assert_eq!(None, parse_server_path("/pools/127.0.0.1/12345"));
+    }
+
+    #[test]
+    fn test_parse_pool_path() {
+        assert_eq!(Some(("api", "")), parse_pool_path("/pools/api"));
+        assert_eq!(Some(("api", "/servers")), parse_pool_path("/pools/api/servers"));
+        assert_eq!(
+            Some(("api", "/servers/127.0.0.1/12345")),
+            parse_pool_path("/pools/api/servers/127.0.0.1/12345")
+        );
+        assert_eq!(None, parse_pool_path("/pools/"));
+        assert_eq!(None, parse_pool_path("/pools//servers"));
+        assert_eq!(None, parse_pool_path("/servers/api"));
+    }
+
+    #[test]
+    fn test_parse_route_path() {
+        assert_eq!(Some(0), parse_route_path("/routes/0"));
+        assert_eq!(Some(12), parse_route_path("/routes/12"));
+        assert_eq!(None, parse_route_path("/routes/"));
+        assert_eq!(None, parse_route_path("/routes/-1"));
+        assert_eq!(None, parse_route_path("/routes/api"));
+        assert_eq!(None, parse_route_path("/pools/0"));
+    }
+
+    #[test]
+    fn test_parse_certificate_path() {
+        assert_eq!(Some("example.com"), parse_certificate_path("/certificates/example.com"));
+        assert_eq!(Some("*.example.com"), parse_certificate_path("/certificates/*.example.com"));
+        assert_eq!(None, parse_certificate_path("/certificates/"));
+        assert_eq!(None, parse_certificate_path("/certificates/example.com/extra"));
+        assert_eq!(None, parse_certificate_path("/servers/example.com"));
+    }
+
+    #[test]
+    fn test_server_port() {
+        let url = FromStr::from_str("http://127.0.0.1:12345").unwrap();
+        assert_eq!(Some(12345), server_port(&url));
+
+        let url = FromStr::from_str("http://127.0.0.1").unwrap();
+        assert_eq!(Some(80), server_port(&url));
+
+        let url = FromStr::from_str("https://127.0.0.1").unwrap();
+        assert_eq!(Some(443), server_port(&url));
+    }
+
+    #[test]
+    fn test_parse_server_url() {
+        assert!(parse_server_url("http://127.0.0.1:12345").is_ok());
+        assert!(parse_server_url("https://example.com").is_ok());
+        assert!(parse_server_url("127.0.0.1:12345").is_err());
+        assert!(parse_server_url("ftp://127.0.0.1").is_err());
+        assert!(parse_server_url("/servers").is_err());
+        assert!(parse_server_url("http://[::1").is_err());
+    }
+}
@@ -6,11 +6,12 @@ use std::collections::HashMap;
 use futures::Future;
 use tokio_core::reactor::Handle;
 use hyper::{Client, Uri};
-use hyper_tls::HttpsConnector;
 
-use pool::{Pool, Backend};
+use pool::Backend;
 use config::Config;
 use mgmt::Manager;
+use tls;
+use vhost::Pools;
 
 #[derive(Debug, Clone, Copy)]
 enum HealthState {
@@ -106,14 +107,24 @@ impl BackendHealth {
     }
 }
 
-pub fn run(pool: Pool, handle: &Handle, config: &Config, manager: Manager, health: BackendHealth) {
+/// Check the health of the servers in every pool
+pub fn run(pools: Pools, handle: &Handle, config: &Config, manager: Manager, health: BackendHealth) {
+    // health checks are not made on behalf of a client, so servers that want a PROXY protocol
+    // header are sent one without addresses
     let client = Client::configure()
-        .connector(HttpsConnector::new(4, &handle).unwrap())
+        .connector(tls::https_connector(4, &handle, pools.clone(), None).unwrap())
         .build(&handle);
 
-    let backends = pool.all();
+    let backends = pools
+        .all()
+        .into_iter()
+        .flat_map(|vhost| {
+            let name = vhost.name().to_string();
+            vhost.pool().all().into_iter().map(move |backend| (name.clone(), backend))
+        })
+        .collect::<Vec<_>>();
     let handle1 = handle.clone();
-    for backend in backends {
+    for (name, backend) in backends {
         let manager = manager.clone();
         let handle1 = handle1.clone();
         let server = backend.server();
@@ -126,8 +137,7 @@ pub fn run(pool: Pool, handle: &Handle, config: &Config, manager: Manager, healt
                 if backend.is_active() {
                     info!("Disabling {:?} in pool", backend);
                     backend.mark_down();
-                    let uri = backend.server().url();
-                    manager.publish_server_state_down(&uri, handle1.clone());
+                    manager.publish_server_state_down(&name, &backend.server(), handle1.clone());
                 }
                 continue;
             }
@@ -145,15 +155,13 @@ pub fn run(pool: Pool, handle: &Handle, config: &Config, manager: Manager, healt
                     if health.should_mark_active(backend.clone(), allowed_successes) {
                         info!("Enabling {:?} in pool", backend);
                         backend.mark_active();
-                        let uri = backend.server().url();
-                        manager.publish_server_state_active(&uri, handle1.clone());
+                        manager.publish_server_state_active(&name, &backend.server(), handle1.clone());
                     }
                 } else {
                     if health.should_mark_down(backend.clone(), allowed_failures) {
                         info!("Disabling {:?} in pool", backend);
                         backend.mark_down();
-                        let uri = backend.server().url();
-                        manager.publish_server_state_down(&uri, handle1.clone());
+                        manager.publish_server_state_down(&name, &backend.server(), handle1.clone());
                     }
                 }
                 ::futures::finished(())
@@ -163,8 +171,7 @@ pub fn run(pool: Pool, handle: &Handle, config: &Config, manager: Manager, healt
                 if health.should_mark_down(backend.clone(), allowed_failures) {
                     info!("Disabling {:?} in pool", backend);
                     backend.mark_down();
-                    let uri = backend.server().url();
-                    manager.publish_server_state_down(&uri, handle1.clone());
+                    manager.publish_server_state_down(&name, &backend.server(), handle1.clone());
                 }
                 ::futures::finished(())
             }
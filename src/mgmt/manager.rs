@@ -4,22 +4,141 @@
 //! functionality. The pubsub implementation is subject to change, so the implementation is
 //! hidden from the rest of the system.
 
-use std::net::SocketAddr;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::ffi::OsString;
+use std::net::{self, SocketAddr};
 use std::io;
 use std::process::Command;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::process::CommandExt;
 use std::cell::RefCell;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-use libc::pid_t;
+use futures::{Future, Stream};
+use futures::sync::oneshot;
+use libc::{self, pid_t, SIGCHLD, SIGHUP, SIGUSR2};
+use net2::TcpBuilder;
+use net2::unix::UnixTcpBuilderExt;
+use nix;
+use nix::errno::Errno;
+use nix::sys::signal::{self, kill};
+use nix::sys::wait::{waitpid, WaitStatus, WNOHANG};
 use nix::unistd::{fork, ForkResult};
-use tokio_core::reactor::Handle;
-use hyper::Uri;
+use tokio_core::reactor::{Handle, Interval, Timeout};
+use tokio_signal::unix::Signal;
+
+use config::{Config, ConfigError};
+use header_rules::HeaderRules;
+use server::Server;
+use tls::Certificate;
+use vhost::Pools;
+use super::state;
+
+/// A worker that exits within this many seconds of being started is considered to be crash looping
+const CRASH_LOOP_WINDOW_SECS: u64 = 10;
+
+/// The longest we will wait before respawning a crash looping worker
+const MAX_RESPAWN_DELAY_SECS: u64 = 60;
+
+/// Extra time, on top of the drain timeout, given to workers to exit before they are killed
+const SHUTDOWN_GRACE_SECS: u64 = 5;
+
+/// Time a new generation of workers has to become ready before the upgrade is abandoned
+const UPGRADE_TIMEOUT_SECS: u64 = 30;
+
+/// Environment variable that holds the fd of the listener a worker inherits from the manager
+pub const LISTEN_FD: &'static str = "WELDR_LISTEN_FD";
+
+/// Environment variable that holds the fd of the TLS listener a worker inherits from the manager
+pub const TLS_LISTEN_FD: &'static str = "WELDR_TLS_LISTEN_FD";
+
+/// Bind a listener for the workers to accept client connections on
+pub fn bind_listener(addr: SocketAddr) -> io::Result<net::TcpListener> {
+    let listener = TcpBuilder::new_v4()?;
+    listener.reuse_address(true)?;
+    listener.reuse_port(true)?;
+    listener.bind(&addr)?;
+    listener.listen(128)
+}
+
+/// The listener a worker inherited from the manager in the environment variable `var`, if any
+pub fn inherited_listener(var: &str) -> Option<net::TcpListener> {
+    match env::var(var).ok().and_then(|fd| fd.parse::<RawFd>().ok()) {
+        // the manager keeps the listener open and only passes its fd to the workers it starts
+        Some(fd) => Some(unsafe { net::TcpListener::from_raw_fd(fd) }),
+        None => None,
+    }
+}
 
 #[derive(Debug)]
 pub struct Worker {
     id: u64,
     pid: pid_t,
+    started: Instant,
+
+    /// Number of consecutive times this worker exited shortly after being started
+    crashes: u32,
+
+    /// Workers are started in generations. A new generation is started on upgrade or reload.
+    generation: u64,
+
+    /// Set when the worker has been asked to drain and exit, so it is not respawned
+    retiring: bool,
+}
+
+/// Servers that workers ejected from their pool as outliers
+#[derive(Debug, Default)]
+pub struct Ejections {
+    /// When each worker, by pid, sends requests to a server again, keyed by the server url
+    servers: HashMap<String, HashMap<pid_t, Instant>>,
+}
+
+impl Ejections {
+    pub fn new() -> Ejections {
+        Ejections::default()
+    }
+
+    /// Record that the worker with `pid` ejected the server with `url` for `duration`
+    pub fn eject(&mut self, url: &str, pid: pid_t, duration: Duration) {
+        self.servers
+            .entry(url.to_string())
+            .or_insert_with(HashMap::new)
+            .insert(pid, Instant::now() + duration);
+    }
+
+    /// The workers, by pid, that have the server with `url` ejected and the time left
+    ///
+    /// Ejections that have ended are forgotten.
+    pub fn ejected(&mut self, url: &str) -> Vec<(pid_t, Duration)> {
+        let now = Instant::now();
+        let ejected = match self.servers.get_mut(url) {
+            Some(workers) => {
+                workers.retain(|_, until| *until > now);
+                let mut ejected: Vec<(pid_t, Duration)> = workers
+                    .iter()
+                    .map(|(pid, until)| (*pid, until.duration_since(now)))
+                    .collect();
+                ejected.sort();
+                ejected
+            }
+            None => return Vec::new(),
+        };
+
+        if ejected.is_empty() {
+            self.servers.remove(url);
+        }
+
+        ejected
+    }
+
+    /// Forget all ejections of the server with `url`
+    pub fn remove(&mut self, url: &str) {
+        self.servers.remove(url);
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -31,85 +150,771 @@ pub struct Manager {
 pub struct Inner {
     workers: Vec<Worker>,
     subscribers: Rc<RefCell<capnp::SubscriberMap>>,
+
+    /// The pools of servers published to the workers
+    pools: Pools,
+
+    /// Servers the workers ejected from their pool as outliers
+    ejections: Rc<RefCell<Ejections>>,
+
+    /// The certificates published to the workers, by the hostname they are served for
+    certificates: Rc<RefCell<BTreeMap<String, Certificate>>>,
+
+    /// Set once the manager starts shutting down so exited workers are not respawned
+    shutting_down: bool,
+
+    /// Notified when the last worker exits during shutdown
+    stopped: Option<oneshot::Sender<()>>,
+
+    /// Path to the executable used to start workers
+    ///
+    /// This is resolved when the manager starts. If the binary is replaced on disk, new workers
+    /// will be started using the replacement.
+    exe: PathBuf,
+
+    /// Arguments the manager was started with, passed along to each worker
+    args: Vec<OsString>,
+
+    /// The current configuration
+    config: Config,
+
+    /// Path the configuration was loaded from, if any
+    config_path: Option<PathBuf>,
+
+    /// Number of workers in each generation
+    worker_count: usize,
+
+    /// The generation new workers belong to
+    generation: u64,
+
+    /// Set while a new generation of workers is being started
+    upgrading: bool,
+
+    /// The listeners, with the environment variable their fd is passed to workers in
+    ///
+    /// The manager binds them once and every generation of workers inherits them, so connections
+    /// waiting to be accepted are not lost when the old workers exit.
+    listeners: Vec<(&'static str, net::TcpListener)>,
 }
 
 impl Manager {
-    pub fn new() -> Manager {
+    pub fn new(config: Config, config_path: Option<PathBuf>, pools: Pools) -> Manager {
+        let exe = env::current_exe().expect("Failed to get executable path");
+        let mut certificates = BTreeMap::new();
+        add_configured_certificates(&config, &mut certificates);
+
         Manager {
             inner: Rc::new(RefCell::new(Inner {
                 workers: Vec::new(),
                 subscribers: Rc::new(RefCell::new(capnp::SubscriberMap::new())),
+                pools: pools,
+                ejections: Rc::new(RefCell::new(Ejections::new())),
+                certificates: Rc::new(RefCell::new(certificates)),
+                shutting_down: false,
+                stopped: None,
+                exe: exe,
+                args: env::args_os().skip(1).collect(),
+                config: config,
+                config_path: config_path,
+                worker_count: 0,
+                generation: 0,
+                upgrading: false,
+                listeners: Vec::new(),
             })),
         }
     }
 
-    pub fn start_workers(&mut self, count: usize) -> io::Result<()> {
-        (0..count as u64)
-            .map(|id| start_worker(id))
-            .collect::<io::Result<Vec<Worker>>>()
-            .and_then(|workers| {
-                self.inner.borrow_mut().workers.extend(workers);
+    /// The current configuration
+    pub fn config(&self) -> Config {
+        self.inner.borrow().config.clone()
+    }
+
+    pub fn start_workers(&mut self) -> io::Result<()> {
+        let mut inner = self.inner.borrow_mut();
+        let count = inner.config.workers;
+        inner.worker_count = count;
+
+        if inner.listeners.is_empty() {
+            let mut listeners = vec![(LISTEN_FD, bind_listener(inner.config.listen)?)];
+            if let Some(ref tls) = inner.config.tls {
+                listeners.push((TLS_LISTEN_FD, bind_listener(tls.listen)?));
+            }
+            inner.listeners = listeners;
+        }
+
+        let generation = inner.generation;
+        let workers = (0..count as u64)
+            .map(|id| start_worker(id, &inner.exe, &inner.args, &inner.listeners, generation))
+            .collect::<io::Result<Vec<Worker>>>()?;
+
+        inner.workers.extend(workers);
+        Ok(())
+    }
+
+    /// Watch for workers that exit and respawn them
+    ///
+    /// The manager is sent `SIGCHLD` whenever a worker exits. The worker is reaped and a
+    /// replacement with the same id is started. A worker that keeps exiting right after it starts
+    /// is respawned with an exponentially increasing delay.
+    pub fn supervise(&self, handle: Handle) {
+        let manager = self.clone();
+        let handle1 = handle.clone();
+        let sigchld = Signal::new(SIGCHLD, &handle)
+            .flatten_stream()
+            .for_each(move |_| {
+                manager.reap(&handle1);
+                Ok(())
+            })
+            .map_err(|e| {
+                error!("Failed to handle SIGCHLD: {:?}", e);
+            });
+
+        handle.spawn(sigchld);
+    }
+
+    /// Reap all workers that have exited
+    ///
+    /// Signals are coalesced, so a single `SIGCHLD` may mean more than one worker exited.
+    fn reap(&self, handle: &Handle) {
+        loop {
+            match waitpid(-1, Some(WNOHANG)) {
+                Ok(WaitStatus::Exited(pid, status)) => {
+                    warn!("Worker pid {} exited with status {}", pid, status);
+                    self.respawn(pid, handle);
+                }
+                Ok(WaitStatus::Signaled(pid, signal, _)) => {
+                    warn!("Worker pid {} was killed by signal {:?}", pid, signal);
+                    self.respawn(pid, handle);
+                }
+                Ok(WaitStatus::StillAlive) => return,
+                Ok(status) => {
+                    debug!("Ignoring worker status change {:?}", status);
+                }
+                Err(nix::Error::Sys(Errno::ECHILD)) => return,
+                Err(e) => {
+                    error!("Failed to reap workers: {:?}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn respawn(&self, pid: pid_t, handle: &Handle) {
+        let worker = {
+            let workers = &mut self.inner.borrow_mut().workers;
+            match workers.iter().position(|w| w.pid == pid) {
+                Some(i) => workers.remove(i),
+                None => {
+                    debug!("Reaped unknown child pid {}", pid);
+                    return;
+                }
+            }
+        };
+
+        {
+            let mut inner = self.inner.borrow_mut();
+            if inner.shutting_down {
+                info!("Worker id {} stopped", worker.id);
+                if inner.workers.is_empty() {
+                    if let Some(stopped) = inner.stopped.take() {
+                        let _ = stopped.send(());
+                    }
+                }
+                return;
+            }
+        }
+
+        if worker.retiring {
+            info!(
+                "Worker id {} from generation {} retired",
+                worker.id,
+                worker.generation
+            );
+            return;
+        }
+
+        let crashes = if worker.started.elapsed() < Duration::from_secs(CRASH_LOOP_WINDOW_SECS) {
+            worker.crashes + 1
+        } else {
+            0
+        };
+
+        let delay = respawn_delay(crashes);
+        if delay > Duration::from_secs(0) {
+            warn!(
+                "Worker id {} is crash looping. Respawning in {} seconds",
+                worker.id,
+                delay.as_secs()
+            );
+        }
+
+        let id = worker.id;
+        let generation = worker.generation;
+        let manager = self.clone();
+        let timeout = match Timeout::new(delay, handle) {
+            Ok(timeout) => timeout,
+            Err(e) => {
+                error!("Failed to schedule respawn of worker id {}: {:?}", id, e);
+                return;
+            }
+        };
+
+        let respawn = timeout.then(move |_| {
+            {
+                let inner = manager.inner.borrow();
+                if inner.shutting_down {
+                    return Ok(());
+                }
+
+                // the generation of the worker may have been retired during the delay. Workers
+                // of the previous generation are still needed until an upgrade finishes, and are
+                // retired along with the rest of their generation.
+                if generation != inner.generation && !inner.upgrading {
+                    info!(
+                        "Not respawning worker id {} from retired generation {}",
+                        id,
+                        generation
+                    );
+                    return Ok(());
+                }
+            }
+
+            let started = {
+                let inner = manager.inner.borrow();
+                start_worker(id, &inner.exe, &inner.args, &inner.listeners, generation)
+            };
+
+            match started {
+                Ok(mut worker) => {
+                    worker.crashes = crashes;
+                    manager.inner.borrow_mut().workers.push(worker);
+                }
+                Err(e) => {
+                    error!("Failed to respawn worker id {}: {:?}", id, e);
+                }
+            }
+
+            Ok(())
+        });
+
+        handle.spawn(respawn);
+    }
+
+    /// Start a new generation of workers on `SIGUSR2` or `SIGHUP`
+    ///
+    /// `SIGUSR2` is used to upgrade the workers to a new weldr binary. `SIGHUP` is used to reload
+    /// the configuration. In both cases, the new workers are started from the executable on disk and
+    /// the current workers are drained without dropping connections. The manager itself keeps
+    /// running the binary it was started with.
+    pub fn upgrade_on_signal(&self, handle: Handle) {
+        let sighup = Signal::new(SIGHUP, &handle).flatten_stream();
+        let sigusr2 = Signal::new(SIGUSR2, &handle).flatten_stream();
+
+        let manager = self.clone();
+        let handle1 = handle.clone();
+        let upgrades = sighup
+            .select(sigusr2)
+            .for_each(move |signal| {
+                if signal == SIGHUP {
+                    info!("Received SIGHUP. Reloading configuration");
+                    if let Err(e) = manager.reload_config() {
+                        error!("Keeping the current configuration and workers. {}", e);
+                        return Ok(());
+                    }
+                } else {
+                    info!("Received SIGUSR2. Upgrading workers");
+                }
+
+                manager.upgrade(&handle1);
                 Ok(())
             })
+            .map_err(|e| {
+                error!("Failed to handle upgrade signal: {:?}", e);
+            });
+
+        handle.spawn(upgrades);
+    }
+
+    /// Read the configuration file again
+    ///
+    /// The health check, timeout, drain and worker settings take effect right away or with the
+    /// next generation of workers. The admin, internal and listen addresses, as well as the health
+    /// check interval, are only read when weldr starts. The initial pools, servers and routes are ignored,
+    /// since they may have been changed using the management API. Certificates in the
+    /// configuration replace any certificate uploaded for the same hostname.
+    fn reload_config(&self) -> Result<(), ConfigError> {
+        let path = match self.inner.borrow().config_path {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+
+        let config = Config::from_file(&path)?;
+
+        let mut inner = self.inner.borrow_mut();
+        if config.admin != inner.config.admin || config.internal != inner.config.internal {
+            warn!("Changing the admin or internal address requires a restart");
+        }
+        if config.health_check.interval != inner.config.health_check.interval {
+            warn!("Changing the health check interval requires a restart");
+        }
+        let tls_listen = |config: &Config| config.tls.as_ref().map(|tls| tls.listen);
+        if config.listen != inner.config.listen || tls_listen(&config) != tls_listen(&inner.config) {
+            warn!("Changing the listen addresses requires a restart");
+        }
+
+        inner.worker_count = config.workers;
+        add_configured_certificates(&config, &mut inner.certificates.borrow_mut());
+        inner.config = config;
+        info!("Reloaded configuration from {}", path.display());
+
+        Ok(())
+    }
+
+    /// Replace the current workers with a new generation
+    ///
+    /// Every worker accepts connections on the listeners of the manager, so both generations
+    /// accept connections during the handover, and connections waiting to be accepted when the old
+    /// workers exit are accepted by the new ones. Each new worker is sent the current pool when it subscribes. Once
+    /// every new worker has received the pool, the old workers are asked to drain and exit. If the
+    /// new generation is not ready in time, it is stopped and the old generation keeps running.
+    pub fn upgrade(&self, handle: &Handle) {
+        let (previous, generation) = {
+            let mut inner = self.inner.borrow_mut();
+            if inner.shutting_down {
+                warn!("Ignoring upgrade request while shutting down");
+                return;
+            }
+            if inner.upgrading {
+                warn!("Ignoring upgrade request while an upgrade is in progress");
+                return;
+            }
+
+            inner.upgrading = true;
+            let previous = inner.generation;
+            inner.generation += 1;
+
+            (previous, inner.generation)
+        };
+
+        info!("Starting worker generation {}", generation);
+
+        let started = {
+            let inner = self.inner.borrow();
+            (0..inner.worker_count as u64)
+                .map(|id| start_worker(id, &inner.exe, &inner.args, &inner.listeners, generation))
+                .collect::<io::Result<Vec<Worker>>>()
+        };
+
+        match started {
+            Ok(workers) => {
+                self.inner.borrow_mut().workers.extend(workers);
+            }
+            Err(e) => {
+                error!("Failed to start worker generation {}: {:?}", generation, e);
+                self.retire_workers(|w| w.generation == generation);
+                self.finish_upgrade(Some(previous));
+                return;
+            }
+        }
+
+        let interval = Interval::new(Duration::from_millis(100), handle);
+        let deadline = Timeout::new(Duration::from_secs(UPGRADE_TIMEOUT_SECS), handle);
+        let (interval, deadline) = match (interval, deadline) {
+            (Ok(interval), Ok(deadline)) => (interval, deadline),
+            (Err(e), _) | (_, Err(e)) => {
+                error!("Failed to schedule upgrade: {:?}", e);
+                self.retire_workers(|w| w.generation == generation);
+                self.finish_upgrade(Some(previous));
+                return;
+            }
+        };
+
+        let manager = self.clone();
+        let ready = interval
+            .take_while(move |_| Ok(!manager.is_generation_ready(generation)))
+            .for_each(|_| Ok(()))
+            .map(|_| true);
+        let deadline = deadline.map(|_| false);
+
+        let manager = self.clone();
+        let upgrade = ready.select(deadline).then(move |res| {
+            match res {
+                Ok((true, _)) => {
+                    info!("Worker generation {} is ready", generation);
+                    manager.retire_workers(|w| w.generation <= previous);
+                    manager.finish_upgrade(None);
+                }
+                Ok((false, _)) => {
+                    error!(
+                        "Worker generation {} was not ready in time. Keeping generation {}",
+                        generation,
+                        previous
+                    );
+                    manager.retire_workers(|w| w.generation == generation);
+                    manager.finish_upgrade(Some(previous));
+                }
+                Err((e, _)) => {
+                    error!("Upgrade to generation {} failed: {:?}", generation, e);
+                    manager.retire_workers(|w| w.generation == generation);
+                    manager.finish_upgrade(Some(previous));
+                }
+            }
+
+            Ok(())
+        });
+
+        handle.spawn(upgrade);
+    }
+
+    /// A generation is ready when all of its workers have subscribed and received the pool
+    fn is_generation_ready(&self, generation: u64) -> bool {
+        let inner = self.inner.borrow();
+        let subscribers = inner.subscribers.borrow();
+        let workers: Vec<&Worker> = inner
+            .workers
+            .iter()
+            .filter(|w| w.generation == generation)
+            .collect();
+
+        workers.len() == inner.worker_count && workers.iter().all(|w| subscribers.is_synced(w.pid))
+    }
+
+    /// Ask all workers matching `retire` to drain and exit
+    fn retire_workers<F>(&self, retire: F)
+    where
+        F: Fn(&Worker) -> bool,
+    {
+        let workers = &mut self.inner.borrow_mut().workers;
+        for worker in workers.iter_mut() {
+            if worker.retiring || !retire(worker) {
+                continue;
+            }
+
+            debug!(
+                "Retiring worker id {} from generation {}",
+                worker.id,
+                worker.generation
+            );
+            worker.retiring = true;
+            if let Err(e) = kill(worker.pid, signal::Signal::SIGTERM) {
+                error!("Failed to signal worker id {}: {:?}", worker.id, e);
+            }
+        }
+    }
+
+    /// Mark the upgrade as done, optionally rolling back to a previous generation
+    fn finish_upgrade(&self, rollback: Option<u64>) {
+        let mut inner = self.inner.borrow_mut();
+        inner.upgrading = false;
+        if let Some(generation) = rollback {
+            inner.generation = generation;
+        }
+    }
+
+    /// Gracefully stop the workers when the manager is asked to shutdown
+    ///
+    /// On `SIGTERM` or `SIGINT`, every worker is sent `SIGTERM` so that it stops accepting new
+    /// connections and drains its in-flight requests. The returned future resolves once all
+    /// workers have exited. Workers still running after the drain timeout are killed.
+    pub fn shutdown_on_signal(&self, handle: Handle) -> Box<Future<Item = (), Error = io::Error>> {
+        let manager = self.clone();
+        let shutdown = ::signal::shutdown(&handle).and_then(move |signal| {
+            info!("Received signal {}. Shutting down workers", signal);
+            let drain_timeout = manager.inner.borrow().config.drain_timeout;
+            manager.stop_workers(&handle, drain_timeout)
+        });
+
+        Box::new(shutdown)
+    }
+
+    fn stop_workers(
+        &self,
+        handle: &Handle,
+        drain_timeout: Duration,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        let (tx, rx) = oneshot::channel();
+
+        {
+            let mut inner = self.inner.borrow_mut();
+            inner.shutting_down = true;
+
+            if inner.workers.is_empty() {
+                return Box::new(::futures::finished(()));
+            }
+
+            inner.stopped = Some(tx);
+
+            for worker in inner.workers.iter() {
+                debug!("Sending SIGTERM to worker id {}", worker.id);
+                if let Err(e) = kill(worker.pid, signal::Signal::SIGTERM) {
+                    error!("Failed to signal worker id {}: {:?}", worker.id, e);
+                }
+            }
+        }
+
+        let grace = drain_timeout + Duration::from_secs(SHUTDOWN_GRACE_SECS);
+        let deadline = match Timeout::new(grace, handle) {
+            Ok(deadline) => deadline,
+            Err(e) => return Box::new(::futures::failed(e)),
+        };
+
+        let manager = self.clone();
+        let deadline = deadline.map(move |_| {
+            let workers = &mut manager.inner.borrow_mut().workers;
+            for worker in workers.drain(..) {
+                warn!("Worker id {} did not exit in time. Killing it", worker.id);
+                let _ = kill(worker.pid, signal::Signal::SIGKILL);
+                let _ = waitpid(worker.pid, None);
+            }
+        });
+
+        let stopped = rx.map(|_| info!("All workers stopped")).map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "Worker shutdown was cancelled")
+        });
+
+        Box::new(stopped.select(deadline).map(|_| ()).map_err(|(e, _)| e))
     }
 
     /// Listen for workers requesting to subscribe
     ///
     /// This works using a handle instead of running on the main core. This was done to allow the
     /// manager to perform other essential functions using the main core.
+    ///
+    /// Each new subscriber is sent a snapshot of the pools and the certificates so it starts with
+    /// the same view of the backends as the manager.
     pub fn listen(&self, addr: SocketAddr, handle: Handle) {
+        let inner = self.inner.borrow();
 
         // TODO should the publisher should check against the worker list?
-        capnp::listen(addr, handle, self.inner.borrow().subscribers.clone())
+        capnp::listen(
+            addr,
+            handle,
+            inner.subscribers.clone(),
+            inner.pools.clone(),
+            inner.ejections.clone(),
+            inner.certificates.clone(),
+        )
+    }
+
+    /// The workers that ejected `server` from their pool, by worker id, and the time left
+    ///
+    /// Ejections reported by workers that have since exited are left out.
+    pub fn ejections(&self, server: &Server) -> Vec<(u64, Duration)> {
+        let inner = self.inner.borrow();
+        let ejected = inner.ejections.borrow_mut().ejected(&format!("{}", server.url()));
+
+        ejected
+            .into_iter()
+            .filter_map(|(pid, remaining)| {
+                inner
+                    .workers
+                    .iter()
+                    .find(|w| w.pid == pid)
+                    .map(|w| (w.id, remaining))
+            })
+            .collect()
+    }
+
+    /// Restore the pools from the state file, if one is configured
+    pub fn load_state(&self) -> io::Result<()> {
+        let inner = self.inner.borrow();
+        if let Some(ref path) = inner.config.state_file {
+            let added = state::load(path, &inner.pools)?;
+            info!("Restored {} servers from {}", added, path.display());
+        }
+
+        Ok(())
+    }
+
+    /// Write the pools to the state file, if one is configured
+    ///
+    /// This is called each time a change to a pool is published. Failing to save the pools is
+    /// logged, but does not stop the change from being published.
+    fn save_state(&self) {
+        let inner = self.inner.borrow();
+        if let Some(ref path) = inner.config.state_file {
+            if let Err(e) = state::save(path, &inner.pools) {
+                error!("Failed to save pool to {}: {:?}", path.display(), e);
+            }
+        }
+    }
+
+    /// Ask all workers to add a new server to the pool called `pool`
+    pub fn publish_new_server(&self, pool: &str, server: &Server, handle: Handle) {
+        self.save_state();
+        capnp::publish_new_server(pool, server, handle, self.inner.borrow().subscribers.clone())
+    }
+
+    /// Ask all workers to remove a server from the pool called `pool`
+    pub fn publish_remove_server(&self, pool: &str, server: &Server, handle: Handle) {
+        self.save_state();
+        self.inner.borrow().ejections.borrow_mut().remove(&format!("{}", server.url()));
+        capnp::publish_remove_server(pool, server, handle, self.inner.borrow().subscribers.clone())
+    }
+
+    /// Ask all workers to mark a server down in the pool called `pool`
+    pub fn publish_server_state_down(&self, pool: &str, server: &Server, handle: Handle) {
+        self.save_state();
+        capnp::publish_server_state_down(pool, server, handle, self.inner.borrow().subscribers.clone())
+    }
+
+    /// Ask all workers to mark a server active in the pool called `pool`
+    pub fn publish_server_state_active(&self, pool: &str, server: &Server, handle: Handle) {
+        self.save_state();
+        capnp::publish_server_state_active(pool, server, handle, self.inner.borrow().subscribers.clone())
+    }
+
+    /// Ask all workers to add a pool called `name` that serves `hosts`, or to change the hosts and
+    /// header rules of the pool if they already have it
+    pub fn publish_add_pool(&self, name: &str, hosts: &[String], headers: &HeaderRules, handle: Handle) {
+        self.save_state();
+        capnp::publish_add_pool(name, hosts, headers, handle, self.inner.borrow().subscribers.clone())
+    }
+
+    /// Ask all workers to replace the header rules of the pool called `name`
+    pub fn publish_pool_headers(&self, name: &str, headers: &HeaderRules, handle: Handle) {
+        self.save_state();
+        capnp::publish_set_pool_headers(name, headers, handle, self.inner.borrow().subscribers.clone())
+    }
+
+    /// Ask all workers to remove the pool called `name` along with its servers
+    pub fn publish_remove_pool(&self, name: &str, handle: Handle) {
+        self.save_state();
+        capnp::publish_remove_pool(name, handle, self.inner.borrow().subscribers.clone())
+    }
+
+    /// Ask all workers to replace their routes with the routes of the manager
+    pub fn publish_routes(&self, handle: Handle) {
+        self.save_state();
+        let routes = self.inner.borrow().pools.routes();
+        capnp::publish_set_routes(&routes, handle, self.inner.borrow().subscribers.clone())
+    }
+
+    /// Whether the workers terminate TLS, and so can be sent certificates
+    pub fn tls_enabled(&self) -> bool {
+        self.inner.borrow().config.tls.is_some()
     }
 
-    /// Ask all workers to add a new server to their pool
-    pub fn publish_new_server(&self, url: Uri, handle: Handle) {
-        capnp::publish_new_server(url, handle, self.inner.borrow().subscribers.clone())
+    /// The hostnames the workers have a certificate for, sorted
+    pub fn certificates(&self) -> Vec<String> {
+        self.inner.borrow().certificates.borrow().keys().cloned().collect()
     }
 
-    /// Ask all workers to mark a server down in their pool
-    pub fn publish_server_state_down(&self, url: &Uri, handle: Handle) {
-        capnp::publish_server_state_down(url, handle, self.inner.borrow().subscribers.clone())
+    /// Ask all workers to serve `certificate` for `hostnames`
+    ///
+    /// New handshakes use the certificate as soon as a worker receives it. Connections that are
+    /// already established keep the certificate they were started with.
+    pub fn publish_add_certificate(
+        &self,
+        hostnames: &[String],
+        certificate: &Certificate,
+        handle: Handle,
+    ) {
+        let inner = self.inner.borrow();
+        {
+            let mut certificates = inner.certificates.borrow_mut();
+            for hostname in hostnames {
+                certificates.insert(hostname.to_lowercase(), certificate.clone());
+            }
+        }
+        capnp::publish_add_certificate(hostnames, certificate, handle, inner.subscribers.clone())
     }
 
-    /// Ask all workers to mark a server active in their pool
-    pub fn publish_server_state_active(&self, url: &Uri, handle: Handle) {
-        capnp::publish_server_state_active(url, handle, self.inner.borrow().subscribers.clone())
+    /// Ask all workers to stop serving a certificate for `hostname`
+    ///
+    /// Returns false if there was no certificate for `hostname`.
+    pub fn publish_remove_certificate(&self, hostname: &str, handle: Handle) -> bool {
+        let inner = self.inner.borrow();
+        let hostname = hostname.to_lowercase();
+        if inner.certificates.borrow_mut().remove(&hostname).is_none() {
+            return false;
+        }
+        capnp::publish_remove_certificate(&hostname, handle, inner.subscribers.clone());
+        true
     }
 }
 
-fn start_worker(id: u64) -> io::Result<Worker> {
-    let path = ::std::env::current_exe().expect("Failed to get executable path");
+/// Add the certificates in the TLS configuration to `certificates`, by hostname
+fn add_configured_certificates(config: &Config, certificates: &mut BTreeMap<String, Certificate>) {
+    if let Some(ref tls) = config.tls {
+        for configured in &tls.certificates {
+            for hostname in &configured.hostnames {
+                certificates.insert(hostname.to_lowercase(), configured.certificate.clone());
+            }
+        }
+    }
+}
 
+/// Fork and exec a worker
+///
+/// The worker is started with the same arguments as the manager, so it loads the same
+/// configuration. It inherits the `listeners` of the manager.
+fn start_worker(
+    id: u64,
+    path: &Path,
+    args: &[OsString],
+    listeners: &[(&'static str, net::TcpListener)],
+    generation: u64,
+) -> io::Result<Worker> {
     match fork()? {
         ForkResult::Parent { child, .. } => {
-            info!("Spawned worker id {} as child pid {}", id, child);
+            info!(
+                "Spawned worker id {} of generation {} as child pid {}",
+                id,
+                generation,
+                child
+            );
 
-            return Ok(Worker { id: id, pid: child });
+            return Ok(Worker {
+                id: id,
+                pid: child,
+                started: Instant::now(),
+                crashes: 0,
+                generation: generation,
+                retiring: false,
+            });
         }
         ForkResult::Child => {
             trace!("I am a new child");
 
-            Command::new(path.to_str().unwrap())
-                .arg("worker")
-                .arg("--id")
-                .arg(id.to_string())
-                .exec();
+            let mut command = Command::new(path.to_str().unwrap());
+            command.args(args).arg("worker").arg("--id").arg(id.to_string());
+            for &(var, ref listener) in listeners {
+                // sockets are closed on exec by default, so the flag is cleared for the worker
+                let fd = listener.as_raw_fd();
+                unsafe {
+                    libc::fcntl(fd, libc::F_SETFD, 0);
+                }
+                command.env(var, fd.to_string());
+            }
+            command.exec();
 
             unreachable!();
         }
     }
 }
 
+/// The delay before respawning a worker that has crashed `crashes` consecutive times
+///
+/// The first crash is respawned right away. After that, the delay doubles on each crash.
+fn respawn_delay(crashes: u32) -> Duration {
+    if crashes <= 1 {
+        return Duration::from_secs(0);
+    }
+
+    let secs = 1u64 << cmp::min(crashes - 2, 6);
+    Duration::from_secs(cmp::min(secs, MAX_RESPAWN_DELAY_SECS))
+}
+
 mod capnp {
     use std::cell::RefCell;
-    use std::collections::HashMap;
+    use std::collections::{BTreeMap, HashMap};
     use std::net::SocketAddr;
     use std::rc::Rc;
     use std::fmt;
+    use std::time::Duration;
 
-    use weldr_capnp::{publisher, subscriber, subscription};
+    use weldr_capnp::{publisher, route, server_tls, subscriber, subscription};
 
     use futures::{Future, Stream};
 
@@ -117,14 +922,35 @@ mod capnp {
     use capnp::capability::Promise;
     use capnp::Error;
 
+    use libc::pid_t;
+
     use tokio_io::AsyncRead;
-    use tokio_core::reactor::Handle;
+    use tokio_core::reactor::{Handle, Interval};
 
-    use hyper::Uri;
+    use server::Server;
+    use header_rules::{HeaderRule, HeaderRules};
+    use route::Route;
+    use tls::{Certificate, ServerTls};
+    use vhost::{Pools, DEFAULT_POOL};
+    use super::Ejections;
 
     struct SubscriberHandle {
         client: subscriber::Client<::capnp::data::Owned>,
         requests_in_flight: i32,
+
+        /// Process id of the worker that subscribed
+        pid: pid_t,
+
+        /// Set once the worker has received the pool snapshot
+        synced: bool,
+
+        /// Set when a pool was added or removed without telling the worker because it was busy,
+        /// so the worker is sent the pool snapshot again once it catches up
+        stale_pools: bool,
+
+        /// Set when a certificate change was not sent because the worker was busy, so the worker
+        /// is sent all certificates again once it catches up
+        stale_certificates: bool,
     }
 
     pub struct SubscriberMap {
@@ -145,6 +971,11 @@ mod capnp {
         pub fn new() -> SubscriberMap {
             SubscriberMap { subscribers: HashMap::new() }
         }
+
+        /// Whether the worker with `pid` has subscribed and received the pool snapshot
+        pub fn is_synced(&self, pid: pid_t) -> bool {
+            self.subscribers.values().any(|s| s.pid == pid && s.synced)
+        }
     }
 
     struct SubscriptionImpl {
@@ -173,13 +1004,27 @@ mod capnp {
     pub struct PublisherImpl {
         next_id: u64,
         subscribers: Rc<RefCell<SubscriberMap>>,
+        pools: Pools,
+        ejections: Rc<RefCell<Ejections>>,
+        certificates: Rc<RefCell<BTreeMap<String, Certificate>>>,
+        handle: Handle,
     }
 
     impl PublisherImpl {
-        pub fn new(subscribers: Rc<RefCell<SubscriberMap>>) -> PublisherImpl {
+        pub fn new(
+            subscribers: Rc<RefCell<SubscriberMap>>,
+            pools: Pools,
+            ejections: Rc<RefCell<Ejections>>,
+            certificates: Rc<RefCell<BTreeMap<String, Certificate>>>,
+            handle: Handle,
+        ) -> PublisherImpl {
             PublisherImpl {
                 next_id: 0,
                 subscribers: subscribers,
+                pools: pools,
+                ejections: ejections,
+                certificates: certificates,
+                handle: handle,
             }
         }
     }
@@ -190,12 +1035,18 @@ mod capnp {
             params: publisher::SubscribeParams<::capnp::data::Owned>,
             mut results: publisher::SubscribeResults<::capnp::data::Owned>,
         ) -> Promise<(), ::capnp::Error> {
-            info!("subscribe");
+            let params = pry!(params.get());
+            let pid = params.get_pid();
+            info!("subscribe from worker pid {}", pid);
             self.subscribers.borrow_mut().subscribers.insert(
                 self.next_id,
                 SubscriberHandle {
-                    client: pry!(pry!(params.get()).get_subscriber()),
+                    client: pry!(params.get_subscriber()),
                     requests_in_flight: 0,
+                    pid: pid,
+                    synced: false,
+                    stale_pools: false,
+                    stale_certificates: false,
                 },
             );
 
@@ -206,15 +1057,61 @@ mod capnp {
                 )).from_server::<::capnp_rpc::Server>(),
             );
 
+            // calls on a capability are delivered in the order they are made, so the snapshot
+            // reaches the worker before any message published after this point
+            publish_pool_snapshot(
+                self.next_id,
+                &self.pools,
+                self.handle.clone(),
+                self.subscribers.clone(),
+            );
+            publish_certificates_snapshot(
+                self.next_id,
+                &self.certificates.borrow(),
+                self.handle.clone(),
+                self.subscribers.clone(),
+            );
+
             self.next_id += 1;
             Promise::ok(())
         }
+
+        fn report_ejection(
+            &mut self,
+            params: publisher::ReportEjectionParams<::capnp::data::Owned>,
+            _results: publisher::ReportEjectionResults<::capnp::data::Owned>,
+        ) -> Promise<(), ::capnp::Error> {
+            let params = pry!(params.get());
+            let pid = params.get_pid();
+            let url = pry!(params.get_url());
+            let duration = Duration::from_millis(params.get_duration_ms());
+            warn!(
+                "Worker pid {} ejected {} for {} seconds",
+                pid,
+                url,
+                duration.as_secs()
+            );
+
+            self.ejections.borrow_mut().eject(url, pid, duration);
+            Promise::ok(())
+        }
     }
 
-    pub fn listen(addr: SocketAddr, handle: Handle, subscribers: Rc<RefCell<SubscriberMap>>) {
+    pub fn listen(
+        addr: SocketAddr,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+        pools: Pools,
+        ejections: Rc<RefCell<Ejections>>,
+        certificates: Rc<RefCell<BTreeMap<String, Certificate>>>,
+    ) {
         let socket = ::tokio_core::net::TcpListener::bind(&addr, &handle).unwrap();
 
-        let publisher_impl = PublisherImpl::new(subscribers);
+        let subscribers1 = subscribers.clone();
+        let pools1 = pools.clone();
+        let certificates1 = certificates.clone();
+        let publisher_impl =
+            PublisherImpl::new(subscribers, pools, ejections, certificates, handle.clone());
 
         let publisher = publisher::ToClient::new(publisher_impl)
             .from_server::<::capnp_rpc::Server>();
@@ -242,24 +1139,337 @@ mod capnp {
             .map_err(|_| ());
 
         handle.spawn(done);
+
+        catch_up(handle, subscribers1, pools1, certificates1);
     }
 
-    pub fn publish_new_server(url: Uri, handle: Handle, subscribers: Rc<RefCell<SubscriberMap>>) {
-        trace!("publish_new_server");
+    /// Send the pools or the certificates again to the workers that missed a change because they
+    /// were busy
+    fn catch_up(
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+        pools: Pools,
+        certificates: Rc<RefCell<BTreeMap<String, Certificate>>>,
+    ) {
+        let interval = match Interval::new(Duration::from_secs(1), &handle) {
+            Ok(interval) => interval,
+            Err(e) => {
+                error!("Failed to schedule catching up busy workers: {:?}", e);
+                return;
+            }
+        };
 
-        let subscribers1 = subscribers.clone();
-        let subs = &mut subscribers.borrow_mut().subscribers;
-        for (&idx, mut subscriber) in subs.iter_mut() {
-            if subscriber.requests_in_flight < 5 {
-                subscriber.requests_in_flight += 1;
+        let handle1 = handle.clone();
+        let catch_up = interval
+            .for_each(move |_| {
+                let stale: Vec<(u64, bool, bool)> = {
+                    let mut subscribers = subscribers.borrow_mut();
+                    subscribers
+                        .subscribers
+                        .iter_mut()
+                        .filter(|&(_, ref s)| {
+                            (s.stale_pools || s.stale_certificates) && s.requests_in_flight < 5
+                        })
+                        .map(|(&idx, s)| {
+                            let stale = (idx, s.stale_pools, s.stale_certificates);
+                            s.stale_pools = false;
+                            s.stale_certificates = false;
+                            stale
+                        })
+                        .collect()
+                };
 
-                let mut request = subscriber.client.add_server_request();
+                for (idx, stale_pools, stale_certificates) in stale {
+                    if stale_pools {
+                        debug!("Sending pool snapshot again to subscriber {}", idx);
+                        publish_pool_snapshot(idx, &pools, handle1.clone(), subscribers.clone());
+                    }
+                    if stale_certificates {
+                        debug!("Sending certificates again to subscriber {}", idx);
+                        publish_certificates_snapshot(
+                            idx,
+                            &certificates.borrow(),
+                            handle1.clone(),
+                            subscribers.clone(),
+                        );
+                    }
+                }
+                Ok(())
+            })
+            .map_err(|e| {
+                error!("Failed to catch up busy workers: {:?}", e);
+            });
 
-                request.get().set_url(&format!("{}", &url));
+        handle.spawn(catch_up);
+    }
 
-                let subscribers2 = subscribers1.clone();
-                handle.spawn(
-                    request
+    /// Fill in the TLS settings of a server, leaving out the settings that are not used
+    fn set_tls(mut builder: server_tls::Builder, tls: &ServerTls) {
+        builder.set_ca(tls.ca.as_ref().map_or("", |ca| ca.as_str()));
+        builder.set_cert(tls.cert.as_ref().map_or("", |cert| cert.as_str()));
+        builder.set_key(tls.key.as_ref().map_or("", |key| key.as_str()));
+        builder.set_server_name(tls.server_name.as_ref().map_or("", |name| name.as_str()));
+        builder.set_insecure_skip_verify(tls.insecure_skip_verify);
+    }
+
+    /// Fill in the header rules of a pool or a route
+    fn set_header_rules(mut builder: ::weldr_capnp::header_rules::Builder, rules: &HeaderRules) {
+        fn set(
+            mut list: ::capnp::struct_list::Builder<::weldr_capnp::header_rule::Owned>,
+            rules: &[HeaderRule],
+        ) {
+            for (i, rule) in rules.iter().enumerate() {
+                let mut r = list.borrow().get(i as u32);
+                r.set_action(&rule.action.to_string());
+                r.set_name(&rule.name);
+                r.set_value(rule.value.as_ref().map_or("", |value| value.as_str()));
+            }
+        }
+
+        set(builder.borrow().init_request(rules.request.len() as u32), &rules.request);
+        set(builder.init_response(rules.response.len() as u32), &rules.response);
+    }
+
+    /// Fill in a route, leaving out the settings that are not used
+    fn set_route(mut builder: route::Builder, route: &Route) {
+        let spec = route.spec();
+        builder.set_prefix(spec.prefix.as_ref().map_or("", |prefix| prefix.as_str()));
+        builder.set_regex(spec.regex.as_ref().map_or("", |regex| regex.as_str()));
+        builder.set_pool(if spec.pool == DEFAULT_POOL { "" } else { spec.pool.as_str() });
+        builder.set_host(spec.host.as_ref().map_or("", |host| host.as_str()));
+        builder.set_strip_prefix(spec.strip_prefix);
+        builder.set_rewrite(spec.rewrite.as_ref().map_or("", |rewrite| rewrite.as_str()));
+        set_header_rules(builder.init_headers(), &spec.headers);
+    }
+
+    fn publish_pool_snapshot(
+        idx: u64,
+        pools: &Pools,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        trace!("publish_pool_snapshot");
+
+        let subscribers1 = subscribers.clone();
+        let subs = &mut subscribers.borrow_mut().subscribers;
+        let subscriber = match subs.get_mut(&idx) {
+            Some(subscriber) => subscriber,
+            None => return,
+        };
+
+        subscriber.requests_in_flight += 1;
+
+        let mut request = subscriber.client.sync_pool_request();
+
+        {
+            let vhosts = pools.all();
+            let all: Vec<_> = vhosts
+                .iter()
+                .flat_map(|vhost| vhost.pool().all().into_iter().map(move |b| (vhost.name(), b)))
+                .collect();
+
+            let named: Vec<_> = vhosts.iter().filter(|vhost| !vhost.is_default()).collect();
+            {
+                let mut list = request.get().init_pools(named.len() as u32);
+                for (i, vhost) in named.iter().enumerate() {
+                    let mut v = list.borrow().get(i as u32);
+                    v.set_name(vhost.name());
+                    {
+                        let mut hosts = v.borrow().init_hosts(vhost.hosts().len() as u32);
+                        for (j, host) in vhost.hosts().iter().enumerate() {
+                            hosts.set(j as u32, host);
+                        }
+                    }
+                    set_header_rules(v.init_headers(), &vhost.headers());
+                }
+            }
+            // the default pool is always first
+            set_header_rules(request.get().init_headers(), &vhosts[0].headers());
+
+            let routes = pools.routes();
+            {
+                let mut list = request.get().init_routes(routes.len() as u32);
+                for (i, route) in routes.iter().enumerate() {
+                    set_route(list.borrow().get(i as u32), route);
+                }
+            }
+
+            let mut backends = request.get().init_backends(all.len() as u32);
+            for (i, &(name, ref backend)) in all.iter().enumerate() {
+                let server = backend.server();
+                let mut b = backends.borrow().get(i as u32);
+                b.set_pool(name);
+                b.set_url(&format!("{}", server.url()));
+                b.set_map_host(server.map_host());
+                b.set_active(backend.is_active());
+                b.set_weight(server.weight());
+                b.set_proxy_protocol(server.proxy_protocol().map_or(0, |p| p.version()));
+                if let Some(tls) = server.tls() {
+                    set_tls(b.init_tls(), &tls);
+                }
+            }
+        }
+
+        handle.spawn(
+            request
+                .send()
+                .promise
+                .then(move |r| {
+                    match r {
+                        Ok(_) => {
+                            subscribers1
+                                .borrow_mut()
+                                .subscribers
+                                .get_mut(&idx)
+                                .map(|ref mut s| {
+                                    s.requests_in_flight -= 1;
+                                    s.synced = true;
+                                });
+                        }
+                        Err(e) => {
+                            error!("Got error: {:?}. Dropping subscriber.", e);
+                            subscribers1.borrow_mut().subscribers.remove(&idx);
+                        }
+                    }
+                    Ok::<(), Error>(())
+                })
+                .map_err(|_| unreachable!()),
+        );
+    }
+
+    fn publish_certificates_snapshot(
+        idx: u64,
+        certificates: &BTreeMap<String, Certificate>,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        trace!("publish_certificates_snapshot");
+
+        let subscribers1 = subscribers.clone();
+        let subs = &mut subscribers.borrow_mut().subscribers;
+        let subscriber = match subs.get_mut(&idx) {
+            Some(subscriber) => subscriber,
+            None => return,
+        };
+
+        subscriber.requests_in_flight += 1;
+
+        let mut request = subscriber.client.sync_certificates_request();
+
+        {
+            let mut list = request.get().init_certificates(certificates.len() as u32);
+            for (i, (hostname, certificate)) in certificates.iter().enumerate() {
+                let mut c = list.borrow().get(i as u32);
+                c.set_hostname(hostname);
+                c.set_chain(&String::from_utf8_lossy(&certificate.chain));
+                c.set_key(&String::from_utf8_lossy(&certificate.key));
+            }
+        }
+
+        handle.spawn(
+            request
+                .send()
+                .promise
+                .then(move |r| {
+                    match r {
+                        Ok(_) => {
+                            subscribers1
+                                .borrow_mut()
+                                .subscribers
+                                .get_mut(&idx)
+                                .map(|ref mut s| { s.requests_in_flight -= 1; });
+                        }
+                        Err(e) => {
+                            error!("Got error: {:?}. Dropping subscriber.", e);
+                            subscribers1.borrow_mut().subscribers.remove(&idx);
+                        }
+                    }
+                    Ok::<(), Error>(())
+                })
+                .map_err(|_| unreachable!()),
+        );
+    }
+
+    pub fn publish_new_server(
+        pool: &str,
+        server: &Server,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        trace!("publish_new_server");
+
+        let subscribers1 = subscribers.clone();
+        let subs = &mut subscribers.borrow_mut().subscribers;
+        for (&idx, mut subscriber) in subs.iter_mut() {
+            if subscriber.requests_in_flight < 5 {
+                subscriber.requests_in_flight += 1;
+
+                let mut request = subscriber.client.add_server_request();
+
+                request.get().set_pool(pool);
+                request.get().set_url(&format!("{}", server.url()));
+                request.get().set_map_host(server.map_host());
+                request.get().set_weight(server.weight());
+                request.get().set_proxy_protocol(
+                    server.proxy_protocol().map_or(0, |p| p.version()),
+                );
+                if let Some(tls) = server.tls() {
+                    set_tls(request.get().init_tls(), &tls);
+                }
+
+                let subscribers2 = subscribers1.clone();
+                handle.spawn(
+                    request
+                        .send()
+                        .promise
+                        .then(move |r| {
+                            match r {
+                                Ok(_) => {
+                                    subscribers2
+                                        .borrow_mut()
+                                        .subscribers
+                                        .get_mut(&idx)
+                                        .map(|ref mut s| { s.requests_in_flight -= 1; });
+                                }
+                                Err(e) => {
+                                    error!("Got error: {:?}. Dropping subscriber.", e);
+                                    subscribers2.borrow_mut().subscribers.remove(&idx);
+                                }
+                            }
+                            Ok::<(), Error>(())
+                        })
+                        .map_err(|_| unreachable!()),
+                );
+            } else {
+                debug!("Subscriber {} is busy. Sending it the pool snapshot later", idx);
+                subscriber.stale_pools = true;
+            }
+        }
+    }
+
+    pub fn publish_remove_server(
+        pool: &str,
+        server: &Server,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        trace!("publish_remove_server");
+
+        let subscribers1 = subscribers.clone();
+        let subs = &mut subscribers.borrow_mut().subscribers;
+        for (&idx, mut subscriber) in subs.iter_mut() {
+            if subscriber.requests_in_flight < 5 {
+                subscriber.requests_in_flight += 1;
+
+                let mut request = subscriber.client.remove_server_request();
+
+                request.get().set_pool(pool);
+                request.get().set_url(&format!("{}", server.url()));
+                request.get().set_map_host(server.map_host());
+
+                let subscribers2 = subscribers1.clone();
+                handle.spawn(
+                    request
                         .send()
                         .promise
                         .then(move |r| {
@@ -280,12 +1490,16 @@ mod capnp {
                         })
                         .map_err(|_| unreachable!()),
                 );
+            } else {
+                debug!("Subscriber {} is busy. Sending it the pool snapshot later", idx);
+                subscriber.stale_pools = true;
             }
         }
     }
 
     pub fn publish_server_state_down(
-        url: &Uri,
+        pool: &str,
+        server: &Server,
         handle: Handle,
         subscribers: Rc<RefCell<SubscriberMap>>,
     ) {
@@ -299,7 +1513,9 @@ mod capnp {
 
                 let mut request = subscriber.client.mark_server_down_request();
 
-                request.get().set_url(&format!("{}", &url));
+                request.get().set_pool(pool);
+                request.get().set_url(&format!("{}", server.url()));
+                request.get().set_map_host(server.map_host());
 
                 let subscribers2 = subscribers1.clone();
                 handle.spawn(
@@ -324,12 +1540,16 @@ mod capnp {
                         })
                         .map_err(|_| unreachable!()),
                 );
+            } else {
+                debug!("Subscriber {} is busy. Sending it the pool snapshot later", idx);
+                subscriber.stale_pools = true;
             }
         }
     }
 
     pub fn publish_server_state_active(
-        url: &Uri,
+        pool: &str,
+        server: &Server,
         handle: Handle,
         subscribers: Rc<RefCell<SubscriberMap>>,
     ) {
@@ -343,7 +1563,318 @@ mod capnp {
 
                 let mut request = subscriber.client.mark_server_active_request();
 
-                request.get().set_url(&format!("{}", &url));
+                request.get().set_pool(pool);
+                request.get().set_url(&format!("{}", server.url()));
+                request.get().set_map_host(server.map_host());
+
+                let subscribers2 = subscribers1.clone();
+                handle.spawn(
+                    request
+                        .send()
+                        .promise
+                        .then(move |r| {
+                            match r {
+                                Ok(_) => {
+                                    subscribers2
+                                        .borrow_mut()
+                                        .subscribers
+                                        .get_mut(&idx)
+                                        .map(|ref mut s| { s.requests_in_flight -= 1; });
+                                }
+                                Err(e) => {
+                                    error!("Got error: {:?}. Dropping subscriber.", e);
+                                    subscribers2.borrow_mut().subscribers.remove(&idx);
+                                }
+                            }
+                            Ok::<(), Error>(())
+                        })
+                        .map_err(|_| unreachable!()),
+                );
+            } else {
+                debug!("Subscriber {} is busy. Sending it the pool snapshot later", idx);
+                subscriber.stale_pools = true;
+            }
+        }
+    }
+
+    pub fn publish_add_certificate(
+        hostnames: &[String],
+        certificate: &Certificate,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        trace!("publish_add_certificate");
+
+        let chain = String::from_utf8_lossy(&certificate.chain);
+        let key = String::from_utf8_lossy(&certificate.key);
+
+        let subscribers1 = subscribers.clone();
+        let subs = &mut subscribers.borrow_mut().subscribers;
+        for (&idx, mut subscriber) in subs.iter_mut() {
+            if subscriber.requests_in_flight < 5 {
+                subscriber.requests_in_flight += 1;
+
+                let mut request = subscriber.client.add_certificate_request();
+
+                {
+                    let mut list = request.get().init_hostnames(hostnames.len() as u32);
+                    for (i, hostname) in hostnames.iter().enumerate() {
+                        list.set(i as u32, hostname);
+                    }
+                }
+                request.get().set_chain(&chain);
+                request.get().set_key(&key);
+
+                let subscribers2 = subscribers1.clone();
+                handle.spawn(
+                    request
+                        .send()
+                        .promise
+                        .then(move |r| {
+                            match r {
+                                Ok(_) => {
+                                    subscribers2
+                                        .borrow_mut()
+                                        .subscribers
+                                        .get_mut(&idx)
+                                        .map(|ref mut s| { s.requests_in_flight -= 1; });
+                                }
+                                Err(e) => {
+                                    error!("Got error: {:?}. Dropping subscriber.", e);
+                                    subscribers2.borrow_mut().subscribers.remove(&idx);
+                                }
+                            }
+                            Ok::<(), Error>(())
+                        })
+                        .map_err(|_| unreachable!()),
+                );
+            } else {
+                debug!("Subscriber {} is busy. Sending it all certificates later", idx);
+                subscriber.stale_certificates = true;
+            }
+        }
+    }
+
+    pub fn publish_remove_certificate(
+        hostname: &str,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        trace!("publish_remove_certificate");
+
+        let subscribers1 = subscribers.clone();
+        let subs = &mut subscribers.borrow_mut().subscribers;
+        for (&idx, mut subscriber) in subs.iter_mut() {
+            if subscriber.requests_in_flight < 5 {
+                subscriber.requests_in_flight += 1;
+
+                let mut request = subscriber.client.remove_certificate_request();
+
+                request.get().set_hostname(hostname);
+
+                let subscribers2 = subscribers1.clone();
+                handle.spawn(
+                    request
+                        .send()
+                        .promise
+                        .then(move |r| {
+                            match r {
+                                Ok(_) => {
+                                    subscribers2
+                                        .borrow_mut()
+                                        .subscribers
+                                        .get_mut(&idx)
+                                        .map(|ref mut s| { s.requests_in_flight -= 1; });
+                                }
+                                Err(e) => {
+                                    error!("Got error: {:?}. Dropping subscriber.", e);
+                                    subscribers2.borrow_mut().subscribers.remove(&idx);
+                                }
+                            }
+                            Ok::<(), Error>(())
+                        })
+                        .map_err(|_| unreachable!()),
+                );
+            } else {
+                debug!("Subscriber {} is busy. Sending it all certificates later", idx);
+                subscriber.stale_certificates = true;
+            }
+        }
+    }
+
+    pub fn publish_add_pool(
+        name: &str,
+        hosts: &[String],
+        headers: &HeaderRules,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        trace!("publish_add_pool");
+
+        let subscribers1 = subscribers.clone();
+        let subs = &mut subscribers.borrow_mut().subscribers;
+        for (&idx, mut subscriber) in subs.iter_mut() {
+            if subscriber.requests_in_flight < 5 {
+                subscriber.requests_in_flight += 1;
+
+                let mut request = subscriber.client.add_pool_request();
+
+                request.get().set_name(name);
+                {
+                    let mut list = request.get().init_hosts(hosts.len() as u32);
+                    for (i, host) in hosts.iter().enumerate() {
+                        list.set(i as u32, host);
+                    }
+                }
+                set_header_rules(request.get().init_headers(), headers);
+
+                let subscribers2 = subscribers1.clone();
+                handle.spawn(
+                    request
+                        .send()
+                        .promise
+                        .then(move |r| {
+                            match r {
+                                Ok(_) => {
+                                    subscribers2
+                                        .borrow_mut()
+                                        .subscribers
+                                        .get_mut(&idx)
+                                        .map(|ref mut s| { s.requests_in_flight -= 1; });
+                                }
+                                Err(e) => {
+                                    error!("Got error: {:?}. Dropping subscriber.", e);
+                                    subscribers2.borrow_mut().subscribers.remove(&idx);
+                                }
+                            }
+                            Ok::<(), Error>(())
+                        })
+                        .map_err(|_| unreachable!()),
+                );
+            } else {
+                debug!("Subscriber {} is busy. Sending it the pool snapshot later", idx);
+                subscriber.stale_pools = true;
+            }
+        }
+    }
+
+    pub fn publish_remove_pool(
+        name: &str,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        trace!("publish_remove_pool");
+
+        let subscribers1 = subscribers.clone();
+        let subs = &mut subscribers.borrow_mut().subscribers;
+        for (&idx, mut subscriber) in subs.iter_mut() {
+            if subscriber.requests_in_flight < 5 {
+                subscriber.requests_in_flight += 1;
+
+                let mut request = subscriber.client.remove_pool_request();
+
+                request.get().set_name(name);
+
+                let subscribers2 = subscribers1.clone();
+                handle.spawn(
+                    request
+                        .send()
+                        .promise
+                        .then(move |r| {
+                            match r {
+                                Ok(_) => {
+                                    subscribers2
+                                        .borrow_mut()
+                                        .subscribers
+                                        .get_mut(&idx)
+                                        .map(|ref mut s| { s.requests_in_flight -= 1; });
+                                }
+                                Err(e) => {
+                                    error!("Got error: {:?}. Dropping subscriber.", e);
+                                    subscribers2.borrow_mut().subscribers.remove(&idx);
+                                }
+                            }
+                            Ok::<(), Error>(())
+                        })
+                        .map_err(|_| unreachable!()),
+                );
+            } else {
+                debug!("Subscriber {} is busy. Sending it the pool snapshot later", idx);
+                subscriber.stale_pools = true;
+            }
+        }
+    }
+
+    pub fn publish_set_routes(
+        routes: &[Route],
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        trace!("publish_set_routes");
+
+        let subscribers1 = subscribers.clone();
+        let subs = &mut subscribers.borrow_mut().subscribers;
+        for (&idx, mut subscriber) in subs.iter_mut() {
+            if subscriber.requests_in_flight < 5 {
+                subscriber.requests_in_flight += 1;
+
+                let mut request = subscriber.client.set_routes_request();
+
+                {
+                    let mut list = request.get().init_routes(routes.len() as u32);
+                    for (i, route) in routes.iter().enumerate() {
+                        set_route(list.borrow().get(i as u32), route);
+                    }
+                }
+
+                let subscribers2 = subscribers1.clone();
+                handle.spawn(
+                    request
+                        .send()
+                        .promise
+                        .then(move |r| {
+                            match r {
+                                Ok(_) => {
+                                    subscribers2
+                                        .borrow_mut()
+                                        .subscribers
+                                        .get_mut(&idx)
+                                        .map(|ref mut s| { s.requests_in_flight -= 1; });
+                                }
+                                Err(e) => {
+                                    error!("Got error: {:?}. Dropping subscriber.", e);
+                                    subscribers2.borrow_mut().subscribers.remove(&idx);
+                                }
+                            }
+                            Ok::<(), Error>(())
+                        })
+                        .map_err(|_| unreachable!()),
+                );
+            } else {
+                debug!("Subscriber {} is busy. Sending it the pool snapshot later", idx);
+                subscriber.stale_pools = true;
+            }
+        }
+    }
+
+    pub fn publish_set_pool_headers(
+        name: &str,
+        headers: &HeaderRules,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        trace!("publish_set_pool_headers");
+
+        let subscribers1 = subscribers.clone();
+        let subs = &mut subscribers.borrow_mut().subscribers;
+        for (&idx, mut subscriber) in subs.iter_mut() {
+            if subscriber.requests_in_flight < 5 {
+                subscriber.requests_in_flight += 1;
+
+                let mut request = subscriber.client.set_pool_headers_request();
+
+                request.get().set_name(if name == DEFAULT_POOL { "" } else { name });
+                set_header_rules(request.get().init_headers(), headers);
 
                 let subscribers2 = subscribers1.clone();
                 handle.spawn(
@@ -368,7 +1899,48 @@ mod capnp {
                         })
                         .map_err(|_| unreachable!()),
                 );
+            } else {
+                debug!("Subscriber {} is busy. Sending it the pool snapshot later", idx);
+                subscriber.stale_pools = true;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{respawn_delay, Ejections};
+
+    #[test]
+    fn test_respawn_delay() {
+        assert_eq!(Duration::from_secs(0), respawn_delay(0));
+        assert_eq!(Duration::from_secs(0), respawn_delay(1));
+        assert_eq!(Duration::from_secs(1), respawn_delay(2));
+        assert_eq!(Duration::from_secs(2), respawn_delay(3));
+        assert_eq!(Duration::from_secs(4), respawn_delay(4));
+        assert_eq!(Duration::from_secs(60), respawn_delay(8));
+        assert_eq!(Duration::from_secs(60), respawn_delay(100));
+    }
+
+    #[test]
+    fn test_ejections() {
+        let mut ejections = Ejections::new();
+        assert!(ejections.ejected("http://127.0.0.1:6000").is_empty());
+
+        ejections.eject("http://127.0.0.1:6000", 200, Duration::from_secs(60));
+        ejections.eject("http://127.0.0.1:6000", 100, Duration::from_secs(30));
+        ejections.eject("http://127.0.0.1:6001", 100, Duration::from_secs(0));
+
+        let ejected = ejections.ejected("http://127.0.0.1:6000");
+        assert_eq!(vec![100, 200], ejected.iter().map(|e| e.0).collect::<Vec<_>>());
+        assert!(ejected[0].1 <= Duration::from_secs(30));
+        assert!(ejected[1].1 > Duration::from_secs(30));
+
+        // ended ejections are forgotten
+        assert!(ejections.ejected("http://127.0.0.1:6001").is_empty());
+
+        ejections.remove("http://127.0.0.1:6000");
+        assert!(ejections.ejected("http://127.0.0.1:6000").is_empty());
+    }
+}
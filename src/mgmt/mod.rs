@@ -1,36 +1,37 @@
 use std::io;
 use std::net::SocketAddr;
 
-use futures::Stream;
+use futures::{Future, Stream};
 use tokio_core::reactor::{Core, Handle};
 use tokio_core::net::{TcpStream, TcpListener};
 use tokio_timer::Timer;
 use hyper::server::Http;
 
-use pool::Pool;
+use vhost::Pools;
 use self::api::Mgmt;
 use self::manager::Manager;
 use self::health::BackendHealth;
-use config::Config;
 
 pub mod api;
 pub mod health;
 pub mod manager;
+pub mod state;
 pub mod worker;
 
 /// Run manager server and start health check timer
+///
+/// Returns once the manager has been asked to shutdown and all workers have exited.
 pub fn run(sock: SocketAddr,
-           pool: Pool,
+           pools: Pools,
            mut core: Core,
            manager: Manager,
-           config: &Config,
            health: BackendHealth)
            -> io::Result<()> {
     let handle = core.handle();
     let listener = TcpListener::bind(&sock, &handle)?;
     let timer = Timer::default();
     let health_timer = timer
-        .interval(config.health_check.interval)
+        .interval(manager.config().health_check.interval)
         .map(|_| None)
         .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
 
@@ -39,19 +40,20 @@ pub fn run(sock: SocketAddr,
         .incoming()
         .map(|stream| Some(stream))
         .select(health_timer);
+    let shutdown = manager.shutdown_on_signal(handle.clone());
     let srv = listener.for_each(move |stream| {
 
         // first stream is the management ip
         // second stream is health interval
         match stream {
             Some((socket, addr)) => {
-                mgmt(socket, addr, pool.clone(), &handle, manager.clone());
+                mgmt(socket, addr, pools.clone(), &handle, manager.clone());
             }
             None => {
                 info!("health check");
-                health::run(pool.clone(),
+                health::run(pools.clone(),
                             &handle,
-                            &config,
+                            &manager.config(),
                             manager.clone(),
                             health.clone());
             }
@@ -61,11 +63,11 @@ pub fn run(sock: SocketAddr,
     });
 
     info!("Listening on http://{}", &admin_addr);
-    core.run(srv)
+    core.run(srv.select(shutdown).map(|_| ()).map_err(|(e, _)| e))
 }
 
-fn mgmt(socket: TcpStream, addr: SocketAddr, pool: Pool, handle: &Handle, manager: Manager) {
-    let service = Mgmt::new(pool, handle.clone(), manager);
+fn mgmt(socket: TcpStream, addr: SocketAddr, pools: Pools, handle: &Handle, manager: Manager) {
+    let service = Mgmt::new(pools, handle.clone(), manager);
     let http = Http::new();
     http.bind_connection(&handle, socket, addr, service);
 }
@@ -0,0 +1,298 @@
+//! Persist the pools to disk
+//!
+//! The manager writes the pools to a JSON state file whenever a pool changes and reads them back
+//! when it starts. This keeps pools, servers and routes changed using the management API across
+//! restarts.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use hyper::Uri;
+use serde_json;
+
+use header_rules::HeaderRules;
+use pool::Pool;
+use proxy_protocol::ProxyProtocol;
+use route::{Route, RouteSpec};
+use server::{Server, DEFAULT_WEIGHT};
+use tls::ServerTls;
+use vhost::{Pools, DEFAULT_POOL};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    /// The servers in the default pool
+    servers: Vec<SavedServer>,
+
+    /// The header rules of the default pool
+    #[serde(default)]
+    headers: HeaderRules,
+
+    /// The named pools, which state files written before pools were added do not have
+    #[serde(default)]
+    pools: Vec<SavedPool>,
+
+    /// The routes, which state files written before routes were added do not have. Those files
+    /// leave the configured routes in place.
+    #[serde(default)]
+    routes: Option<Vec<RouteSpec>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedPool {
+    name: String,
+    hosts: Vec<String>,
+    servers: Vec<SavedServer>,
+
+    #[serde(default)]
+    headers: HeaderRules,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedServer {
+    url: String,
+    map_host: bool,
+
+    #[serde(default = "default_weight")]
+    weight: u32,
+
+    #[serde(default)]
+    proxy_protocol: Option<ProxyProtocol>,
+
+    #[serde(default)]
+    tls: Option<ServerTls>,
+
+    /// The health state of the server when the state file was written
+    active: bool,
+}
+
+fn default_weight() -> u32 {
+    DEFAULT_WEIGHT
+}
+
+fn saved_servers(pool: &Pool) -> Vec<SavedServer> {
+    pool.all()
+        .into_iter()
+        .map(|backend| {
+            let server = backend.server();
+            SavedServer {
+                url: server.url().as_ref().to_string(),
+                map_host: server.map_host(),
+                weight: server.weight(),
+                proxy_protocol: server.proxy_protocol(),
+                tls: server.tls(),
+                active: backend.is_active(),
+            }
+        })
+        .collect()
+}
+
+/// Write all pools, their servers and the routes to `path`
+///
+/// The state is first written to a temporary file next to `path` and then renamed over `path`, so
+/// a crash while saving never leaves a partially written state file behind.
+pub fn save(path: &Path, pools: &Pools) -> io::Result<()> {
+    let named = pools
+        .all()
+        .into_iter()
+        .filter(|vhost| !vhost.is_default())
+        .map(|vhost| {
+            SavedPool {
+                name: vhost.name().to_string(),
+                hosts: vhost.hosts().to_vec(),
+                servers: saved_servers(&vhost.pool()),
+                headers: (*vhost.headers()).clone(),
+            }
+        })
+        .collect();
+
+    let state = State {
+        servers: saved_servers(&pools.default_pool()),
+        headers: (*pools.all()[0].headers()).clone(),
+        pools: named,
+        routes: Some(pools.routes().iter().map(|route| route.spec().clone()).collect()),
+    };
+    let json = serde_json::to_vec_pretty(&state).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    })?;
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    {
+        let mut file = File::create(&tmp)?;
+        file.write_all(&json)?;
+        file.sync_all()?;
+    }
+
+    fs::rename(&tmp, path)
+}
+
+/// Add the pools and servers saved in `path` to `pools`, and replace the routes with the saved
+/// routes
+///
+/// A missing state file is not an error, as there is nothing to restore the first time weldr is
+/// started. Returns the number of servers added to the pools.
+pub fn load(path: &Path, pools: &Pools) -> io::Result<usize> {
+    let mut contents = String::new();
+    match File::open(path) {
+        Ok(mut file) => {
+            file.read_to_string(&mut contents)?;
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    }
+
+    let state: State = serde_json::from_str(&contents).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    })?;
+
+    let mut added = load_servers(state.servers, &pools.default_pool())?;
+    pools.set_headers(DEFAULT_POOL, valid_headers(state.headers)?);
+    for saved in state.pools {
+        let pool = match pools.get(&saved.name) {
+            Some(pool) => pool,
+            None => pools.add(&saved.name, saved.hosts.clone()).unwrap(),
+        };
+        pools.set_hosts(&saved.name, saved.hosts);
+        pools.set_headers(&saved.name, valid_headers(saved.headers)?);
+        added += load_servers(saved.servers, &pool)?;
+    }
+
+    if let Some(saved) = state.routes {
+        let mut routes = Vec::new();
+        for spec in saved {
+            let route = Route::new(spec).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("invalid route: {}", e))
+            })?;
+            routes.push(route);
+        }
+        pools.set_routes(routes);
+    }
+
+    Ok(added)
+}
+
+fn valid_headers(headers: HeaderRules) -> io::Result<HeaderRules> {
+    headers.validate().map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("invalid header rule: {}", e))
+    })?;
+    Ok(headers)
+}
+
+fn load_servers(servers: Vec<SavedServer>, pool: &Pool) -> io::Result<usize> {
+    let mut added = 0;
+    for saved in servers {
+        let url = saved.url.parse::<Uri>().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid server url {:?}: {}", saved.url, e),
+            )
+        })?;
+
+        let server = Server::new(url, saved.map_host)
+            .with_weight(saved.weight)
+            .with_proxy_protocol(saved.proxy_protocol)
+            .with_tls(saved.tls);
+        if !pool.add(server.clone()) {
+            continue;
+        }
+
+        if !saved.active {
+            if let Some(backend) = pool.find(&server) {
+                backend.mark_down();
+            }
+        }
+
+        added += 1;
+    }
+
+    Ok(added)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use nix::unistd::getpid;
+
+    use super::{load, save};
+    use header_rules::{Action, HeaderRule, HeaderRules};
+    use proxy_protocol::ProxyProtocol;
+    use route::{Route, RouteSpec};
+    use server::Server;
+    use tls::ServerTls;
+    use vhost::Pools;
+
+    #[test]
+    fn test_save_and_load() {
+        let path = env::temp_dir().join(format!("weldr-state-{}.json", getpid()));
+
+        let pools = Pools::default();
+        let pool = pools.default_pool();
+        let server1 = Server::new("http://127.0.0.1:6000".parse().unwrap(), true);
+        let tls = ServerTls {
+            server_name: Some("backend.internal".to_string()),
+            ..ServerTls::default()
+        };
+        let server2 = Server::new("https://127.0.0.1:6001".parse().unwrap(), false)
+            .with_weight(4)
+            .with_proxy_protocol(Some(ProxyProtocol::V1))
+            .with_tls(Some(tls.clone()));
+        pool.add(server1.clone());
+        pool.add(server2.clone());
+        pool.find(&server2).unwrap().mark_down();
+        let server3 = Server::new("http://127.0.0.1:6002".parse().unwrap(), true);
+        let hosts = vec!["api.example.com".to_string(), "*.api.example.com".to_string()];
+        pools.add("api", hosts.clone()).unwrap().add(server3.clone());
+        let headers = HeaderRules {
+            request: Vec::new(),
+            response: vec![
+                HeaderRule {
+                    action: Action::Remove,
+                    name: "Server".to_string(),
+                    value: None,
+                },
+            ],
+        };
+        pools.set_headers("api", headers.clone());
+        let route = RouteSpec {
+            prefix: Some("/api/".to_string()),
+            pool: "api".to_string(),
+            strip_prefix: true,
+            ..RouteSpec::default()
+        };
+        pools.set_routes(vec![Route::new(route.clone()).unwrap()]);
+        save(&path, &pools).unwrap();
+
+        let restored = Pools::default();
+        assert_eq!(3, load(&path, &restored).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let api = restored.find("api").unwrap();
+        assert_eq!(&hosts[..], api.hosts());
+        assert_eq!(headers, *api.headers());
+        let servers: Vec<Server> = api.pool().all().iter().map(|b| b.server()).collect();
+        assert_eq!(vec![server3], servers);
+        let routes: Vec<RouteSpec> = restored.routes().iter().map(|r| r.spec().clone()).collect();
+        assert_eq!(vec![route], routes);
+
+        let backends = restored.default_pool().all();
+        let servers: Vec<Server> = backends.iter().map(|b| b.server()).collect();
+        assert_eq!(vec![server1, server2], servers);
+        assert!(backends[0].is_active());
+        assert!(backends[1].is_down());
+        assert_eq!(4, backends[1].weight());
+        assert_eq!(Some(ProxyProtocol::V1), backends[1].server().proxy_protocol());
+        assert_eq!(Some(tls), backends[1].server().tls());
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let path = env::temp_dir().join("weldr-state-does-not-exist.json");
+        let pools = Pools::default();
+        assert_eq!(0, load(&path, &pools).unwrap());
+        assert!(pools.backends().is_empty());
+    }
+}
@@ -2,8 +2,9 @@ use std::net::SocketAddr;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::str::FromStr;
+use std::time::Duration;
 
-use weldr_capnp::{publisher, subscriber};
+use weldr_capnp::{publisher, route, server_tls, subscriber};
 
 use futures::Future;
 
@@ -11,22 +12,136 @@ use capnp_rpc::{RpcSystem, twoparty, rpc_twoparty_capnp};
 use capnp::capability::{Response, Promise};
 
 use hyper::Uri;
+use nix::unistd::getpid;
 
 use tokio_io::AsyncRead;
 use tokio_core::reactor::Handle;
 use tokio_core::net::TcpStream;
 
 use server::Server;
+use header_rules::{Action, HeaderRule, HeaderRules};
 use pool::Pool;
+use proxy_protocol::ProxyProtocol;
+use route::{Route, RouteSpec};
+use tls::{Certificate, Certificates, ServerTls};
+use vhost::{Pools, DEFAULT_POOL};
 
 struct SubscriberImpl {
-    pool: Pool,
+    pools: Pools,
+
+    /// The certificates of the TLS listener, if the worker has one
+    certificates: Option<Certificates>,
 }
 
 impl SubscriberImpl {
-    pub fn new(pool: Pool) -> SubscriberImpl {
-        SubscriberImpl { pool: pool }
+    pub fn new(pools: Pools, certificates: Option<Certificates>) -> SubscriberImpl {
+        SubscriberImpl {
+            pools: pools,
+            certificates: certificates,
+        }
+    }
+
+    /// The pool called `name`, where an empty name is the default pool
+    fn pool(&self, name: &str) -> Option<Pool> {
+        if name.is_empty() {
+            Some(self.pools.default_pool())
+        } else {
+            self.pools.get(name)
+        }
+    }
+}
+
+/// The name of a pool sent by the manager, where an empty name is the default pool
+fn pool_name(name: &str) -> &str {
+    if name.is_empty() { DEFAULT_POOL } else { name }
+}
+
+/// A setting sent by the manager, where empty text means the setting is not used
+fn text(value: &str) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+/// The TLS settings of a server sent by the manager
+fn read_tls(tls: server_tls::Reader) -> ::capnp::Result<ServerTls> {
+    Ok(ServerTls {
+        ca: text(tls.get_ca()?),
+        cert: text(tls.get_cert()?),
+        key: text(tls.get_key()?),
+        server_name: text(tls.get_server_name()?),
+        insecure_skip_verify: tls.get_insecure_skip_verify(),
+    })
+}
+
+/// The header rules of a pool or a route sent by the manager
+///
+/// The manager checks each rule before sending it, so a rule that is not valid here is logged and
+/// left out.
+fn read_header_rules(rules: ::weldr_capnp::header_rules::Reader) -> ::capnp::Result<HeaderRules> {
+    fn read(
+        list: ::capnp::struct_list::Reader<::weldr_capnp::header_rule::Owned>,
+    ) -> ::capnp::Result<Vec<HeaderRule>> {
+        let mut rules = Vec::new();
+        for rule in list.iter() {
+            let action = match rule.get_action()?.parse::<Action>() {
+                Ok(action) => action,
+                Err(e) => {
+                    error!("Unable to add header rule: {}", e);
+                    continue;
+                }
+            };
+            let rule = HeaderRule {
+                action: action,
+                name: rule.get_name()?.to_string(),
+                value: if action == Action::Remove {
+                    None
+                } else {
+                    Some(rule.get_value()?.to_string())
+                },
+            };
+
+            match rule.validate() {
+                Ok(()) => rules.push(rule),
+                Err(e) => error!("Unable to add header rule {:?}: {}", rule, e),
+            }
+        }
+
+        Ok(rules)
     }
+
+    Ok(HeaderRules {
+        request: read(rules.get_request()?)?,
+        response: read(rules.get_response()?)?,
+    })
+}
+
+/// The routes sent by the manager
+///
+/// The manager checks each route before sending it, so a route that cannot be compiled here is
+/// logged and left out.
+fn read_routes(list: ::capnp::struct_list::Reader<route::Owned>) -> ::capnp::Result<Vec<Route>> {
+    let mut routes = Vec::new();
+    for route in list.iter() {
+        let spec = RouteSpec {
+            prefix: text(route.get_prefix()?),
+            regex: text(route.get_regex()?),
+            pool: pool_name(route.get_pool()?).to_string(),
+            host: text(route.get_host()?),
+            strip_prefix: route.get_strip_prefix(),
+            rewrite: text(route.get_rewrite()?),
+            headers: read_header_rules(route.get_headers()?)?,
+        };
+
+        match Route::new(spec.clone()) {
+            Ok(route) => routes.push(route),
+            Err(e) => error!("Unable to add route {:?}: {}", spec, e),
+        }
+    }
+
+    Ok(routes)
 }
 
 impl subscriber::Server<::capnp::data::Owned> for SubscriberImpl {
@@ -37,12 +152,29 @@ impl subscriber::Server<::capnp::data::Owned> for SubscriberImpl {
     ) -> Promise<(), ::capnp::Error> {
         trace!("add_server");
 
-        let url_str = pry!(pry!(params.get()).get_url());
+        let params = pry!(params.get());
+        let url_str = pry!(params.get_url());
         info!("url from publisher: {:?}", url_str);
 
         let url = Uri::from_str(url_str).expect("Failed to parse server uri");
-        let server = Server::new(url, true);
-        self.pool.add(server);
+        let tls = if params.has_tls() {
+            Some(pry!(read_tls(pry!(params.get_tls()))))
+        } else {
+            None
+        };
+        let server = Server::new(url, params.get_map_host())
+            .with_weight(params.get_weight())
+            .with_proxy_protocol(ProxyProtocol::from_version(params.get_proxy_protocol()))
+            .with_tls(tls);
+        let name = pry!(params.get_pool());
+        match self.pool(name) {
+            Some(pool) => {
+                pool.add(server);
+            }
+            None => {
+                error!("Unable to find pool {} to add server {:?}", pool_name(name), server);
+            }
+        }
 
         Promise::ok(())
     }
@@ -54,18 +186,24 @@ impl subscriber::Server<::capnp::data::Owned> for SubscriberImpl {
     ) -> Promise<(), ::capnp::Error> {
         trace!("mark_server_down");
 
-        let url_str = pry!(pry!(params.get()).get_url());
+        let params = pry!(params.get());
+        let url_str = pry!(params.get_url());
         info!("url from publisher: {:?}", url_str);
 
         let url = Uri::from_str(url_str).expect("Failed to parse server uri");
 
-        let server = Server::new(url, true);
-        match self.pool.find(&server) {
+        let server = Server::new(url, params.get_map_host());
+        let name = pry!(params.get_pool());
+        match self.pool(name).and_then(|pool| pool.find(&server)) {
             Some(backend) => {
                 backend.mark_down();
             }
             None => {
-                error!("Unable to find server {:?} to mark as down", server);
+                error!(
+                    "Unable to find server {:?} in pool {} to mark as down",
+                    server,
+                    pool_name(name)
+                );
             }
         }
 
@@ -79,35 +217,338 @@ impl subscriber::Server<::capnp::data::Owned> for SubscriberImpl {
     ) -> Promise<(), ::capnp::Error> {
         trace!("mark_server_active");
 
-        let url_str = pry!(pry!(params.get()).get_url());
+        let params = pry!(params.get());
+        let url_str = pry!(params.get_url());
         info!("url from publisher: {:?}", url_str);
 
         let url = Uri::from_str(url_str).expect("Failed to parse server uri");
 
-        let server = Server::new(url, true);
-        match self.pool.find(&server) {
+        let server = Server::new(url, params.get_map_host());
+        let name = pry!(params.get_pool());
+        match self.pool(name).and_then(|pool| pool.find(&server)) {
             Some(backend) => {
                 backend.mark_active();
             }
             None => {
-                error!("Unable to find server {:?} to mark as active", server);
+                error!(
+                    "Unable to find server {:?} in pool {} to mark as active",
+                    server,
+                    pool_name(name)
+                );
             }
         }
 
         Promise::ok(())
     }
+
+    fn remove_server(
+        &mut self,
+        params: subscriber::RemoveServerParams<::capnp::data::Owned>,
+        _results: subscriber::RemoveServerResults<::capnp::data::Owned>,
+    ) -> Promise<(), ::capnp::Error> {
+        trace!("remove_server");
+
+        let params = pry!(params.get());
+        let url_str = pry!(params.get_url());
+        info!("url from publisher: {:?}", url_str);
+
+        let url = Uri::from_str(url_str).expect("Failed to parse server uri");
+
+        let server = Server::new(url, params.get_map_host());
+        let name = pry!(params.get_pool());
+        match self.pool(name) {
+            Some(pool) => pool.remove(&server),
+            None => {
+                error!("Unable to find pool {} to remove server {:?}", pool_name(name), server);
+            }
+        }
+
+        Promise::ok(())
+    }
+
+    fn sync_pool(
+        &mut self,
+        params: subscriber::SyncPoolParams<::capnp::data::Owned>,
+        _results: subscriber::SyncPoolResults<::capnp::data::Owned>,
+    ) -> Promise<(), ::capnp::Error> {
+        trace!("sync_pool");
+
+        let params = pry!(params.get());
+        let vhosts = pry!(params.get_pools());
+        let backends = pry!(params.get_backends());
+        info!(
+            "pool snapshot from publisher with {} named pools and {} servers",
+            vhosts.len(),
+            backends.len()
+        );
+
+        let mut names = vec![DEFAULT_POOL.to_string()];
+        for vhost in vhosts.iter() {
+            let name = pry!(vhost.get_name()).to_string();
+            let mut hosts = Vec::new();
+            for host in pry!(vhost.get_hosts()).iter() {
+                hosts.push(pry!(host).to_string());
+            }
+
+            if self.pools.add(&name, hosts.clone()).is_none() {
+                self.pools.set_hosts(&name, hosts);
+            }
+            self.pools.set_headers(&name, pry!(read_header_rules(pry!(vhost.get_headers()))));
+            names.push(name);
+        }
+        self.pools.set_headers(DEFAULT_POOL, pry!(read_header_rules(pry!(params.get_headers()))));
+
+        let mut servers = Vec::new();
+        for backend in backends.iter() {
+            let url_str = pry!(backend.get_url());
+            let url = Uri::from_str(url_str).expect("Failed to parse server uri");
+            let tls = if backend.has_tls() {
+                Some(pry!(read_tls(pry!(backend.get_tls()))))
+            } else {
+                None
+            };
+            let server = Server::new(url, backend.get_map_host())
+                .with_weight(backend.get_weight())
+                .with_proxy_protocol(ProxyProtocol::from_version(backend.get_proxy_protocol()))
+                .with_tls(tls);
+
+            let name = pool_name(pry!(backend.get_pool()));
+            let pool = match self.pools.get(name) {
+                Some(pool) => pool,
+                None => {
+                    error!("Unable to find pool {} to add server {:?}", name, server);
+                    continue;
+                }
+            };
+
+            pool.add(server.clone());
+            if let Some(b) = pool.find(&server) {
+                if backend.get_active() {
+                    b.mark_active();
+                } else {
+                    b.mark_down();
+                }
+            }
+
+            servers.push((name.to_string(), server));
+        }
+
+        // the manager is the source of truth, so drop anything it does not know about
+        for vhost in self.pools.all() {
+            let name = vhost.name().to_string();
+            if !names.contains(&name) {
+                self.pools.remove(&name);
+                continue;
+            }
+
+            let pool = vhost.pool();
+            for backend in pool.all() {
+                let server = backend.server();
+                if !servers.contains(&(name.clone(), server.clone())) {
+                    pool.remove(&server);
+                }
+            }
+        }
+
+        self.pools.set_routes(pry!(read_routes(pry!(params.get_routes()))));
+
+        Promise::ok(())
+    }
+
+    fn add_certificate(
+        &mut self,
+        params: subscriber::AddCertificateParams<::capnp::data::Owned>,
+        _results: subscriber::AddCertificateResults<::capnp::data::Owned>,
+    ) -> Promise<(), ::capnp::Error> {
+        trace!("add_certificate");
+
+        let params = pry!(params.get());
+        let mut hostnames = Vec::new();
+        for hostname in pry!(params.get_hostnames()).iter() {
+            hostnames.push(pry!(hostname).to_string());
+        }
+        info!("certificate for {:?} from publisher", hostnames);
+
+        let certificates = match self.certificates {
+            Some(ref certificates) => certificates,
+            None => {
+                warn!("Ignoring certificate for {:?}, TLS is not enabled", hostnames);
+                return Promise::ok(());
+            }
+        };
+
+        let certificate = Certificate {
+            chain: pry!(params.get_chain()).as_bytes().to_vec(),
+            key: pry!(params.get_key()).as_bytes().to_vec(),
+        };
+        if let Err(e) = certificates.add(&hostnames, &certificate) {
+            error!("Failed to add certificate for {:?}: {}", hostnames, e);
+        }
+
+        Promise::ok(())
+    }
+
+    fn remove_certificate(
+        &mut self,
+        params: subscriber::RemoveCertificateParams<::capnp::data::Owned>,
+        _results: subscriber::RemoveCertificateResults<::capnp::data::Owned>,
+    ) -> Promise<(), ::capnp::Error> {
+        trace!("remove_certificate");
+
+        let hostname = pry!(pry!(params.get()).get_hostname());
+        info!("hostname from publisher: {:?}", hostname);
+
+        if let Some(ref certificates) = self.certificates {
+            if !certificates.remove(hostname) {
+                error!("Unable to find certificate for {} to remove", hostname);
+            }
+        }
+
+        Promise::ok(())
+    }
+
+    fn sync_certificates(
+        &mut self,
+        params: subscriber::SyncCertificatesParams<::capnp::data::Owned>,
+        _results: subscriber::SyncCertificatesResults<::capnp::data::Owned>,
+    ) -> Promise<(), ::capnp::Error> {
+        trace!("sync_certificates");
+
+        let snapshot = pry!(pry!(params.get()).get_certificates());
+        info!("certificate snapshot from publisher with {} hostnames", snapshot.len());
+
+        let certificates = match self.certificates {
+            Some(ref certificates) => certificates,
+            None => return Promise::ok(()),
+        };
+
+        let mut all = Vec::new();
+        for certificate in snapshot.iter() {
+            let hostname = pry!(certificate.get_hostname()).to_string();
+            let certificate = Certificate {
+                chain: pry!(certificate.get_chain()).as_bytes().to_vec(),
+                key: pry!(certificate.get_key()).as_bytes().to_vec(),
+            };
+            all.push((hostname, certificate));
+        }
+
+        // keep the certificates from the configuration rather than serve none at all
+        if let Err(e) = certificates.replace(&all) {
+            error!("Failed to sync certificates: {}", e);
+        }
+
+        Promise::ok(())
+    }
+
+    fn add_pool(
+        &mut self,
+        params: subscriber::AddPoolParams<::capnp::data::Owned>,
+        _results: subscriber::AddPoolResults<::capnp::data::Owned>,
+    ) -> Promise<(), ::capnp::Error> {
+        trace!("add_pool");
+
+        let params = pry!(params.get());
+        let name = pry!(params.get_name());
+        let mut hosts = Vec::new();
+        for host in pry!(params.get_hosts()).iter() {
+            hosts.push(pry!(host).to_string());
+        }
+        let headers = pry!(read_header_rules(pry!(params.get_headers())));
+        info!("pool {} for {:?} from publisher", name, hosts);
+
+        if self.pools.add(name, hosts.clone()).is_none() {
+            self.pools.set_hosts(name, hosts);
+        }
+        self.pools.set_headers(name, headers);
+
+        Promise::ok(())
+    }
+
+    fn remove_pool(
+        &mut self,
+        params: subscriber::RemovePoolParams<::capnp::data::Owned>,
+        _results: subscriber::RemovePoolResults<::capnp::data::Owned>,
+    ) -> Promise<(), ::capnp::Error> {
+        trace!("remove_pool");
+
+        let name = pry!(pry!(params.get()).get_name());
+        info!("pool name from publisher: {:?}", name);
+
+        if !self.pools.remove(name) {
+            error!("Unable to find pool {} to remove", name);
+        }
+
+        Promise::ok(())
+    }
+
+    fn set_routes(
+        &mut self,
+        params: subscriber::SetRoutesParams<::capnp::data::Owned>,
+        _results: subscriber::SetRoutesResults<::capnp::data::Owned>,
+    ) -> Promise<(), ::capnp::Error> {
+        trace!("set_routes");
+
+        let routes = pry!(read_routes(pry!(pry!(params.get()).get_routes())));
+        info!("{} routes from publisher", routes.len());
+
+        self.pools.set_routes(routes);
+
+        Promise::ok(())
+    }
+
+    fn set_pool_headers(
+        &mut self,
+        params: subscriber::SetPoolHeadersParams<::capnp::data::Owned>,
+        _results: subscriber::SetPoolHeadersResults<::capnp::data::Owned>,
+    ) -> Promise<(), ::capnp::Error> {
+        trace!("set_pool_headers");
+
+        let params = pry!(params.get());
+        let name = pool_name(pry!(params.get_name()));
+        let headers = pry!(read_header_rules(pry!(params.get_headers())));
+        info!("header rules of pool {} from publisher", name);
+
+        if !self.pools.set_headers(name, headers) {
+            error!("Unable to find pool {} to set header rules", name);
+        }
+
+        Promise::ok(())
+    }
 }
 
 pub struct S {
     pub response: Option<Response<publisher::subscribe_results::Owned<::capnp::data::Owned>>>,
+
+    /// The manager, once the worker is connected to it
+    publisher: Option<publisher::Client<::capnp::data::Owned>>,
 }
 
-pub fn subscribe(addr: SocketAddr, handle: Handle, pool: Pool) -> Rc<RefCell<S>> {
+/// Subscribe to changes to the pools published by the manager
+///
+/// Servers ejected from the pools of this worker as outliers are reported back to the manager.
+/// Certificates published by the manager are added to `certificates`, if the worker terminates
+/// TLS.
+pub fn subscribe(
+    addr: SocketAddr,
+    handle: Handle,
+    pools: Pools,
+    certificates: Option<Certificates>,
+) -> Rc<RefCell<S>> {
     let handle1 = handle.clone();
 
-    let s = S { response: None };
+    let s = S {
+        response: None,
+        publisher: None,
+    };
     let s = Rc::new(RefCell::new(s));
     let s1 = s.clone();
+    let s2 = s.clone();
+
+    let s3 = s.clone();
+    let handle2 = handle.clone();
+    pools.on_ejection(move |server, duration| {
+        report_ejection(&s3, server, duration, &handle2)
+    });
 
     let request = TcpStream::connect(&addr, &handle)
         .map_err(|e| e.into())
@@ -125,12 +566,14 @@ pub fn subscribe(addr: SocketAddr, handle: Handle, pool: Pool) -> Rc<RefCell<S>>
             let mut rpc_system = RpcSystem::new(rpc_network, None);
             let publisher: publisher::Client<::capnp::data::Owned> =
                 rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
+            s2.borrow_mut().publisher = Some(publisher.clone());
 
-            let sub = subscriber::ToClient::new(SubscriberImpl::new(pool))
+            let sub = subscriber::ToClient::new(SubscriberImpl::new(pools, certificates))
                 .from_server::<::capnp_rpc::Server>();
 
             let mut request = publisher.subscribe_request();
             request.get().set_subscriber(sub);
+            request.get().set_pid(getpid());
             handle1.spawn(rpc_system.map_err(|e| {
                 error!("Subscribe RPC System error {:?}", e);
             }));
@@ -150,3 +593,25 @@ pub fn subscribe(addr: SocketAddr, handle: Handle, pool: Pool) -> Rc<RefCell<S>>
 
     s
 }
+
+/// Let the manager know this worker ejected `server` from its pool
+fn report_ejection(s: &Rc<RefCell<S>>, server: &Server, duration: Duration, handle: &Handle) {
+    let publisher = match s.borrow().publisher {
+        Some(ref publisher) => publisher.clone(),
+        None => {
+            warn!("Unable to report ejection of {:?}, not connected to the manager", server);
+            return;
+        }
+    };
+
+    let mut request = publisher.report_ejection_request();
+    request.get().set_pid(getpid());
+    request.get().set_url(&format!("{}", server.url()));
+    request.get().set_duration_ms(
+        duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64,
+    );
+
+    handle.spawn(request.send().promise.map(|_| ()).map_err(|e| {
+        error!("Failed to report ejection to the manager: {:?}", e);
+    }));
+}
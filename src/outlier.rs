@@ -0,0 +1,190 @@
+//! Passive health checking of a backend using the results of live requests
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use config::OutlierDetection;
+
+/// Tracks the failed requests of a backend to decide when it is an outlier
+///
+/// Unlike the health checks run by the manager, each worker detects outliers on its own using the
+/// requests it sends.
+#[derive(Debug, Default, Eq, PartialEq, Hash)]
+pub struct Outlier {
+    /// Number of requests that failed in a row
+    consecutive_errors: u32,
+
+    /// When the current error rate window started
+    window_start: Option<Instant>,
+
+    /// Number of requests within the current window
+    requests: u32,
+
+    /// Number of failed requests within the current window
+    errors: u32,
+
+    /// Number of times the backend was ejected in a row
+    ejections: u32,
+
+    /// When the last ejection ends, or ended
+    ejected_until: Option<Instant>,
+}
+
+impl Outlier {
+    pub fn new() -> Outlier {
+        Outlier::default()
+    }
+
+    pub fn is_ejected(&self, now: Instant) -> bool {
+        match self.ejected_until {
+            Some(until) => now < until,
+            None => false,
+        }
+    }
+
+    /// Record whether a request to the backend succeeded
+    ///
+    /// Returns true if the backend should be ejected. Requests that complete while the backend
+    /// is ejected are ignored.
+    pub fn record(&mut self, config: &OutlierDetection, success: bool, now: Instant) -> bool {
+        if self.is_ejected(now) {
+            return false;
+        }
+
+        let window_ended = match self.window_start {
+            Some(start) => now.duration_since(start) >= config.window,
+            None => true,
+        };
+        if window_ended {
+            self.window_start = Some(now);
+            self.requests = 0;
+            self.errors = 0;
+        }
+
+        self.requests += 1;
+
+        if success {
+            self.consecutive_errors = 0;
+
+            // a backend that stayed in the pool for the longest ejection time starts over
+            if let Some(until) = self.ejected_until {
+                if now.duration_since(until) >= config.max_ejection {
+                    self.ejections = 0;
+                }
+            }
+
+            return false;
+        }
+
+        self.consecutive_errors += 1;
+        self.errors += 1;
+
+        if let Some(errors) = config.consecutive_errors {
+            if self.consecutive_errors >= errors {
+                return true;
+            }
+        }
+
+        if let Some(rate) = config.error_rate {
+            if self.requests >= config.min_requests && self.errors * 100 >= rate * self.requests {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Eject the backend and return how long it is ejected for
+    ///
+    /// The ejection time doubles each time the backend is ejected again, up to the
+    /// `max_ejection` time.
+    pub fn eject(&mut self, config: &OutlierDetection, now: Instant) -> Duration {
+        let factor = 1u32 << cmp::min(self.ejections, 16);
+        let duration = cmp::min(config.base_ejection * factor, config.max_ejection);
+
+        self.ejections += 1;
+        self.ejected_until = Some(now + duration);
+        self.consecutive_errors = 0;
+        self.window_start = None;
+
+        duration
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use config::OutlierDetection;
+    use super::Outlier;
+
+    #[test]
+    fn test_consecutive_errors() {
+        let config = OutlierDetection::default();
+        let mut outlier = Outlier::new();
+        let now = Instant::now();
+
+        for _ in 0..4 {
+            assert_eq!(false, outlier.record(&config, false, now));
+        }
+        // a success resets the count
+        assert_eq!(false, outlier.record(&config, true, now));
+        for _ in 0..4 {
+            assert_eq!(false, outlier.record(&config, false, now));
+        }
+        assert_eq!(true, outlier.record(&config, false, now));
+    }
+
+    #[test]
+    fn test_error_rate() {
+        let mut config = OutlierDetection::default();
+        config.consecutive_errors = None;
+        config.error_rate = Some(50);
+        config.min_requests = 10;
+        let mut outlier = Outlier::new();
+        let now = Instant::now();
+
+        for _ in 0..4 {
+            assert_eq!(false, outlier.record(&config, true, now));
+            assert_eq!(false, outlier.record(&config, false, now));
+        }
+        assert_eq!(false, outlier.record(&config, true, now));
+        assert_eq!(true, outlier.record(&config, false, now));
+
+        // errors from an earlier window do not count
+        let mut outlier = Outlier::new();
+        for _ in 0..9 {
+            outlier.record(&config, false, now);
+        }
+        let later = now + config.window;
+        assert_eq!(false, outlier.record(&config, false, later));
+    }
+
+    #[test]
+    fn test_ejection_time() {
+        let config = OutlierDetection::default();
+        let mut outlier = Outlier::new();
+        let now = Instant::now();
+
+        assert_eq!(Duration::from_secs(30), outlier.eject(&config, now));
+        assert!(outlier.is_ejected(now));
+        assert!(outlier.is_ejected(now + Duration::from_secs(29)));
+        assert!(!outlier.is_ejected(now + Duration::from_secs(30)));
+
+        // requests that finish while ejected are ignored
+        for _ in 0..10 {
+            assert_eq!(false, outlier.record(&config, false, now));
+        }
+
+        assert_eq!(Duration::from_secs(60), outlier.eject(&config, now));
+        assert_eq!(Duration::from_secs(120), outlier.eject(&config, now));
+        assert_eq!(Duration::from_secs(240), outlier.eject(&config, now));
+        assert_eq!(Duration::from_secs(300), outlier.eject(&config, now));
+        assert_eq!(Duration::from_secs(300), outlier.eject(&config, now));
+
+        // a backend that stays healthy long enough is ejected for the base time again
+        let later = now + Duration::from_secs(900);
+        assert_eq!(false, outlier.record(&config, true, later));
+        assert_eq!(Duration::from_secs(30), outlier.eject(&config, later));
+    }
+}
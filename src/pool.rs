@@ -1,17 +1,26 @@
+use std::error;
+use std::fmt;
 use std::io;
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::time::{Duration, Instant};
 
 use futures::Future;
 
-use hyper::{self, server};
+use hyper::{self, server, Uri};
+use hyper::client::Service;
 
+use balancer::{Balancer, RoundRobin, DEFAULT_PENALTY_SECS};
+use config::OutlierDetection;
+use outlier::Outlier;
 use server::Server;
 use stats::Stats;
+use vhost::Pools;
 
-/// A round-robin pool for servers
+/// A pool for servers
 ///
-/// A simple pool that stores socket addresses and, for now, clones them out.
+/// A simple pool that stores socket addresses and, for now, clones them out. The `Balancer`
+/// decides which server each request is sent to. The default is weighted round-robin.
 ///
 /// Inspired by https://github.com/NicolasLM/nucleon/blob/master/src/backend.rs
 // TODO can probably get rid of the Rc<RefCell<_>> part
@@ -21,38 +30,82 @@ pub struct Pool {
 }
 
 impl Pool {
+    /// Create a pool that uses `balancer` to pick a server for each request
+    pub fn new(balancer: Box<Balancer>) -> Pool {
+        Pool { inner: Rc::new(RefCell::new(InnerPool::with_balancer(balancer))) }
+    }
+
     /// Send a request to the pool
     ///
     /// The pool may be exhausted of eligible addresses to connect to and will return an error.
-    pub fn request<F>(&self, f: F) -> Box<Future<Item = server::Response, Error = hyper::Error>>
+    /// If `sticky` is the id of an active server, the request is sent to that server. Otherwise,
+    /// the `key` is passed to the balancer, which may use it to send related requests to the same
+    /// backend. The servers in `exclude`, such as servers a request was already sent to, are not
+    /// picked. The request counts as in flight for the chosen backend until the returned future
+    /// completes or is dropped. The time until the backend responds is added to the latency of the
+    /// backend. Requests that fail without a response add the failure latency instead, so a backend
+    /// that refuses connections does not look fast.
+    pub fn request<F>(
+        &self,
+        key: Option<&[u8]>,
+        sticky: Option<&str>,
+        exclude: &[Server],
+        f: F,
+    ) -> Box<Future<Item = server::Response, Error = hyper::Error>>
     where
         F: FnOnce(&Server) -> Box<Future<Item = server::Response, Error = hyper::Error>>,
     {
-        match self.inner.borrow_mut().get() {
+        let backend = self.inner.borrow_mut().get(key, sticky, exclude);
+        match backend {
             Some(backend) => {
-                Box::new(f(&backend.server()).then(move |res| match res {
-                    Ok(res) => {
-                        if res.status().is_server_error() {
+                backend.inc_requests();
+                let in_flight = InFlight::new(backend.clone());
+                let start = Instant::now();
+                let failure_latency = self.inner.borrow().failure_latency;
+                let pool = self.clone();
+                Box::new(f(&backend.server()).then(move |res| {
+                    drop(in_flight);
+                    match res {
+                        Ok(res) => {
+                            backend.record_latency(start.elapsed());
+                            if res.status().is_server_error() {
+                                backend.inc_failure();
+                                pool.record(&backend, false);
+                            } else {
+                                backend.inc_success();
+                                pool.record(&backend, true);
+                            }
+                            ::futures::finished(res)
+                        }
+                        Err(e) => {
+                            backend.record_latency(failure_latency);
                             backend.inc_failure();
-                        } else {
-                            backend.inc_success();
+                            pool.record(&backend, false);
+                            ::futures::failed(e)
                         }
-                        ::futures::finished(res)
-                    }
-                    Err(e) => {
-                        backend.inc_failure();
-                        ::futures::failed(e)
                     }
                 }))
             }
             None => {
-                let e = io::Error::new(io::ErrorKind::Other, "Pool is exhausted of servers");
-                // TODO should this return a Bad Gateway error ?
+                let e = io::Error::new(io::ErrorKind::Other, Exhausted);
                 Box::new(::futures::failed(hyper::Error::Io(e)))
             }
         }
     }
 
+    /// Pick a backend for a connection that is tunneled to it, such as an upgraded WebSocket
+    /// connection
+    ///
+    /// The backend is picked the same way as for `request`. The tunnel counts as in flight for the
+    /// backend until the returned `InFlight` is dropped.
+    pub fn tunnel(&self, key: Option<&[u8]>, sticky: Option<&str>) -> Option<(Server, InFlight)> {
+        let backend = self.inner.borrow_mut().get(key, sticky, &[]);
+        backend.map(|backend| {
+            backend.inc_requests();
+            (backend.server(), InFlight::new(backend))
+        })
+    }
+
     /// Returns all `Backend` from the pool
     pub fn all(&self) -> Vec<Backend> {
         self.inner.borrow().all()
@@ -75,6 +128,140 @@ impl Pool {
     pub fn find(&self, server: &Server) -> Option<Backend> {
         self.inner.borrow().find(server)
     }
+
+    /// The server a request for `uri` is being sent to, matched by scheme and authority
+    pub fn server_for(&self, uri: &Uri) -> Option<Server> {
+        self.all()
+            .into_iter()
+            .map(|backend| backend.server())
+            .find(|server| {
+                let url = server.url();
+                url.scheme() == uri.scheme() && url.authority() == uri.authority()
+            })
+    }
+
+    /// Set the latency recorded for a request that fails without a response, such as the read
+    /// timeout
+    pub fn set_failure_latency(&self, latency: Duration) {
+        self.inner.borrow_mut().failure_latency = latency;
+    }
+
+    /// Eject backends that fail live requests, as configured by `outlier_detection`
+    pub fn detect_outliers(&self, outlier_detection: OutlierDetection) {
+        self.inner.borrow_mut().outlier_detection = Some(outlier_detection);
+    }
+
+    /// Call `listener` with the server and the ejection time each time a backend is ejected
+    pub fn on_ejection<F>(&self, listener: F)
+    where
+        F: Fn(&Server, Duration) + 'static,
+    {
+        self.inner.borrow_mut().ejection_listener = Some(EjectionListener(Box::new(listener)));
+    }
+
+    /// Record the result of a request for outlier detection
+    fn record(&self, backend: &Backend, success: bool) {
+        let ejected = self.inner.borrow_mut().record(backend, success);
+
+        if let Some(duration) = ejected {
+            let inner = self.inner.borrow();
+            if let Some(ref listener) = inner.ejection_listener {
+                (listener.0)(&backend.server(), duration);
+            }
+        }
+    }
+}
+
+struct EjectionListener(Box<Fn(&Server, Duration)>);
+
+impl fmt::Debug for EjectionListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EjectionListener")
+    }
+}
+
+/// The error returned when the pool has no backend to send a request to
+#[derive(Debug)]
+pub struct Exhausted;
+
+impl fmt::Display for Exhausted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Pool is exhausted of servers")
+    }
+}
+
+impl error::Error for Exhausted {
+    fn description(&self) -> &str {
+        "Pool is exhausted of servers"
+    }
+}
+
+/// Whether the request failed because the pool had no backend to send it to
+pub fn is_exhausted(e: &hyper::Error) -> bool {
+    match *e {
+        hyper::Error::Io(ref e) => {
+            match e.get_ref() {
+                Some(inner) => inner.is::<Exhausted>(),
+                None => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// A connector that counts the connections opened to each backend of the pools
+///
+/// Together with the number of requests, this shows how often requests reuse a connection.
+#[derive(Clone, Debug)]
+pub struct CountConnections<C> {
+    inner: C,
+    pools: Pools,
+}
+
+impl<C> CountConnections<C> {
+    pub fn new(inner: C, pools: Pools) -> CountConnections<C> {
+        CountConnections {
+            inner: inner,
+            pools: pools,
+        }
+    }
+}
+
+impl<C> Service for CountConnections<C>
+where
+    C: Service<Request = Uri, Error = io::Error>,
+    C::Future: 'static,
+{
+    type Request = Uri;
+    type Response = C::Response;
+    type Error = io::Error;
+    type Future = Box<Future<Item = C::Response, Error = io::Error>>;
+
+    fn call(&self, uri: Uri) -> Self::Future {
+        let backend = self.pools.server_for(&uri).and_then(|server| self.pools.backend(&server));
+        Box::new(self.inner.call(uri).map(move |conn| {
+            if let Some(backend) = backend {
+                backend.inc_connections();
+            }
+            conn
+        }))
+    }
+}
+
+/// Counts a request as in flight for a backend for as long as it is alive
+pub struct InFlight(Backend);
+
+impl InFlight {
+    fn new(backend: Backend) -> InFlight {
+        backend.inc_in_flight();
+        InFlight(backend)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec_in_flight();
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
@@ -102,6 +289,10 @@ struct InnerBackend {
     server: Server,
     state: ServerState,
     stats: Stats,
+    outlier: Outlier,
+
+    /// Number of requests sent to this backend that have not completed yet
+    in_flight: usize,
 }
 
 impl Backend {
@@ -111,6 +302,8 @@ impl Backend {
                 server: server,
                 state: ServerState::Active,
                 stats: Stats::new(),
+                outlier: Outlier::new(),
+                in_flight: 0,
             })),
         }
     }
@@ -123,12 +316,62 @@ impl Backend {
         self.inner.borrow_mut().stats.inc_failure()
     }
 
+    pub fn record_latency(&self, latency: Duration) {
+        self.inner.borrow_mut().stats.record_latency(latency)
+    }
+
+    pub fn inc_requests(&self) {
+        self.inner.borrow_mut().stats.inc_requests()
+    }
+
+    pub fn inc_connections(&self) {
+        self.inner.borrow_mut().stats.inc_connections()
+    }
+
+    /// The number of requests sent to this backend, how many connections were opened to send
+    /// them and how many of them reused an open connection
+    pub fn connection_reuse(&self) -> (usize, usize, usize) {
+        let inner = self.inner.borrow();
+        (inner.stats.requests(), inner.stats.connections(), inner.stats.reused())
+    }
+
+    /// Moving average of the time this backend takes to respond
+    pub fn latency(&self) -> Option<Duration> {
+        self.inner.borrow().stats.latency()
+    }
+
     pub fn server(&self) -> Server {
         self.inner.borrow().server.clone()
     }
 
+    pub fn weight(&self) -> u32 {
+        self.inner.borrow().server.weight()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.inner.borrow().in_flight
+    }
+
+    pub fn inc_in_flight(&self) {
+        self.inner.borrow_mut().in_flight += 1;
+    }
+
+    pub fn dec_in_flight(&self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.in_flight = inner.in_flight.saturating_sub(1);
+    }
+
+    /// Whether requests can be sent to this backend
+    ///
+    /// A backend that is ejected as an outlier is not active until the ejection ends, even if the
+    /// health checks pass.
     pub fn is_active(&self) -> bool {
-        self.inner.borrow().state == ServerState::Active
+        let inner = self.inner.borrow();
+        inner.state == ServerState::Active && !inner.outlier.is_ejected(Instant::now())
+    }
+
+    pub fn is_ejected(&self) -> bool {
+        self.inner.borrow().outlier.is_ejected(Instant::now())
     }
 
     pub fn is_down(&self) -> bool {
@@ -144,47 +387,83 @@ impl Backend {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct InnerPool {
     backends: Vec<Backend>,
-    last_used: usize,
+    balancer: Box<Balancer>,
+    failure_latency: Duration,
+    outlier_detection: Option<OutlierDetection>,
+    ejection_listener: Option<EjectionListener>,
+}
+
+impl Default for InnerPool {
+    fn default() -> InnerPool {
+        InnerPool::with_balancer(Box::new(RoundRobin::new()))
+    }
 }
 
 impl InnerPool {
     // this is only used in test code
     #[cfg(test)]
     fn new(backends: Vec<Backend>) -> InnerPool {
+        let mut pool = InnerPool::default();
+        pool.backends = backends;
+        pool.balancer.update(&pool.backends);
+        pool
+    }
+
+    fn with_balancer(balancer: Box<Balancer>) -> InnerPool {
         InnerPool {
-            backends: backends.into_iter().map(|b| b).collect(),
-            last_used: 0,
+            backends: Vec::new(),
+            balancer: balancer,
+            failure_latency: Duration::from_secs(DEFAULT_PENALTY_SECS),
+            outlier_detection: None,
+            ejection_listener: None,
         }
     }
 
-    fn get(&mut self) -> Option<Backend> {
+    fn get(&mut self, key: Option<&[u8]>, sticky: Option<&str>, exclude: &[Server]) -> Option<Backend> {
         if self.backends.is_empty() {
             warn!("Pool is empty of backends");
             return None;
         }
 
-        let start = self.last_used;
-        loop {
-            self.last_used = (self.last_used + 1) % self.backends.len();
-            let backend = match self.backends.get(self.last_used) {
-                Some(b) => b,
-                None => return None,
-            };
+        if let Some(id) = sticky {
+            let backend = self.backends.iter().find(|b| {
+                b.is_active() && b.server().id() == id && !exclude.contains(&b.server())
+            });
+            if let Some(backend) = backend {
+                debug!("Pool is sending sticky session to {:?}", backend);
+                return Some(backend.clone());
+            }
+        }
 
-            if backend.is_down() {
-                if start == self.last_used {
+        // the balancer does not know about excluded servers, so ask again until it picks a
+        // different one
+        for _ in 0..self.backends.len() {
+            match self.balancer.pick(&self.backends, key) {
+                Some(ref backend) if exclude.contains(&backend.server()) => continue,
+                Some(backend) => {
+                    debug!("Pool is cloaning (hehe) out {:?}", backend);
+                    return Some(backend);
+                }
+                None => {
                     warn!("Pool has no active backends");
                     return None;
                 }
-                continue;
             }
+        }
 
-            debug!("Pool is cloaning (hehe) out {:?}", backend);
-            return Some(backend.clone());
+        // a balancer, such as consistent hash, may keep picking the same server
+        let backend = self.backends
+            .iter()
+            .find(|b| b.is_active() && !exclude.contains(&b.server()))
+            .cloned();
+        if backend.is_none() {
+            warn!("Pool has no active backends that were not excluded");
         }
+
+        backend
     }
 
     fn all(&self) -> Vec<Backend> {
@@ -198,17 +477,20 @@ impl InnerPool {
     }
 
     fn add(&mut self, backend: Backend) -> bool {
-        let backend = backend;
-        if self.backends.contains(&backend) {
+        // compare servers, as the state and stats of an existing backend have likely changed
+        let server = backend.server();
+        if self.backends.iter().any(|b| b.server() == server) {
             return false;
         }
 
         self.backends.push(backend);
+        self.balancer.update(&self.backends);
         true
     }
 
     fn remove(&mut self, server: &Server) {
         self.backends.retain(|b| &b.server() != server);
+        self.balancer.update(&self.backends);
     }
 
     fn find(&self, server: &Server) -> Option<Backend> {
@@ -217,13 +499,55 @@ impl InnerPool {
             None => None,
         }
     }
+
+    /// Record the result of a request and eject the backend if it is an outlier
+    ///
+    /// Returns the ejection time if the backend was ejected. A backend is not ejected if that
+    /// would eject more than `max_ejected_percent` of the backends in the pool.
+    fn record(&mut self, backend: &Backend, success: bool) -> Option<Duration> {
+        let config = match self.outlier_detection {
+            Some(ref config) => config,
+            None => return None,
+        };
+
+        let now = Instant::now();
+        if !backend.inner.borrow_mut().outlier.record(config, success, now) {
+            return None;
+        }
+
+        let ejected = self.backends.iter().filter(|b| b.is_ejected()).count();
+        if (ejected + 1) * 100 > self.backends.len() * config.max_ejected_percent as usize {
+            warn!(
+                "Not ejecting {:?}, too many backends are ejected already",
+                backend.server()
+            );
+            return None;
+        }
+
+        let duration = backend.inner.borrow_mut().outlier.eject(config, now);
+        warn!(
+            "Ejecting outlier {:?} from pool for {} seconds",
+            backend.server(),
+            duration.as_secs()
+        );
+
+        Some(duration)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Backend, InnerPool};
+    use super::{Backend, InnerPool, Pool};
+    use balancer::P2cEwma;
+    use config::OutlierDetection;
     use server::Server;
+    use std::io;
     use std::str::FromStr;
+    use std::time::Duration;
+
+    use futures::Future;
+    use hyper::{self, server};
+    use rand::{SeedableRng, XorShiftRng};
 
     #[test]
     fn test_rrb_backend() {
@@ -241,10 +565,10 @@ mod tests {
         let mut rrb = InnerPool::new(backends);
         assert_eq!(2, rrb.backends.len());
 
-        let first = rrb.get().unwrap();
-        let second = rrb.get().unwrap();
-        let third = rrb.get().unwrap();
-        let fourth = rrb.get().unwrap();
+        let first = rrb.get(None, None, &[]).unwrap();
+        let second = rrb.get(None, None, &[]).unwrap();
+        let third = rrb.get(None, None, &[]).unwrap();
+        let fourth = rrb.get(None, None, &[]).unwrap();
         assert_eq!(first, third);
         assert_eq!(second, fourth);
         assert!(first != second);
@@ -255,19 +579,19 @@ mod tests {
         let backends = vec![];
         let mut rrb = InnerPool::new(backends);
         assert_eq!(0, rrb.backends.len());
-        assert!(rrb.get().is_none());
+        assert!(rrb.get(None, None, &[]).is_none());
         assert!(rrb.all().is_empty());
     }
 
     #[test]
     fn test_add_to_rrb_backend() {
         let mut rrb = InnerPool::new(vec![]);
-        assert!(rrb.get().is_none());
+        assert!(rrb.get(None, None, &[]).is_none());
         let server = Server::new(FromStr::from_str("http://127.0.0.1:6000").unwrap(), false);
         let backend = Backend::new(server.clone());
         rrb.add(backend);
         let b1 = Backend::new(server.clone());
-        assert!(rrb.get().is_some());
+        assert!(rrb.get(None, None, &[]).is_some());
         assert_eq!(vec![b1], rrb.all());
     }
 
@@ -297,4 +621,122 @@ mod tests {
         assert_eq!(0, rrb.backends.len());
         assert!(rrb.all().is_empty());
     }
+
+    #[test]
+    fn test_weighted_rrb_backend() {
+        let server = |port: u16, weight: u32| {
+            Server::new(
+                FromStr::from_str(&format!("http://127.0.0.1:{}", port)).unwrap(),
+                false,
+            ).with_weight(weight)
+        };
+
+        let a = Backend::new(server(6000, 5));
+        let b = Backend::new(server(6001, 1));
+        let c = Backend::new(server(6002, 1));
+        let mut rrb = InnerPool::new(vec![a.clone(), b.clone(), c.clone()]);
+
+        // traffic is proportional to the weights and spread out over the whole cycle
+        let expected = vec![&a, &a, &b, &a, &c, &a, &a];
+        for _ in 0..2 {
+            for backend in expected.iter() {
+                assert_eq!(**backend, rrb.get(None, None, &[]).unwrap());
+            }
+        }
+
+        a.mark_down();
+        for _ in 0..4 {
+            assert!(rrb.get(None, None, &[]).unwrap() != a);
+        }
+
+        b.mark_down();
+        c.mark_down();
+        assert!(rrb.get(None, None, &[]).is_none());
+    }
+
+    #[test]
+    fn test_sticky_backend() {
+        let server1 = Server::new(FromStr::from_str("http://127.0.0.1:6000").unwrap(), false);
+        let server2 = Server::new(FromStr::from_str("http://127.0.0.1:6001").unwrap(), false);
+        let b1 = Backend::new(server1.clone());
+        let b2 = Backend::new(server2.clone());
+        let mut rrb = InnerPool::new(vec![b1.clone(), b2.clone()]);
+
+        let id = server2.id();
+        assert!(id != server1.id());
+        assert!(!id.contains("127.0.0.1"));
+        for _ in 0..3 {
+            assert_eq!(b2, rrb.get(None, Some(&id), &[]).unwrap());
+        }
+
+        // fall back to the balancer when the server is down or gone
+        b2.mark_down();
+        assert_eq!(b1, rrb.get(None, Some(&id), &[]).unwrap());
+        rrb.remove(&server2);
+        assert_eq!(b1, rrb.get(None, Some(&id), &[]).unwrap());
+        assert_eq!(b1, rrb.get(None, Some("unknown"), &[]).unwrap());
+    }
+
+    #[test]
+    fn test_eject_outliers() {
+        let b1 = Backend::new(Server::new(FromStr::from_str("http://127.0.0.1:6000").unwrap(), false));
+        let b2 = Backend::new(Server::new(FromStr::from_str("http://127.0.0.1:6001").unwrap(), false));
+        let mut rrb = InnerPool::new(vec![b1.clone(), b2.clone()]);
+        let mut config = OutlierDetection::default();
+        config.consecutive_errors = Some(2);
+        rrb.outlier_detection = Some(config);
+
+        assert_eq!(None, rrb.record(&b1, false));
+        assert_eq!(Some(Duration::from_secs(30)), rrb.record(&b1, false));
+        assert!(b1.is_ejected());
+        assert!(!b1.is_active());
+        for _ in 0..3 {
+            assert_eq!(b2, rrb.get(None, None, &[]).unwrap());
+        }
+
+        // ejecting the other backend would eject more than half of the pool
+        assert_eq!(None, rrb.record(&b2, false));
+        assert_eq!(None, rrb.record(&b2, false));
+        assert!(b2.is_active());
+    }
+
+    #[test]
+    fn test_exclude_backend() {
+        let server1 = Server::new(FromStr::from_str("http://127.0.0.1:6000").unwrap(), false);
+        let server2 = Server::new(FromStr::from_str("http://127.0.0.1:6001").unwrap(), false);
+        let b1 = Backend::new(server1.clone());
+        let b2 = Backend::new(server2.clone());
+        let mut rrb = InnerPool::new(vec![b1.clone(), b2.clone()]);
+
+        for _ in 0..3 {
+            assert_eq!(b2, rrb.get(None, None, &[server1.clone()]).unwrap());
+        }
+        assert_eq!(b1, rrb.get(None, Some(&server2.id()), &[server2.clone()]).unwrap());
+        assert!(rrb.get(None, None, &[server1, server2]).is_none());
+    }
+
+    #[test]
+    fn test_p2c_avoids_refused_backend() {
+        let refused = Server::new(FromStr::from_str("http://127.0.0.1:6000").unwrap(), false);
+        let healthy = Server::new(FromStr::from_str("http://127.0.0.1:6001").unwrap(), false);
+        let balancer = P2cEwma::with_rng(XorShiftRng::from_seed([1, 2, 3, 4]), Duration::from_secs(2));
+        let pool = Pool::new(Box::new(balancer));
+        pool.add(refused.clone());
+        pool.add(healthy.clone());
+
+        let mut sent = 0;
+        for _ in 0..100 {
+            let _ = pool.request(None, None, &[], |server| {
+                if *server == refused {
+                    sent += 1;
+                    let e = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+                    Box::new(::futures::failed(hyper::Error::Io(e)))
+                } else {
+                    Box::new(::futures::finished(server::Response::new()))
+                }
+            }).wait();
+        }
+
+        assert!(sent < 10, "sent {} requests to the refused backend", sent);
+    }
 }
@@ -1,20 +1,34 @@
+use std::cell::{Cell, RefCell};
 use std::io;
 use std::net::SocketAddr;
+use std::rc::Rc;
 use std::str::{self, FromStr};
+use std::time::{Duration, Instant};
 
-use futures::{Future, Stream};
-use tokio_core::reactor::Handle;
+use futures::{Async, Future, Sink, Stream};
+use tokio_core::reactor::{Handle, Interval, Timeout};
 use tokio_core::net::{TcpListener, TcpStream};
-use hyper::{self, Headers, Body, Client, HttpVersion};
+use hyper::{self, Headers, Body, Client, HttpVersion, Method, StatusCode};
 use hyper::client::{self, HttpConnector, Service};
 use hyper::header;
 use hyper::server::{self, Http};
-use hyper_tls::HttpsConnector;
 use hyper::Uri;
 use hyper_timeout::TimeoutConnector;
-
-use pool::Pool;
-use config::Config;
+use tokio_io::{self, AsyncRead, AsyncWrite};
+use tokio_tls::TlsAcceptorExt;
+use native_tls::TlsAcceptor;
+
+use balancer::{self, HashKey};
+use pool::{self, CountConnections, Pool};
+use config::{Config, ErrorPage, ErrorPages, ForwardedHeaders, Retry};
+use forwarded;
+use header_rules::{self, HeaderRules, Variables};
+use proxy_protocol::{self, Addresses, Prefixed, ProxyProtocolConnector};
+use retry::{self, ConnectErrors, RetryBudget};
+use server::Server;
+use tls::{self, HttpsConnector};
+use upgrade::{self, RequestHead};
+use vhost::Pools;
 
 // testing here before sending PR upstream
 // TODO make this typed
@@ -99,21 +113,82 @@ pub fn filter_frontend_request_headers(headers: &Headers) -> Headers {
     h
 }
 
+/// A backend request without its body
+///
+/// The body is kept apart, so the same request can be sent to more than one backend.
+#[derive(Debug)]
+struct BackendRequest {
+    method: Method,
+    uri: Uri,
+    headers: Headers,
+
+    /// Whether the client sent a body. A request sent without one does not need a length, so the
+    /// connection to the backend can be reused.
+    has_body: bool,
+}
+
+impl BackendRequest {
+    fn to_request(&self, body: Body) -> client::Request {
+        let mut r = client::Request::new(self.method.clone(), self.uri.clone());
+        r.headers_mut().extend(self.headers.iter());
+        if self.has_body {
+            r.set_body(body);
+        }
+        r
+    }
+}
+
 /// Map a frontend request to a backend request
 ///
 /// The primary purpose of this function is to add and remove headers as required by an
 /// intermediary conforming to the HTTP spec.
-fn map_request(req: server::Request) -> client::Request {
+fn map_request(req: server::Request) -> (BackendRequest, Body) {
     let via = create_via_header(req.headers().get::<Via>(), &req.version());
 
     let mut headers = filter_frontend_request_headers(req.headers());
     headers.set(via);
 
     // TODO fix clone
-    let mut r = client::Request::new(req.method().clone(), req.uri().clone());
-    r.headers_mut().extend(headers.iter());
-    r.set_body(req.body());
-    r
+    let r = BackendRequest {
+        method: req.method().clone(),
+        uri: req.uri().clone(),
+        headers: headers,
+        has_body: req.headers().has::<header::ContentLength>() ||
+            req.headers().has::<header::TransferEncoding>(),
+    };
+    (r, req.body())
+}
+
+/// The hostname a request is for, taken from an absolute request uri or the `Host` header
+fn request_host<'a>(uri: &'a Uri, headers: &'a Headers) -> Option<&'a str> {
+    uri.host().or_else(|| headers.get::<header::Host>().map(|host| host.hostname()))
+}
+
+/// The request uri with its path replaced by `path`, keeping the query
+fn rewrite_path(uri: &Uri, path: &str) -> Result<Uri, hyper::error::UriError> {
+    match uri.query() {
+        Some(query) => format!("{}?{}", path, query).parse(),
+        None => path.parse(),
+    }
+}
+
+/// The header rules of a pool followed by those of the route that picked it, if any
+fn header_rules_for(pool: Rc<HeaderRules>, route: Option<&HeaderRules>) -> Rc<HeaderRules> {
+    match route {
+        Some(route) if !route.is_empty() => Rc::new(pool.then(route)),
+        _ => pool,
+    }
+}
+
+/// Whether the body of a request is small enough to be kept in memory
+///
+/// Only bodies with a `Content-Length` are kept. A request without a `Content-Length` or a
+/// `Transfer-Encoding` does not have a body.
+fn can_buffer_body(headers: &Headers, max_body_bytes: u64) -> bool {
+    match headers.get::<header::ContentLength>() {
+        Some(&header::ContentLength(len)) => len <= max_body_bytes,
+        None => !headers.has::<header::TransferEncoding>(),
+    }
 }
 
 pub fn filter_backend_response_headers(headers: &Headers) -> Headers {
@@ -141,9 +216,212 @@ fn map_response(res: client::Response) -> server::Response {
     r
 }
 
+/// Counts a request as in flight until it is dropped
+struct InFlightRequest(Rc<Cell<usize>>);
+
+impl InFlightRequest {
+    fn new(in_flight: Rc<Cell<usize>>) -> InFlightRequest {
+        in_flight.set(in_flight.get() + 1);
+        InFlightRequest(in_flight)
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
+/// Keep `request` in flight until the body of `res` has been sent to the client
+///
+/// hyper is done with a request once it has the response head, so the body is passed on through a
+/// channel that is only closed when the last chunk was taken. A drain then waits for the body
+/// instead of cutting it off.
+fn hold_until_sent(res: server::Response, request: InFlightRequest, handle: &Handle) -> server::Response {
+    if res.body_ref().map_or(true, |body| body.is_empty()) {
+        return res;
+    }
+
+    let mut head = server::Response::new()
+        .with_status(res.status())
+        .with_headers(res.headers().clone());
+    let (tx, body) = Body::pair();
+    let chunks = res.body().then(|chunk| Ok(chunk));
+    // the send fails when the client goes away, which also ends the request
+    handle.spawn(tx.send_all(chunks).then(move |_| {
+        drop(request);
+        Ok(())
+    }));
+    head.set_body(body);
+    head
+}
+
+/// Map an error sending a request to a backend to a response for the client
+///
+/// If there was no active backend, the response is a 503 with a `Retry-After` header. If the
+/// backend took too long to respond, the response is a 504. Any other error, such as failing to
+/// connect to the backend or an invalid response, is a 502.
+fn error_response(e: &hyper::Error, pages: &ErrorPages) -> server::Response {
+    let (status, page) = error_page(e, pages);
+
+    let mut res = server::Response::new()
+        .with_status(status)
+        .with_header(header::ContentLength(page.body.len() as u64))
+        .with_body(page.body.clone());
+    res.headers_mut().set_raw("Content-Type", page.content_type.clone());
+
+    if status == StatusCode::ServiceUnavailable {
+        res.headers_mut().set_raw("Retry-After", pages.retry_after.as_secs().to_string());
+    }
+
+    res
+}
+
+/// The status and page of the error response for `e`
+fn error_page<'a>(e: &hyper::Error, pages: &'a ErrorPages) -> (StatusCode, &'a ErrorPage) {
+    if pool::is_exhausted(e) {
+        (StatusCode::ServiceUnavailable, &pages.service_unavailable)
+    } else if is_timeout(e) {
+        (StatusCode::GatewayTimeout, &pages.gateway_timeout)
+    } else {
+        (StatusCode::BadGateway, &pages.bad_gateway)
+    }
+}
+
+/// The error response for a connection that is not served by hyper, as sent on the wire
+///
+/// The connection is closed after the response.
+fn raw_error_response(e: &hyper::Error, pages: &ErrorPages) -> Vec<u8> {
+    let (status, page) = error_page(e, pages);
+    let res = error_response(e, pages);
+
+    let mut raw = format!("HTTP/1.1 {}\r\n{}Connection: close\r\n\r\n", status, res.headers()).into_bytes();
+    raw.extend_from_slice(&page.body);
+    raw
+}
+
+/// Fail `future` with a `TimedOut` error if it does not resolve within `timeout`
+fn with_timeout<F>(future: F, timeout: Option<Duration>, handle: &Handle) -> Box<Future<Item = F::Item, Error = io::Error>>
+where
+    F: Future<Error = io::Error> + 'static,
+    F::Item: 'static,
+{
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Box::new(future),
+    };
+    let timer = match Timeout::new(timeout, handle) {
+        Ok(timer) => timer,
+        Err(e) => return Box::new(::futures::failed(e)),
+    };
+
+    let timer = timer.and_then(|()| -> Result<F::Item, io::Error> {
+        Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+    });
+    Box::new(future.select(timer).map(|(item, _)| item).map_err(|(e, _)| e))
+}
+
+/// Whether the backend timed out after the connection was made
+fn is_timeout(e: &hyper::Error) -> bool {
+    match *e {
+        hyper::Error::Io(ref io) => io.kind() == io::ErrorKind::TimedOut && !retry::is_connect_error(e),
+        _ => false,
+    }
+}
+
+/// How often each worker logs how many requests reused a connection to a backend
+const REUSE_REPORT_INTERVAL: u64 = 60;
+
+type BackendClient = Client<ConnectErrors<CountConnections<TimeoutConnector<HttpsConnector<ProxyProtocolConnector<HttpConnector>>>>>, Body>;
+
+/// Create a client that keeps its connections to the backends open, as configured by `keep_alive`
+///
+/// The PROXY protocol header sent to servers that want one holds `addresses`, if given.
+fn backend_client(handle: &Handle, pools: &Pools, config: &Config, addresses: Option<Addresses>) -> BackendClient {
+    let connector = tls::https_connector(4, handle, pools.clone(), addresses).unwrap();
+    let mut tm = TimeoutConnector::new(connector, handle);
+    tm.set_connect_timeout(config.timeout.connect);
+    tm.set_read_timeout(config.timeout.read);
+    tm.set_write_timeout(config.timeout.write);
+    Client::configure()
+        .connector(ConnectErrors::new(CountConnections::new(tm, pools.clone())))
+        .keep_alive(config.keep_alive.enabled)
+        .keep_alive_timeout(config.keep_alive.idle_timeout)
+        .build(handle)
+}
+
+/// The clients a connection sends requests to the backends with
+struct Clients {
+    /// Shared by all connections of the worker, so requests from any client reuse the connections
+    /// to the backends
+    shared: BackendClient,
+
+    /// Used for servers that want a PROXY protocol header. The header holds the addresses of this
+    /// client connection, so connections to those servers cannot be shared with other clients.
+    /// The client is created on the first request to such a server.
+    proxied: RefCell<Option<BackendClient>>,
+
+    handle: Handle,
+    pools: Pools,
+    config: Rc<Config>,
+    addresses: Addresses,
+}
+
+impl Clients {
+    /// The client used to send a request to `server`
+    fn client_for(&self, server: &Server) -> BackendClient {
+        if server.proxy_protocol().is_none() {
+            return self.shared.clone();
+        }
+
+        self.proxied
+            .borrow_mut()
+            .get_or_insert_with(|| {
+                backend_client(&self.handle, &self.pools, &self.config, Some(self.addresses))
+            })
+            .clone()
+    }
+}
+
 struct Proxy {
-    client: Client<TimeoutConnector<HttpsConnector<HttpConnector>>, Body>,
-    pool: Pool,
+    clients: Rc<Clients>,
+    pools: Pools,
+
+    /// Number of requests, across all connections in this worker, whose response has not been
+    /// sent to the client yet
+    in_flight: Rc<Cell<usize>>,
+
+    /// Spawns the tasks that pass response bodies on to the client
+    handle: Handle,
+
+    /// The head of a later request on this connection that asked to upgrade it, which is tunneled
+    /// to a server once hyper gives the connection back
+    upgrade: Rc<RefCell<Option<RequestHead>>>,
+
+    /// Address of the client that opened this connection
+    client_addr: SocketAddr,
+
+    /// The scheme the client used to connect, either `http` or `https`
+    proto: &'static str,
+
+    /// The part of the request the balancer hashes, if the balancer uses one
+    hash_key: Option<HashKey>,
+
+    /// Name of the cookie used to send a client back to the same backend, if sticky sessions are
+    /// enabled
+    sticky_cookie: Option<String>,
+
+    retry: Retry,
+
+    /// Retries left to this worker, shared across all connections
+    retry_budget: Rc<RefCell<RetryBudget>>,
+
+    error_pages: Rc<ErrorPages>,
+
+    forwarded: ForwardedHeaders,
+
+    /// The number of unused connections kept open to each server, if keep-alive is enabled
+    max_idle: Option<usize>,
 }
 
 impl Service for Proxy {
@@ -153,86 +431,709 @@ impl Service for Proxy {
     type Future = Box<Future<Item = server::Response, Error = Self::Error>>;
 
     fn call(&self, req: server::Request) -> Self::Future {
+        // hyper cannot switch protocols, so it is stopped without a response and the connection is
+        // tunneled instead. hyper may already have read part of a body, so a request with a body
+        // is proxied as usual.
+        if req.version() == HttpVersion::Http11 && req.body_ref().is_none() && upgrade::is_upgrade(req.headers()) {
+            let (method, uri, _, headers, _) = req.deconstruct();
+            *self.upgrade.borrow_mut() = Some(RequestHead {
+                method: method,
+                uri: uri,
+                headers: headers,
+            });
+            let e = io::Error::new(io::ErrorKind::Other, "connection upgraded");
+            return Box::new(::futures::failed(hyper::Error::Io(e)));
+        }
 
-        let mut client_req = map_request(req);
+        let host = request_host(req.uri(), req.headers()).map(|host| host.to_string());
+        let (vhost, route) = self.pools.route(host.as_ref().map(|host| host.as_str()), req.uri().path());
+        debug!("Sending request for {} to pool {}", req.uri(), vhost.name());
+        let path = route.as_ref().and_then(|route| route.rewrite(req.uri().path()));
+        let headers = header_rules_for(vhost.headers(), route.as_ref().map(|route| &route.spec().headers));
+        let vars = Variables {
+            client_ip: self.client_addr.ip(),
+            // the id is only made when a rule may use it
+            request_id: if headers.is_empty() { String::new() } else { header_rules::request_id() },
+            host: host.unwrap_or_default(),
+            scheme: self.proto,
+            backend: String::new(),
+        };
+
+        let key = self.hash_key
+            .as_ref()
+            .and_then(|k| k.extract(&self.client_addr, req.uri(), req.headers()));
+        let sticky = self.sticky_cookie
+            .as_ref()
+            .and_then(|name| balancer::cookie(req.headers(), name))
+            .map(|id| id.to_string());
+
+        // the body must be kept to send the request again, so larger bodies are not retried
+        let retries = if can_buffer_body(req.headers(), self.retry.max_body_bytes) {
+            self.retry.attempts
+        } else {
+            0
+        };
+        self.retry_budget.borrow_mut().deposit(Instant::now());
+
+        let (mut backend_req, body) = map_request(req);
+        if let Some(path) = path {
+            match rewrite_path(&backend_req.uri, &path) {
+                Ok(uri) => {
+                    debug!("Rewrote path {} to {}", backend_req.uri.path(), path);
+                    backend_req.uri = uri;
+                }
+                Err(e) => {
+                    error!("Route rewrote path {} to an invalid path {:?}", backend_req.uri.path(), path);
+                    return Box::new(::futures::finished(error_response(&e.into(), &self.error_pages)));
+                }
+            }
+        }
+        forwarded::set_headers(&mut backend_req.headers, &self.forwarded, &self.client_addr, self.proto);
+        // the sticky cookie is only meant for weldr
+        if let Some(ref name) = self.sticky_cookie {
+            balancer::remove_cookie(&mut backend_req.headers, name);
+        }
 
-        self.pool.request(|server| {
+        let request = InFlightRequest::new(self.in_flight.clone());
+
+        let mut forward = Forward {
+            clients: self.clients.clone(),
+            pool: vhost.pool(),
+            request: backend_req,
+            body: None,
+            key: key,
+            sticky: sticky,
+            sticky_cookie: self.sticky_cookie.clone(),
+            statuses: self.retry.statuses.clone(),
+            retry_budget: self.retry_budget.clone(),
+            max_idle: self.max_idle,
+            headers: headers,
+            vars: vars,
+        };
+
+        let res: Box<Future<Item = server::Response, Error = hyper::Error>> = if retries > 0 {
+            Box::new(body.concat2().and_then(move |chunk| {
+                let body = chunk.to_vec();
+                forward.body = Some(body.clone());
+                send(Rc::new(forward), Body::from(body), Vec::new(), retries, None)
+            }))
+        } else {
+            send(Rc::new(forward), body, Vec::new(), 0, None)
+        };
+
+        let error_pages = self.error_pages.clone();
+        let handle = self.handle.clone();
+        Box::new(res.then(move |res| {
+            let res = match res {
+                Ok(res) => res,
+                Err(e) => error_response(&e, &error_pages),
+            };
+            Ok(hold_until_sent(res, request, &handle))
+        }))
+    }
+}
 
-            let url = format!(
-                "{}{}?{}",
-                server.url(),
-                client_req.uri().path(),
-                client_req.uri().query().unwrap_or("")
-            );
-            // TODO proper error handling
-            let uri = Uri::from_str(&url).expect("Failed to parse url");
-            let map_host = server.map_host();
-            debug!("Preparing backend request to {:?}", url);
-
-            if map_host {
-                // add host header related to backend
-                let _ = client_req.headers_mut().remove::<header::Host>();
-                let host = uri.host().unwrap().to_string();
-                let port = uri.port();
-                client_req.headers_mut().set(header::Host::new(host, port));
+/// Everything needed to send a request to a backend, and send it again if it fails
+struct Forward {
+    clients: Rc<Clients>,
+    pool: Pool,
+    request: BackendRequest,
+
+    /// The request body, if it was kept so the request can be retried
+    body: Option<Vec<u8>>,
+
+    key: Option<Vec<u8>>,
+    sticky: Option<String>,
+    sticky_cookie: Option<String>,
+
+    /// Response statuses that cause an idempotent request to be retried
+    statuses: Vec<u16>,
+
+    retry_budget: Rc<RefCell<RetryBudget>>,
+    max_idle: Option<usize>,
+
+    /// Rules of the pool followed by those of the route, and the values they can refer to
+    headers: Rc<HeaderRules>,
+    vars: Variables,
+}
+
+impl Forward {
+    /// Send the request to `server`
+    fn call(&self, server: &Server, body: Body) -> Box<Future<Item = server::Response, Error = hyper::Error>> {
+        let mut client_req = self.request.to_request(body);
+
+        let url = format!(
+            "{}{}?{}",
+            server.url(),
+            client_req.uri().path(),
+            client_req.uri().query().unwrap_or("")
+        );
+        // TODO proper error handling
+        let uri = Uri::from_str(&url).expect("Failed to parse url");
+        let map_host = server.map_host();
+        debug!("Preparing backend request to {:?}", url);
+
+        if map_host {
+            // add host header related to backend
+            let _ = client_req.headers_mut().remove::<header::Host>();
+            let host = uri.host().unwrap().to_string();
+            let port = uri.port();
+            client_req.headers_mut().set(header::Host::new(host, port));
+        }
+
+        // the variables of the rules include the server, which changes when the request is retried
+        let vars = Variables {
+            backend: uri.authority().unwrap_or("").to_string(),
+            ..self.vars.clone()
+        };
+        header_rules::apply(&self.headers.request, client_req.headers_mut(), &vars);
+        let headers = self.headers.clone();
+        client_req.set_uri(uri);
+
+        // hyper keeps every connection open once the response is read, so the connection is closed
+        // instead when more requests are in flight to the server than connections may be idle
+        if let Some(max_idle) = self.max_idle {
+            let in_flight = self.pool.find(server).map(|b| b.in_flight()).unwrap_or(0);
+            if in_flight > max_idle {
+                client_req.headers_mut().set(header::Connection::close());
             }
-            client_req.set_uri(uri);
+        }
 
-            let backend = self.client.call(client_req).then(move |res| match res {
-                Ok(res) => {
-                    debug!("Response: {}", res.status());
-                    debug!("Headers: \n{}", res.headers());
+        // (re)issue the cookie when the client is new or its server is no longer available
+        let id = server.id();
+        let set_cookie = match self.sticky_cookie {
+            Some(ref name) if self.sticky.as_ref() != Some(&id) => {
+                Some(format!("{}={}; Path=/; HttpOnly", name, id))
+            }
+            _ => None,
+        };
 
-                    let server_response = map_response(res);
+        let backend = self.clients.client_for(server).call(client_req).then(move |res| match res {
+            Ok(res) => {
+                debug!("Response: {}", res.status());
+                debug!("Headers: \n{}", res.headers());
 
-                    ::futures::finished(server_response)
+                let mut server_response = map_response(res);
+                if let Some(set_cookie) = set_cookie {
+                    server_response.headers_mut().append_raw("Set-Cookie", set_cookie);
                 }
-                Err(e) => {
-                    error!("Error connecting to backend: {:?}", e);
-                    ::futures::failed(e)
-                }
-            });
+                header_rules::apply(&headers.response, server_response.headers_mut(), &vars);
 
-            Box::new(backend)
-        })
+                ::futures::finished(server_response)
+            }
+            Err(e) => {
+                error!("Error connecting to backend: {:?}", e);
+                ::futures::failed(e)
+            }
+        });
+
+        Box::new(backend)
+    }
+
+    /// A request that failed to connect is always retried. Otherwise, only idempotent requests are
+    /// retried, and only for the configured response statuses.
+    fn should_retry(&self, res: &Result<server::Response, hyper::Error>) -> bool {
+        match *res {
+            Ok(ref res) => {
+                self.request.method.idempotent() && self.statuses.contains(&u16::from(res.status()))
+            }
+            Err(ref e) => retry::is_connect_error(e),
+        }
     }
 }
 
-pub fn serve(listener: TcpListener, pool: Pool, handle: &Handle, config: &Config) -> io::Result<Box<Future<Item = (), Error = io::Error>>>
+/// Send a request to a backend from the pool, retrying up to `retries` times on backends not
+/// already `tried`
+///
+/// If there is no backend left to retry on, the `previous` result is returned.
+fn send(
+    forward: Rc<Forward>,
+    body: Body,
+    mut tried: Vec<Server>,
+    retries: u32,
+    previous: Option<Result<server::Response, hyper::Error>>,
+) -> Box<Future<Item = server::Response, Error = hyper::Error>> {
+    let chosen = Rc::new(RefCell::new(None));
+    let chosen1 = chosen.clone();
+    let forward1 = forward.clone();
+    let res = {
+        let key = forward.key.as_ref().map(|k| &k[..]);
+        let sticky = forward.sticky.as_ref().map(|id| &id[..]);
+        forward.pool.request(key, sticky, &tried, move |server| {
+            *chosen1.borrow_mut() = Some(server.clone());
+            forward1.call(server, body)
+        })
+    };
+
+    Box::new(res.then(move |res| -> Box<Future<Item = server::Response, Error = hyper::Error>> {
+        let server = match chosen.borrow_mut().take() {
+            Some(server) => server,
+            None => return Box::new(::futures::done(previous.unwrap_or(res))),
+        };
+
+        if retries == 0 || !forward.should_retry(&res) {
+            return Box::new(::futures::done(res));
+        }
+
+        if !forward.retry_budget.borrow_mut().withdraw(Instant::now()) {
+            warn!("Retry budget is used up. Not retrying request to {:?}", server);
+            return Box::new(::futures::done(res));
+        }
+
+        let body = match forward.body {
+            Some(ref body) => Body::from(body.clone()),
+            None => return Box::new(::futures::done(res)),
+        };
+
+        info!("Retrying request to {:?} on a different backend", server);
+        tried.push(server);
+        send(forward, body, tried, retries - 1, Some(res))
+    }))
+}
+
+pub fn serve(listener: TcpListener, pools: Pools, handle: &Handle, config: &Config) -> io::Result<Box<Future<Item = (), Error = io::Error>>>
+{
+    serve_with_shutdown(listener, None, pools, handle, config, ::futures::empty())
+}
+
+/// Serve requests until `shutdown` resolves
+///
+/// Requests are accepted on `listener` and, if given, on a second listener that terminates TLS
+/// using the acceptor. Once `shutdown` resolves, the listeners are closed so no new connections
+/// are accepted. The returned future resolves when all in-flight requests are finished or the
+/// configured drain timeout has passed, whichever comes first. Each request is sent to the pool
+/// that serves the hostname it is for.
+pub fn serve_with_shutdown<S>(
+    listener: TcpListener,
+    tls: Option<(TcpListener, TlsAcceptor)>,
+    pools: Pools,
+    handle: &Handle,
+    config: &Config,
+    shutdown: S,
+) -> io::Result<Box<Future<Item = (), Error = io::Error>>>
+where
+    S: Future<Item = (), Error = io::Error> + 'static,
 {
     let handle = handle.clone();
-    let config = config.clone();
+    let drain_timeout = config.drain_timeout;
     let local_addr = listener.local_addr()?;
     info!("Listening on http://{}", &local_addr);
-    let srv = listener.incoming().for_each(move |(socket, addr)| {
-        proxy(socket, addr, pool.clone(), &handle, &config);
+
+    let incoming = listener.incoming().map(|(socket, addr)| (socket, addr, None));
+    let incoming: Box<Stream<Item = (TcpStream, SocketAddr, Option<Rc<TlsAcceptor>>), Error = io::Error>> = match tls {
+        Some((tls_listener, acceptor)) => {
+            info!("Listening on https://{}", tls_listener.local_addr()?);
+            let acceptor = Rc::new(acceptor);
+            let tls_incoming = tls_listener.incoming().map(move |(socket, addr)| {
+                (socket, addr, Some(acceptor.clone()))
+            });
+            Box::new(incoming.select(tls_incoming))
+        }
+        None => Box::new(incoming),
+    };
+
+    let in_flight = Rc::new(Cell::new(0));
+    report_connection_reuse(pools.clone(), &handle)?;
+    let tunnels = upgrade::Connector::new(&handle, pools.clone())
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let connections = Connections {
+        client: backend_client(&handle, &pools, config, None),
+        tunnels: tunnels,
+        pools: pools,
+        handle: handle.clone(),
+        config: Rc::new(config.clone()),
+        in_flight: in_flight.clone(),
+        retry_budget: Rc::new(RefCell::new(RetryBudget::new(
+            config.retry.budget_percent,
+            config.retry.budget_min_retries,
+        ))),
+        error_pages: Rc::new(config.error_pages.clone()),
+    };
+    let srv = incoming.for_each(move |(socket, addr, acceptor)| {
+        connections.accept(socket, addr, acceptor);
 
         Ok(())
     });
 
+    let srv = srv.select(shutdown).map_err(|(e, _)| e).and_then(
+        move |((), incoming)| {
+            // dropping the incoming stream closes the listener
+            drop(incoming);
+            info!(
+                "Stopped listening on http://{}. Draining {} in-flight requests",
+                &local_addr,
+                in_flight.get()
+            );
+
+            drain(in_flight, drain_timeout, &handle)
+        },
+    );
+
     return Ok(Box::new(srv));
 }
 
-fn proxy(socket: TcpStream, addr: SocketAddr, pool: Pool, handle: &Handle, config: &Config) {
+/// Log how many requests to the backends reused a connection, every `REUSE_REPORT_INTERVAL` seconds
+fn report_connection_reuse(pools: Pools, handle: &Handle) -> io::Result<()> {
+    let interval = Interval::new(Duration::from_secs(REUSE_REPORT_INTERVAL), handle)?;
+    handle.spawn(
+        interval
+            .for_each(move |_| {
+                let (mut requests, mut connections) = (0, 0);
+                for backend in pools.backends() {
+                    let (r, c, reused) = backend.connection_reuse();
+                    debug!(
+                        "{} requests to {} reused a connection, {} connections were opened",
+                        reused,
+                        backend.server().url(),
+                        c
+                    );
+                    requests += r;
+                    connections += c;
+                }
 
-    // disable Nagle's algo
-    // https://github.com/hyperium/hyper/issues/944
-    socket.set_nodelay(true).unwrap();
-    let connector = HttpsConnector::new(4, handle).unwrap();
-    let mut tm = TimeoutConnector::new(connector, &handle);
-    tm.set_connect_timeout(config.timeout.connect);
-    tm.set_read_timeout(config.timeout.read);
-    tm.set_write_timeout(config.timeout.write);
-    let client = Client::configure()
-        .connector(tm)
-        .build(&handle);
-    let service = Proxy {
-        client: client,
-        pool: pool,
+                if requests > 0 {
+                    info!(
+                        "{} of {} requests to the backends reused a connection, {} connections were opened",
+                        requests.saturating_sub(connections),
+                        requests,
+                        connections
+                    );
+                }
+                Ok(())
+            })
+            .map_err(|e| warn!("Stopped reporting connection reuse: {}", e)),
+    );
+
+    Ok(())
+}
+
+/// Wait for the in-flight requests to finish, giving up after `timeout`
+fn drain(
+    in_flight: Rc<Cell<usize>>,
+    timeout: Duration,
+    handle: &Handle,
+) -> Box<Future<Item = (), Error = io::Error>> {
+    let interval = match Interval::new(Duration::from_millis(100), handle) {
+        Ok(interval) => interval,
+        Err(e) => return Box::new(::futures::failed(e)),
+    };
+    let deadline = match Timeout::new(timeout, handle) {
+        Ok(deadline) => deadline,
+        Err(e) => return Box::new(::futures::failed(e)),
     };
 
-    let http = Http::new();
-    http.bind_connection(&handle, socket, addr, service);
+    let in_flight1 = in_flight.clone();
+    let drained = interval
+        .take_while(move |_| Ok(in_flight1.get() > 0))
+        .for_each(|_| Ok(()))
+        .map(|_| info!("All in-flight requests finished"));
+
+    let deadline = deadline.map(move |_| {
+        warn!(
+            "Drain timeout expired with {} requests still in-flight",
+            in_flight.get()
+        );
+    });
+
+    Box::new(drained.select(deadline).map(|_| ()).map_err(|(e, _)| e))
+}
+
+/// The state shared by all client connections of a worker
+#[derive(Clone)]
+struct Connections {
+    /// Client for the backends, shared by all connections
+    client: BackendClient,
+
+    /// Opens the connections that upgraded client connections are tunneled to
+    tunnels: upgrade::Connector,
+    pools: Pools,
+    handle: Handle,
+    config: Rc<Config>,
+
+    /// Number of requests, across all connections, whose response has not been sent to the client
+    in_flight: Rc<Cell<usize>>,
+
+    retry_budget: Rc<RefCell<RetryBudget>>,
+    error_pages: Rc<ErrorPages>,
+}
+
+impl Connections {
+    /// Start serving a connection from a client
+    ///
+    /// The PROXY protocol header is read first, if enabled, followed by the TLS handshake for
+    /// connections to the TLS listener.
+    fn accept(&self, socket: TcpStream, addr: SocketAddr, acceptor: Option<Rc<TlsAcceptor>>) {
+        // disable Nagle's algo
+        // https://github.com/hyperium/hyper/issues/944
+        socket.set_nodelay(true).unwrap();
+        let addresses = match socket.local_addr() {
+            Ok(local_addr) => {
+                Addresses {
+                    source: addr,
+                    destination: local_addr,
+                }
+            }
+            Err(e) => {
+                warn!("Closing connection from {}: {}", addr, e);
+                return;
+            }
+        };
+
+        // a client that does not send the header, or finish the TLS handshake, in time is closed,
+        // so it cannot hold the connection open
+        let read_timeout = self.config.timeout.read;
+        let header: Box<Future<Item = (Prefixed<TcpStream>, Addresses), Error = io::Error>> = if self.config.proxy_protocol {
+            let header = with_timeout(proxy_protocol::read_header(socket), read_timeout, &self.handle);
+            Box::new(header.map(move |(socket, proxied)| {
+                // a connection that was not proxied, such as a health check from the load
+                // balancer, uses the addresses of the connection itself
+                let proxied = proxied.unwrap_or(addresses);
+                debug!("Accepted connection from {} through {}", proxied.source, addr);
+                (socket, proxied)
+            }))
+        } else {
+            Box::new(::futures::finished((Prefixed::new(Vec::new(), socket), addresses)))
+        };
+
+        let connections = self.clone();
+        let connection = header.and_then(move |(socket, addresses)| -> Box<Future<Item = (), Error = io::Error>> {
+            match acceptor {
+                Some(acceptor) => {
+                    let handshake = acceptor
+                        .accept_async(socket)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+                    Box::new(
+                        with_timeout(handshake, read_timeout, &connections.handle)
+                            .and_then(move |socket| connections.serve(socket, addresses, "https")),
+                    )
+                }
+                None => connections.serve(socket, addresses, "http"),
+            }
+        });
+
+        self.handle.spawn(connection.map_err(move |e| {
+            warn!("Closing connection from {}: {}", addr, e);
+        }));
+    }
+
+    /// Serve a connection from a client
+    ///
+    /// `proto` is the scheme the client used to connect. A connection whose first request asks to
+    /// upgrade, such as a WebSocket handshake, is tunneled to a server. Any other connection is
+    /// served by hyper until a request asks to upgrade. A client that does not send the head of its
+    /// first request within the read timeout is closed.
+    fn serve<I>(&self, socket: I, addresses: Addresses, proto: &'static str) -> Box<Future<Item = (), Error = io::Error>>
+    where
+        I: AsyncRead + AsyncWrite + 'static,
+    {
+        // hyper and its timeouts only get the connection once the head is read
+        let head = with_timeout(upgrade::read_head(socket), self.config.timeout.read, &self.handle);
+        let connections = self.clone();
+        Box::new(head.and_then(
+            move |(socket, head)| -> Box<Future<Item = (), Error = io::Error>> {
+                match head {
+                    Some(head) => connections.tunnel(socket, head, addresses, proto),
+                    None => connections.serve_http(socket, addresses, proto),
+                }
+            },
+        ))
+    }
+
+    /// Send an upgrade request to a server and splice the client connection to the server
+    /// connection if the server switches protocols
+    ///
+    /// Any other response is sent to the client and the connection is closed, so later requests
+    /// from the client are not sent straight to the server. The tunnel counts as in flight for the
+    /// server and for draining until either side closes it or it is idle for too long.
+    fn tunnel<I>(
+        &self,
+        client: Prefixed<I>,
+        mut head: RequestHead,
+        addresses: Addresses,
+        proto: &'static str,
+    ) -> Box<Future<Item = (), Error = io::Error>>
+    where
+        I: AsyncRead + AsyncWrite + 'static,
+    {
+        let config = &self.config;
+        let key = config.balancer
+            .hash_key()
+            .and_then(|k| k.extract(&addresses.source, &head.uri, &head.headers));
+        let sticky = config.sticky_cookie
+            .as_ref()
+            .and_then(|name| balancer::cookie(&head.headers, name))
+            .map(|id| id.to_string());
+
+        let host = request_host(&head.uri, &head.headers).map(|host| host.to_string());
+        let (vhost, route) = self.pools.route(host.as_ref().map(|host| host.as_str()), head.uri.path());
+        let rules = header_rules_for(vhost.headers(), route.as_ref().map(|route| &route.spec().headers));
+        if let Some(path) = route.as_ref().and_then(|route| route.rewrite(head.uri.path())) {
+            match rewrite_path(&head.uri, &path) {
+                Ok(uri) => head.uri = uri,
+                Err(e) => {
+                    error!("Route rewrote path {} to an invalid path {:?}", head.uri.path(), path);
+                    let res = raw_error_response(&e.into(), &self.error_pages);
+                    return Box::new(tokio_io::io::write_all(client, res).map(|_| ()));
+                }
+            }
+        }
+        let chosen = vhost.pool().tunnel(
+            key.as_ref().map(|k| &k[..]),
+            sticky.as_ref().map(|id| &id[..]),
+        );
+        let (server, backend_in_flight) = match chosen {
+            Some(chosen) => chosen,
+            None => {
+                let e = hyper::Error::Io(io::Error::new(io::ErrorKind::Other, pool::Exhausted));
+                let res = raw_error_response(&e, &self.error_pages);
+                return Box::new(tokio_io::io::write_all(client, res).map(|_| ()));
+            }
+        };
+
+        // the upgrade headers are hop-by-hop, so they are sent again after the other hop-by-hop
+        // headers are removed
+        let mut headers = filter_frontend_request_headers(&head.headers);
+        headers.set(create_via_header(head.headers.get::<Via>(), &HttpVersion::Http11));
+        forwarded::set_headers(&mut headers, &config.forwarded, &addresses.source, proto);
+        if let Some(ref name) = config.sticky_cookie {
+            balancer::remove_cookie(&mut headers, name);
+        }
+        headers.set_raw("Connection", "Upgrade");
+        if let Some(upgrade) = head.headers.get_raw("Upgrade") {
+            headers.set_raw("Upgrade", upgrade.clone());
+        }
+        let url = server.url();
+        if server.map_host() {
+            let _ = headers.remove::<header::Host>();
+            let host = url.host().unwrap_or("").to_string();
+            headers.set(header::Host::new(host, url.port()));
+        }
+        if !rules.request.is_empty() {
+            let vars = Variables {
+                client_ip: addresses.source.ip(),
+                request_id: header_rules::request_id(),
+                host: host.unwrap_or_default(),
+                scheme: proto,
+                backend: url.authority().unwrap_or("").to_string(),
+            };
+            header_rules::apply(&rules.request, &mut headers, &vars);
+        }
+        head.headers = headers;
+        let request = head.to_bytes();
+
+        debug!("Tunneling connection from {} to {}", addresses.source, url);
+        let handle = self.handle.clone();
+        let read_timeout = config.timeout.read;
+        let connect = with_timeout(self.tunnels.connect(url, addresses), config.timeout.connect, &self.handle)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+        let response = connect
+            .and_then(move |conn| tokio_io::io::write_all(conn, request))
+            .and_then(move |(conn, _)| {
+                with_timeout(upgrade::read_response(conn), read_timeout, &handle)
+            });
+
+        let handle = self.handle.clone();
+        let error_pages = self.error_pages.clone();
+        let idle_timeout = config.timeout.tunnel_idle;
+        let tunnel = response.then(move |res| -> Box<Future<Item = (), Error = io::Error>> {
+            match res {
+                Ok((conn, response, 101)) => {
+                    debug!("Server {:?} switched protocols", server);
+                    Box::new(tokio_io::io::write_all(client, response).and_then(move |(client, _)| {
+                        ::futures::done(upgrade::splice(client, conn, idle_timeout, &handle)).flatten()
+                    }))
+                }
+                Ok((conn, response, status)) => {
+                    debug!("Server {:?} did not switch protocols: {}", server, status);
+                    with_timeout(upgrade::refuse(client, conn, response), read_timeout, &handle)
+                }
+                Err(e) => {
+                    error!("Error tunneling to backend: {:?}", e);
+                    let res = raw_error_response(&hyper::Error::Io(e), &error_pages);
+                    Box::new(tokio_io::io::write_all(client, res).map(|_| ()))
+                }
+            }
+        });
+
+        let request = InFlightRequest::new(self.in_flight.clone());
+        Box::new(tunnel.then(move |res| {
+            drop(backend_in_flight);
+            drop(request);
+            res
+        }))
+    }
+
+    /// Serve the requests on a connection with hyper
+    ///
+    /// If a request asks to upgrade, hyper stops and the connection, along with any bytes hyper
+    /// read past the head of that request, is tunneled to a server.
+    fn serve_http<I>(
+        &self,
+        socket: I,
+        addresses: Addresses,
+        proto: &'static str,
+    ) -> Box<Future<Item = (), Error = io::Error>>
+    where
+        I: AsyncRead + AsyncWrite + 'static,
+    {
+        let config = &self.config;
+        let clients = Clients {
+            shared: self.client.clone(),
+            proxied: RefCell::new(None),
+            handle: self.handle.clone(),
+            pools: self.pools.clone(),
+            config: config.clone(),
+            addresses: addresses,
+        };
+        let upgrade = Rc::new(RefCell::new(None));
+        let service = Proxy {
+            clients: Rc::new(clients),
+            pools: self.pools.clone(),
+            in_flight: self.in_flight.clone(),
+            handle: self.handle.clone(),
+            upgrade: upgrade.clone(),
+            client_addr: addresses.source,
+            proto: proto,
+            hash_key: config.balancer.hash_key().cloned(),
+            sticky_cookie: config.sticky_cookie.clone(),
+            retry: config.retry.clone(),
+            retry_budget: self.retry_budget.clone(),
+            error_pages: self.error_pages.clone(),
+            forwarded: config.forwarded.clone(),
+            max_idle: if config.keep_alive.enabled {
+                Some(config.keep_alive.max_idle_per_server)
+            } else {
+                None
+            },
+        };
+
+        let mut conn = Some(Http::<hyper::Chunk>::new().serve_connection(socket, service));
+        let served = ::futures::future::poll_fn(move || {
+            match conn.as_mut().expect("poll connection after completion").poll() {
+                Ok(Async::Ready(())) => Ok(Async::Ready(None)),
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                Err(e) => match upgrade.borrow_mut().take() {
+                    Some(head) => Ok(Async::Ready(Some((conn.take().unwrap().into_parts(), head)))),
+                    None => Err(e),
+                },
+            }
+        });
+
+        let connections = self.clone();
+        Box::new(served.then(move |res| -> Box<Future<Item = (), Error = io::Error>> {
+            match res {
+                Ok(Some((parts, head))) => {
+                    let socket = Prefixed::new(parts.read_buf.to_vec(), parts.io);
+                    connections.tunnel(socket, head, addresses, proto)
+                }
+                Ok(None) => Box::new(::futures::finished(())),
+                Err(e) => {
+                    debug!("Connection from {} failed: {}", addresses.source, e);
+                    Box::new(::futures::finished(()))
+                }
+            }
+        }))
+    }
 }
 
 #[cfg(test)]
@@ -315,6 +1216,35 @@ mod tests {
         assert_eq!(false, given.has::<header::Upgrade>());
     }
 
+    #[test]
+    fn test_error_response() {
+        use std::io;
+        use config::ErrorPages;
+        use pool::Exhausted;
+
+        let pages = ErrorPages::default();
+
+        let e = hyper::Error::Io(io::Error::new(io::ErrorKind::Other, Exhausted));
+        let res = error_response(&e, &pages);
+        assert_eq!(hyper::StatusCode::ServiceUnavailable, res.status());
+        assert_eq!(
+            Some(&b"10"[..]),
+            res.headers().get_raw("Retry-After").and_then(|raw| raw.one())
+        );
+
+        let e = hyper::Error::Io(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+        let res = error_response(&e, &pages);
+        assert_eq!(hyper::StatusCode::GatewayTimeout, res.status());
+        assert!(res.headers().get_raw("Retry-After").is_none());
+
+        let e = hyper::Error::Io(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"));
+        assert_eq!(hyper::StatusCode::BadGateway, error_response(&e, &pages).status());
+        assert_eq!(
+            hyper::StatusCode::BadGateway,
+            error_response(&hyper::Error::Incomplete, &pages).status()
+        );
+    }
+
     #[test]
     /// Per RFC 2616 Section 13.5.1 - MUST remove hop-by-hop headers
     fn test_filter_backend_response_headers() {
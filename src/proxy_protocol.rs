@@ -0,0 +1,540 @@
+//! The HAProxy PROXY protocol, which passes the address of the client along with a connection
+//!
+//! See https://www.haproxy.org/download/1.8/doc/proxy-protocol.txt
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::{self, FromStr};
+
+use futures::{Async, Future, Poll};
+use hyper::Uri;
+use hyper::client::Service;
+use tokio_core::net::TcpStream;
+use tokio_io::{self, AsyncRead, AsyncWrite};
+
+use vhost::Pools;
+
+/// The signature that starts a version 2 header
+const V2_SIGNATURE: &'static [u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest a version 1 header can be, including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum ProxyProtocol {
+    /// The human readable text header
+    #[serde(rename = "v1")]
+    V1,
+
+    /// The binary header
+    #[serde(rename = "v2")]
+    V2,
+}
+
+impl ProxyProtocol {
+    pub fn version(&self) -> u8 {
+        match *self {
+            ProxyProtocol::V1 => 1,
+            ProxyProtocol::V2 => 2,
+        }
+    }
+
+    /// The protocol with the given version number, if there is one
+    pub fn from_version(version: u8) -> Option<ProxyProtocol> {
+        match version {
+            1 => Some(ProxyProtocol::V1),
+            2 => Some(ProxyProtocol::V2),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for ProxyProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ProxyProtocol, String> {
+        match s {
+            "v1" => Ok(ProxyProtocol::V1),
+            "v2" => Ok(ProxyProtocol::V2),
+            _ => Err(format!("unknown PROXY protocol version {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ProxyProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "v{}", self.version())
+    }
+}
+
+/// The addresses of a proxied connection
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Addresses {
+    /// The address of the client
+    pub source: SocketAddr,
+
+    /// The address the client connected to
+    pub destination: SocketAddr,
+}
+
+fn invalid<T>(msg: &str) -> Result<T, io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid PROXY protocol header: {}", msg),
+    ))
+}
+
+/// Parse a version 1 or version 2 header at the start of `buf`
+///
+/// Returns `None` if more bytes are needed to finish the header. Otherwise returns the length of
+/// the header and the addresses it holds. A header for a connection that was not proxied, such as
+/// a health check from the proxy, has no addresses.
+pub fn parse(buf: &[u8]) -> Result<Option<(usize, Option<Addresses>)>, io::Error> {
+    if buf.len() < V2_SIGNATURE.len() {
+        // not enough to tell the versions apart, but a header that is neither can be refused early
+        if !V2_SIGNATURE.starts_with(buf) && !b"PROXY ".starts_with(&buf[..buf.len().min(6)]) {
+            return invalid("unknown signature");
+        }
+        return Ok(None);
+    }
+
+    if buf.starts_with(V2_SIGNATURE) {
+        parse_v2(buf)
+    } else if buf.starts_with(b"PROXY ") {
+        parse_v1(buf)
+    } else {
+        invalid("unknown signature")
+    }
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Option<(usize, Option<Addresses>)>, io::Error> {
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() >= V1_MAX_LEN => return invalid("header is too long"),
+        None => return Ok(None),
+    };
+    if end + 2 > V1_MAX_LEN {
+        return invalid("header is too long");
+    }
+
+    let line = match str::from_utf8(&buf[..end]) {
+        Ok(line) => line,
+        Err(_) => return invalid("header is not ASCII"),
+    };
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    let addresses = match parts.get(1).cloned() {
+        Some("UNKNOWN") => None,
+        Some(family @ "TCP4") |
+        Some(family @ "TCP6") => {
+            if parts.len() != 6 {
+                return invalid("wrong number of fields");
+            }
+
+            let ip = |s: &str| match s.parse::<IpAddr>() {
+                Ok(ref ip) if ip.is_ipv4() == (family == "TCP4") => Ok(*ip),
+                _ => invalid("bad address"),
+            };
+            let port = |s: &str| match s.parse::<u16>() {
+                Ok(port) => Ok(port),
+                Err(_) => invalid("bad port"),
+            };
+
+            Some(Addresses {
+                source: SocketAddr::new(ip(parts[2])?, port(parts[4])?),
+                destination: SocketAddr::new(ip(parts[3])?, port(parts[5])?),
+            })
+        }
+        _ => return invalid("unknown protocol"),
+    };
+
+    Ok(Some((end + 2, addresses)))
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<(usize, Option<Addresses>)>, io::Error> {
+    if buf.len() < 16 {
+        return Ok(None);
+    }
+
+    let version_command = buf[12];
+    let family = buf[13];
+    let len = ((buf[14] as usize) << 8) | buf[15] as usize;
+
+    if version_command >> 4 != 2 {
+        return invalid("unknown version");
+    }
+    if buf.len() < 16 + len {
+        return Ok(None);
+    }
+    let addrs = &buf[16..16 + len];
+
+    let addresses = match (version_command & 0x0f, family) {
+        // LOCAL
+        (0, _) => None,
+
+        // PROXY over TCP or UDP on IPv4
+        (1, 0x11) |
+        (1, 0x12) => {
+            if addrs.len() < 12 {
+                return invalid("address block is too short");
+            }
+            let ip = |b: &[u8]| IpAddr::V4(Ipv4Addr::new(b[0], b[1], b[2], b[3]));
+            Some(Addresses {
+                source: SocketAddr::new(ip(&addrs[0..4]), port(&addrs[8..10])),
+                destination: SocketAddr::new(ip(&addrs[4..8]), port(&addrs[10..12])),
+            })
+        }
+
+        // PROXY over TCP or UDP on IPv6
+        (1, 0x21) |
+        (1, 0x22) => {
+            if addrs.len() < 36 {
+                return invalid("address block is too short");
+            }
+            let ip = |b: &[u8]| {
+                let mut segments = [0u16; 8];
+                for (i, segment) in segments.iter_mut().enumerate() {
+                    *segment = port(&b[i * 2..i * 2 + 2]);
+                }
+                IpAddr::V6(Ipv6Addr::new(
+                    segments[0],
+                    segments[1],
+                    segments[2],
+                    segments[3],
+                    segments[4],
+                    segments[5],
+                    segments[6],
+                    segments[7],
+                ))
+            };
+            Some(Addresses {
+                source: SocketAddr::new(ip(&addrs[0..16]), port(&addrs[32..34])),
+                destination: SocketAddr::new(ip(&addrs[16..32]), port(&addrs[34..36])),
+            })
+        }
+
+        // unix sockets and unspecified families carry no address we can use
+        (1, _) => None,
+        _ => return invalid("unknown command"),
+    };
+
+    Ok(Some((16 + len, addresses)))
+}
+
+fn port(b: &[u8]) -> u16 {
+    ((b[0] as u16) << 8) | b[1] as u16
+}
+
+/// Both addresses as IPv6 if either one is IPv6, since a header has a single address family
+fn same_family(addresses: &Addresses) -> (IpAddr, IpAddr) {
+    match (addresses.source.ip(), addresses.destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V6(destination)) => {
+            (IpAddr::V6(source.to_ipv6_mapped()), IpAddr::V6(destination))
+        }
+        (IpAddr::V6(source), IpAddr::V4(destination)) => {
+            (IpAddr::V6(source), IpAddr::V6(destination.to_ipv6_mapped()))
+        }
+        (source, destination) => (source, destination),
+    }
+}
+
+/// Write the header for a connection
+///
+/// A connection without addresses, such as a health check, is sent as `UNKNOWN` in version 1 and
+/// as `LOCAL` in version 2.
+pub fn encode(version: ProxyProtocol, addresses: Option<&Addresses>) -> Vec<u8> {
+    match version {
+        ProxyProtocol::V1 => {
+            let line = match addresses {
+                Some(addresses) => {
+                    let (source, destination) = same_family(addresses);
+                    format!(
+                        "PROXY {} {} {} {} {}\r\n",
+                        if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                        source,
+                        destination,
+                        addresses.source.port(),
+                        addresses.destination.port()
+                    )
+                }
+                None => "PROXY UNKNOWN\r\n".to_string(),
+            };
+            line.into_bytes()
+        }
+        ProxyProtocol::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            let mut addrs = Vec::new();
+            match addresses {
+                Some(addresses) => {
+                    let (source, destination) = same_family(addresses);
+                    match (source, destination) {
+                        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                            header.extend_from_slice(&[0x21, 0x11]);
+                            addrs.extend_from_slice(&source.octets());
+                            addrs.extend_from_slice(&destination.octets());
+                        }
+                        (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                            header.extend_from_slice(&[0x21, 0x21]);
+                            addrs.extend_from_slice(&source.octets());
+                            addrs.extend_from_slice(&destination.octets());
+                        }
+                        _ => unreachable!(),
+                    }
+                    let (source, destination) = (addresses.source.port(), addresses.destination.port());
+                    addrs.extend_from_slice(&[(source >> 8) as u8, source as u8]);
+                    addrs.extend_from_slice(&[(destination >> 8) as u8, destination as u8]);
+                }
+                None => header.extend_from_slice(&[0x20, 0x00]),
+            }
+            header.extend_from_slice(&[(addrs.len() >> 8) as u8, addrs.len() as u8]);
+            header.extend_from_slice(&addrs);
+            header
+        }
+    }
+}
+
+/// Read the header from the start of a connection
+///
+/// Resolves to the connection, which still has any bytes read past the header, and the addresses
+/// in the header.
+pub fn read_header<S: AsyncRead>(stream: S) -> ReadHeader<S> {
+    ReadHeader {
+        stream: Some(stream),
+        buf: Vec::new(),
+    }
+}
+
+pub struct ReadHeader<S> {
+    stream: Option<S>,
+    buf: Vec<u8>,
+}
+
+impl<S: AsyncRead> Future for ReadHeader<S> {
+    type Item = (Prefixed<S>, Option<Addresses>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        loop {
+            if let Some((len, addresses)) = parse(&self.buf)? {
+                let rest = self.buf.split_off(len);
+                let stream = self.stream.take().expect("poll ReadHeader after completion");
+                return Ok(Async::Ready((Prefixed::new(rest, stream), addresses)));
+            }
+
+            let mut chunk = [0; 256];
+            let read = self.stream
+                .as_mut()
+                .expect("poll ReadHeader after completion")
+                .read(&mut chunk);
+            let n = match read {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(e),
+            };
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before the PROXY protocol header",
+                ));
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+/// A stream that yields `prefix` before reading from the stream itself
+#[derive(Debug)]
+pub struct Prefixed<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> Prefixed<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Prefixed<S> {
+        Prefixed {
+            prefix: prefix,
+            pos: 0,
+            inner: inner,
+        }
+    }
+}
+
+impl<S: Read> Read for Prefixed<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.prefix.len() {
+            let n = (&self.prefix[self.pos..]).read(buf)?;
+            self.pos += n;
+            return Ok(n);
+        }
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Write> Write for Prefixed<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Prefixed<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for Prefixed<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+/// A connector that sends a header to the servers in the pools that want one
+///
+/// The header holds `addresses`, or no addresses when the connection is not made on behalf of a
+/// client.
+#[derive(Clone)]
+pub struct ProxyProtocolConnector<C> {
+    inner: C,
+    pools: Pools,
+    addresses: Option<Addresses>,
+}
+
+impl<C> ProxyProtocolConnector<C> {
+    pub fn new(inner: C, pools: Pools, addresses: Option<Addresses>) -> ProxyProtocolConnector<C> {
+        ProxyProtocolConnector {
+            inner: inner,
+            pools: pools,
+            addresses: addresses,
+        }
+    }
+
+    /// The version the server at `uri` wants, if any
+    fn version(&self, uri: &Uri) -> Option<ProxyProtocol> {
+        self.pools.server_for(uri).and_then(|server| server.proxy_protocol())
+    }
+}
+
+impl<C> fmt::Debug for ProxyProtocolConnector<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProxyProtocolConnector")
+            .field("addresses", &self.addresses)
+            .finish()
+    }
+}
+
+impl<C> Service for ProxyProtocolConnector<C>
+where
+    C: Service<Request = Uri, Response = TcpStream, Error = io::Error>,
+    C::Future: 'static,
+{
+    type Request = Uri;
+    type Response = TcpStream;
+    type Error = io::Error;
+    type Future = Box<Future<Item = TcpStream, Error = io::Error>>;
+
+    fn call(&self, uri: Uri) -> Self::Future {
+        let version = self.version(&uri);
+        let connecting = self.inner.call(uri);
+
+        match version {
+            Some(version) => {
+                let header = encode(version, self.addresses.as_ref());
+                Box::new(connecting.and_then(move |tcp| {
+                    tokio_io::io::write_all(tcp, header).map(|(tcp, _)| tcp)
+                }))
+            }
+            None => Box::new(connecting),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::{encode, parse, Addresses, Prefixed, ProxyProtocol};
+
+    fn addresses(source: &str, destination: &str) -> Addresses {
+        Addresses {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_parse_v1() {
+        let buf = b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(
+            Some((43, Some(addresses("192.168.0.1:56324", "10.0.0.1:443")))),
+            parse(buf).unwrap()
+        );
+
+        let buf = b"PROXY TCP6 2001:db8::1 ::1 56324 443\r\n";
+        assert_eq!(
+            Some((buf.len(), Some(addresses("[2001:db8::1]:56324", "[::1]:443")))),
+            parse(buf).unwrap()
+        );
+
+        assert_eq!(Some((15, None)), parse(b"PROXY UNKNOWN\r\n").unwrap());
+
+        // partial headers need more bytes
+        assert_eq!(None, parse(b"PRO").unwrap());
+        assert_eq!(None, parse(b"PROXY TCP4 192.168.0.1").unwrap());
+
+        assert!(parse(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 ::1 ::1 1 2\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.168.0.1 10.0.0.1 56324\r\n").is_err());
+        assert!(parse(&[b'A'; 200]).is_err());
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let v4 = addresses("192.168.0.1:56324", "10.0.0.1:443");
+        let mut buf = encode(ProxyProtocol::V2, Some(&v4));
+        assert_eq!(28, buf.len());
+        buf.extend_from_slice(b"GET /");
+        assert_eq!(Some((28, Some(v4))), parse(&buf).unwrap());
+        assert_eq!(None, parse(&buf[..20]).unwrap());
+
+        let v6 = addresses("[2001:db8::1]:56324", "[::1]:443");
+        let buf = encode(ProxyProtocol::V2, Some(&v6));
+        assert_eq!(Some((52, Some(v6))), parse(&buf).unwrap());
+
+        let buf = encode(ProxyProtocol::V2, None);
+        assert_eq!(Some((16, None)), parse(&buf).unwrap());
+
+        // version 3 does not exist
+        let mut buf = encode(ProxyProtocol::V2, None);
+        buf[12] = 0x30;
+        assert!(parse(&buf).is_err());
+    }
+
+    #[test]
+    fn test_encode_v1() {
+        assert_eq!(
+            b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 443\r\n".to_vec(),
+            encode(
+                ProxyProtocol::V1,
+                Some(&addresses("192.168.0.1:56324", "10.0.0.1:443")),
+            )
+        );
+        assert_eq!(
+            b"PROXY TCP6 ::ffff:192.168.0.1 ::1 56324 443\r\n".to_vec(),
+            encode(
+                ProxyProtocol::V1,
+                Some(&addresses("192.168.0.1:56324", "[::1]:443")),
+            )
+        );
+        assert_eq!(b"PROXY UNKNOWN\r\n".to_vec(), encode(ProxyProtocol::V1, None));
+    }
+
+    #[test]
+    fn test_prefixed() {
+        let mut stream = Prefixed::new(b"GET ".to_vec(), &b"/ HTTP/1.1"[..]);
+        let mut s = String::new();
+        stream.read_to_string(&mut s).unwrap();
+        assert_eq!("GET / HTTP/1.1", s);
+    }
+}
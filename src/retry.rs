@@ -0,0 +1,185 @@
+//! Sending failed requests again to a different backend
+
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use hyper::{self, Uri};
+use hyper::client::Service;
+
+/// Length of the window the retry budget is measured over
+const BUDGET_WINDOW_SECS: u64 = 10;
+
+/// Limits the number of retries a worker makes, so a failing backend does not cause a retry storm
+///
+/// Within each window, a worker may retry up to `percent` of its requests, or `min_retries`
+/// requests, whichever is more.
+#[derive(Debug)]
+pub struct RetryBudget {
+    percent: u32,
+    min_retries: u32,
+
+    /// When the current window started
+    window_start: Instant,
+
+    /// Number of requests within the current window, not counting retries
+    requests: u32,
+
+    /// Number of retries within the current window
+    retries: u32,
+}
+
+impl RetryBudget {
+    pub fn new(percent: u32, min_retries: u32) -> RetryBudget {
+        RetryBudget {
+            percent: percent,
+            min_retries: min_retries,
+            window_start: Instant::now(),
+            requests: 0,
+            retries: 0,
+        }
+    }
+
+    /// Count a new request towards the budget
+    pub fn deposit(&mut self, now: Instant) {
+        self.roll(now);
+        self.requests = self.requests.saturating_add(1);
+    }
+
+    /// Take a retry out of the budget
+    ///
+    /// Returns false if the budget is used up.
+    pub fn withdraw(&mut self, now: Instant) -> bool {
+        self.roll(now);
+
+        let allowed = cmp::max(
+            self.min_retries as u64,
+            self.requests as u64 * self.percent as u64 / 100,
+        );
+        if self.retries as u64 >= allowed {
+            return false;
+        }
+
+        self.retries += 1;
+        true
+    }
+
+    fn roll(&mut self, now: Instant) {
+        if now.duration_since(self.window_start) >= Duration::from_secs(BUDGET_WINDOW_SECS) {
+            self.window_start = now;
+            self.requests = 0;
+            self.retries = 0;
+        }
+    }
+}
+
+/// Marks an error as having happened while connecting to a backend
+#[derive(Debug)]
+struct ConnectError(io::Error);
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed to connect: {}", self.0)
+    }
+}
+
+impl error::Error for ConnectError {
+    fn description(&self) -> &str {
+        "failed to connect"
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        Some(&self.0)
+    }
+}
+
+/// A connector that marks the errors of the connector it wraps as connect errors
+///
+/// A request that failed to connect was never seen by the backend, so it is always safe to send it
+/// again. Errors that happen after the connection is made, such as a read timeout, are not marked.
+#[derive(Clone, Debug)]
+pub struct ConnectErrors<C> {
+    inner: C,
+}
+
+impl<C> ConnectErrors<C> {
+    pub fn new(inner: C) -> ConnectErrors<C> {
+        ConnectErrors { inner: inner }
+    }
+}
+
+impl<C> Service for ConnectErrors<C>
+where
+    C: Service<Request = Uri, Error = io::Error>,
+    C::Future: 'static,
+{
+    type Request = Uri;
+    type Response = C::Response;
+    type Error = io::Error;
+    type Future = Box<Future<Item = C::Response, Error = io::Error>>;
+
+    fn call(&self, uri: Uri) -> Self::Future {
+        Box::new(self.inner.call(uri).map_err(|e| {
+            io::Error::new(e.kind(), ConnectError(e))
+        }))
+    }
+}
+
+/// Whether the request failed while connecting to the backend
+pub fn is_connect_error(e: &hyper::Error) -> bool {
+    match *e {
+        hyper::Error::Io(ref e) => {
+            match e.get_ref() {
+                Some(inner) => inner.is::<ConnectError>(),
+                None => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::{Duration, Instant};
+
+    use hyper;
+
+    use super::{is_connect_error, ConnectError, RetryBudget};
+
+    #[test]
+    fn test_retry_budget() {
+        let mut budget = RetryBudget::new(20, 2);
+        let now = Instant::now();
+
+        // the minimum is always allowed
+        assert!(budget.withdraw(now));
+        assert!(budget.withdraw(now));
+        assert!(!budget.withdraw(now));
+
+        for _ in 0..20 {
+            budget.deposit(now);
+        }
+        assert!(budget.withdraw(now));
+        assert!(budget.withdraw(now));
+        assert!(!budget.withdraw(now));
+
+        // the budget starts over with each window
+        let later = now + Duration::from_secs(10);
+        assert!(budget.withdraw(later));
+    }
+
+    #[test]
+    fn test_is_connect_error() {
+        let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        let e = io::Error::new(io::ErrorKind::ConnectionRefused, ConnectError(refused));
+        assert!(is_connect_error(&hyper::Error::Io(e)));
+
+        let e = io::Error::new(io::ErrorKind::TimedOut, "read timed out");
+        assert!(!is_connect_error(&hyper::Error::Io(e)));
+        assert!(!is_connect_error(&hyper::Error::Incomplete));
+    }
+}
@@ -0,0 +1,284 @@
+//! Path routes
+//!
+//! Routes send requests to a pool by the path of the request. They are checked in order before
+//! the hostname, so `/api/` can go to one pool and `/static/` to another. A route applies to
+//! requests for any hostname unless it has a `host`. A route can also change the path before the
+//! request is sent to the server.
+
+use regex::Regex;
+
+use header_rules::HeaderRules;
+use tls;
+use vhost::{self, DEFAULT_POOL};
+
+/// A route as written in the configuration file, the management API and the state file
+///
+/// Exactly one of `prefix` and `regex` is set.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteSpec {
+    /// Paths starting with this prefix match the route. A prefix that does not end with `/` only
+    /// matches whole path segments.
+    pub prefix: Option<String>,
+
+    /// Paths matching this regular expression match the route
+    pub regex: Option<String>,
+
+    /// Name of the pool requests matching the route are sent to
+    pub pool: String,
+
+    /// Only requests for this hostname match the route, which may be a wildcard such as
+    /// `*.example.com`. A route without a host matches requests for any hostname.
+    pub host: Option<String>,
+
+    /// Remove the prefix from the path before the request is sent to the server
+    #[serde(default)]
+    pub strip_prefix: bool,
+
+    /// Replacement for the part of the path matched by `regex`, which may refer to capture groups
+    /// as `$1` or `$name`
+    pub rewrite: Option<String>,
+
+    /// Rules that change the headers of matching requests and their responses, applied after the
+    /// rules of the pool
+    #[serde(default)]
+    pub headers: HeaderRules,
+}
+
+#[derive(Clone, Debug)]
+enum Matcher {
+    Prefix(String),
+    Regex(Regex),
+}
+
+/// A rule that sends requests with a matching path to a pool
+#[derive(Clone, Debug)]
+pub struct Route {
+    spec: RouteSpec,
+    matcher: Matcher,
+}
+
+impl Route {
+    /// Check `spec` and compile its regular expression
+    pub fn new(spec: RouteSpec) -> Result<Route, String> {
+        if spec.pool != DEFAULT_POOL && !vhost::is_pool_name(&spec.pool) {
+            return Err(format!("invalid pool name {:?}", spec.pool));
+        }
+        if let Some(ref host) = spec.host {
+            if !vhost::is_host(host) {
+                return Err(format!("invalid host {:?}", host));
+            }
+        }
+
+        let matcher = match (&spec.prefix, &spec.regex) {
+            (&Some(ref prefix), &None) => {
+                if !prefix.starts_with('/') {
+                    return Err(format!("prefix {:?} must start with `/`", prefix));
+                }
+                if spec.rewrite.is_some() {
+                    return Err("rewrite can only be used with a regex".to_string());
+                }
+                Matcher::Prefix(prefix.clone())
+            }
+            (&None, &Some(ref regex)) => {
+                if spec.strip_prefix {
+                    return Err("strip_prefix can only be used with a prefix".to_string());
+                }
+                let regex = Regex::new(regex)
+                    .map_err(|e| format!("invalid regex {:?}: {}", regex, e))?;
+                Matcher::Regex(regex)
+            }
+            _ => return Err("a route must have either a prefix or a regex".to_string()),
+        };
+        spec.headers.validate()?;
+
+        Ok(Route {
+            spec: spec,
+            matcher: matcher,
+        })
+    }
+
+    /// Name of the pool requests matching the route are sent to
+    pub fn pool(&self) -> &str {
+        &self.spec.pool
+    }
+
+    pub fn spec(&self) -> &RouteSpec {
+        &self.spec
+    }
+
+    /// Whether a request for `host`, without its port, with `path` matches the route
+    pub fn is_match(&self, host: Option<&str>, path: &str) -> bool {
+        let host_matches = match (&self.spec.host, host) {
+            (&Some(ref pattern), Some(host)) => tls::matches(pattern, host),
+            (&Some(_), None) => false,
+            (&None, _) => true,
+        };
+        if !host_matches {
+            return false;
+        }
+
+        match self.matcher {
+            Matcher::Prefix(ref prefix) if path.starts_with(prefix.as_str()) => {
+                // `/api` matches `/api` and `/api/users` but not `/apiary`
+                let rest = &path[prefix.len()..];
+                prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/')
+            }
+            Matcher::Prefix(_) => false,
+            Matcher::Regex(ref regex) => regex.is_match(path),
+        }
+    }
+
+    /// The path sent to the server for a request with a matching `path`, if the route changes it
+    pub fn rewrite(&self, path: &str) -> Option<String> {
+        let rewritten = match self.matcher {
+            Matcher::Prefix(ref prefix) if self.spec.strip_prefix => {
+                path[prefix.len()..].to_string()
+            }
+            Matcher::Regex(ref regex) => {
+                match self.spec.rewrite {
+                    Some(ref rewrite) => regex.replace(path, rewrite.as_str()).into_owned(),
+                    None => return None,
+                }
+            }
+            _ => return None,
+        };
+
+        // the path sent to the server must still be absolute
+        if rewritten.starts_with('/') {
+            Some(rewritten)
+        } else {
+            Some(format!("/{}", rewritten))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Route, RouteSpec};
+
+    fn prefix(prefix: &str, strip_prefix: bool) -> Route {
+        Route::new(RouteSpec {
+            prefix: Some(prefix.to_string()),
+            pool: "api".to_string(),
+            strip_prefix: strip_prefix,
+            ..RouteSpec::default()
+        }).unwrap()
+    }
+
+    fn regex(regex: &str, rewrite: Option<&str>) -> Route {
+        Route::new(RouteSpec {
+            regex: Some(regex.to_string()),
+            pool: "assets".to_string(),
+            rewrite: rewrite.map(|r| r.to_string()),
+            ..RouteSpec::default()
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_prefix() {
+        let route = prefix("/api/", false);
+        assert!(route.is_match(None, "/api/users"));
+        assert!(!route.is_match(None, "/api"));
+        assert!(!route.is_match(None, "/static/app.js"));
+        assert_eq!(None, route.rewrite("/api/users"));
+
+        let route = prefix("/api/", true);
+        assert_eq!(Some("/users".to_string()), route.rewrite("/api/users"));
+        assert_eq!(Some("/".to_string()), route.rewrite("/api/"));
+
+        let route = prefix("/api", true);
+        assert_eq!(Some("/users".to_string()), route.rewrite("/api/users"));
+        assert_eq!(Some("/".to_string()), route.rewrite("/api"));
+
+        // a prefix only matches whole path segments
+        assert!(route.is_match(None, "/api"));
+        assert!(route.is_match(None, "/api/users"));
+        assert!(!route.is_match(None, "/apiary"));
+        assert!(!route.is_match(None, "/api-docs/"));
+    }
+
+    #[test]
+    fn test_regex() {
+        let route = regex(r"^/static/(.+)\.v[0-9]+\.(js|css)$", Some("/assets/$1.$2"));
+        assert!(route.is_match(None, "/static/app.v12.js"));
+        assert!(!route.is_match(None, "/static/app.js"));
+        assert_eq!(Some("/assets/app.js".to_string()), route.rewrite("/static/app.v12.js"));
+
+        let route = regex(r"^/(?P<user>[a-z]+)/avatar$", Some("avatars/${user}.png"));
+        assert_eq!(Some("/avatars/alice.png".to_string()), route.rewrite("/alice/avatar"));
+
+        let route = regex(r"\.png$", None);
+        assert!(route.is_match(None, "/images/logo.png"));
+        assert_eq!(None, route.rewrite("/images/logo.png"));
+    }
+
+    #[test]
+    fn test_host() {
+        let route = Route::new(RouteSpec {
+            prefix: Some("/api/".to_string()),
+            pool: "api".to_string(),
+            host: Some("*.example.com".to_string()),
+            ..RouteSpec::default()
+        }).unwrap();
+        assert!(route.is_match(Some("www.example.com"), "/api/users"));
+        assert!(route.is_match(Some("WWW.Example.com"), "/api/users"));
+        assert!(!route.is_match(Some("www.example.com"), "/static/app.js"));
+        assert!(!route.is_match(Some("example.com"), "/api/users"));
+        assert!(!route.is_match(Some("www.example.org"), "/api/users"));
+        assert!(!route.is_match(None, "/api/users"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let invalid = vec![
+            RouteSpec {
+                pool: "api".to_string(),
+                ..RouteSpec::default()
+            },
+            RouteSpec {
+                prefix: Some("/api/".to_string()),
+                regex: Some("^/api/".to_string()),
+                pool: "api".to_string(),
+                ..RouteSpec::default()
+            },
+            RouteSpec {
+                prefix: Some("api/".to_string()),
+                pool: "api".to_string(),
+                ..RouteSpec::default()
+            },
+            RouteSpec {
+                prefix: Some("/api/".to_string()),
+                pool: "a/b".to_string(),
+                ..RouteSpec::default()
+            },
+            RouteSpec {
+                prefix: Some("/api/".to_string()),
+                pool: "api".to_string(),
+                rewrite: Some("/v2/".to_string()),
+                ..RouteSpec::default()
+            },
+            RouteSpec {
+                regex: Some("^/api/".to_string()),
+                pool: "api".to_string(),
+                strip_prefix: true,
+                ..RouteSpec::default()
+            },
+            RouteSpec {
+                regex: Some("^/api/(".to_string()),
+                pool: "api".to_string(),
+                ..RouteSpec::default()
+            },
+            RouteSpec {
+                prefix: Some("/api/".to_string()),
+                pool: "api".to_string(),
+                host: Some("example.com:8080".to_string()),
+                ..RouteSpec::default()
+            },
+        ];
+
+        for spec in invalid {
+            assert!(Route::new(spec.clone()).is_err(), "{:?} should be invalid", spec);
+        }
+    }
+}
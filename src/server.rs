@@ -1,11 +1,35 @@
+use std::hash::{Hash, Hasher};
+
 use hyper::Uri;
 
-#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+use balancer::hash;
+use proxy_protocol::ProxyProtocol;
+use tls::ServerTls;
+
+/// The weight given to a server when none is specified
+pub const DEFAULT_WEIGHT: u32 = 1;
+
+/// The largest weight a server can have
+pub const MAX_WEIGHT: u32 = 1000;
+
+#[derive(Clone, Debug)]
 pub struct Server {
     url: Uri,
 
     /// Track whether the upstream server wants the client host or server host header
     map_host: bool,
+
+    /// Share of requests sent to this server relative to the other servers in the pool
+    ///
+    /// The weight is not part of the identity of a server. Two servers with the same url and
+    /// `map_host` are equal, even if their weights differ.
+    weight: u32,
+
+    /// The PROXY protocol header sent when connecting to this server, if any
+    proxy_protocol: Option<ProxyProtocol>,
+
+    /// How to connect to an `https` server, if not with the defaults
+    tls: Option<ServerTls>,
 }
 
 impl Server {
@@ -13,9 +37,32 @@ impl Server {
         Server {
             url: url,
             map_host: map_host,
+            weight: DEFAULT_WEIGHT,
+            proxy_protocol: None,
+            tls: None,
         }
     }
 
+    /// Set the weight of the server
+    ///
+    /// A weight of `0` is treated as the default weight.
+    pub fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = if weight == 0 { DEFAULT_WEIGHT } else { weight };
+        self
+    }
+
+    /// Send a PROXY protocol header with the address of the client on each connection
+    pub fn with_proxy_protocol(mut self, proxy_protocol: Option<ProxyProtocol>) -> Self {
+        self.proxy_protocol = proxy_protocol;
+        self
+    }
+
+    /// Connect to the server using these TLS settings
+    pub fn with_tls(mut self, tls: Option<ServerTls>) -> Self {
+        self.tls = tls;
+        self
+    }
+
     pub fn url(&self) -> Uri {
         self.url.clone()
     }
@@ -23,4 +70,38 @@ impl Server {
     pub fn map_host(&self) -> bool {
         self.map_host
     }
+
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    pub fn proxy_protocol(&self) -> Option<ProxyProtocol> {
+        self.proxy_protocol
+    }
+
+    pub fn tls(&self) -> Option<ServerTls> {
+        self.tls.clone()
+    }
+
+    /// An opaque id for the server that is the same in every worker
+    ///
+    /// This is safe to hand out to clients, as it does not reveal the url of the server.
+    pub fn id(&self) -> String {
+        format!("{:016x}", hash(format!("{}", self.url).as_bytes()))
+    }
+}
+
+impl PartialEq for Server {
+    fn eq(&self, other: &Server) -> bool {
+        self.url == other.url && self.map_host == other.map_host
+    }
+}
+
+impl Eq for Server {}
+
+impl Hash for Server {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.url.hash(state);
+        self.map_host.hash(state);
+    }
 }
@@ -0,0 +1,24 @@
+//! Unix signals handled by the manager and the workers
+
+use std::io;
+
+use futures::{Future, Stream};
+use libc::{c_int, SIGINT, SIGTERM};
+use tokio_core::reactor::Handle;
+use tokio_signal::unix::Signal;
+
+/// Resolves with the signal number when the process is asked to shutdown
+///
+/// Both `SIGTERM` and `SIGINT` are treated as a request to gracefully shutdown.
+pub fn shutdown(handle: &Handle) -> Box<Future<Item = c_int, Error = io::Error>> {
+    let sigterm = Signal::new(SIGTERM, handle).flatten_stream();
+    let sigint = Signal::new(SIGINT, handle).flatten_stream();
+
+    let signal = sigterm
+        .select(sigint)
+        .into_future()
+        .map(|(signal, _)| signal.unwrap_or(SIGTERM))
+        .map_err(|(e, _)| e);
+
+    Box::new(signal)
+}
@@ -1,7 +1,21 @@
+use std::time::Duration;
+
+/// Weight given to the newest sample in the latency moving average
+const LATENCY_DECAY: f64 = 0.3;
+
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct Stats {
     failure: usize,
     success: usize,
+
+    /// Number of requests sent
+    requests: usize,
+
+    /// Number of connections opened to send the requests
+    connections: usize,
+
+    /// Exponentially weighted moving average of the response latency, in nanoseconds
+    latency: Option<u64>,
 }
 
 impl Stats {
@@ -9,9 +23,30 @@ impl Stats {
         Stats {
             failure: 0,
             success: 0,
+            requests: 0,
+            connections: 0,
+            latency: None,
         }
     }
 
+    /// Add a response latency to the moving average
+    pub fn record_latency(&mut self, latency: Duration) {
+        let sample = latency.as_secs() as f64 * 1e9 + latency.subsec_nanos() as f64;
+        let average = match self.latency {
+            Some(average) => average as f64 + (sample - average as f64) * LATENCY_DECAY,
+            None => sample,
+        };
+
+        self.latency = Some(average as u64);
+    }
+
+    /// The moving average of the response latency, if any responses were recorded
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency.map(|nanos| {
+            Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+        })
+    }
+
     pub fn inc_success(&mut self) {
         self.success += 1;
     }
@@ -27,4 +62,51 @@ impl Stats {
     pub fn failure(&self) -> usize {
         self.failure
     }
+
+    pub fn inc_requests(&mut self) {
+        self.requests += 1;
+    }
+
+    pub fn inc_connections(&mut self) {
+        self.connections += 1;
+    }
+
+    pub fn requests(&self) -> usize {
+        self.requests
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections
+    }
+
+    /// Number of requests sent on a connection that was opened for an earlier request
+    pub fn reused(&self) -> usize {
+        self.requests.saturating_sub(self.connections)
+    }
+}
+
+#[test]
+fn test_record_latency() {
+    let mut stats = Stats::new();
+    assert_eq!(None, stats.latency());
+
+    stats.record_latency(Duration::from_millis(100));
+    assert_eq!(Some(Duration::from_millis(100)), stats.latency());
+
+    stats.record_latency(Duration::from_millis(200));
+    assert_eq!(Some(Duration::from_millis(130)), stats.latency());
+}
+
+#[test]
+fn test_reused() {
+    let mut stats = Stats::new();
+    assert_eq!(0, stats.reused());
+
+    stats.inc_requests();
+    stats.inc_connections();
+    assert_eq!(0, stats.reused());
+
+    stats.inc_requests();
+    stats.inc_requests();
+    assert_eq!(2, stats.reused());
 }
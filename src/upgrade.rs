@@ -0,0 +1,589 @@
+//! Tunneling connections that switch protocols, such as WebSocket connections
+//!
+//! hyper does not hand over the connection after a `101 Switching Protocols` response, so upgrade
+//! requests are handled outside of hyper. The head of the first request on each connection is
+//! read. If it asks for an upgrade, the request is sent to a server and the two connections are
+//! spliced together. Otherwise the bytes that were read are given back to hyper along with the
+//! rest of the connection. hyper is stopped when a later request asks for an upgrade, and the
+//! connection it gives back is tunneled the same way.
+
+use std::io::{self, Read};
+use std::str::{self, FromStr};
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+use httparse;
+use hyper::{Headers, Method, Uri};
+use hyper::client::{HttpConnector, Service};
+use hyper::header;
+use hyper_tls::MaybeHttpsStream;
+use native_tls::{self, TlsConnector};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{self, AsyncRead, AsyncWrite};
+
+use proxy_protocol::{Addresses, Prefixed, ProxyProtocolConnector};
+use tls::HttpsConnector;
+use vhost::Pools;
+
+/// The largest request or response head that is read before giving up
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+
+/// The most headers a request or response head may have
+const MAX_HEADERS: usize = 100;
+
+/// The head of a request that asks to upgrade the connection
+#[derive(Debug)]
+pub struct RequestHead {
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: Headers,
+}
+
+impl RequestHead {
+    /// The head as sent on the wire
+    pub fn to_bytes(&self) -> Vec<u8> {
+        format!("{} {} HTTP/1.1\r\n{}\r\n", self.method, self.uri, self.headers).into_bytes()
+    }
+}
+
+/// Whether the request asks to switch to the protocol in its `Upgrade` header
+///
+/// Per RFC 7230 Section 6.7, the `Connection` header must have the `upgrade` option.
+pub fn is_upgrade(headers: &Headers) -> bool {
+    let upgrade = match headers.get::<header::Connection>() {
+        Some(&header::Connection(ref options)) => {
+            options.iter().any(|option| match *option {
+                header::ConnectionOption::ConnectionHeader(ref name) => {
+                    name.eq_ignore_ascii_case("upgrade")
+                }
+                _ => false,
+            })
+        }
+        None => false,
+    };
+
+    upgrade && headers.get_raw("Upgrade").is_some()
+}
+
+/// Parse the head of the request at the start of `buf`
+///
+/// Returns `None` if more bytes are needed. Otherwise returns the length and the head of an
+/// upgrade request, or `Some(None)` for a request that is not an upgrade or is not valid. Those
+/// are left for hyper to handle.
+pub fn parse(buf: &[u8]) -> Option<Option<(usize, RequestHead)>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    let len = match req.parse(buf) {
+        Ok(httparse::Status::Complete(len)) => len,
+        Ok(httparse::Status::Partial) if buf.len() < MAX_HEAD_BYTES => return None,
+        _ => return Some(None),
+    };
+
+    // HTTP/1.0 does not have upgrades
+    if req.version != Some(1) {
+        return Some(None);
+    }
+
+    let mut h = Headers::new();
+    for header in req.headers.iter() {
+        h.append_raw(header.name.to_string(), header.value.to_vec());
+    }
+    if !is_upgrade(&h) {
+        return Some(None);
+    }
+
+    let method = req.method.and_then(|m| Method::from_str(m).ok());
+    let uri = req.path.and_then(|p| Uri::from_str(p).ok());
+    match (method, uri) {
+        (Some(method), Some(uri)) => {
+            Some(Some((
+                len,
+                RequestHead {
+                    method: method,
+                    uri: uri,
+                    headers: h,
+                },
+            )))
+        }
+        _ => Some(None),
+    }
+}
+
+/// Parse the status line of the response at the start of `buf`
+///
+/// Returns `None` if more bytes are needed. Otherwise returns the length of the head and the
+/// status of the response.
+fn parse_response(buf: &[u8]) -> io::Result<Option<(usize, u16)>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut res = httparse::Response::new(&mut headers);
+    match res.parse(buf) {
+        Ok(httparse::Status::Complete(len)) => Ok(Some((len, res.code.unwrap_or(0)))),
+        Ok(httparse::Status::Partial) if buf.len() < MAX_HEAD_BYTES => Ok(None),
+        Ok(httparse::Status::Partial) => {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "response head is too large"))
+        }
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+/// The length of the head and of the body of a response read by `read_response`
+///
+/// The body length is `None` if the body is chunked or ends when the server closes the connection.
+fn response_lengths(buf: &[u8]) -> io::Result<(usize, Option<u64>)> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut res = httparse::Response::new(&mut headers);
+    let len = match res.parse(buf) {
+        Ok(httparse::Status::Complete(len)) => len,
+        Ok(httparse::Status::Partial) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "incomplete response head"))
+        }
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+    };
+
+    // RFC 7230 Section 3.3.3
+    match res.code.unwrap_or(0) {
+        100...199 | 204 | 304 => return Ok((len, Some(0))),
+        _ => {}
+    }
+    if res.headers.iter().any(|h| h.name.eq_ignore_ascii_case("Transfer-Encoding")) {
+        return Ok((len, None));
+    }
+    let content_length = res.headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("Content-Length"))
+        .and_then(|h| str::from_utf8(h.value).ok())
+        .and_then(|value| value.trim().parse().ok());
+
+    Ok((len, content_length))
+}
+
+/// The head of a response read by `read_response`, rewritten to close the connection
+///
+/// The hop-by-hop headers of the server, including any named by its `Connection` header, are
+/// replaced by `Connection: close`. The headers that frame the body are kept, since the body is
+/// copied as is.
+fn close_head(buf: &[u8]) -> io::Result<Vec<u8>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut res = httparse::Response::new(&mut headers);
+    match res.parse(buf) {
+        Ok(httparse::Status::Complete(_)) => {}
+        Ok(httparse::Status::Partial) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "incomplete response head"))
+        }
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+
+    let mut hop_by_hop = vec!["Connection", "Keep-Alive", "Proxy-Connection", "Upgrade"];
+    for header in res.headers.iter().filter(|h| h.name.eq_ignore_ascii_case("Connection")) {
+        if let Ok(value) = str::from_utf8(header.value) {
+            hop_by_hop.extend(value.split(',').map(|name| name.trim()));
+        }
+    }
+    let is_hop_by_hop = |name: &str| {
+        !name.eq_ignore_ascii_case("Content-Length") && !name.eq_ignore_ascii_case("Transfer-Encoding")
+            && hop_by_hop.iter().any(|hop| hop.eq_ignore_ascii_case(name))
+    };
+
+    let mut head = format!(
+        "HTTP/1.{} {} {}\r\n",
+        res.version.unwrap_or(1),
+        res.code.unwrap_or(0),
+        res.reason.unwrap_or("")
+    ).into_bytes();
+    for header in res.headers.iter().filter(|h| !is_hop_by_hop(h.name)) {
+        head.extend_from_slice(header.name.as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(header.value);
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"Connection: close\r\n\r\n");
+    Ok(head)
+}
+
+/// Send a response that does not switch protocols to the client, followed by its body, and close
+/// both connections
+///
+/// `response` holds the bytes read by `read_response`. Only the upgrade request was sent to the
+/// server, so the client is told the connection is closed and sends any later requests on a new
+/// connection, which is served as usual. A body without a length is copied until the server
+/// closes the connection.
+pub fn refuse<C, S>(client: C, server: S, response: Vec<u8>) -> Box<Future<Item = (), Error = io::Error>>
+where
+    C: AsyncWrite + 'static,
+    S: AsyncRead + 'static,
+{
+    let parsed = response_lengths(&response).and_then(|lengths| close_head(&response).map(|head| (lengths, head)));
+    let ((len, body_length), mut head) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Box::new(::futures::failed(e)),
+    };
+
+    let mut body = response[len..].to_vec();
+    let remaining = match body_length {
+        Some(length) => {
+            body.truncate(length as usize);
+            length - body.len() as u64
+        }
+        None => u64::max_value(),
+    };
+    head.extend_from_slice(&body);
+
+    Box::new(
+        tokio_io::io::write_all(client, head)
+            .and_then(move |(client, _)| tokio_io::io::copy(server.take(remaining), client))
+            .map(|_| ()),
+    )
+}
+
+/// Read more bytes from `stream` into `buf`, returning the number of bytes read
+fn read_more<S: Read>(stream: &mut S, buf: &mut Vec<u8>) -> Poll<usize, io::Error> {
+    let mut chunk = [0; 4096];
+    match stream.read(&mut chunk) {
+        Ok(n) => {
+            buf.extend_from_slice(&chunk[..n]);
+            Ok(Async::Ready(n))
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+        Err(e) => Err(e),
+    }
+}
+
+/// Read the head of the first request on a connection
+///
+/// Resolves to the connection and, if the request asks for an upgrade, its head. The connection
+/// still has the bytes read past the head of an upgrade request, or every byte read otherwise.
+pub fn read_head<S: AsyncRead>(stream: S) -> ReadHead<S> {
+    ReadHead {
+        stream: Some(stream),
+        buf: Vec::new(),
+    }
+}
+
+pub struct ReadHead<S> {
+    stream: Option<S>,
+    buf: Vec<u8>,
+}
+
+impl<S: AsyncRead> Future for ReadHead<S> {
+    type Item = (Prefixed<S>, Option<RequestHead>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        loop {
+            if let Some(parsed) = parse(&self.buf) {
+                let stream = self.stream.take().expect("poll ReadHead after completion");
+                return Ok(Async::Ready(match parsed {
+                    Some((len, head)) => {
+                        let rest = self.buf.split_off(len);
+                        (Prefixed::new(rest, stream), Some(head))
+                    }
+                    None => (Prefixed::new(self.buf.split_off(0), stream), None),
+                }));
+            }
+
+            let read = read_more(
+                self.stream.as_mut().expect("poll ReadHead after completion"),
+                &mut self.buf,
+            );
+            let n = match read? {
+                Async::Ready(n) => n,
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+
+            // a connection closed before a full head is left for hyper to close
+            if n == 0 {
+                let stream = self.stream.take().unwrap();
+                return Ok(Async::Ready((Prefixed::new(self.buf.split_off(0), stream), None)));
+            }
+        }
+    }
+}
+
+/// Read the head of the response from the server
+///
+/// Resolves to the connection, every byte read from it, and the status of the response.
+pub fn read_response<S: AsyncRead>(stream: S) -> ReadResponse<S> {
+    ReadResponse {
+        stream: Some(stream),
+        buf: Vec::new(),
+    }
+}
+
+pub struct ReadResponse<S> {
+    stream: Option<S>,
+    buf: Vec<u8>,
+}
+
+impl<S: AsyncRead> Future for ReadResponse<S> {
+    type Item = (S, Vec<u8>, u16);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        loop {
+            if let Some((_, status)) = parse_response(&self.buf)? {
+                let stream = self.stream.take().expect("poll ReadResponse after completion");
+                return Ok(Async::Ready((stream, self.buf.split_off(0), status)));
+            }
+
+            let read = read_more(
+                self.stream.as_mut().expect("poll ReadResponse after completion"),
+                &mut self.buf,
+            );
+            let n = match read? {
+                Async::Ready(n) => n,
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before the response head",
+                ));
+            }
+        }
+    }
+}
+
+/// Opens the connections to the servers that requests are tunneled to
+///
+/// Each tunnel has its own connection, so the PROXY protocol header sent to servers that want one
+/// holds the addresses of the client.
+#[derive(Clone)]
+pub struct Connector {
+    http: HttpConnector,
+    tls: TlsConnector,
+    pools: Pools,
+}
+
+impl Connector {
+    pub fn new(handle: &Handle, pools: Pools) -> Result<Connector, native_tls::Error> {
+        let mut http = HttpConnector::new(1, handle);
+        http.enforce_http(false);
+
+        Ok(Connector {
+            http: http,
+            tls: TlsConnector::builder()?.build()?,
+            pools: pools,
+        })
+    }
+
+    /// Connect to the server at `url` on behalf of the client at `addresses`
+    pub fn connect(&self, url: Uri, addresses: Addresses) -> Box<Future<Item = MaybeHttpsStream<TcpStream>, Error = io::Error>> {
+        let connector = HttpsConnector::new(
+            ProxyProtocolConnector::new(self.http.clone(), self.pools.clone(), Some(addresses)),
+            self.pools.clone(),
+            self.tls.clone(),
+        );
+        connector.call(url)
+    }
+}
+
+/// Copies the bytes read from one stream to another
+struct Pipe {
+    buf: Box<[u8]>,
+    pos: usize,
+    cap: usize,
+    read_done: bool,
+    done: bool,
+}
+
+impl Pipe {
+    fn new() -> Pipe {
+        Pipe {
+            buf: vec![0; 8 * 1024].into_boxed_slice(),
+            pos: 0,
+            cap: 0,
+            read_done: false,
+            done: false,
+        }
+    }
+
+    /// Copy as much as possible from `reader` to `writer` without blocking
+    ///
+    /// Returns whether any bytes were copied. Once `reader` is closed and every byte is written,
+    /// `writer` is shut down so the other side knows no more bytes are coming.
+    fn copy<R: Read, W: AsyncWrite>(&mut self, reader: &mut R, writer: &mut W) -> io::Result<bool> {
+        let mut progress = false;
+        while !self.done {
+            if self.pos == self.cap && !self.read_done {
+                match reader.read(&mut self.buf) {
+                    Ok(0) => self.read_done = true,
+                    Ok(n) => {
+                        self.pos = 0;
+                        self.cap = n;
+                        progress = true;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            }
+
+            while self.pos < self.cap {
+                match writer.write(&self.buf[self.pos..self.cap]) {
+                    Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "write zero bytes")),
+                    Ok(n) => {
+                        self.pos += n;
+                        progress = true;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(progress),
+                    Err(e) => return Err(e),
+                }
+            }
+
+            match writer.flush() {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(progress),
+                Err(e) => return Err(e),
+            }
+
+            if self.read_done {
+                if writer.shutdown()?.is_not_ready() {
+                    return Ok(progress);
+                }
+                self.done = true;
+            }
+        }
+
+        Ok(progress)
+    }
+}
+
+/// Copy bytes both ways between the client and the server until both are closed
+///
+/// The tunnel is closed with a `TimedOut` error if no bytes are sent either way for
+/// `idle_timeout`.
+pub fn splice<C, S>(client: C, server: S, idle_timeout: Option<Duration>, handle: &Handle) -> io::Result<Splice<C, S>>
+where
+    C: AsyncRead + AsyncWrite,
+    S: AsyncRead + AsyncWrite,
+{
+    let idle = match idle_timeout {
+        Some(timeout) => Some((Timeout::new(timeout, handle)?, timeout)),
+        None => None,
+    };
+
+    Ok(Splice {
+        client: client,
+        server: server,
+        to_server: Pipe::new(),
+        to_client: Pipe::new(),
+        idle: idle,
+    })
+}
+
+pub struct Splice<C, S> {
+    client: C,
+    server: S,
+    to_server: Pipe,
+    to_client: Pipe,
+    idle: Option<(Timeout, Duration)>,
+}
+
+impl<C, S> Future for Splice<C, S>
+where
+    C: AsyncRead + AsyncWrite,
+    S: AsyncRead + AsyncWrite,
+{
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let sent = self.to_server.copy(&mut self.client, &mut self.server)?;
+        let received = self.to_client.copy(&mut self.server, &mut self.client)?;
+
+        if self.to_server.done && self.to_client.done {
+            return Ok(Async::Ready(()));
+        }
+
+        if let Some((ref mut timeout, duration)) = self.idle {
+            if sent || received {
+                timeout.reset(Instant::now() + duration);
+            }
+            if timeout.poll()?.is_ready() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "tunnel was idle for too long"));
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Headers, Method};
+
+    use super::{close_head, is_upgrade, parse, parse_response, response_lengths};
+
+    #[test]
+    fn test_is_upgrade() {
+        let mut headers = Headers::new();
+        headers.set_raw("Connection", "keep-alive, Upgrade");
+        headers.set_raw("Upgrade", "websocket");
+        assert!(is_upgrade(&headers));
+
+        headers.set_raw("Connection", "keep-alive");
+        assert!(!is_upgrade(&headers));
+
+        headers.set_raw("Connection", "upgrade");
+        headers.remove_raw("Upgrade");
+        assert!(!is_upgrade(&headers));
+    }
+
+    #[test]
+    fn test_parse() {
+        let req = b"GET /chat HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\nframe";
+        let (len, head) = parse(req).unwrap().unwrap();
+        assert_eq!(req.len() - 5, len);
+        assert_eq!(Method::Get, head.method);
+        assert_eq!("/chat", head.uri.path());
+        assert_eq!(Some("websocket".to_string()), head.headers.get_raw("Upgrade").and_then(|raw| {
+            raw.one().map(|v| String::from_utf8_lossy(v).into_owned())
+        }));
+
+        // more bytes are needed
+        assert!(parse(b"GET /chat HTTP/1.1\r\nHost: exa").is_none());
+
+        // other requests are left for hyper
+        assert!(parse(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap().is_none());
+        assert!(parse(b"GET / HTTP/1.0\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n").unwrap().is_none());
+        assert!(parse(b"not http\r\n\r\n").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let res = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n";
+        assert_eq!(Some((res.len(), 101)), parse_response(res).unwrap());
+        assert_eq!(None, parse_response(b"HTTP/1.1 101 Swi").unwrap());
+        assert!(parse_response(b"garbage\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_response_lengths() {
+        let res = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 3\r\n\r\nbad";
+        assert_eq!((res.len() - 3, Some(3)), response_lengths(res).unwrap());
+
+        let res = b"HTTP/1.1 400 Bad Request\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!((res.len(), None), response_lengths(res).unwrap());
+
+        let res = b"HTTP/1.1 304 Not Modified\r\nContent-Length: 3\r\n\r\n";
+        assert_eq!((res.len(), Some(0)), response_lengths(res).unwrap());
+
+        let res = b"HTTP/1.1 426 Upgrade Required\r\n\r\n";
+        assert_eq!((res.len(), None), response_lengths(res).unwrap());
+    }
+
+    #[test]
+    fn test_close_head() {
+        let res = b"HTTP/1.1 403 Forbidden\r\nConnection: keep-alive, X-Hop\r\nKeep-Alive: timeout=5\r\nX-Hop: 1\r\nContent-Length: 3\r\n\r\nno!";
+        assert_eq!(
+            &b"HTTP/1.1 403 Forbidden\r\nContent-Length: 3\r\nConnection: close\r\n\r\n"[..],
+            &close_head(res).unwrap()[..]
+        );
+
+        let res = b"HTTP/1.1 426 Upgrade Required\r\nUpgrade: websocket\r\nConnection: Upgrade, Transfer-Encoding\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(
+            &b"HTTP/1.1 426 Upgrade Required\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n"[..],
+            &close_head(res).unwrap()[..]
+        );
+    }
+}
@@ -0,0 +1,476 @@
+//! Virtual hosts
+//!
+//! A worker holds a default pool and any number of named pools. Each named pool serves the
+//! hostnames it is configured with, so a single weldr cluster can sit in front of many services.
+//! Requests for a hostname no pool claims are sent to the default pool. Path routes are checked
+//! before the hostname.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+use hyper::Uri;
+
+use header_rules::HeaderRules;
+use pool::{Backend, Pool};
+use route::Route;
+use server::Server;
+use tls;
+
+/// The name of the pool that serves requests for hostnames no other pool claims
+pub const DEFAULT_POOL: &'static str = "default";
+
+/// Whether `name` can be used as the name of a pool
+///
+/// Names are used in the management API paths, so they are limited to ASCII letters, digits, `-`
+/// and `_`.
+pub fn is_pool_name(name: &str) -> bool {
+    !name.is_empty() &&
+        name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Whether `host` can be served by a pool
+///
+/// A host is a hostname without a port, such as `www.example.com`, or a wildcard that matches a
+/// single label, such as `*.example.com`.
+pub fn is_host(host: &str) -> bool {
+    let name = if host.starts_with("*.") { &host[2..] } else { host };
+    !name.is_empty() &&
+        name.split('.').all(|label| {
+            !label.is_empty() &&
+                label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
+}
+
+/// A pool and the hostnames it serves
+#[derive(Clone, Debug)]
+pub struct VirtualHost {
+    name: String,
+    hosts: Vec<String>,
+    pool: Pool,
+    headers: Rc<HeaderRules>,
+}
+
+impl VirtualHost {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The hostnames served by the pool, which may be wildcards such as `*.example.com`
+    pub fn hosts(&self) -> &[String] {
+        &self.hosts
+    }
+
+    pub fn pool(&self) -> Pool {
+        self.pool.clone()
+    }
+
+    /// The rules that change the headers of requests to the pool and their responses
+    pub fn headers(&self) -> Rc<HeaderRules> {
+        self.headers.clone()
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.name == DEFAULT_POOL
+    }
+}
+
+/// The pools of a worker or the manager, by name
+#[derive(Clone, Debug)]
+pub struct Pools {
+    inner: Rc<RefCell<Inner>>,
+}
+
+struct Inner {
+    /// The default pool first, followed by the named pools in the order they were added
+    hosts: Vec<VirtualHost>,
+
+    /// Checked in order before the hostname, the first route that matches picks the pool
+    routes: Vec<Route>,
+
+    /// Creates the pool for each named pool that is added
+    new_pool: Box<Fn() -> Pool>,
+
+    ejection_listener: Option<Rc<Fn(&Server, Duration)>>,
+}
+
+impl fmt::Debug for Inner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pools").field("hosts", &self.hosts).finish()
+    }
+}
+
+impl Pools {
+    /// Create the pools, using `new_pool` to create the default pool and each named pool
+    pub fn new<F>(new_pool: F) -> Pools
+    where
+        F: Fn() -> Pool + 'static,
+    {
+        let default = VirtualHost {
+            name: DEFAULT_POOL.to_string(),
+            hosts: Vec::new(),
+            pool: new_pool(),
+            headers: Rc::new(HeaderRules::default()),
+        };
+
+        Pools {
+            inner: Rc::new(RefCell::new(Inner {
+                hosts: vec![default],
+                routes: Vec::new(),
+                new_pool: Box::new(new_pool),
+                ejection_listener: None,
+            })),
+        }
+    }
+
+    /// The pool that serves requests for hostnames no other pool claims
+    pub fn default_pool(&self) -> Pool {
+        self.inner.borrow().hosts[0].pool.clone()
+    }
+
+    /// The pool called `name`
+    pub fn get(&self, name: &str) -> Option<Pool> {
+        self.find(name).map(|vhost| vhost.pool)
+    }
+
+    /// The pool called `name` and the hostnames it serves
+    pub fn find(&self, name: &str) -> Option<VirtualHost> {
+        self.inner.borrow().hosts.iter().find(|v| v.name == name).cloned()
+    }
+
+    /// The default pool followed by the named pools
+    pub fn all(&self) -> Vec<VirtualHost> {
+        self.inner.borrow().hosts.clone()
+    }
+
+    /// Add a new pool called `name` that serves `hosts`
+    ///
+    /// Returns `None` if there already is a pool called `name`.
+    pub fn add(&self, name: &str, hosts: Vec<String>) -> Option<Pool> {
+        let mut inner = self.inner.borrow_mut();
+        if inner.hosts.iter().any(|v| v.name == name) {
+            return None;
+        }
+
+        let pool = (inner.new_pool)();
+        if let Some(ref listener) = inner.ejection_listener {
+            let listener = listener.clone();
+            pool.on_ejection(move |server, duration| listener(server, duration));
+        }
+
+        inner.hosts.push(VirtualHost {
+            name: name.to_string(),
+            hosts: hosts,
+            pool: pool.clone(),
+            headers: Rc::new(HeaderRules::default()),
+        });
+        Some(pool)
+    }
+
+    /// Change the hostnames served by the pool called `name`
+    ///
+    /// Returns false if there is no such pool. The default pool serves every hostname no other pool
+    /// claims, so its hostnames cannot be changed.
+    pub fn set_hosts(&self, name: &str, hosts: Vec<String>) -> bool {
+        let mut inner = self.inner.borrow_mut();
+        match inner.hosts.iter_mut().find(|v| v.name == name && !v.is_default()) {
+            Some(vhost) => {
+                vhost.hosts = hosts;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Change the header rules of the pool called `name`, which may be the default pool
+    ///
+    /// Returns false if there is no such pool.
+    pub fn set_headers(&self, name: &str, headers: HeaderRules) -> bool {
+        let mut inner = self.inner.borrow_mut();
+        match inner.hosts.iter_mut().find(|v| v.name == name) {
+            Some(vhost) => {
+                vhost.headers = Rc::new(headers);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove the pool called `name`, along with its servers
+    ///
+    /// Returns false if there is no such pool. The default pool cannot be removed.
+    pub fn remove(&self, name: &str) -> bool {
+        let mut inner = self.inner.borrow_mut();
+        let before = inner.hosts.len();
+        inner.hosts.retain(|v| v.name != name || v.is_default());
+        inner.hosts.len() != before
+    }
+
+    /// The name of the pool that serves `host`, not counting wildcards or the default pool
+    pub fn owner(&self, host: &str) -> Option<String> {
+        self.inner
+            .borrow()
+            .hosts
+            .iter()
+            .find(|v| v.hosts.iter().any(|h| h.eq_ignore_ascii_case(host)))
+            .map(|v| v.name.clone())
+    }
+
+    /// The pool that serves requests for `host`
+    ///
+    /// A pool that serves `host` exactly is picked over a pool with a wildcard that matches it. If
+    /// no pool serves `host`, or the request did not have one, the default pool is picked. Any
+    /// port is ignored.
+    pub fn select(&self, host: Option<&str>) -> VirtualHost {
+        let inner = self.inner.borrow();
+        let host = match host {
+            Some(host) => strip_port(host),
+            None => return inner.hosts[0].clone(),
+        };
+
+        let exact = inner.hosts.iter().find(|v| {
+            v.hosts.iter().any(|h| !h.starts_with("*.") && h.eq_ignore_ascii_case(host))
+        });
+        let vhost = exact.or_else(|| {
+            inner.hosts.iter().find(|v| {
+                v.hosts.iter().any(|h| h.starts_with("*.") && tls::matches(h, host))
+            })
+        });
+
+        vhost.unwrap_or(&inner.hosts[0]).clone()
+    }
+
+    /// The pool that serves a request for `host` with `path`, and the route that picked it
+    ///
+    /// The first route that matches `host` and `path` picks the pool. Routes to a pool that does not
+    /// exist are skipped. If no route matches, the pool is picked by `host`.
+    pub fn route(&self, host: Option<&str>, path: &str) -> (VirtualHost, Option<Route>) {
+        let routed = {
+            let inner = self.inner.borrow();
+            let hostname = host.map(strip_port);
+            inner.routes.iter().filter(|route| route.is_match(hostname, path)).filter_map(|route| {
+                inner
+                    .hosts
+                    .iter()
+                    .find(|v| v.name == route.pool())
+                    .map(|vhost| (vhost.clone(), Some(route.clone())))
+            }).next()
+        };
+
+        routed.unwrap_or_else(|| (self.select(host), None))
+    }
+
+    /// The routes, in the order they are checked
+    pub fn routes(&self) -> Vec<Route> {
+        self.inner.borrow().routes.clone()
+    }
+
+    /// Replace the routes
+    pub fn set_routes(&self, routes: Vec<Route>) {
+        self.inner.borrow_mut().routes = routes;
+    }
+
+    /// Every backend in every pool
+    pub fn backends(&self) -> Vec<Backend> {
+        self.inner
+            .borrow()
+            .hosts
+            .iter()
+            .flat_map(|v| v.pool.all())
+            .collect()
+    }
+
+    /// The backend for `server`, in whichever pool it is in
+    pub fn backend(&self, server: &Server) -> Option<Backend> {
+        self.inner.borrow().hosts.iter().filter_map(|v| v.pool.find(server)).next()
+    }
+
+    /// The server a request for `uri` is being sent to, in whichever pool it is in
+    pub fn server_for(&self, uri: &Uri) -> Option<Server> {
+        self.inner.borrow().hosts.iter().filter_map(|v| v.pool.server_for(uri)).next()
+    }
+
+    /// The name of the pool with a server at the scheme and authority of `uri`
+    ///
+    /// A server is only ever in one pool, so the connectors use the settings of that pool.
+    pub fn pool_of(&self, uri: &Uri) -> Option<String> {
+        self.inner
+            .borrow()
+            .hosts
+            .iter()
+            .find(|v| v.pool.server_for(uri).is_some())
+            .map(|v| v.name.clone())
+    }
+
+    /// Call `listener` with the server and the ejection time each time a backend in any pool is
+    /// ejected, including pools added later
+    pub fn on_ejection<F>(&self, listener: F)
+    where
+        F: Fn(&Server, Duration) + 'static,
+    {
+        let listener: Rc<Fn(&Server, Duration)> = Rc::new(listener);
+        let mut inner = self.inner.borrow_mut();
+        for vhost in inner.hosts.iter() {
+            let listener = listener.clone();
+            vhost.pool.on_ejection(move |server, duration| listener(server, duration));
+        }
+        inner.ejection_listener = Some(listener);
+    }
+}
+
+impl Default for Pools {
+    fn default() -> Pools {
+        Pools::new(Pool::default)
+    }
+}
+
+/// Pools where `pool` is the default pool
+impl From<Pool> for Pools {
+    fn from(pool: Pool) -> Pools {
+        let pools = Pools::default();
+        pools.inner.borrow_mut().hosts[0].pool = pool;
+        pools
+    }
+}
+
+/// Remove the port from the value of a `Host` header
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return match host.find(']') {
+            Some(end) => &host[..end + 1],
+            None => host,
+        };
+    }
+
+    match host.rfind(':') {
+        Some(colon) => &host[..colon],
+        None => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_host, is_pool_name, strip_port, Pools, DEFAULT_POOL};
+    use route::{Route, RouteSpec};
+
+    fn pools() -> Pools {
+        let pools = Pools::default();
+        pools.add("api", vec!["api.example.com".to_string()]).unwrap();
+        pools.add("tenants", vec!["*.example.com".to_string()]).unwrap();
+        pools.add("www", vec!["www.example.com".to_string(), "example.com".to_string()]).unwrap();
+        pools
+    }
+
+    #[test]
+    fn test_select() {
+        let pools = pools();
+        assert_eq!("api", pools.select(Some("api.example.com")).name());
+        assert_eq!("api", pools.select(Some("API.example.com:8080")).name());
+        assert_eq!("www", pools.select(Some("example.com")).name());
+
+        // an exact match is picked over a wildcard added before it
+        assert_eq!("www", pools.select(Some("www.example.com")).name());
+        assert_eq!("tenants", pools.select(Some("acme.example.com")).name());
+
+        assert_eq!(DEFAULT_POOL, pools.select(Some("a.b.example.com")).name());
+        assert_eq!(DEFAULT_POOL, pools.select(Some("example.org")).name());
+        assert_eq!(DEFAULT_POOL, pools.select(None).name());
+    }
+
+    #[test]
+    fn test_route() {
+        let pools = pools();
+        pools.add("assets", Vec::new()).unwrap();
+        let route = |prefix: &str, pool: &str| {
+            Route::new(RouteSpec {
+                prefix: Some(prefix.to_string()),
+                pool: pool.to_string(),
+                strip_prefix: pool == "assets",
+                ..RouteSpec::default()
+            }).unwrap()
+        };
+        let scoped = Route::new(RouteSpec {
+            prefix: Some("/api/".to_string()),
+            pool: "tenants".to_string(),
+            host: Some("acme.example.com".to_string()),
+            ..RouteSpec::default()
+        }).unwrap();
+        pools.set_routes(vec![
+            route("/static/", "assets"),
+            route("/", "missing"),
+            scoped,
+            route("/static/", "api"),
+            route("/api/", "api"),
+        ]);
+
+        let (vhost, route) = pools.route(Some("www.example.com"), "/static/app.js");
+        assert_eq!("assets", vhost.name());
+        assert_eq!(Some("/app.js".to_string()), route.unwrap().rewrite("/static/app.js"));
+
+        let (vhost, route) = pools.route(None, "/api/users");
+        assert_eq!("api", vhost.name());
+        assert_eq!(Some("/api/"), route.unwrap().spec().prefix.as_ref().map(|p| p.as_str()));
+
+        // a route with a host only matches requests for that host
+        let (vhost, _) = pools.route(Some("acme.example.com:8080"), "/api/users");
+        assert_eq!("tenants", vhost.name());
+        let (vhost, _) = pools.route(Some("www.example.com"), "/api/users");
+        assert_eq!("api", vhost.name());
+
+        // a route to a pool that does not exist is skipped
+        let (vhost, route) = pools.route(Some("www.example.com"), "/index.html");
+        assert_eq!("www", vhost.name());
+        assert!(route.is_none());
+    }
+
+    #[test]
+    fn test_add_and_remove() {
+        let pools = pools();
+        assert!(pools.add("api", Vec::new()).is_none());
+        assert!(pools.add(DEFAULT_POOL, Vec::new()).is_none());
+        assert_eq!(Some("api".to_string()), pools.owner("api.example.com"));
+
+        assert!(pools.set_hosts("api", vec!["api.example.org".to_string()]));
+        assert!(!pools.set_hosts(DEFAULT_POOL, vec!["api.example.com".to_string()]));
+        assert_eq!("tenants", pools.select(Some("api.example.com")).name());
+        assert_eq!("api", pools.select(Some("api.example.org")).name());
+
+        assert!(pools.remove("api"));
+        assert!(!pools.remove("api"));
+        assert!(!pools.remove(DEFAULT_POOL));
+        assert!(pools.get("api").is_none());
+        assert!(pools.get(DEFAULT_POOL).is_some());
+        let names: Vec<String> = pools.all().iter().map(|v| v.name().to_string()).collect();
+        assert_eq!(vec![DEFAULT_POOL, "tenants", "www"], names);
+    }
+
+    #[test]
+    fn test_is_pool_name() {
+        assert!(is_pool_name("api"));
+        assert!(is_pool_name("static-assets_2"));
+        assert!(!is_pool_name(""));
+        assert!(!is_pool_name("a/b"));
+        assert!(!is_pool_name("a b"));
+    }
+
+    #[test]
+    fn test_is_host() {
+        assert!(is_host("example.com"));
+        assert!(is_host("*.example.com"));
+        assert!(is_host("localhost"));
+        assert!(!is_host(""));
+        assert!(!is_host("*"));
+        assert!(!is_host("a.*.example.com"));
+        assert!(!is_host("example.com:8080"));
+        assert!(!is_host("example..com"));
+    }
+
+    #[test]
+    fn test_strip_port() {
+        assert_eq!("example.com", strip_port("example.com:8080"));
+        assert_eq!("example.com", strip_port("example.com"));
+        assert_eq!("[::1]", strip_port("[::1]:8080"));
+        assert_eq!("[::1]", strip_port("[::1]"));
+    }
+}
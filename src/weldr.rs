@@ -5,27 +5,39 @@ extern crate hyper;
 extern crate weldr;
 extern crate clap;
 extern crate tokio_core;
-extern crate net2;
+extern crate futures;
 
 use std::io;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
 
 use clap::{Arg, App, SubCommand};
-use net2::TcpBuilder;
-use net2::unix::UnixTcpBuilderExt;
+use futures::Future;
 
 use tokio_core::reactor::{Core, Handle};
 use tokio_core::net::TcpListener;
 
+use weldr::balancer::DEFAULT_PENALTY_SECS;
 use weldr::pool::Pool;
 use weldr::config::Config;
+use weldr::vhost::{Pools, DEFAULT_POOL};
 use weldr::mgmt::{worker, manager};
 use weldr::mgmt::health::BackendHealth;
+use weldr::tls::Certificates;
 
 fn main() {
     env_logger::init().expect("Failed to start logger");
 
     let matches = App::new("weldr")
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("config")
+                .takes_value(true)
+                .help("path to a TOML configuration file"),
+        )
         .arg(
             Arg::with_name("admin-ip")
                 .long("admin-ip")
@@ -57,47 +69,124 @@ fn main() {
     let mut core = Core::new().unwrap();
     let handle = core.handle();
 
-    // TODO make this dynamic and pass it down to workers
-    let internal_addr = "127.0.0.1:4000";
-    let internal_addr = internal_addr
-        .parse::<SocketAddr>()
-        .expect("Failed to parse addr");
+    let config_path = matches.value_of("config").map(PathBuf::from);
+    let mut config = match config_path {
+        Some(ref path) => {
+            Config::from_file(path).unwrap_or_else(|e| {
+                eprintln!("Failed to load {}: {}", path.display(), e);
+                process::exit(1);
+            })
+        }
+        None => Config::default(),
+    };
+
+    if let Some(ip) = matches.value_of("ip") {
+        config.listen = ip.parse::<SocketAddr>().expect("Failed to parse ip");
+    }
 
-    let ip = matches.value_of("worker").unwrap_or("0.0.0.0:8080");
-    let ip = ip.parse::<SocketAddr>().unwrap();
+    if let Some(admin_ip) = matches.value_of("admin-ip") {
+        config.admin = admin_ip.parse::<SocketAddr>().expect("Failed to parse admin-ip");
+    }
 
-    let pool = Pool::default();
-    let config = Config::default();
+    let internal_addr = config.internal;
+    // requests that fail without a response count as taking as long as the read timeout
+    let penalty = config.timeout.read.unwrap_or(Duration::from_secs(DEFAULT_PENALTY_SECS));
 
     if let Some(matches) = matches.subcommand_matches("worker") {
         let id = matches.value_of("id").unwrap();
         debug!("Spawned worker {}", id);
-        let _result = worker::subscribe(internal_addr, handle, pool.clone());
-
-        let listener = setup_listener(ip, &core.handle()).expect("Failed to setup listener");
-        //weldr::proxy::run(ip, pool, core).expect("Failed to start server");
-        let srv = weldr::proxy::serve(listener, pool, &core.handle(), &config).expect("Failed to create server future");
+        let balancer = config.balancer.clone();
+        let outlier_detection = config.outlier_detection.clone();
+        let pools = Pools::new(move || {
+            let pool = Pool::new(balancer.balancer(penalty));
+            pool.set_failure_latency(penalty);
+            if let Some(ref outlier_detection) = outlier_detection {
+                pool.detect_outliers(outlier_detection.clone());
+            }
+            pool
+        });
+        let certificates = config.tls.as_ref().map(|tls| {
+            let certificates = Certificates::new();
+            for certificate in &tls.certificates {
+                certificates
+                    .add(&certificate.hostnames, &certificate.certificate)
+                    .expect("Failed to add certificate");
+            }
+            certificates
+        });
+        let _result = worker::subscribe(internal_addr, handle, pools.clone(), certificates.clone());
+
+        let listener = setup_listener(config.listen, manager::LISTEN_FD, &core.handle())
+            .expect("Failed to setup listener");
+        let tls = config.tls.as_ref().map(|tls| {
+            let certificates = certificates.clone().unwrap();
+            let acceptor = weldr::tls::acceptor(&tls.certificates[0].certificate, certificates)
+                .expect("Failed to setup TLS");
+            let listener = setup_listener(tls.listen, manager::TLS_LISTEN_FD, &core.handle())
+                .expect("Failed to setup TLS listener");
+            (listener, acceptor)
+        });
+        let worker_id = id.to_string();
+        let shutdown = weldr::signal::shutdown(&core.handle()).map(move |signal| {
+            info!("Worker {} received signal {}. Shutting down", worker_id, signal);
+        });
+        let srv = weldr::proxy::serve_with_shutdown(listener, tls, pools, &core.handle(), &config, shutdown)
+            .expect("Failed to create server future");
         core.run(srv).expect("Server failed");
+        info!("Worker {} stopped", id);
     } else {
-        let mut manager = manager::Manager::new();
+        let servers = config.servers.clone();
+        let named = config.pools.clone();
+        let headers = config.headers.clone();
+        let admin_ip = config.admin;
+        let balancer = config.balancer.clone();
+        let pools = Pools::new(move || Pool::new(balancer.balancer(penalty)));
+        // routes restored from the state file replace the routes in the configuration
+        pools.set_routes(config.routes.clone());
+        let mut manager = manager::Manager::new(config, config_path, pools.clone());
+        manager.load_state().expect("Failed to restore pools from state file");
+        for server in servers {
+            pools.default_pool().add(server);
+        }
+        if let Some(headers) = headers {
+            pools.set_headers(DEFAULT_POOL, headers);
+        }
+
+        // the hosts, and any header rules, in the configuration replace those of a pool restored
+        // from the state file
+        for named in named {
+            let pool = match pools.get(&named.name) {
+                Some(pool) => pool,
+                None => pools.add(&named.name, Vec::new()).unwrap(),
+            };
+            pools.set_hosts(&named.name, named.hosts);
+            if let Some(headers) = named.headers {
+                pools.set_headers(&named.name, headers);
+            }
+            for server in named.servers {
+                pool.add(server);
+            }
+        }
+
         manager.listen(internal_addr, handle.clone());
-        manager.start_workers(5).expect("Failed to start manager");
+        manager.start_workers().expect("Failed to start manager");
+        manager.supervise(handle.clone());
+        manager.upgrade_on_signal(handle.clone());
 
         let health = BackendHealth::new();
 
-        let admin_ip = matches.value_of("worker").unwrap_or("0.0.0.0:8687");
-        let admin_ip = admin_ip.parse::<SocketAddr>().unwrap();
-        weldr::mgmt::run(admin_ip, pool, core, manager.clone(), &config, health.clone())
+        weldr::mgmt::run(admin_ip, pools, core, manager.clone(), health.clone())
             .expect("Failed to start server");
     }
 }
 
-fn setup_listener(addr: SocketAddr, handle: &Handle) -> io::Result<TcpListener> {
-    let listener = TcpBuilder::new_v4()?;
-    listener.reuse_address(true)?;
-    listener.reuse_port(true)?;
-    let listener = listener.bind(&addr)?;
-    let listener = listener.listen(128)?;
+/// Use the listener inherited from the manager in the environment variable `fd_var`, or bind
+/// `addr` if the worker was started some other way
+fn setup_listener(addr: SocketAddr, fd_var: &str, handle: &Handle) -> io::Result<TcpListener> {
+    let listener = match manager::inherited_listener(fd_var) {
+        Some(listener) => listener,
+        None => manager::bind_listener(addr)?,
+    };
     let listener = TcpListener::from_listener(listener, &addr, &handle)?;
 
     Ok(listener)
@@ -5,11 +5,17 @@ extern crate futures;
 extern crate tokio_core;
 extern crate tokio_io;
 extern crate hyper;
+extern crate native_tls;
+extern crate openssl;
+extern crate tokio_tls;
 extern crate weldr;
 
+use std::cell::Cell;
+use std::io::{Read, Write};
 use std::net::SocketAddr;
 use std::sync::mpsc::channel;
 use std::thread;
+use std::time::{Duration, Instant};
 use std::str::FromStr;
 
 use futures::{future, Future, Stream};
@@ -21,10 +27,28 @@ use hyper::{Get, Post, StatusCode, Method, HttpVersion, Headers, Uri};
 use hyper::client;
 use hyper::server::{Http, Service, Request, Response};
 use hyper::header::{self, ContentLength, TransferEncoding};
+use native_tls::{TlsAcceptor, TlsConnector};
+use native_tls::backend::openssl::TlsAcceptorBuilderExt;
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::nid;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::x509::{X509, X509NameBuilder};
+use openssl::ssl::{SSL_VERIFY_FAIL_IF_NO_PEER_CERT, SSL_VERIFY_PEER};
+use openssl::x509::extension::SubjectAlternativeName;
+use tokio_tls::{TlsAcceptorExt, TlsConnectorExt};
 
 use weldr::server::Server;
 use weldr::pool::Pool;
-use weldr::config::Config;
+use weldr::config::{Config, Tls, TlsCertificate};
+use weldr::header_rules::{Action, HeaderRule, HeaderRules};
+use weldr::mgmt::{manager, worker};
+use weldr::route::{Route, RouteSpec};
+use weldr::tls::{self, Certificate, Certificates, ServerTls};
+use weldr::vhost::{Pools, DEFAULT_POOL};
 
 #[derive(Clone, Copy)]
 struct Origin;
@@ -62,6 +86,32 @@ impl Service for Origin {
             }
             res.with_body(req.body())
         }
+                                (_, "/forwarded-for") => {
+            let body = req.headers()
+                .get_raw("X-Forwarded-For")
+                .and_then(|raw| raw.one())
+                .map(|value| String::from_utf8_lossy(value).into_owned())
+                .unwrap_or_default();
+            Response::new()
+                .with_header(ContentLength(body.len() as u64))
+                .with_body(body)
+        }
+                                (_, "/forwarded-proto") => {
+            let body = req.headers()
+                .get_raw("X-Forwarded-Proto")
+                .and_then(|raw| raw.one())
+                .map(|value| String::from_utf8_lossy(value).into_owned())
+                .unwrap_or_default();
+            Response::new()
+                .with_header(ContentLength(body.len() as u64))
+                .with_body(body)
+        }
+                                (_, path) if path.ends_with("/echo-path") => {
+                                    let body = format!("{}", req.uri());
+                                    Response::new()
+                                        .with_header(ContentLength(body.len() as u64))
+                                        .with_body(body)
+                                }
                                 (_, "/chunked") => {
                                     Response::new()
                                         .with_header(TransferEncoding::chunked())
@@ -101,9 +151,33 @@ impl SimpleResponse {
 fn with_server<R>(req: R)
     where R: Fn(String, Handle) -> Box<Future<Item = (), Error = hyper::Error>>
 {
-    let _ = env_logger::init();
+    with_server_config(Config::default(), req)
+}
 
-    let pool = Pool::default();
+/// Send a request through a proxy started with `config` and get back a response.
+fn with_server_config<R>(config: Config, req: R)
+    where R: Fn(String, Handle) -> Box<Future<Item = (), Error = hyper::Error>>
+{
+    with_server_pool(config, Pool::default(), req)
+}
+
+/// Send a request through a proxy started with `config` and get back a response.
+///
+/// The origin server is added to the end of `pool`. If `config` has a TLS listener, the callback is
+/// given the host of the TLS listener instead of the plain HTTP listener.
+fn with_server_pool<R>(config: Config, pool: Pool, req: R)
+    where R: Fn(String, Handle) -> Box<Future<Item = (), Error = hyper::Error>>
+{
+    with_server_pools(config, Pools::from(pool), DEFAULT_POOL, req)
+}
+
+/// Send a request through a proxy with `pools` and get back a response.
+///
+/// The origin server is added to the end of the pool called `name`.
+fn with_server_pools<R>(config: Config, pools: Pools, name: &str, req: R)
+    where R: Fn(String, Handle) -> Box<Future<Item = (), Error = hyper::Error>>
+{
+    let _ = env_logger::init();
 
     let addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
     let mut core = Core::new().unwrap();
@@ -114,6 +188,19 @@ fn with_server<R>(req: R)
 
     let _admin_listener = TcpListener::bind(&addr, &handle).unwrap();
 
+    let tls = config.tls.as_ref().map(|tls| {
+        let certificates = Certificates::new();
+        for certificate in &tls.certificates {
+            certificates.add(&certificate.hostnames, &certificate.certificate).unwrap();
+        }
+        let acceptor = tls::acceptor(&tls.certificates[0].certificate, certificates).unwrap();
+        (TcpListener::bind(&addr, &handle).unwrap(), acceptor)
+    });
+    let proxy_host = match tls {
+        Some((ref listener, _)) => format!("https://{}", listener.local_addr().unwrap()),
+        None => format!("http://{}:{}", proxy_addr.ip(), proxy_addr.port()),
+    };
+
     let (tx, rx) = channel();
     let _h2 = thread::spawn(move || {
                                 let addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
@@ -128,23 +215,145 @@ fn with_server<R>(req: R)
     let origin_str = format!("http://127.0.0.1:{}", origin.port());
     let origin_url = origin_str.parse::<Uri>().unwrap();
     let origin_server = Server::new(origin_url, false);
-    pool.add(origin_server);
+    pools.get(name).unwrap().add(origin_server);
 
     let shutdown_signal =
         future::lazy(|| {
                          req(origin_str, handle.clone());
-                         req(format!("http://{}:{}", proxy_addr.ip(), proxy_addr.port()),
-                             handle.clone())
+                         req(proxy_host, handle.clone())
                      });
 
-    let config = Config::default();
-    let srv = weldr::proxy::serve(listener, pool.clone(), &handle, &config).unwrap();
+    let srv = weldr::proxy::serve_with_shutdown(listener, tls, pools.clone(), &handle, &config, future::empty()).unwrap();
     match core.run(shutdown_signal.select(srv.map_err(|e| e.into()))) {
         Ok(((), _incoming)) => {}
         Err((e, _other)) => panic!(e),
     }
 }
 
+/// Generate a self-signed certificate and key for `hostname`
+fn self_signed(hostname: &str) -> (X509, PKey) {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(nid::COMMONNAME, hostname).unwrap();
+    let name = name.build();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+    builder.set_serial_number(&serial).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    let san = SubjectAlternativeName::new()
+        .dns(hostname)
+        .build(&builder.x509v3_context(None, None))
+        .unwrap();
+    builder.append_extension(san).unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+    (builder.build(), key)
+}
+
+/// A config with a TLS listener serving `certificates`
+fn tls_config(certificates: Vec<Certificate>) -> Config {
+    let certificates = certificates
+        .into_iter()
+        .map(|certificate| {
+            TlsCertificate {
+                hostnames: certificate.hostnames().unwrap(),
+                certificate: certificate,
+            }
+        })
+        .collect();
+
+    let mut config = Config::default();
+    config.tls = Some(Tls {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        certificates: certificates,
+    });
+    config
+}
+
+/// Send a GET request for `path` over TLS, trusting `root`, and get back the response body
+fn tls_get(host: String, domain: &'static str, root: &X509, path: &'static str, handle: &Handle) -> Box<Future<Item = String, Error = hyper::Error>> {
+    let mut connector = TlsConnector::builder().unwrap();
+    connector
+        .add_root_certificate(native_tls::Certificate::from_der(&root.to_der().unwrap()).unwrap())
+        .unwrap();
+    let connector = connector.build().unwrap();
+
+    // strip out the "https://"
+    let addr: SocketAddr = host[8..].parse().unwrap();
+    let req = TcpStream::connect(&addr, handle)
+        .and_then(move |stream| {
+            connector
+                .connect_async(domain, stream)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+        })
+        .and_then(move |stream| {
+            let req = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, domain);
+            io::write_all(stream, req.into_bytes())
+        })
+        .and_then(|(stream, _)| io::read_to_end(stream, Vec::new()))
+        .map(|(_, res)| {
+            let res = String::from_utf8(res).unwrap();
+            let n = res.find("\r\n\r\n").unwrap() + 4;
+            res[n..].to_string()
+        })
+        .map_err(From::from);
+
+    Box::new(req)
+}
+
+/// Start an origin server that only accepts TLS connections and get back its address
+fn with_tls_origin(acceptor: TlsAcceptor) -> SocketAddr {
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+        let listener = TcpListener::bind(&addr, &handle).unwrap();
+        tx.send(listener.local_addr().unwrap()).unwrap();
+
+        let http = Http::new();
+        let server = listener.incoming().for_each(move |(socket, addr)| {
+            let handle1 = handle.clone();
+            let http = http.clone();
+            let conn = acceptor
+                .accept_async(socket)
+                .map(move |socket| http.bind_connection(&handle1, socket, addr, Origin))
+                .map_err(|e| debug!("Origin handshake failed: {}", e));
+            handle.spawn(conn);
+            Ok(())
+        });
+        core.run(server).unwrap();
+    });
+
+    rx.recv().unwrap()
+}
+
+/// Send a request for `/` through a proxy to an https origin, connecting with `tls`
+fn get_tls_origin(origin: SocketAddr, tls: Option<ServerTls>, status: StatusCode) {
+    let url = format!("https://127.0.0.1:{}", origin.port());
+    let pool = Pool::default();
+    pool.add(Server::new(url.parse::<Uri>().unwrap(), false).with_tls(tls));
+
+    with_server_pool(Config::default(), pool, move |host, handle| {
+        let url = hyper::Uri::from_str(&format!("{}{}", host, "/")).unwrap();
+        let req = client::Request::new(Method::Get, url);
+        let work = client_send_request(req, &handle).and_then(move |res| {
+            assert_eq!(status, res.status);
+
+            future::ok(())
+        });
+
+        Box::new(work)
+    })
+}
+
 fn client_send_request(request: client::Request,
                        handle: &Handle)
                        -> Box<Future<Item = SimpleResponse, Error = hyper::Error>> {
@@ -229,6 +438,27 @@ fn test_request_body() {
     })
 }
 
+#[test]
+fn test_x_forwarded_for() {
+    with_server(|host, handle| {
+
+        let url = hyper::Uri::from_str(&format!("{}{}", host, "/forwarded-for")).unwrap();
+        let mut req = client::Request::new(Method::Get, url);
+
+        // the client is not a trusted proxy, so the value it sent is replaced
+        req.headers_mut().set_raw("X-Forwarded-For", "1.1.1.1");
+        let work = client_send_request(req, &handle).and_then(move |res| {
+
+            assert_eq!(res.status, hyper::StatusCode::Ok);
+            assert_eq!(res.body.unwrap(), "127.0.0.1");
+
+            future::ok(())
+        });
+
+        Box::new(work)
+    })
+}
+
 #[test]
 fn test_request_and_response_body_chunked() {
     // hyper client does not currently support chunked requests
@@ -267,6 +497,182 @@ fn test_request_and_response_body_chunked() {
     })
 }
 
+#[test]
+fn test_proxy_protocol_listener() {
+    let mut config = Config::default();
+    config.proxy_protocol = true;
+
+    with_server_config(config, |host, handle| {
+
+        // strip out the "http://"
+        let addr: SocketAddr = host[7..].parse().unwrap();
+        let tcp = TcpStream::connect(&addr, &handle);
+        let req = tcp.and_then(|stream| {
+
+                io::write_all(stream,
+                              &b"\
+            PROXY TCP4 192.168.0.1 10.0.0.1 56324 80\r\n\
+            GET /forwarded-for HTTP/1.1\r\n\
+            Host: www.example.com\r\n\
+            Connection: close\r\n\
+            \r\n\
+            "
+                                   [..])
+                        .and_then(|(stream, _)| io::read_to_end(stream, Vec::new()))
+                        .and_then(|(_, body)| {
+                                      let body = String::from_utf8(body).unwrap();
+                                      let n = body.find("\r\n\r\n").unwrap() + 4;
+                                      assert_eq!(&body[n..], "192.168.0.1");
+
+                                      future::ok(())
+                                  })
+            })
+            .map_err(From::from);
+
+        Box::new(req)
+    })
+}
+
+#[test]
+fn test_tls_listener() {
+    let (cert, key) = self_signed("a.test");
+    let certificate = Certificate::from_pem(cert.to_pem().unwrap(), key.private_key_to_pem().unwrap()).unwrap();
+
+    with_server_config(tls_config(vec![certificate]), move |host, handle| {
+        let work = tls_get(host, "a.test", &cert, "/forwarded-proto", &handle).and_then(|body| {
+            assert_eq!("https", body);
+
+            future::ok(())
+        });
+
+        Box::new(work)
+    })
+}
+
+#[test]
+fn test_tls_sni() {
+    let (cert_a, key_a) = self_signed("a.test");
+    let a = Certificate::from_pem(cert_a.to_pem().unwrap(), key_a.private_key_to_pem().unwrap()).unwrap();
+
+    // the second certificate is loaded from a PKCS#12 archive
+    let (cert_b, key_b) = self_signed("b.test");
+    let der = Pkcs12::builder().build("secret", "b.test", &key_b, &cert_b).unwrap().to_der().unwrap();
+    let b = Certificate::from_pkcs12(&der, "secret").unwrap();
+    assert_eq!(vec!["b.test".to_string()], b.hostnames().unwrap());
+
+    with_server_config(tls_config(vec![a, b]), move |host, handle| {
+        // the handshake fails unless the certificate for b.test is served
+        let work = tls_get(host, "b.test", &cert_b, "/", &handle).and_then(|body| {
+            assert_eq!("Hello World", body);
+
+            future::ok(())
+        });
+
+        Box::new(work)
+    })
+}
+
+/// Run `core` until `done` returns true, failing the test if that takes more than a few seconds
+fn turn_until<F>(core: &mut Core, done: F)
+    where F: Fn() -> bool
+{
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out");
+        core.turn(Some(Duration::from_millis(10)));
+    }
+}
+
+#[test]
+fn test_publish_certificates() {
+    let _ = env_logger::init();
+
+    let (cert_a, key_a) = self_signed("a.test");
+    let a = Certificate::from_pem(cert_a.to_pem().unwrap(), key_a.private_key_to_pem().unwrap()).unwrap();
+    let (cert_b, key_b) = self_signed("b.test");
+    let b = Certificate::from_pem(cert_b.to_pem().unwrap(), key_b.private_key_to_pem().unwrap()).unwrap();
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let internal = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+    let config = tls_config(vec![a.clone()]);
+    let manager = manager::Manager::new(config.clone(), None, Pools::default());
+    manager.listen(internal, handle.clone());
+
+    // a certificate uploaded before the worker starts is sent when the worker subscribes
+    manager.publish_add_certificate(&["b.test".to_string()], &b, handle.clone());
+    assert_eq!(vec!["a.test".to_string(), "b.test".to_string()], manager.certificates());
+
+    let certificates = Certificates::new();
+    certificates.add(&["a.test".to_string()], &a).unwrap();
+    let _subscription = worker::subscribe(internal, handle.clone(), Pools::default(), Some(certificates.clone()));
+    turn_until(&mut core, || certificates.hostnames().len() == 2);
+
+    // later changes are published to the worker as they happen
+    assert!(manager.publish_remove_certificate("a.test", handle.clone()));
+    turn_until(&mut core, || certificates.hostnames() == vec!["b.test".to_string()]);
+
+    assert!(!manager.publish_remove_certificate("a.test", handle.clone()));
+}
+
+#[test]
+fn test_server_tls_ca() {
+    let (cert, key) = self_signed("backend.test");
+    let certificate = Certificate::from_pem(cert.to_pem().unwrap(), key.private_key_to_pem().unwrap()).unwrap();
+    let origin = with_tls_origin(tls::acceptor(&certificate, Certificates::new()).unwrap());
+
+    let tls = ServerTls {
+        ca: Some(String::from_utf8(cert.to_pem().unwrap()).unwrap()),
+        server_name: Some("backend.test".to_string()),
+        ..ServerTls::default()
+    };
+    get_tls_origin(origin, Some(tls), StatusCode::Ok);
+
+    // the self-signed certificate is not trusted by default
+    get_tls_origin(origin, None, StatusCode::BadGateway);
+}
+
+#[test]
+fn test_server_tls_insecure_skip_verify() {
+    let (cert, key) = self_signed("backend.test");
+    let certificate = Certificate::from_pem(cert.to_pem().unwrap(), key.private_key_to_pem().unwrap()).unwrap();
+    let origin = with_tls_origin(tls::acceptor(&certificate, Certificates::new()).unwrap());
+
+    let tls = ServerTls {
+        insecure_skip_verify: true,
+        ..ServerTls::default()
+    };
+    get_tls_origin(origin, Some(tls), StatusCode::Ok);
+}
+
+#[test]
+fn test_server_tls_client_certificate() {
+    let (cert, key) = self_signed("backend.test");
+    let (client_cert, client_key) = self_signed("weldr.test");
+
+    // the origin only accepts clients with the client certificate
+    let der = Pkcs12::builder().build("", "backend.test", &key, &cert).unwrap().to_der().unwrap();
+    let mut acceptor = TlsAcceptor::builder(native_tls::Pkcs12::from_der(&der, "").unwrap()).unwrap();
+    {
+        let context = acceptor.builder_mut().builder_mut();
+        context.set_verify(SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT);
+        context.cert_store_mut().add_cert(client_cert.clone()).unwrap();
+    }
+    let origin = with_tls_origin(acceptor.build().unwrap());
+
+    let mut tls = ServerTls {
+        ca: Some(String::from_utf8(cert.to_pem().unwrap()).unwrap()),
+        server_name: Some("backend.test".to_string()),
+        ..ServerTls::default()
+    };
+    get_tls_origin(origin, Some(tls.clone()), StatusCode::BadGateway);
+
+    tls.cert = Some(String::from_utf8(client_cert.to_pem().unwrap()).unwrap());
+    tls.key = Some(String::from_utf8(client_key.private_key_to_pem().unwrap()).unwrap());
+    get_tls_origin(origin, Some(tls), StatusCode::Ok);
+}
+
 #[test]
 fn test_response_body_streaming() {
 
@@ -293,3 +699,441 @@ fn test_response_body_streaming() {
         Box::new(work)
     })
 }
+
+#[test]
+fn test_sticky_session_cookie() {
+    let mut config = Config::default();
+    config.sticky_cookie = Some("weldr_backend".to_string());
+
+    with_server_config(config, |host, handle| {
+
+        let url = hyper::Uri::from_str(&format!("{}{}", host, "/")).unwrap();
+        let req = client::Request::new(Method::Get, url.clone());
+        let handle1 = handle.clone();
+        let work = client_send_request(req, &handle).and_then(move |res| {
+
+            assert_eq!(res.status, hyper::StatusCode::Ok);
+            let cookie = res.headers
+                .get::<header::SetCookie>()
+                .expect("Missing sticky session cookie")[0]
+                .clone();
+            assert!(cookie.starts_with("weldr_backend="));
+
+            // a client that already has the cookie is not sent it again
+            let mut req = client::Request::new(Method::Get, url);
+            let value = cookie.split(';').next().unwrap().to_string();
+            req.headers_mut().set_raw("Cookie", value);
+            client_send_request(req, &handle1).and_then(|res| {
+                assert_eq!(res.status, hyper::StatusCode::Ok);
+                assert!(res.headers.get::<header::SetCookie>().is_none());
+
+                future::ok(())
+            })
+        });
+
+        Box::new(work)
+    })
+}
+
+#[test]
+fn test_backend_connection_reused_across_clients() {
+    let pool = Pool::default();
+
+    with_server_pool(Config::default(), pool.clone(), |host, handle| {
+
+        // each request is sent by a new client, so it opens a new connection to the proxy
+        let url = hyper::Uri::from_str(&format!("{}{}", host, "/")).unwrap();
+        let req = client::Request::new(Method::Get, url.clone());
+        let handle1 = handle.clone();
+        let work = client_send_request(req, &handle).and_then(move |res| {
+            assert_eq!(res.status, hyper::StatusCode::Ok);
+
+            let req = client::Request::new(Method::Get, url);
+            client_send_request(req, &handle1).map(|res| {
+                assert_eq!(res.status, hyper::StatusCode::Ok);
+            })
+        });
+
+        Box::new(work)
+    });
+
+    let backend = pool.all().pop().unwrap();
+    assert_eq!((2, 1, 1), backend.connection_reuse());
+}
+
+#[test]
+fn test_virtual_host() {
+    // requests for any other host are sent to the default pool, which refuses connections
+    let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let closed_url = format!("http://127.0.0.1:{}", closed.local_addr().unwrap().port());
+    drop(closed);
+
+    let pools = Pools::default();
+    pools.default_pool().add(Server::new(closed_url.parse::<Uri>().unwrap(), false));
+    pools.add("api", vec!["api.test".to_string()]).unwrap();
+
+    with_server_pools(Config::default(), pools.clone(), "api", |host, handle| {
+        let url = hyper::Uri::from_str(&format!("{}{}", host, "/")).unwrap();
+        let mut req = client::Request::new(Method::Get, url);
+        req.headers_mut().set(header::Host::new("api.test", Some(8080)));
+        let work = client_send_request(req, &handle).and_then(move |res| {
+            assert_eq!(res.status, hyper::StatusCode::Ok);
+
+            future::ok(())
+        });
+
+        Box::new(work)
+    });
+
+    let (requests, _, _) = pools.get("api").unwrap().all()[0].connection_reuse();
+    assert_eq!(1, requests);
+    let (requests, _, _) = pools.default_pool().all()[0].connection_reuse();
+    assert_eq!(0, requests);
+}
+
+#[test]
+fn test_path_route() {
+    // requests that do not match the route are sent to the default pool, which refuses connections
+    let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let closed_url = format!("http://127.0.0.1:{}", closed.local_addr().unwrap().port());
+    drop(closed);
+
+    let pools = Pools::default();
+    pools.default_pool().add(Server::new(closed_url.parse::<Uri>().unwrap(), false));
+    pools.add("api", Vec::new()).unwrap();
+    let route = Route::new(RouteSpec {
+        prefix: Some("/api/".to_string()),
+        pool: "api".to_string(),
+        strip_prefix: true,
+        ..RouteSpec::default()
+    });
+    pools.set_routes(vec![route.unwrap()]);
+
+    // the first request goes straight to the origin, so only the second one has its path stripped
+    let proxied = Cell::new(false);
+    with_server_pools(Config::default(), pools.clone(), "api", |host, handle| {
+        let expected = if proxied.replace(true) {
+            "/users/echo-path?page=2"
+        } else {
+            "/api/users/echo-path?page=2"
+        };
+
+        let url = hyper::Uri::from_str(&format!("{}{}", host, "/api/users/echo-path?page=2")).unwrap();
+        let req = client::Request::new(Method::Get, url);
+        let work = client_send_request(req, &handle).and_then(move |res| {
+            assert_eq!(res.status, hyper::StatusCode::Ok);
+            assert_eq!(Some(expected.to_string()), res.body);
+
+            future::ok(())
+        });
+
+        Box::new(work)
+    });
+
+    let (requests, _, _) = pools.get("api").unwrap().all()[0].connection_reuse();
+    assert_eq!(1, requests);
+}
+
+#[test]
+fn test_header_rules() {
+    fn rule(action: Action, name: &str, value: Option<&str>) -> HeaderRule {
+        HeaderRule {
+            action: action,
+            name: name.to_string(),
+            value: value.map(|v| v.to_string()),
+        }
+    }
+
+    let pools = Pools::default();
+    pools.set_headers(DEFAULT_POOL, HeaderRules {
+        request: vec![rule(Action::Set, "X-Forwarded-For", Some("$client_ip via $scheme"))],
+        response: vec![
+            rule(Action::Add, "Strict-Transport-Security", Some("max-age=31536000")),
+            rule(Action::Set, "X-Route", Some("none")),
+        ],
+    });
+
+    // the rules of the route are applied after those of the pool
+    let route = Route::new(RouteSpec {
+        prefix: Some("/forwarded-for".to_string()),
+        pool: DEFAULT_POOL.to_string(),
+        headers: HeaderRules {
+            request: Vec::new(),
+            response: vec![rule(Action::Set, "X-Route", Some("$host"))],
+        },
+        ..RouteSpec::default()
+    });
+    pools.set_routes(vec![route.unwrap()]);
+
+    // the first request goes straight to the origin, so only the second one has its headers changed
+    let proxied = Cell::new(false);
+    with_server_pools(Config::default(), pools, DEFAULT_POOL, |host, handle| {
+        let proxied = proxied.replace(true);
+
+        let url = hyper::Uri::from_str(&format!("{}{}", host, "/forwarded-for")).unwrap();
+        let req = client::Request::new(Method::Get, url);
+        let work = client_send_request(req, &handle).and_then(move |res| {
+            assert_eq!(res.status, hyper::StatusCode::Ok);
+            let raw = |name: &str| {
+                res.headers.get_raw(name).and_then(|raw| raw.one()).map(|value| {
+                    String::from_utf8_lossy(value).into_owned()
+                })
+            };
+
+            if proxied {
+                assert_eq!(Some("127.0.0.1 via http".to_string()), res.body);
+                assert_eq!(Some("max-age=31536000".to_string()), raw("Strict-Transport-Security"));
+                assert_eq!(Some("127.0.0.1".to_string()), raw("X-Route"));
+            } else {
+                assert_eq!(None, raw("Strict-Transport-Security"));
+                assert_eq!(None, raw("X-Route"));
+            }
+
+            future::ok(())
+        });
+
+        Box::new(work)
+    });
+}
+
+#[test]
+fn test_upgrade_tunnel() {
+    let _ = env_logger::init();
+
+    // a server that switches protocols and then echoes back whatever it is sent
+    let origin = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut conn, _) = origin.accept().unwrap();
+        let mut head = Vec::new();
+        let mut byte = [0; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            conn.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+
+        let head = String::from_utf8(head).unwrap();
+        if head.contains("Connection: Upgrade\r\n") && head.contains("Upgrade: websocket\r\n") {
+            conn.write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n").unwrap();
+            std::io::copy(&mut conn.try_clone().unwrap(), &mut conn).unwrap();
+        } else {
+            conn.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n").unwrap();
+        }
+    });
+
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+        let listener = TcpListener::bind(&addr, &handle).unwrap();
+        tx.send(listener.local_addr().unwrap()).unwrap();
+
+        let pool = Pool::default();
+        let url = format!("http://{}", origin_addr).parse::<Uri>().unwrap();
+        pool.add(Server::new(url, false));
+
+        let srv = weldr::proxy::serve(listener, Pools::from(pool), &handle, &Config::default()).unwrap();
+        core.run(srv).unwrap();
+    });
+    let proxy = rx.recv().unwrap();
+
+    let mut client = std::net::TcpStream::connect(proxy).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client
+        .write_all(b"GET /chat HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\nping")
+        .unwrap();
+
+    // bytes sent right after the request head are tunneled as well
+    let expected = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\nping";
+    let mut res = vec![0; expected.len()];
+    client.read_exact(&mut res).unwrap();
+    assert_eq!(&expected[..], &res[..]);
+
+    client.write_all(b"pong").unwrap();
+    let mut res = [0; 4];
+    client.read_exact(&mut res).unwrap();
+    assert_eq!(b"pong", &res);
+}
+
+#[test]
+fn test_upgrade_after_first_request() {
+    let _ = env_logger::init();
+
+    // a server that answers plain requests and switches protocols for upgrade requests, then
+    // echoes back whatever it is sent
+    let origin = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    thread::spawn(move || for conn in origin.incoming() {
+        let mut conn = conn.unwrap();
+        thread::spawn(move || loop {
+            let mut head = Vec::new();
+            let mut byte = [0; 1];
+            while !head.ends_with(b"\r\n\r\n") {
+                if conn.read(&mut byte).unwrap() == 0 {
+                    return;
+                }
+                head.push(byte[0]);
+            }
+
+            let head = String::from_utf8(head).unwrap();
+            if head.contains("Upgrade: websocket\r\n") {
+                conn.write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n").unwrap();
+                std::io::copy(&mut conn.try_clone().unwrap(), &mut conn).unwrap();
+                return;
+            }
+            conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").unwrap();
+        });
+    });
+
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+        let listener = TcpListener::bind(&addr, &handle).unwrap();
+        tx.send(listener.local_addr().unwrap()).unwrap();
+
+        let pool = Pool::default();
+        let url = format!("http://{}", origin_addr).parse::<Uri>().unwrap();
+        pool.add(Server::new(url, false));
+
+        let srv = weldr::proxy::serve(listener, Pools::from(pool), &handle, &Config::default()).unwrap();
+        core.run(srv).unwrap();
+    });
+    let proxy = rx.recv().unwrap();
+
+    let mut client = std::net::TcpStream::connect(proxy).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+    let mut res = Vec::new();
+    let mut byte = [0; 1];
+    while !res.ends_with(b"ok") {
+        client.read_exact(&mut byte).unwrap();
+        res.push(byte[0]);
+    }
+    assert!(res.starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+    // a later request on the same connection can upgrade it
+    client
+        .write_all(b"GET /chat HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\nping")
+        .unwrap();
+    let expected = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\nping";
+    let mut res = vec![0; expected.len()];
+    client.read_exact(&mut res).unwrap();
+    assert_eq!(&expected[..], &res[..]);
+}
+
+#[test]
+fn test_upgrade_refused() {
+    let _ = env_logger::init();
+
+    // a server that refuses to switch protocols and keeps the connection open
+    let origin = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut conn, _) = origin.accept().unwrap();
+        let mut head = Vec::new();
+        let mut byte = [0; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            conn.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+
+        conn.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 3\r\n\r\nbad").unwrap();
+        let _ = std::io::copy(&mut conn, &mut std::io::sink());
+    });
+
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+        let listener = TcpListener::bind(&addr, &handle).unwrap();
+        tx.send(listener.local_addr().unwrap()).unwrap();
+
+        let pool = Pool::default();
+        let url = format!("http://{}", origin_addr).parse::<Uri>().unwrap();
+        pool.add(Server::new(url, false));
+
+        let srv = weldr::proxy::serve(listener, Pools::from(pool), &handle, &Config::default()).unwrap();
+        core.run(srv).unwrap();
+    });
+    let proxy = rx.recv().unwrap();
+
+    let mut client = std::net::TcpStream::connect(proxy).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client
+        .write_all(b"GET /chat HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n")
+        .unwrap();
+
+    // the response is passed on and the connection is closed instead of being spliced
+    let mut res = Vec::new();
+    client.read_to_end(&mut res).unwrap();
+    let res = String::from_utf8(res).unwrap();
+    assert!(res.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", res);
+    assert!(res.contains("Connection: close\r\n"), "{}", res);
+    assert!(res.ends_with("\r\n\r\nbad"), "{}", res);
+}
+
+#[test]
+fn test_retry_on_connect_failure() {
+    // a server that refuses connections
+    let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let closed_url = format!("http://127.0.0.1:{}", closed.local_addr().unwrap().port());
+    drop(closed);
+
+    let pool = Pool::default();
+    pool.add(Server::new(closed_url.parse::<Uri>().unwrap(), false));
+
+    let mut config = Config::default();
+    config.retry.attempts = 1;
+
+    with_server_pool(config, pool, |host, handle| {
+
+        // the request body is sent again to the origin server. Only bodies with a Content-Length
+        // are kept for retries.
+        let url = hyper::Uri::from_str(&format!("{}{}", host, "/echo")).unwrap();
+        let mut req = client::Request::new(Method::Post, url);
+        req.headers_mut().set(ContentLength(5));
+        req.set_body("hello");
+        let work = client_send_request(req, &handle).and_then(move |res| {
+
+            assert_eq!(res.status, hyper::StatusCode::Ok);
+            assert_eq!(res.body.unwrap(), "hello");
+
+            future::ok(())
+        });
+
+        Box::new(work)
+    })
+}
+
+#[test]
+fn test_bad_gateway_on_connect_failure() {
+    // a server that refuses connections
+    let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let closed_url = format!("http://127.0.0.1:{}", closed.local_addr().unwrap().port());
+    drop(closed);
+
+    let pool = Pool::default();
+    pool.add(Server::new(closed_url.parse::<Uri>().unwrap(), false));
+
+    with_server_pool(Config::default(), pool, |host, handle| {
+
+        let url = hyper::Uri::from_str(&format!("{}{}", host, "/")).unwrap();
+        let req = client::Request::new(Method::Get, url);
+        let work = client_send_request(req, &handle).and_then(move |res| {
+
+            assert_eq!(res.status, hyper::StatusCode::BadGateway);
+            assert_eq!(res.body.unwrap(), "502 Bad Gateway\n");
+
+            future::ok(())
+        });
+
+        Box::new(work)
+    })
+}